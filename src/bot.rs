@@ -2,11 +2,13 @@ use crate::*;
 use anyhow::Result;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 use teloxide::{
     dispatching::DefaultKey,
+    types::MessageKind,
     utils::command::{BotCommands, ParseError},
 };
+use tokio::sync::watch;
 
 #[derive(BotCommands, Clone)]
 #[command(
@@ -21,36 +23,111 @@ pub enum Command {
         parse_with = parse_subscribe_message
     )]
     Sub(SubscriptionArgs),
+    #[command(
+        description = "admin: subscribe another chat, e.g. /subfor -1001234 rust limit=5",
+        parse_with = parse_subfor_message
+    )]
+    SubFor(i64, SubscriptionArgs),
     #[command(description = "unsubscribe from subreddit's top posts")]
     Unsub(String),
+    #[command(
+        description = "admin: subscribe every chat in broadcast_chat_ids, e.g. /suball rust",
+        parse_with = parse_subscribe_message
+    )]
+    SubAll(SubscriptionArgs),
+    #[command(description = "admin: unsubscribe every chat in broadcast_chat_ids")]
+    UnsubAll(String),
     #[command(description = "list subreddit subscriptions")]
     ListSubs,
+    #[command(description = "show which posts a subscription would send, without sending them")]
+    TestSub(String),
+    #[command(description = "send the latest post from a subreddit again")]
+    Resend(String),
     #[command(description = "get top posts", parse_with = parse_subscribe_message)]
     Get(SubscriptionArgs),
+    #[command(
+        description = "list top posts without sending media",
+        parse_with = parse_subscribe_message
+    )]
+    Preview(SubscriptionArgs),
+    #[command(description = "search posts, optionally in a subreddit, e.g. /search r/rust async")]
+    Search(String),
+    #[command(description = "set the maximum media size in MB for this chat, or \"off\"")]
+    MaxMedia(String),
+    #[command(description = "hold back new posts during e.g. 23:00-07:00, or \"off\"")]
+    QuietHours(String),
+    #[command(description = "show statistics of this chat")]
+    Stats,
+    #[command(
+        description = "never send posts by a Reddit user in this chat, e.g. /blockauthor spez"
+    )]
+    BlockAuthor(String),
+    #[command(description = "get the url of an RSS feed of the posts sent to this chat")]
+    Feed,
+    #[command(description = "send feedback to the operator of the bot")]
+    Feedback(String),
+    #[command(description = "admin commands: stats")]
+    Admin(String),
+    #[command(description = "show how a post is detected, e.g. /debug v6nu75")]
+    Debug(String),
+    #[command(description = "show a comment, by its link or id")]
+    GetComment(String),
+    #[command(description = "show versions and configuration, for bug reports")]
+    Version,
 }
 
+/// How often a user may send feedback
+const FEEDBACK_MIN_INTERVAL_SECS: i64 = 60;
+
+/// When the bot was started, for reporting uptime
+#[derive(Clone, Copy)]
+pub struct StartedAt(Instant);
+
 pub struct MyBot {
     pub dispatcher: Dispatcher<Arc<Bot>, anyhow::Error, DefaultKey>,
     pub tg: Arc<Bot>,
 }
 
 impl MyBot {
-    pub async fn new(config: Arc<config::Config>) -> Result<Self> {
-        let tg = Arc::new(Bot::new(config.telegram_bot_token.expose_secret()));
+    /// Handlers get the config current when an update arrives, so that a config reloaded on SIGHUP
+    /// applies to commands too. The bot token of the initial config is kept.
+    pub async fn new(config_rx: watch::Receiver<Arc<config::Config>>) -> Result<Self> {
+        let tg = Arc::new(Bot::new(
+            config_rx.borrow().telegram_bot_token.expose_secret(),
+        ));
         tg.set_my_commands(Command::bot_commands()).await?;
 
-        let handler = Update::filter_message().branch(
-            dptree::filter(|msg: Message, config: Arc<config::Config>| {
-                msg.from()
-                    .map(|user| config.authorized_user_ids.contains(&user.id.0))
-                    .unwrap_or_default()
-            })
-            .filter_command::<Command>()
-            .endpoint(handle_command),
-        );
+        let handler = dptree::entry()
+            .map(|config_rx: watch::Receiver<Arc<config::Config>>| config_rx.borrow().clone())
+            .branch(
+                Update::filter_message()
+                    .filter_command::<Command>()
+                    .branch(
+                        dptree::filter(|msg: Message, config: Arc<config::Config>| {
+                            msg.from()
+                                .map(|user| config.is_authorized(user.id.0))
+                                .unwrap_or_default()
+                        })
+                        .endpoint(handle_command),
+                    )
+                    .branch(
+                        dptree::filter(|command: Command, config: Arc<config::Config>| {
+                            is_public_command(&command, &config)
+                        })
+                        .endpoint(handle_command),
+                    )
+                    .endpoint(handle_unauthorized_command),
+            )
+            .branch(
+                Update::filter_callback_query()
+                    .filter(|query: CallbackQuery, config: Arc<config::Config>| {
+                        config.is_authorized(query.from.id.0)
+                    })
+                    .endpoint(handle_callback_query),
+            );
 
         let dispatcher = Dispatcher::builder(tg.clone(), handler)
-            .dependencies(dptree::deps![config.clone()])
+            .dependencies(dptree::deps![config_rx, StartedAt(Instant::now())])
             .default_handler(|upd| async move {
                 warn!("unhandled update: {:?}", upd);
             })
@@ -85,12 +162,14 @@ pub async fn handle_command(
     tg: Arc<Bot>,
     command: Command,
     config: Arc<config::Config>,
+    started_at: StartedAt,
 ) -> Result<()> {
     async fn handle(
         message: &Message,
         tg: &Bot,
         command: Command,
         config: Arc<config::Config>,
+        started_at: StartedAt,
     ) -> Result<()> {
         match command {
             Command::Help => {
@@ -98,119 +177,1068 @@ pub async fn handle_command(
                     .await?;
             }
             Command::Sub(mut args) => {
+                args.message_thread_id = topic_thread_id(message);
+                subscribe(tg, &config, message.chat.id.0, message.chat.id, args).await?;
+            }
+            Command::SubFor(chat_id, args) => {
+                let is_admin = message
+                    .from()
+                    .map(|user| config.is_admin(user.id.0))
+                    .unwrap_or_default();
+                if !is_admin {
+                    tg.send_message(message.chat.id, "Not authorized").await?;
+                    return Ok(());
+                }
+
+                if can_send_to_chat(tg, chat_id, message.chat.id).await? {
+                    subscribe(tg, &config, chat_id, message.chat.id, args).await?;
+                }
+            }
+            // "all" is reserved for removing every subscription, r/all can still be removed
+            // with the prefix
+            Command::Unsub(subreddit) if subreddit.trim() == "all" => {
+                let db = db::Database::open(&config)?;
+                let count = db.get_subscriptions_for_chat(message.chat.id.0)?.len();
+                tg.send_message(
+                    message.chat.id,
+                    format!(
+                        "This removes all {count} subscription(s) of this chat. \
+                         Send /unsub all confirm to proceed."
+                    ),
+                )
+                .await?;
+            }
+            Command::Unsub(subreddit) if subreddit.trim() == "all confirm" => {
+                let db = db::Database::open(&config)?;
+                let count = db.unsubscribe_all(message.chat.id.0)?;
+                tg.send_message(
+                    message.chat.id,
+                    format!("Unsubscribed from {count} subscription(s)"),
+                )
+                .await?;
+            }
+            Command::Unsub(subreddit) => {
                 let db = db::Database::open(&config)?;
                 let chat_id = message.chat.id.0;
-                let subreddit_about = reddit::get_subreddit_about(&args.subreddit).await;
-                match subreddit_about {
-                    Ok(data) => {
-                        args.subreddit = data.display_name;
-                        db.subscribe(chat_id, &args)?;
-                        info!("subscribed in chat id {chat_id} with {args:#?};");
+                let (source_type, name) = parse_source(subreddit.trim());
+                let prefix = source_type.prefix();
+                let reply = match unsubscribe(&db, chat_id, source_type, &name) {
+                    Ok(name) => format!("Unsubscribed from {prefix}{name}"),
+                    Err(_) => format!("Error: Not subscribed to {prefix}{name}"),
+                };
+                tg.send_message(ChatId(chat_id), reply).await?;
+            }
+            Command::SubAll(args) => {
+                let is_admin = message
+                    .from()
+                    .map(|user| config.is_admin(user.id.0))
+                    .unwrap_or_default();
+                if !is_admin {
+                    tg.send_message(message.chat.id, "Not authorized").await?;
+                    return Ok(());
+                }
+                if config.broadcast_chat_ids.is_empty() {
+                    tg.send_message(message.chat.id, "No broadcast_chat_ids configured")
+                        .await?;
+                    return Ok(());
+                }
+
+                // The target is validated once for all chats
+                let Some(args) =
+                    resolve_subscription(tg, &config, message.chat.id, false, args).await?
+                else {
+                    return Ok(());
+                };
+                // A chat that can't be subscribed doesn't prevent subscribing the rest
+                for &chat_id in &config.broadcast_chat_ids {
+                    if !can_send_to_chat(tg, chat_id, message.chat.id).await? {
+                        continue;
+                    }
+                    if let Err(err) =
+                        store_subscription(tg, &config, chat_id, message.chat.id, &args).await
+                    {
+                        error!("failed to subscribe chat_id={chat_id}: {err}");
                         tg.send_message(
-                            ChatId(chat_id),
-                            format!("Subscribed to r/{}", args.subreddit),
+                            message.chat.id,
+                            format!("Failed to subscribe chat {chat_id}: {err}"),
                         )
                         .await?;
                     }
-                    Err(reddit::SubredditAboutError::NoSuchSubreddit) => {
-                        tg.send_message(ChatId(chat_id), "No such subreddit")
-                            .await?;
-                    }
-                    Err(err) => {
-                        Err(err)?;
-                    }
                 }
             }
-            Command::Unsub(subreddit) => {
+            Command::UnsubAll(subreddit) => {
+                let is_admin = message
+                    .from()
+                    .map(|user| config.is_admin(user.id.0))
+                    .unwrap_or_default();
+                if !is_admin {
+                    tg.send_message(message.chat.id, "Not authorized").await?;
+                    return Ok(());
+                }
+
                 let db = db::Database::open(&config)?;
-                let chat_id = message.chat.id.0;
-                let subreddit = subreddit.replace("r/", "");
-                let reply = match db.unsubscribe(chat_id, &subreddit) {
-                    Ok(sub) => format!("Unsubscribed from r/{sub}"),
-                    Err(_) => format!("Error: Not subscribed to r/{subreddit}"),
-                };
-                tg.send_message(ChatId(chat_id), reply).await?;
+                let (source_type, name) = parse_source(subreddit.trim());
+                let prefix = source_type.prefix();
+                let count = config
+                    .broadcast_chat_ids
+                    .iter()
+                    .filter(|&&chat_id| unsubscribe(&db, chat_id, source_type, &name).is_ok())
+                    .count();
+                info!("unsubscribed {prefix}{name} in {count} broadcast chat(s)");
+                tg.send_message(
+                    message.chat.id,
+                    format!(
+                        "Unsubscribed from {prefix}{name} in {count} of {} chat(s)",
+                        config.broadcast_chat_ids.len()
+                    ),
+                )
+                .await?;
             }
             Command::ListSubs => {
                 let db = db::Database::open(&config)?;
                 let subs = db.get_subscriptions_for_chat(message.chat.id.0)?;
-                let reply = messages::format_subscription_list(&subs);
-                tg.send_message(message.chat.id, reply).await?;
+                let (reply, keyboard) = subscription_list_page(&subs, 0, SUBSCRIPTIONS_PAGE_SIZE);
+                let mut request = tg.send_message(message.chat.id, reply);
+                if let Some(keyboard) = keyboard {
+                    request = request.reply_markup(keyboard);
+                }
+                request.await?;
+            }
+            Command::TestSub(subreddit) => {
+                let db = db::Database::open(&config)?;
+                let chat_id = message.chat.id.0;
+                let (source_type, name) = parse_source(subreddit.trim());
+                let prefix = source_type.prefix();
+                let sub = db
+                    .get_subscriptions_for_chat(chat_id)?
+                    .into_iter()
+                    .find(|sub| {
+                        sub.source_type == source_type && sub.subreddit.eq_ignore_ascii_case(&name)
+                    });
+                let Some(sub) = sub else {
+                    tg.send_message(
+                        message.chat.id,
+                        format!("Error: Not subscribed to {prefix}{name}"),
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                // Same listing as the subscription's checks, but nothing is sent or marked seen
+                let limit = config.resolve_limit(sub.limit);
+                let time = config.resolve_time(sub.time);
+                let filter = config.resolve_filter(sub.filter);
+                let max_age_hours = config.resolve_max_age_hours(sub.max_age_hours);
+                let blocked_authors = db.get_blocked_authors(chat_id)?;
+                let posts = get_subscription_posts(&reddit::ApiClient, &sub, limit, &time)
+                    .await
+                    .context("failed to get posts")?;
+                let now = chrono::Utc::now();
+                let matching = posts
+                    .iter()
+                    .filter(|p| filter.is_none() || filter.as_ref() == Some(&p.post_type))
+                    .filter(|p| !max_age_hours.is_some_and(|hours| p.is_older_than(hours, now)))
+                    .filter(|p| !config.is_post_blocked(p))
+                    .filter(|p| !config::is_author_blocked(&blocked_authors, &p.author))
+                    .map(|p| Ok((p, db.is_post_seen(chat_id, p)?)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let reply = messages::format_subscription_test(
+                    &format!("{prefix}{}", sub.subreddit),
+                    limit,
+                    time,
+                    filter,
+                    posts.len(),
+                    &matching,
+                );
+                tg.send_message(message.chat.id, reply)
+                    .disable_web_page_preview(true)
+                    .await?;
+            }
+            Command::Resend(subreddit) => {
+                let chat_id = message.chat.id.0;
+                let (source_type, name) = parse_source(subreddit.trim());
+                if name.is_empty() || source_type != SourceType::Subreddit {
+                    tg.send_message(message.chat.id, "Usage: /resend <subreddit>")
+                        .await?;
+                    return Ok(());
+                }
+
+                let db = db::Database::open(&config)?;
+                let Some(post_id) = db.latest_seen_post(chat_id, &name)? else {
+                    tg.send_message(message.chat.id, format!("No posts sent from r/{name} yet"))
+                        .await?;
+                    return Ok(());
+                };
+
+                // Seen state is left as is, as the post has been sent before
+                let post = match reddit::get_link(&post_id).await {
+                    Ok(post) if !post.is_deleted() => post,
+                    Ok(_) => {
+                        tg.send_message(
+                            message.chat.id,
+                            format!("Post {post_id} has been deleted from Reddit"),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        warn!("failed to get post for resend post_id={post_id}: {err:?}");
+                        tg.send_message(
+                            message.chat.id,
+                            format!("Post {post_id} is no longer available on Reddit"),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                };
+                let chat_settings = db.get_chat_settings(chat_id)?;
+                let mut media_options =
+                    config.resolve_media_options(None, None, None, &chat_settings);
+                media_options.show_progress = true;
+                handle_new_post(
+                    &config,
+                    tg,
+                    chat_id,
+                    topic_thread_id(message),
+                    &post,
+                    &media_options,
+                )
+                .await?;
             }
             Command::Get(args) => {
                 let subreddit = &args.subreddit;
-                let limit = args
-                    .limit
-                    .or(config.default_limit)
-                    .unwrap_or(config::DEFAULT_LIMIT);
-                let time = args
-                    .time
-                    .or(config.default_time)
-                    .unwrap_or(config::DEFAULT_TIME_PERIOD);
-                let filter = args.filter.or(config.default_filter);
+                let limit = config.resolve_limit(args.limit);
+                let time = config.resolve_time(args.time);
+                let filter = config.resolve_filter(args.filter);
+                let max_age_hours = config.resolve_max_age_hours(args.max_age_hours);
                 let chat_id = message.chat.id.0;
+                let db = db::Database::open(&config)?;
+                let chat_settings = db.get_chat_settings(chat_id)?;
+                let blocked_authors = db.get_blocked_authors(chat_id)?;
+                let mut media_options = config.resolve_media_options(
+                    args.max_media_mb,
+                    args.ytdlp_format.as_deref(),
+                    args.include_top_comment,
+                    &chat_settings,
+                );
+                media_options.show_progress = true;
 
-                let posts = reddit::get_subreddit_top_posts(subreddit, limit, &time)
-                    .await
-                    .context("failed to get posts")?
-                    .into_iter()
-                    .filter(|p| {
-                        if filter.is_some() {
-                            filter.as_ref() == Some(&p.post_type)
-                        } else {
-                            true
-                        }
-                    })
-                    .collect::<Vec<_>>();
+                let now = chrono::Utc::now();
+                let posts = get_sorted_posts(
+                    &reddit::ApiClient,
+                    args.source_type,
+                    subreddit,
+                    args.sort,
+                    limit,
+                    &time,
+                )
+                .await
+                .context("failed to get posts")?
+                .into_iter()
+                .filter(|p| {
+                    if filter.is_some() {
+                        filter.as_ref() == Some(&p.post_type)
+                    } else {
+                        true
+                    }
+                })
+                .filter(|p| !max_age_hours.is_some_and(|hours| p.is_older_than(hours, now)))
+                .filter(|p| !config.is_post_blocked(p))
+                .filter(|p| !config::is_author_blocked(&blocked_authors, &p.author))
+                .collect::<Vec<_>>();
 
                 debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
 
                 if !posts.is_empty() {
-                    for post in posts {
-                        if let Err(e) = handle_new_post(&config, tg, chat_id, &post).await {
-                            error!("failed to handle new post: {e}");
+                    let mut failure_reasons = vec![];
+                    for post in &posts {
+                        if let Err(e) = handle_new_post(
+                            &config,
+                            tg,
+                            chat_id,
+                            topic_thread_id(message),
+                            post,
+                            &media_options,
+                        )
+                        .await
+                        {
+                            error!("failed to handle new post post_id={}: {e:?}", post.id);
+                            failure_reasons.push(messages::format_send_failure_reason(&e));
                         }
                     }
+                    // Sent posts speak for themselves, so the summary is only for failures
+                    if !failure_reasons.is_empty() {
+                        let summary = messages::format_get_summary(posts.len(), &failure_reasons);
+                        tg.send_message(message.chat.id, summary).await?;
+                    }
                 } else {
                     tg.send_message(message.chat.id, "No posts found").await?;
                 }
             }
+            Command::Preview(args) => {
+                let subreddit = &args.subreddit;
+                let limit = config.resolve_limit(args.limit);
+                let time = config.resolve_time(args.time);
+                let filter = config.resolve_filter(args.filter);
+
+                let posts = get_sorted_posts(
+                    &reddit::ApiClient,
+                    args.source_type,
+                    subreddit,
+                    args.sort,
+                    limit,
+                    &time,
+                )
+                .await
+                .context("failed to get posts")?
+                .into_iter()
+                .filter(|p| filter.is_none() || filter.as_ref() == Some(&p.post_type))
+                .collect::<Vec<_>>();
+
+                let reply = messages::format_post_list(&posts, config.links_base_url.as_deref());
+                tg.send_message(message.chat.id, reply)
+                    .parse_mode(teloxide::types::ParseMode::Html)
+                    .disable_web_page_preview(true)
+                    .await?;
+            }
+            Command::Search(input) => {
+                let (subreddit, query) = parse_search_query(&input);
+                if query.is_empty() {
+                    tg.send_message(message.chat.id, "Usage: /search [r/<subreddit>] <query>")
+                        .await?;
+                    return Ok(());
+                }
+
+                let posts = reddit::search_posts(query, subreddit, SEARCH_RESULTS_LIMIT).await?;
+                let reply = messages::format_post_list(&posts, config.links_base_url.as_deref());
+                let mut request = tg
+                    .send_message(message.chat.id, reply)
+                    .parse_mode(teloxide::types::ParseMode::Html)
+                    .disable_web_page_preview(true);
+                if !posts.is_empty() {
+                    request = request.reply_markup(build_search_keyboard(&posts));
+                }
+                request.await?;
+            }
+            Command::MaxMedia(value) => {
+                let db = db::Database::open(&config)?;
+                let chat_id = message.chat.id.0;
+                let reply = match value.trim() {
+                    "off" => {
+                        db.set_chat_max_media_mb(chat_id, None)?;
+                        "Maximum media size unset".to_string()
+                    }
+                    value => match value.parse::<u32>() {
+                        Ok(max_media_mb) => {
+                            db.set_chat_max_media_mb(chat_id, Some(max_media_mb))?;
+                            format!("Media larger than {max_media_mb} MB will be sent as links")
+                        }
+                        Err(_) => "Usage: /maxmedia <megabytes|off>".to_string(),
+                    },
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::QuietHours(value) => {
+                let db = db::Database::open(&config)?;
+                let chat_id = message.chat.id.0;
+                let reply = match value.trim() {
+                    "off" => {
+                        db.set_chat_quiet_hours(chat_id, None)?;
+                        "Quiet hours unset".to_string()
+                    }
+                    value => match value.parse::<QuietHours>() {
+                        Ok(quiet_hours) => {
+                            db.set_chat_quiet_hours(chat_id, Some(quiet_hours))?;
+                            format!("New posts will be held back during {quiet_hours}")
+                        }
+                        Err(_) => "Usage: /quiethours <HH:MM-HH:MM|off>".to_string(),
+                    },
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::BlockAuthor(author) => {
+                let author = author.trim().trim_start_matches('/');
+                let author = author.strip_prefix("u/").unwrap_or(author);
+                if author.is_empty() || author.contains(char::is_whitespace) {
+                    tg.send_message(message.chat.id, "Usage: /blockauthor <username>")
+                        .await?;
+                    return Ok(());
+                }
+
+                let db = db::Database::open(&config)?;
+                let reply = if db.block_author(message.chat.id.0, author)? {
+                    info!(
+                        "blocked author author={author} chat_id={}",
+                        message.chat.id.0
+                    );
+                    format!("Posts by u/{author} won't be sent in this chat")
+                } else {
+                    format!("u/{author} is already blocked in this chat")
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Feed => {
+                let Some(health_addr) = config.health_addr else {
+                    tg.send_message(message.chat.id, "RSS feeds are not enabled")
+                        .await?;
+                    return Ok(());
+                };
+                let db = db::Database::open(&config)?;
+                let feed_token = db.get_or_create_feed_token(message.chat.id.0)?;
+                let base_url = config
+                    .feed_base_url
+                    .clone()
+                    .unwrap_or_else(|| format!("http://{health_addr}"));
+                tg.send_message(
+                    message.chat.id,
+                    format!(
+                        "RSS feed of this chat: {}\nAnyone with the link can read the feed.",
+                        feed::format_feed_url(&base_url, &feed_token)
+                    ),
+                )
+                .disable_web_page_preview(true)
+                .await?;
+            }
+            Command::Stats => {
+                let db = db::Database::open(&config)?;
+                let stats = db.get_chat_stats(message.chat.id.0)?;
+                tg.send_message(message.chat.id, messages::format_chat_stats(&stats))
+                    .await?;
+            }
+            Command::Version => {
+                // Running yt-dlp blocks, so it's done outside of the async runtime
+                let ytdlp_config = config.clone();
+                let ytdlp_version =
+                    tokio::task::spawn_blocking(move || ytdlp::check_installed(&ytdlp_config))
+                        .await?
+                        .map_err(|err| warn!("failed to get yt-dlp version: {err}"))
+                        .ok();
+                let db_size = std::fs::metadata(&config.db_path).ok().map(|m| m.len());
+                let reply = messages::format_version(
+                    ytdlp_version.as_deref(),
+                    &config.db_path,
+                    db_size,
+                    config.reddit_client_id.is_some() && config.reddit_client_secret.is_some(),
+                );
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Feedback(text) => {
+                let text = text.trim();
+                let user = match message.from() {
+                    Some(user) => user,
+                    None => return Ok(()),
+                };
+                if text.is_empty() {
+                    tg.send_message(message.chat.id, "Usage: /feedback <text>")
+                        .await?;
+                    return Ok(());
+                }
+
+                let db = db::Database::open(&config)?;
+                let now = chrono::Utc::now();
+                if let Some(last_feedback_at) = db.get_last_feedback_at(user.id.0)? {
+                    if now - last_feedback_at
+                        < chrono::Duration::seconds(FEEDBACK_MIN_INTERVAL_SECS)
+                    {
+                        tg.send_message(
+                            message.chat.id,
+                            "Feedback was sent recently, please try again later",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                }
+
+                let chat_id = message.chat.id.0;
+                db.add_feedback(chat_id, user.id.0, text)?;
+                info!("got feedback user_id={} chat_id={chat_id}", user.id.0);
+                if let Some(operator_chat_id) = config.operator_chat_id {
+                    let feedback = messages::format_feedback(user, chat_id, text);
+                    if let Err(err) = tg.send_message(ChatId(operator_chat_id), feedback).await {
+                        error!("failed to forward feedback to operator: {err}");
+                    }
+                }
+                tg.send_message(message.chat.id, "Thanks for the feedback!")
+                    .await?;
+            }
+            Command::Admin(subcommand) => {
+                let is_admin = message
+                    .from()
+                    .map(|user| config.is_admin(user.id.0))
+                    .unwrap_or_default();
+                if !is_admin {
+                    tg.send_message(message.chat.id, "Not authorized").await?;
+                    return Ok(());
+                }
+
+                match subcommand.trim() {
+                    "stats" => {
+                        let db = db::Database::open(&config)?;
+                        let stats = db.get_stats()?;
+                        let db_size = std::fs::metadata(&config.db_path).ok().map(|m| m.len());
+                        let reply =
+                            messages::format_admin_stats(&stats, db_size, started_at.0.elapsed());
+                        tg.send_message(message.chat.id, reply).await?;
+                    }
+                    _ => {
+                        tg.send_message(message.chat.id, "Usage: /admin stats")
+                            .await?;
+                    }
+                }
+            }
+            Command::GetComment(input) => {
+                let comment_id = match reddit::parse_comment_id(&input) {
+                    Some(comment_id) => comment_id,
+                    None => {
+                        tg.send_message(message.chat.id, "Usage: /getcomment <link or id>")
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                let comment = reddit::get_comment(&comment_id).await?;
+                let reply = messages::format_comment(&comment, &config);
+                tg.send_message(message.chat.id, reply)
+                    .parse_mode(config.parse_mode.into())
+                    .disable_web_page_preview(true)
+                    .await?;
+            }
+            Command::Debug(post_id) => {
+                let post_id = post_id.trim();
+                let post_id = post_id.strip_prefix("t3_").unwrap_or(post_id);
+                if post_id.is_empty() {
+                    tg.send_message(message.chat.id, "Usage: /debug <post_id>")
+                        .await?;
+                    return Ok(());
+                }
+                let post = reddit::get_link(post_id).await?;
+                tg.send_message(message.chat.id, messages::format_post_debug(&post))
+                    .await?;
+            }
         };
 
         Ok(())
     }
 
-    if let Err(err) = handle(&message, &tg, command, config).await {
-        error!("failed to handle message: {}", err);
-        tg.send_message(message.chat.id, "Something went wrong")
+    if let Err(err) = handle(&message, &tg, command, config, started_at).await {
+        error!("failed to handle message: {:?}", err);
+        tg.send_message(message.chat.id, messages::format_error_reply(&err))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Whether anyone may use the command. Only read-only commands can be made public.
+fn is_public_command(command: &Command, config: &config::Config) -> bool {
+    let public_command = match command {
+        Command::Help => config::PublicCommand::Help,
+        Command::Preview(_) => config::PublicCommand::Preview,
+        _ => return false,
+    };
+    config.public_commands.contains(&public_command)
+}
+
+/// Lets users who aren't authorized know why their command does nothing, which is otherwise
+/// confusing in group chats
+async fn handle_unauthorized_command(message: Message, tg: Arc<Bot>) -> Result<()> {
+    info!(
+        "command from unauthorized user user_id={:?} chat_id={}",
+        message.from().map(|user| user.id.0),
+        message.chat.id.0
+    );
+    tg.send_message(message.chat.id, "Not authorized")
+        .reply_to_message_id(message.id)
+        .await?;
+    Ok(())
+}
+
+/// Forum topic a message was sent in. Messages outside topics can still carry a thread id when
+/// they are replies, so only topic messages count.
+fn topic_thread_id(message: &Message) -> Option<i32> {
+    match &message.kind {
+        MessageKind::Common(common) if common.is_topic_message => message.thread_id,
+        _ => None,
+    }
+}
+
+/// A chat can subscribe to either the posts or the comments of a subreddit, and both are
+/// unsubscribed from with r/<subreddit>. Returns the name of the removed subscription.
+fn unsubscribe(
+    db: &db::Database,
+    chat_id: i64,
+    source_type: SourceType,
+    name: &str,
+) -> Result<String> {
+    db.unsubscribe(chat_id, source_type, name)
+        .or_else(|err| match source_type {
+            SourceType::Subreddit => db.unsubscribe(chat_id, SourceType::Comments, name),
+            _ => Err(err),
+        })
+}
+
+/// The bot must be able to post in a chat for a subscription made for it to be of any use.
+/// Replies to the admin when it can't.
+async fn can_send_to_chat(tg: &Bot, chat_id: i64, reply_to: ChatId) -> Result<bool> {
+    match tg
+        .send_chat_action(ChatId(chat_id), teloxide::types::ChatAction::Typing)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            tg.send_message(
+                reply_to,
+                format!("Can't send messages to chat {chat_id}: {err}"),
+            )
             .await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Validates the subscription target and subscribes the chat to it
+async fn subscribe(
+    tg: &Bot,
+    config: &config::Config,
+    chat_id: i64,
+    reply_to: ChatId,
+    args: SubscriptionArgs,
+) -> Result<()> {
+    // Suggestions are not offered for /subfor as tapping one would subscribe the admin's chat
+    let offer_suggestions = reply_to.0 == chat_id;
+    if let Some(args) = resolve_subscription(tg, config, reply_to, offer_suggestions, args).await? {
+        store_subscription(tg, config, chat_id, reply_to, &args).await?;
+    }
+    Ok(())
+}
+
+/// Validates the subscription target and resolves it to its canonical name. Returns None when
+/// the target doesn't exist, after telling why in reply_to.
+async fn resolve_subscription(
+    tg: &Bot,
+    config: &config::Config,
+    reply_to: ChatId,
+    offer_suggestions: bool,
+    mut args: SubscriptionArgs,
+) -> Result<Option<SubscriptionArgs>> {
+    if let Some(webhook) = &args.webhook {
+        if !config.webhooks.contains_key(webhook) {
+            tg.send_message(reply_to, format!("No such webhook: {webhook}"))
+                .await?;
+            return Ok(None);
+        }
+    }
+    match args.source_type {
+        SourceType::Subreddit | SourceType::Comments => {
+            // Each subreddit of a multireddit like rust+golang is validated separately and stored
+            // with its canonical name
+            let mut subreddits = vec![];
+            for subreddit in reddit::split_multireddit(&args.subreddit) {
+                match reddit::get_subreddit_about(subreddit).await {
+                    Ok(data) => subreddits.push(data.display_name),
+                    Err(reddit::SubredditAboutError::NoSuchSubreddit) => {
+                        // Suggestions are offered only when subscribing to a single subreddit
+                        let suggestions = if subreddit == args.subreddit && offer_suggestions {
+                            get_subreddit_suggestions(subreddit).await
+                        } else {
+                            vec![]
+                        };
+                        let text = format!("No such subreddit: r/{subreddit}");
+                        if suggestions.is_empty() {
+                            tg.send_message(reply_to, text).await?;
+                        } else {
+                            let keyboard = build_suggestions_keyboard(&args, &suggestions);
+                            tg.send_message(reply_to, format!("{text}\nDid you mean:"))
+                                .reply_markup(keyboard)
+                                .await?;
+                        }
+                        return Ok(None);
+                    }
+                    Err(reddit::SubredditAboutError::PrivateOrBanned) => {
+                        tg.send_message(reply_to, format!("r/{subreddit} is private or banned"))
+                            .await?;
+                        return Ok(None);
+                    }
+                    Err(err) => {
+                        Err(err)?;
+                    }
+                }
+            }
+            args.subreddit = subreddits.join("+");
+        }
+        SourceType::User => match reddit::get_user_about(&args.subreddit).await {
+            Ok(data) => args.subreddit = data.name,
+            Err(reddit::UserAboutError::NoSuchUser) => {
+                tg.send_message(reply_to, "No such user").await?;
+                return Ok(None);
+            }
+            Err(err) => {
+                Err(err)?;
+            }
+        },
+        SourceType::Multireddit => match reddit::get_multireddit_about(&args.subreddit).await {
+            Ok(data) => {
+                if let Some(multireddit) = reddit::parse_multireddit(&data.path) {
+                    args.subreddit = multireddit;
+                }
+            }
+            Err(reddit::MultiredditAboutError::NoSuchMultireddit) => {
+                tg.send_message(reply_to, "No such multireddit").await?;
+                return Ok(None);
+            }
+            Err(err) => {
+                Err(err)?;
+            }
+        },
     }
+    Ok(Some(args))
+}
 
+/// Subscribes the chat to an already resolved subscription target
+async fn store_subscription(
+    tg: &Bot,
+    config: &config::Config,
+    chat_id: i64,
+    reply_to: ChatId,
+    args: &SubscriptionArgs,
+) -> Result<()> {
+    let db = db::Database::open(config)?;
+    db.subscribe(chat_id, args)?;
+    info!("subscribed in chat id {chat_id} with {args:#?};");
+    // Subscriptions made with /subfor and /suball are confirmed in the admin's chat
+    let in_chat = if reply_to.0 == chat_id {
+        String::new()
+    } else {
+        format!(" in chat {chat_id}")
+    };
+    let text = match (args.source_type, &args.keyword) {
+        (SourceType::Subreddit | SourceType::Comments, Some(keyword)) => format!(
+            "Subscribed to comments of r/{} containing \"{keyword}\"{in_chat}",
+            args.subreddit
+        ),
+        (SourceType::Subreddit | SourceType::Comments, None) => {
+            format!("Subscribed to r/{}{in_chat}", args.subreddit)
+        }
+        (SourceType::User | SourceType::Multireddit, _) => {
+            format!("Subscribed to u/{}{in_chat}", args.subreddit)
+        }
+    };
+    tg.send_message(reply_to, text).await?;
     Ok(())
 }
 
+const SUBSCRIBE_CALLBACK_PREFIX: &str = "sub:";
+const GET_POST_CALLBACK_PREFIX: &str = "get:";
+const LIST_SUBS_CALLBACK_PREFIX: &str = "subs:";
+/// Subscriptions shown per message of /listsubs, well within Telegram's message length limit
+const SUBSCRIPTIONS_PAGE_SIZE: usize = 25;
+const SEARCH_RESULTS_LIMIT: u32 = 5;
+/// Telegram's limit for the size of callback data
+const CALLBACK_DATA_MAX_BYTES: usize = 64;
+const MAX_SUGGESTIONS: u32 = 3;
+
+/// Names of subreddits similar to the given one. Failing to search is not worth an error, so it
+/// results in no suggestions.
+async fn get_subreddit_suggestions(subreddit: &str) -> Vec<String> {
+    match reddit::search_subreddits(subreddit, MAX_SUGGESTIONS).await {
+        Ok(subreddits) => subreddits
+            .into_iter()
+            .map(|subreddit| subreddit.display_name)
+            .take(MAX_SUGGESTIONS as usize)
+            .collect(),
+        Err(err) => {
+            warn!("failed to search subreddits: {err}");
+            vec![]
+        }
+    }
+}
+
+/// Formats subscription args in the syntax of the /sub command, so that they can be parsed with
+/// parse_subscribe_message
+fn format_subscribe_args(args: &SubscriptionArgs) -> String {
+    let mut parts = vec![format!("{}{}", args.source_type.prefix(), args.subreddit)];
+    match args.limit {
+        Some(config::LIMIT_MAX) => parts.push("limit=max".to_string()),
+        Some(limit) => parts.push(format!("limit={limit}")),
+        None => {}
+    }
+    if let Some(time) = args.time {
+        parts.push(format!("time={time}"));
+    }
+    if let Some(sort) = args.sort {
+        parts.push(format!("sort={sort}"));
+    }
+    if let Some(filter) = args.filter {
+        parts.push(format!("filter={filter}"));
+    }
+    if let Some(max_media_mb) = args.max_media_mb {
+        parts.push(format!("max_media_mb={max_media_mb}"));
+    }
+    if let Some(group_images) = args.group_images {
+        parts.push(format!("group_images={group_images}"));
+    }
+    if let Some(include_top_comment) = args.include_top_comment {
+        parts.push(format!("include_top_comment={include_top_comment}"));
+    }
+    if let Some(max_age_hours) = args.max_age_hours {
+        parts.push(format!("max_age_hours={max_age_hours}"));
+    }
+    if let Some(digest) = args.digest {
+        parts.push(format!("digest={digest}"));
+    }
+    if let Some(ytdlp_format) = &args.ytdlp_format {
+        parts.push(format!("format={ytdlp_format}"));
+    }
+    if args.source_type == SourceType::Comments {
+        parts.push("source=comments".to_string());
+    }
+    if let Some(keyword) = &args.keyword {
+        parts.push(format!("keyword={keyword}"));
+    }
+    if let Some(webhook) = &args.webhook {
+        parts.push(format!("webhook={webhook}"));
+    }
+    parts.join(" ")
+}
+
+/// Splits a search into the subreddit it's restricted to, given as a leading r/<subreddit>, and
+/// the query
+fn parse_search_query(input: &str) -> (Option<&str>, &str) {
+    let input = input.trim();
+    match input.split_once(char::is_whitespace) {
+        Some((first, query)) => {
+            let first = first.strip_prefix('/').unwrap_or(first);
+            match first.strip_prefix("r/") {
+                Some(subreddit) if !subreddit.is_empty() => (Some(subreddit), query.trim()),
+                _ => (None, input),
+            }
+        }
+        None => (None, input),
+    }
+}
+
+/// A row of buttons for each search result, numbered as in the list: one sends the post to the
+/// chat and the other subscribes to its subreddit
+fn build_search_keyboard(posts: &[reddit::Post]) -> teloxide::types::InlineKeyboardMarkup {
+    let buttons = posts.iter().enumerate().map(|(i, post)| {
+        vec![
+            teloxide::types::InlineKeyboardButton::callback(
+                format!("{}. Get", i + 1),
+                format!("{GET_POST_CALLBACK_PREFIX}{}", post.id),
+            ),
+            teloxide::types::InlineKeyboardButton::callback(
+                format!("Sub r/{}", post.subreddit),
+                format!("{SUBSCRIBE_CALLBACK_PREFIX}r/{}", post.subreddit),
+            ),
+        ]
+    });
+    teloxide::types::InlineKeyboardMarkup::new(buttons)
+}
+
+/// One page of the subscription list, with prev/next buttons when there's more than one page. A
+/// page past the end shows the last page, as subscriptions may have been removed in between.
+fn subscription_list_page(
+    subs: &[Subscription],
+    page: usize,
+    page_size: usize,
+) -> (String, Option<teloxide::types::InlineKeyboardMarkup>) {
+    if subs.len() <= page_size {
+        return (messages::format_subscription_list(subs), None);
+    }
+
+    let page_count = subs.len().div_ceil(page_size);
+    let page = page.min(page_count - 1);
+    let start = page * page_size;
+    let end = (start + page_size).min(subs.len());
+    let text = format!(
+        "{}\n\nPage {}/{page_count}",
+        messages::format_subscription_list(&subs[start..end]),
+        page + 1
+    );
+
+    let mut buttons = vec![];
+    if page > 0 {
+        buttons.push(teloxide::types::InlineKeyboardButton::callback(
+            "« Prev",
+            format!("{LIST_SUBS_CALLBACK_PREFIX}{}", page - 1),
+        ));
+    }
+    if page + 1 < page_count {
+        buttons.push(teloxide::types::InlineKeyboardButton::callback(
+            "Next »",
+            format!("{LIST_SUBS_CALLBACK_PREFIX}{}", page + 1),
+        ));
+    }
+    (
+        text,
+        Some(teloxide::types::InlineKeyboardMarkup::new([buttons])),
+    )
+}
+
+/// Buttons that subscribe to a suggested subreddit with the otherwise same args. If the args don't
+/// fit in callback data, only the name is kept.
+fn build_suggestions_keyboard(
+    args: &SubscriptionArgs,
+    suggestions: &[String],
+) -> teloxide::types::InlineKeyboardMarkup {
+    let buttons = suggestions.iter().map(|suggestion| {
+        let suggested_args = SubscriptionArgs {
+            subreddit: suggestion.clone(),
+            ..args.clone()
+        };
+        let mut data = format!(
+            "{SUBSCRIBE_CALLBACK_PREFIX}{}",
+            format_subscribe_args(&suggested_args)
+        );
+        if data.len() > CALLBACK_DATA_MAX_BYTES {
+            data = format!("{SUBSCRIBE_CALLBACK_PREFIX}r/{suggestion}");
+        }
+        vec![teloxide::types::InlineKeyboardButton::callback(
+            format!("r/{suggestion}"),
+            data,
+        )]
+    });
+    teloxide::types::InlineKeyboardMarkup::new(buttons)
+}
+
+pub async fn handle_callback_query(
+    query: CallbackQuery,
+    tg: Arc<Bot>,
+    config: Arc<config::Config>,
+) -> Result<()> {
+    tg.answer_callback_query(&query.id).await?;
+    let (data, message) = match (query.data, query.message) {
+        (Some(data), Some(message)) => (data, message),
+        _ => return Ok(()),
+    };
+
+    if let Some(args) = data.strip_prefix(SUBSCRIBE_CALLBACK_PREFIX) {
+        match parse_subscribe_message(args.to_string()) {
+            Ok((mut args,)) => {
+                args.message_thread_id = topic_thread_id(&message);
+                if let Err(err) =
+                    subscribe(&tg, &config, message.chat.id.0, message.chat.id, args).await
+                {
+                    error!("failed to handle callback query: {err:?}");
+                    tg.send_message(message.chat.id, messages::format_error_reply(&err))
+                        .await?;
+                }
+            }
+            Err(err) => error!("invalid subscribe callback data {data}: {err}"),
+        }
+    }
+
+    if let Some(page) = data.strip_prefix(LIST_SUBS_CALLBACK_PREFIX) {
+        match page.parse::<usize>() {
+            Ok(page) => {
+                let subs =
+                    db::Database::open(&config)?.get_subscriptions_for_chat(message.chat.id.0)?;
+                let (text, keyboard) = subscription_list_page(&subs, page, SUBSCRIPTIONS_PAGE_SIZE);
+                let mut request = tg.edit_message_text(message.chat.id, message.id, text);
+                if let Some(keyboard) = keyboard {
+                    request = request.reply_markup(keyboard);
+                }
+                request.await?;
+            }
+            Err(err) => error!("invalid list subs callback data {data}: {err}"),
+        }
+    }
+
+    if let Some(post_id) = data.strip_prefix(GET_POST_CALLBACK_PREFIX) {
+        if let Err(err) = get_post(&tg, &config, &message, post_id).await {
+            error!("failed to handle callback query: {err:?}");
+            tg.send_message(message.chat.id, messages::format_error_reply(&err))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a post to the chat of the message, like /get does
+async fn get_post(
+    tg: &Bot,
+    config: &config::Config,
+    message: &Message,
+    post_id: &str,
+) -> Result<()> {
+    let chat_id = message.chat.id.0;
+    let chat_settings = db::Database::open(config)?.get_chat_settings(chat_id)?;
+    let mut media_options = config.resolve_media_options(None, None, None, &chat_settings);
+    media_options.show_progress = true;
+    let post = reddit::get_link(post_id).await?;
+    handle_new_post(
+        config,
+        tg,
+        chat_id,
+        topic_thread_id(message),
+        &post,
+        &media_options,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Splits a subscription target like `r/rust`, `/u/spez`, `rust` or a saved multireddit URL into
+/// its source type and name. Names without a prefix are subreddits.
+fn parse_source(input: &str) -> (SourceType, String) {
+    if let Some(multireddit) = reddit::parse_multireddit(input) {
+        return (SourceType::Multireddit, multireddit);
+    }
+
+    let input = input.strip_prefix('/').unwrap_or(input);
+    match input.strip_prefix("u/") {
+        Some(user) => (SourceType::User, user.to_string()),
+        None => (
+            SourceType::Subreddit,
+            input.strip_prefix("r/").unwrap_or(input).to_string(),
+        ),
+    }
+}
+
+/// Parses the target chat id of /subfor followed by the same arguments as /sub
+fn parse_subfor_message(input: String) -> Result<(i64, SubscriptionArgs), ParseError> {
+    let input = input.trim_start();
+    let (chat_id, rest) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let chat_id = chat_id
+        .parse()
+        .map_err(|_| ParseError::Custom(format!("Invalid chat id: {chat_id}").into()))?;
+    let (args,) = parse_subscribe_message(rest.trim_start().to_string())?;
+    Ok((chat_id, args))
+}
+
 fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseError> {
     lazy_static! {
         static ref SUBREDDIT_RE: Regex = Regex::new(r"^[^\s]+").unwrap();
-        static ref LIMIT_RE: Regex = Regex::new(r"\blimit=(\d+)\b").unwrap();
+        static ref LIMIT_RE: Regex = Regex::new(r"\blimit=(\d+|max)\b").unwrap();
         static ref TIME_RE: Regex = Regex::new(r"\btime=(\w+)\b").unwrap();
+        static ref SORT_RE: Regex = Regex::new(r"\bsort=(\w+)\b").unwrap();
         static ref FILTER_RE: Regex = Regex::new(r"\bfilter=(\w+)\b").unwrap();
+        static ref MAX_MEDIA_MB_RE: Regex = Regex::new(r"\bmax_media_mb=(\d+)\b").unwrap();
+        static ref MAX_AGE_HOURS_RE: Regex = Regex::new(r"\bmax_age_hours=(\d+)\b").unwrap();
+        static ref GROUP_IMAGES_RE: Regex = Regex::new(r"\bgroup_images=(true|false)\b").unwrap();
+        static ref INCLUDE_TOP_COMMENT_RE: Regex =
+            Regex::new(r"\binclude_top_comment=(true|false)\b").unwrap();
+        static ref FORMAT_RE: Regex = Regex::new(r"(?:^|\s)format=(\S+)").unwrap();
+        static ref DIGEST_RE: Regex = Regex::new(r"\bdigest=(\w+)\b").unwrap();
+        static ref SOURCE_RE: Regex = Regex::new(r"\bsource=(\w+)\b").unwrap();
+        static ref KEYWORD_RE: Regex = Regex::new(r"(?:^|\s)keyword=(\S+)").unwrap();
+        static ref WEBHOOK_RE: Regex = Regex::new(r"(?:^|\s)webhook=(\S+)").unwrap();
     }
 
     let subreddit_match = SUBREDDIT_RE
         .find(&input)
         .ok_or_else(|| ParseError::Custom("No subreddit given".into()))?;
-    let subreddit = subreddit_match
-        .as_str()
-        .to_string()
-        .replace("/r/", "")
-        .replace("r/", "");
+    let (source_type, subreddit) = parse_source(subreddit_match.as_str());
     let rest = &input[(subreddit_match.end())..];
 
     let limit: Option<u32> = LIMIT_RE
         .captures(rest)
         .and_then(|caps| caps.get(1))
-        .and_then(|m| m.as_str().parse().ok());
+        .and_then(|m| match m.as_str() {
+            "max" => Some(config::LIMIT_MAX),
+            n => n.parse().ok(),
+        });
 
     let time = Ok(TIME_RE.captures(rest))
         .map(|o| o.and_then(|caps| caps.get(1)))
@@ -223,6 +1251,17 @@ fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseEr
             None => Ok(None),
         })?;
 
+    let sort = Ok(SORT_RE.captures(rest))
+        .map(|o| o.and_then(|caps| caps.get(1)))
+        .and_then(|o| match o {
+            Some(m) => m
+                .as_str()
+                .parse::<reddit::SortMode>()
+                .map(Some)
+                .map_err(|e| ParseError::IncorrectFormat(e.into())),
+            None => Ok(None),
+        })?;
+
     let filter = Ok(FILTER_RE.captures(rest))
         .map(|o| o.and_then(|caps| caps.get(1)))
         .and_then(|o| match o {
@@ -234,11 +1273,106 @@ fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseEr
             None => Ok(None),
         })?;
 
+    let max_media_mb: Option<u32> = MAX_MEDIA_MB_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let max_age_hours: Option<u32> = MAX_AGE_HOURS_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let group_images: Option<bool> = GROUP_IMAGES_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let include_top_comment: Option<bool> = INCLUDE_TOP_COMMENT_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok());
+
+    let ytdlp_format = FORMAT_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().to_string());
+
+    let digest = Ok(DIGEST_RE.captures(rest))
+        .map(|o| o.and_then(|caps| caps.get(1)))
+        .and_then(|o| match o {
+            Some(m) => m
+                .as_str()
+                .parse::<DigestMode>()
+                .map(Some)
+                .map_err(|e| ParseError::IncorrectFormat(e.into())),
+            None => Ok(None),
+        })?;
+
+    let keyword = KEYWORD_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().to_string());
+
+    let webhook = WEBHOOK_RE
+        .captures(rest)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().to_string());
+
+    let source_type = match SOURCE_RE.captures(rest).and_then(|caps| caps.get(1)) {
+        Some(m) if m.as_str() == "comments" && source_type == SourceType::Subreddit => {
+            if keyword.is_none() {
+                return Err(ParseError::Custom(
+                    "source=comments requires a keyword=".into(),
+                ));
+            }
+            SourceType::Comments
+        }
+        Some(m) => {
+            return Err(ParseError::Custom(
+                format!(
+                    "Unsupported source for {}: {}",
+                    subreddit_match.as_str(),
+                    m.as_str()
+                )
+                .into(),
+            ))
+        }
+        None => source_type,
+    };
+    if keyword.is_some() && source_type != SourceType::Comments {
+        return Err(ParseError::Custom(
+            "keyword= can only be used with source=comments".into(),
+        ));
+    }
+    if sort.is_some() && source_type != SourceType::Subreddit {
+        return Err(ParseError::Custom(
+            "sort= can only be used with subreddits".into(),
+        ));
+    }
+    // Reddit only orders the front page by best, a subreddit's best listing is its hot one
+    if sort == Some(reddit::SortMode::Best) {
+        return Err(ParseError::Custom(
+            "sort=best is only available for the front page, use sort=hot for subreddits".into(),
+        ));
+    }
+
     let args = SubscriptionArgs {
+        source_type,
         subreddit,
         limit,
         time,
+        sort,
         filter,
+        max_media_mb,
+        ytdlp_format,
+        group_images,
+        include_top_comment,
+        max_age_hours,
+        digest,
+        message_thread_id: None,
+        keyword,
+        webhook,
     };
 
     Ok((args,))
@@ -255,13 +1389,23 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
-                limit: None,
-                time: None,
-                filter: None,
+                ..SubscriptionArgs::test_default()
             },
         )
     }
 
+    #[test]
+    fn test_parse_subfor_message() {
+        let (chat_id, args) =
+            parse_subfor_message("-1001234567890 rust limit=5".to_string()).unwrap();
+        assert_eq!(chat_id, -1001234567890);
+        assert_eq!(args.subreddit, "rust");
+        assert_eq!(args.limit, Some(5));
+
+        assert!(parse_subfor_message("rust limit=5".to_string()).is_err());
+        assert!(parse_subfor_message("-1001234567890".to_string()).is_err());
+    }
+
     #[test]
     fn test_parse_subscribe_message_strips_prefix() {
         let args = parse_subscribe_message("r/AnimalsBeingJerks".to_string()).unwrap();
@@ -269,9 +1413,7 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
-                limit: None,
-                time: None,
-                filter: None,
+                ..SubscriptionArgs::test_default()
             },
         );
 
@@ -280,9 +1422,7 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
-                limit: None,
-                time: None,
-                filter: None,
+                ..SubscriptionArgs::test_default()
             },
         )
     }
@@ -299,7 +1439,252 @@ mod tests {
                 limit: Some(5),
                 time: Some(TopPostsTimePeriod::Week),
                 filter: Some(PostType::Video),
+                ..SubscriptionArgs::test_default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_limit_max() {
+        let args = parse_subscribe_message("AnimalsBeingJerks limit=max".to_string()).unwrap();
+        assert_eq!(args.0.limit, Some(config::LIMIT_MAX));
+
+        let args = parse_subscribe_message("AnimalsBeingJerks limit=100".to_string()).unwrap();
+        assert_eq!(args.0.limit, Some(100));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_user() {
+        for input in ["u/spez limit=5", "/u/spez limit=5"] {
+            let args = parse_subscribe_message(input.to_string()).unwrap();
+            assert_eq!(
+                args.0,
+                SubscriptionArgs {
+                    source_type: SourceType::User,
+                    subreddit: "spez".to_string(),
+                    limit: Some(5),
+                    ..SubscriptionArgs::test_default()
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_multireddit() {
+        let args = parse_subscribe_message("r/rust+golang limit=5".to_string()).unwrap();
+        assert_eq!(args.0.subreddit, "rust+golang");
+        assert_eq!(args.0.limit, Some(5));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_saved_multireddit() {
+        let args = parse_subscribe_message(
+            "https://www.reddit.com/user/someone/m/mymulti limit=3".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.0,
+            SubscriptionArgs {
+                source_type: SourceType::Multireddit,
+                subreddit: "someone/m/mymulti".to_string(),
+                limit: Some(3),
+                ..SubscriptionArgs::test_default()
             },
         )
     }
+
+    #[test]
+    fn test_parse_subscribe_message_max_media_mb() {
+        let args = parse_subscribe_message("AnimalsBeingJerks max_media_mb=20 limit=2".to_string())
+            .unwrap();
+        assert_eq!(args.0.max_media_mb, Some(20));
+        assert_eq!(args.0.limit, Some(2));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_format() {
+        let args = parse_subscribe_message(
+            "AnimalsBeingJerks format=bestvideo[height<=720]+bestaudio/best limit=2".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.0.ytdlp_format.as_deref(),
+            Some("bestvideo[height<=720]+bestaudio/best")
+        );
+        assert_eq!(args.0.limit, Some(2));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_digest() {
+        let args = parse_subscribe_message("pics digest=daily limit=10".to_string()).unwrap();
+        assert_eq!(args.0.digest, Some(DigestMode::Daily));
+        assert_eq!(args.0.limit, Some(10));
+
+        let args = parse_subscribe_message("pics".to_string()).unwrap();
+        assert_eq!(args.0.digest, None);
+
+        assert!(parse_subscribe_message("pics digest=hourly".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_group_images() {
+        let args = parse_subscribe_message("pics group_images=true limit=10".to_string()).unwrap();
+        assert_eq!(args.0.group_images, Some(true));
+        assert_eq!(args.0.limit, Some(10));
+
+        let args = parse_subscribe_message("pics group_images=false".to_string()).unwrap();
+        assert_eq!(args.0.group_images, Some(false));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_include_top_comment() {
+        let args =
+            parse_subscribe_message("AskReddit include_top_comment=true".to_string()).unwrap();
+        assert_eq!(args.0.include_top_comment, Some(true));
+
+        let args = parse_subscribe_message("AskReddit".to_string()).unwrap();
+        assert_eq!(args.0.include_top_comment, None);
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_sort() {
+        let args = parse_subscribe_message("rust sort=new limit=25".to_string()).unwrap();
+        assert_eq!(args.0.sort, Some(reddit::SortMode::New));
+        assert_eq!(args.0.limit, Some(25));
+
+        assert!(parse_subscribe_message("rust sort=oldest".to_string()).is_err());
+        assert!(parse_subscribe_message("u/spez sort=new".to_string()).is_err());
+        assert!(parse_subscribe_message("rust sort=best".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_max_age_hours() {
+        let args = parse_subscribe_message("pics time=all max_age_hours=48".to_string()).unwrap();
+        assert_eq!(args.0.max_age_hours, Some(48));
+        assert_eq!(args.0.time, Some(TopPostsTimePeriod::All));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_comments() {
+        let args =
+            parse_subscribe_message("r/rust source=comments keyword=tokio".to_string()).unwrap();
+        assert_eq!(args.0.source_type, SourceType::Comments);
+        assert_eq!(args.0.subreddit, "rust");
+        assert_eq!(args.0.keyword.as_deref(), Some("tokio"));
+        let (parsed,) = parse_subscribe_message(format_subscribe_args(&args.0)).unwrap();
+        assert_eq!(parsed, args.0);
+
+        assert!(parse_subscribe_message("rust source=comments".to_string()).is_err());
+        assert!(parse_subscribe_message("rust keyword=tokio".to_string()).is_err());
+        assert!(parse_subscribe_message("u/spez source=comments keyword=a".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_webhook() {
+        let args = parse_subscribe_message("pics limit=2 webhook=archive".to_string()).unwrap();
+        assert_eq!(args.0.webhook.as_deref(), Some("archive"));
+        assert_eq!(args.0.limit, Some(2));
+        let (parsed,) = parse_subscribe_message(format_subscribe_args(&args.0)).unwrap();
+        assert_eq!(parsed, args.0);
+    }
+
+    #[test]
+    fn test_format_subscribe_args_roundtrip() {
+        let args = SubscriptionArgs {
+            subreddit: "AnimalsBeingJerks".to_string(),
+            limit: Some(config::LIMIT_MAX),
+            time: Some(TopPostsTimePeriod::Week),
+            filter: Some(PostType::Video),
+            max_media_mb: Some(20),
+            group_images: Some(true),
+            include_top_comment: Some(true),
+            max_age_hours: Some(48),
+            ..SubscriptionArgs::test_default()
+        };
+        let (parsed,) = parse_subscribe_message(format_subscribe_args(&args)).unwrap();
+        assert_eq!(parsed, args);
+    }
+
+    #[test]
+    fn test_is_public_command() {
+        let config = config::Config {
+            public_commands: vec![config::PublicCommand::Help],
+            ..Default::default()
+        };
+        assert!(is_public_command(&Command::Help, &config));
+        assert!(!is_public_command(&Command::ListSubs, &config));
+        assert!(!is_public_command(
+            &Command::Help,
+            &config::Config::default()
+        ));
+    }
+
+    #[test]
+    fn test_parse_search_query() {
+        assert_eq!(parse_search_query("cute cats"), (None, "cute cats"));
+        assert_eq!(
+            parse_search_query(" r/rust async traits "),
+            (Some("rust"), "async traits")
+        );
+        assert_eq!(parse_search_query("/r/rust async"), (Some("rust"), "async"));
+        // A lone subreddit is searched for as a query
+        assert_eq!(parse_search_query("r/rust"), (None, "r/rust"));
+        assert_eq!(parse_search_query(""), (None, ""));
+    }
+
+    #[test]
+    fn test_subscription_list_page() {
+        let subs: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| Subscription {
+                chat_id: 1,
+                subreddit: name.to_string(),
+                ..Subscription::test_default()
+            })
+            .collect();
+
+        let (text, keyboard) = subscription_list_page(&subs, 0, 3);
+        assert_eq!(text.lines().count(), 3);
+        assert!(keyboard.is_none());
+
+        let (text, keyboard) = subscription_list_page(&subs, 0, 2);
+        assert!(text.starts_with("a, "));
+        assert!(text.ends_with("\n\nPage 1/2"));
+        let buttons = &keyboard.unwrap().inline_keyboard[0];
+        assert_eq!(buttons.len(), 1);
+        assert_eq!(
+            buttons[0].kind,
+            teloxide::types::InlineKeyboardButtonKind::CallbackData("subs:1".into())
+        );
+
+        let (text, keyboard) = subscription_list_page(&subs, 5, 2);
+        assert!(text.starts_with("c, "));
+        assert!(text.ends_with("\n\nPage 2/2"));
+        let buttons = &keyboard.unwrap().inline_keyboard[0];
+        assert_eq!(buttons[0].text, "« Prev");
+        assert_eq!(buttons.len(), 1);
+    }
+
+    #[test]
+    fn test_build_suggestions_keyboard() {
+        let (args,) = parse_subscribe_message("rustt limit=5".to_string()).unwrap();
+        let keyboard = build_suggestions_keyboard(&args, &["rust".to_string()]);
+        let button = &keyboard.inline_keyboard[0][0];
+        assert_eq!(button.text, "r/rust");
+        assert_eq!(
+            button.kind,
+            teloxide::types::InlineKeyboardButtonKind::CallbackData("sub:r/rust limit=5".into())
+        );
+
+        // Args too long for callback data are dropped
+        let (args,) = parse_subscribe_message(
+            "rustt format=bestvideo[height<=720][ext=mp4]+bestaudio[ext=m4a]/best".to_string(),
+        )
+        .unwrap();
+        let keyboard = build_suggestions_keyboard(&args, &["rust".to_string()]);
+        assert_eq!(
+            keyboard.inline_keyboard[0][0].kind,
+            teloxide::types::InlineKeyboardButtonKind::CallbackData("sub:r/rust".into())
+        );
+    }
 }
@@ -1,15 +1,59 @@
 use log::error;
+use regex::Regex;
 use secrecy::{ExposeSecret, Secret};
-use serde::Deserialize;
-use std::{env, path::PathBuf};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::{
-    reddit::{PostType, TopPostsTimePeriod},
+    reddit::{Post, PostType, TopPostsTimePeriod},
+    types::{ChatSettings, MediaOptions, QuietHours, Subscription},
     PKG_NAME,
 };
 
 const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
+/// Environment variables with this prefix override top-level config options, e.g.
+/// TGREDDIT_CHECK_INTERVAL_SECS overrides check_interval_secs
+const ENV_OVERRIDE_PREFIX: &str = "TGREDDIT_";
+/// Options whose override values are always strings, so that e.g. a numeric client secret is not
+/// parsed as an integer
+const ENV_OVERRIDE_STRING_KEYS: &[&str] = &[
+    "db_path",
+    "temp_dir",
+    "telegram_bot_token",
+    "telegram_bot_token_file",
+    "links_base_url",
+    "media_proxy_base_url",
+    "caption_template",
+    "ytdlp_format",
+    "ytdlp_path",
+    "ytdlp_cookies_file",
+    "reddit_session_cookie",
+    "reddit_user_agent",
+    "reddit_client_id",
+    "reddit_client_secret",
+    "reddit_client_secret_file",
+    "slack_webhook_url",
+    "health_addr",
+    "feed_base_url",
+];
 pub const DEFAULT_LIMIT: u32 = 1;
+/// Sentinel limit meaning "all posts in the listing, up to a cap". Set with `limit=max`.
+pub const LIMIT_MAX: u32 = u32::MAX;
+/// The maximum number of posts Reddit returns for a single listing request.
+pub const REDDIT_MAX_LIMIT: u32 = 100;
+pub const DEFAULT_MAX_CONCURRENT_FETCHES: usize = 4;
+/// Telegram bots can't upload files larger than this
+pub const DEFAULT_MAX_VIDEO_MB: u32 = 50;
+pub const DEFAULT_DEDUP_WINDOW_HOURS: u32 = 24;
+pub const DEFAULT_YTDLP_PATH: &str = "yt-dlp";
+pub const DEFAULT_YTDLP_TIMEOUT_SECS: u64 = 600;
+pub const DEFAULT_DIGEST_HOUR: u32 = 8;
 pub const DEFAULT_TIME_PERIOD: TopPostsTimePeriod = TopPostsTimePeriod::Day;
 
 #[derive(Debug, Deserialize)]
@@ -27,30 +71,726 @@ impl Default for SecretString {
     }
 }
 
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(Secret::new(secret))
+    }
+}
+
+/// A setting that can be given separately for each post type
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct PerPostType<T> {
+    pub image: Option<T>,
+    pub video: Option<T>,
+    pub link: Option<T>,
+    pub self_text: Option<T>,
+    pub gallery: Option<T>,
+    pub unknown: Option<T>,
+}
+
+// Not derived, as that would require T: Default
+impl<T> Default for PerPostType<T> {
+    fn default() -> Self {
+        PerPostType {
+            image: None,
+            video: None,
+            link: None,
+            self_text: None,
+            gallery: None,
+            unknown: None,
+        }
+    }
+}
+
+impl<T> PerPostType<T> {
+    pub fn get(&self, post_type: PostType) -> Option<&T> {
+        match post_type {
+            PostType::Image => self.image.as_ref(),
+            PostType::Video => self.video.as_ref(),
+            PostType::Link => self.link.as_ref(),
+            PostType::SelfText => self.self_text.as_ref(),
+            PostType::Gallery => self.gallery.as_ref(),
+            PostType::Unknown => self.unknown.as_ref(),
+        }
+    }
+}
+
+/// How posts of a type are sent instead of the default for the type
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostTypeBehavior {
+    /// Send as a link message without downloading any media
+    Link,
+    /// Don't send at all, but still mark as seen
+    Ignore,
+}
+
+/// A regex replacement applied to post titles before they are shown
+#[derive(Deserialize, Debug)]
+pub struct TitleTransform {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    #[serde(default)]
+    pub replacement: String,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_quiet_hours<'de, D>(deserializer: D) -> Result<Option<QuietHours>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(quiet_hours) => quiet_hours
+            .parse()
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Order in which the new posts found on a single check are sent
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOrder {
+    /// Oldest first
+    Chronological,
+    /// Highest score first
+    Score,
+}
+
+/// Payload that posts are pushed to a webhook as
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The post and its media urls as JSON, see webhook::format_payload
+    #[default]
+    Json,
+    /// A Slack message, see slack::format_payload
+    Slack,
+}
+
+/// A webhook that subscriptions can push their posts to with webhook=<name>
+#[derive(Deserialize, Debug)]
+pub struct WebhookConfig {
+    /// Often carries a token, so it's treated as a credential
+    pub url: SecretString,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Push the posts of all subscriptions, not just the ones given webhook=<name>
+    #[serde(default)]
+    pub global: bool,
+}
+
+/// Read-only commands that can be made available to users who are not authorized
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PublicCommand {
+    Help,
+    Preview,
+}
+
+/// Which links to the comments of a post are shown in captions
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommentsLinkStyle {
+    /// Link to reddit.com, or to links_base_url when it's set
+    New,
+    /// Link to old.reddit.com
+    Old,
+    Both,
+    None,
+}
+
+/// Markup that post messages and captions are formatted in
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseMode {
+    #[default]
+    Html,
+    MarkdownV2,
+}
+
+impl From<ParseMode> for teloxide::types::ParseMode {
+    fn from(parse_mode: ParseMode) -> Self {
+        match parse_mode {
+            ParseMode::Html => teloxide::types::ParseMode::Html,
+            ParseMode::MarkdownV2 => teloxide::types::ParseMode::MarkdownV2,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub struct Config {
     pub authorized_user_ids: Vec<u64>,
+    /// Users allowed to use the /admin commands
+    #[serde(default)]
+    pub admin_user_ids: Vec<u64>,
+    /// Chats that /suball and /unsuball apply to
+    #[serde(default)]
+    pub broadcast_chat_ids: Vec<i64>,
+    /// Commands anyone may use, not only authorized users
+    #[serde(default)]
+    pub public_commands: Vec<PublicCommand>,
     #[serde(default = "default_db_path")]
     pub db_path: PathBuf,
+    /// Directory in which media is downloaded before it's sent, instead of the system's temporary
+    /// directory
+    pub temp_dir: Option<PathBuf>,
+    /// Can be left out when telegram_bot_token_file is given
+    #[serde(default)]
     pub telegram_bot_token: SecretString,
+    /// File from which telegram_bot_token is read, e.g. a Docker secret
+    pub telegram_bot_token_file: Option<PathBuf>,
     pub check_interval_secs: u64,
     #[serde(default = "default_skip_initial_send")]
     pub skip_initial_send: bool,
+    /// How many of the freshest posts are sent on the first check of a new subscription. Takes
+    /// precedence over skip_initial_send.
+    pub initial_send_count: Option<u32>,
     pub links_base_url: Option<String>,
     pub default_limit: Option<u32>,
+    pub max_limit: Option<u32>,
     pub default_time: Option<TopPostsTimePeriod>,
     pub default_filter: Option<PostType>,
+    /// Applied to titles in order, e.g. to strip a recurring [OC] prefix
+    #[serde(default)]
+    pub title_transforms: Vec<TitleTransform>,
+    /// Optional emoji or text prepended to captions, per post type
+    #[serde(default)]
+    pub post_type_labels: PerPostType<String>,
+    /// Overrides how posts of each type are sent
+    #[serde(default)]
+    pub post_type_behavior: PerPostType<PostTypeBehavior>,
+    /// Show score and comment count in captions
+    #[serde(default)]
+    pub show_score: bool,
+    /// Privacy frontend through which images on i.redd.it and i.imgur.com are downloaded and
+    /// linked
+    pub media_proxy_base_url: Option<String>,
+    /// Which comments links are shown in captions. Both by default, or only the new one when
+    /// links_base_url is set.
+    pub comments_link_style: Option<CommentsLinkStyle>,
+    /// Caption layout with {placeholder} syntax, see messages::DEFAULT_CAPTION_TEMPLATE
+    pub caption_template: Option<String>,
+    /// Markup of post messages and captions, which caption_template must also be written in
+    #[serde(default)]
+    pub parse_mode: ParseMode,
+    /// Media larger than this is sent as a link instead. Can be overridden per chat and
+    /// subscription.
+    pub max_media_mb: Option<u32>,
+    /// Videos larger than this are downloaded again in a lower resolution, or sent as a link
+    pub max_video_mb: Option<u32>,
+    /// Downloads of images and gifs larger than this are aborted
+    pub max_download_mb: Option<u32>,
+    /// How many posts may be downloading and sending media at the same time
+    pub max_concurrent_downloads: Option<usize>,
+    /// yt-dlp format selector for videos, e.g. bestvideo[height<=720]+bestaudio/best. Can be
+    /// overridden per subscription.
+    pub ytdlp_format: Option<String>,
+    /// Path to the yt-dlp binary, by default yt-dlp is looked up from PATH
+    pub ytdlp_path: Option<PathBuf>,
+    /// Additional arguments passed to yt-dlp, e.g. --cookies
+    #[serde(default)]
+    pub ytdlp_extra_args: Vec<String>,
+    /// yt-dlp is killed if downloading a video takes longer than this
+    pub ytdlp_timeout_secs: Option<u64>,
+    /// Netscape format cookies file passed to yt-dlp, for age-gated or quarantined media
+    pub ytdlp_cookies_file: Option<PathBuf>,
+    /// Value of the reddit_session cookie sent with downloads of images and gifs from Reddit
+    pub reddit_session_cookie: Option<SecretString>,
+    /// Pause between sending consecutive new posts of a subscription
+    #[serde(default)]
+    pub inter_post_delay_ms: u64,
+    /// How many subscriptions are checked concurrently
+    pub max_concurrent_fetches: Option<usize>,
+    /// Minimum time between requests to Reddit, shared by the check loop and commands
+    #[serde(default)]
+    pub reddit_request_interval_ms: u64,
+    /// User agent of requests to Reddit. Defaults to reddit::APP_USER_AGENT.
+    pub reddit_user_agent: Option<String>,
+    /// Credentials of a Reddit "script" app. When set, requests are made to the OAuth API.
+    pub reddit_client_id: Option<String>,
+    pub reddit_client_secret: Option<SecretString>,
+    /// File from which reddit_client_secret is read
+    pub reddit_client_secret_file: Option<PathBuf>,
+    /// How many times Reddit requests failing with 429 or 5xx are retried
+    pub reddit_max_retries: Option<u32>,
+    /// How long a request to Reddit may take before it's aborted
+    pub reddit_request_timeout_secs: Option<u64>,
+    /// How long subreddit lookups, e.g. when subscribing, are cached. 0 disables caching.
+    pub subreddit_cache_ttl_secs: Option<u64>,
+    /// Incoming webhook to which new posts are also sent. Its url is a credential.
+    pub slack_webhook_url: Option<SecretString>,
+    /// Urls to which new posts are also POSTed as JSON. They often carry a token, so they're
+    /// treated as credentials.
+    #[serde(default)]
+    pub webhook_urls: Vec<SecretString>,
+    /// Webhooks by name, to which the subscriptions given webhook=<name> also push their posts
+    #[serde(default)]
+    pub webhooks: HashMap<String, WebhookConfig>,
+    /// How long pushing a post to Slack or a webhook may take before it's aborted
+    pub webhook_timeout_secs: Option<u64>,
+    /// Posts that fail to be sent are retried on the following checks up to this many times.
+    /// Unset, a failed post is not sent again.
+    pub retry_failed: Option<u32>,
+    /// Seen posts older than this are deleted from the database, though never posts that could
+    /// still be in a top list of a subscription
+    pub seen_retention_days: Option<u32>,
+    /// Add buttons for opening the comments, the link and the subreddit to posts
+    #[serde(default)]
+    pub use_inline_buttons: bool,
+    /// Daily window in local time during which new posts are held back. Can be overridden per
+    /// chat.
+    #[serde(default, deserialize_with = "deserialize_quiet_hours")]
+    pub quiet_hours: Option<QuietHours>,
+    /// Hour of the day in local time at which digests are sent
+    pub digest_hour: Option<u32>,
+    /// Send new image posts found on one check as albums. Can be overridden per subscription.
+    #[serde(default)]
+    pub group_images: bool,
+    /// Append the top comment of a post to its message. Can be overridden per subscription.
+    #[serde(default)]
+    pub include_top_comment: bool,
+    /// Skip posts created longer than this many hours ago. Can be overridden per subscription.
+    pub max_age_hours: Option<u32>,
+    /// Order of the new posts sent on a check. Reddit's listing order when unset.
+    pub delivery_order: Option<DeliveryOrder>,
+    /// Posts linking to these domains or their subdomains are never sent
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+    /// Posts with any of these in the title, ignoring case, are never sent
+    #[serde(default)]
+    pub blocked_title_keywords: Vec<String>,
+    /// Posts by these Reddit users, ignoring case, are never sent
+    #[serde(default)]
+    pub blocked_authors: Vec<String>,
+    /// When not empty, only link posts to these domains or their subdomains are sent. Media and
+    /// self posts are not affected.
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// How long a post's content, e.g. the original of a crosspost, is not sent again to the same
+    /// chat. 0 disables deduplication.
+    pub dedup_window_hours: Option<u32>,
+    /// Send a message to authorized users when the bot starts
+    #[serde(default)]
+    pub notify_on_start: bool,
+    /// Address of the HTTP health check endpoint, e.g. 0.0.0.0:8080. Disabled when unset.
+    pub health_addr: Option<SocketAddr>,
+    /// Public url of the HTTP server at health_addr, used for the feed urls given by /feed
+    pub feed_base_url: Option<String>,
+    /// Chat to which /feedback messages are forwarded
+    pub operator_chat_id: Option<i64>,
+    /// Whether Telegram shows a link preview for posts sent as text messages, per post type
+    #[serde(default)]
+    pub web_preview: PerPostType<bool>,
+    /// Whether link posts are sent with the OpenGraph title, description and image of the linked
+    /// page instead of relying on Telegram's preview
+    #[serde(default)]
+    pub fetch_link_metadata: bool,
+}
+
+impl Config {
+    /// Reads secrets given as *_file options, following the _FILE convention of Docker secrets.
+    /// Surrounding whitespace, such as a trailing newline, is trimmed.
+    pub fn read_secret_files(&mut self) -> Result<(), String> {
+        if let Some(path) = &self.telegram_bot_token_file {
+            if !self.telegram_bot_token.expose_secret().is_empty() {
+                return Err(
+                    "set either telegram_bot_token or telegram_bot_token_file, not both"
+                        .to_string(),
+                );
+            }
+            self.telegram_bot_token = read_secret_file("telegram_bot_token_file", path)?;
+        }
+        if let Some(path) = &self.reddit_client_secret_file {
+            if self.reddit_client_secret.is_some() {
+                return Err(
+                    "set either reddit_client_secret or reddit_client_secret_file, not both"
+                        .to_string(),
+                );
+            }
+            self.reddit_client_secret = Some(read_secret_file("reddit_client_secret_file", path)?);
+        }
+        Ok(())
+    }
+
+    /// Checks the config for errors that deserialization can't catch. All errors are reported at
+    /// once, one per line.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+        if self.telegram_bot_token.expose_secret().trim().is_empty() {
+            errors.push(
+                "telegram_bot_token is empty, set it to the token given by @BotFather".to_string(),
+            );
+        }
+        if self.authorized_user_ids.is_empty() {
+            errors.push(
+                "authorized_user_ids is empty, add your Telegram user id so that the bot responds to you"
+                    .to_string(),
+            );
+        }
+        if self.check_interval_secs == 0 {
+            errors.push("check_interval_secs must be greater than 0".to_string());
+        }
+        if self.ytdlp_timeout_secs == Some(0) {
+            errors.push("ytdlp_timeout_secs must be greater than 0".to_string());
+        }
+        if self.webhook_timeout_secs == Some(0) {
+            errors.push("webhook_timeout_secs must be greater than 0".to_string());
+        }
+        if matches!(&self.reddit_user_agent, Some(user_agent) if user_agent.trim().is_empty()) {
+            errors.push("reddit_user_agent must not be empty".to_string());
+        }
+        if let Some(cookies_file) = &self.ytdlp_cookies_file {
+            // The path is left out of the error as it may reveal where credentials are kept
+            if !std::fs::metadata(cookies_file).is_ok_and(|metadata| metadata.is_file()) {
+                errors.push("ytdlp_cookies_file must be a readable file".to_string());
+            }
+        }
+        if matches!(&self.reddit_session_cookie, Some(cookie) if cookie.expose_secret().is_empty())
+        {
+            errors.push("reddit_session_cookie must not be empty".to_string());
+        }
+        if matches!(self.digest_hour, Some(hour) if hour > 23) {
+            errors.push("digest_hour must be an hour of the day, from 0 to 23".to_string());
+        }
+        if let Err(err) = validate_db_path(&self.db_path) {
+            errors.push(err);
+        }
+        if let Some(temp_dir) = &self.temp_dir {
+            if let Err(err) = validate_temp_dir(temp_dir) {
+                errors.push(err);
+            }
+        }
+        if let Some(template) = &self.caption_template {
+            if let Err(err) = crate::messages::validate_caption_template(template) {
+                errors.push(err);
+            }
+        }
+        if let Err(err) = crate::ytdlp::validate_extra_args(&self.ytdlp_extra_args) {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    pub fn ytdlp_path(&self) -> &Path {
+        self.ytdlp_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_YTDLP_PATH))
+    }
+
+    pub fn ytdlp_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.ytdlp_timeout_secs
+                .unwrap_or(DEFAULT_YTDLP_TIMEOUT_SECS),
+        )
+    }
+
+    pub fn is_authorized(&self, user_id: u64) -> bool {
+        self.authorized_user_ids.contains(&user_id)
+    }
+
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        self.admin_user_ids.contains(&user_id)
+    }
+
+    /// Link posts are shown with a preview of the linked page by default, self posts without one.
+    pub fn web_preview_enabled(&self, post_type: PostType) -> bool {
+        self.web_preview
+            .get(post_type)
+            .copied()
+            .unwrap_or(post_type != PostType::SelfText)
+    }
+
+    /// Resolves media options for sending a post. Subscription settings take precedence over
+    /// chat settings, which take precedence over the global config.
+    pub fn resolve_media_options(
+        &self,
+        sub_max_media_mb: Option<u32>,
+        sub_ytdlp_format: Option<&str>,
+        sub_include_top_comment: Option<bool>,
+        chat_settings: &ChatSettings,
+    ) -> MediaOptions {
+        MediaOptions {
+            max_media_mb: sub_max_media_mb
+                .or(chat_settings.max_media_mb)
+                .or(self.max_media_mb),
+            ytdlp_format: sub_ytdlp_format
+                .map(String::from)
+                .or_else(|| self.ytdlp_format.clone()),
+            show_progress: false,
+            include_top_comment: sub_include_top_comment.unwrap_or(self.include_top_comment),
+        }
+    }
+
+    /// Largest video that is uploaded, which is the smaller of max_video_mb and the resolved
+    /// max_media_mb
+    pub fn video_size_cap_mb(&self, media_options: &MediaOptions) -> u32 {
+        let max_video_mb = self.max_video_mb.unwrap_or(DEFAULT_MAX_VIDEO_MB);
+        media_options
+            .max_media_mb
+            .map_or(max_video_mb, |max_media_mb| max_media_mb.min(max_video_mb))
+    }
+
+    /// Whether posts to the chat should be held back at the given local time
+    pub fn is_quiet_time(&self, chat_settings: &ChatSettings, time: chrono::NaiveTime) -> bool {
+        chat_settings
+            .quiet_hours
+            .or(self.quiet_hours)
+            .is_some_and(|quiet_hours| quiet_hours.contains(time))
+    }
+
+    /// Whether a daily digest should be sent at the given local time, i.e. the digest hour of the
+    /// day has been reached and no digest has been sent since
+    pub fn is_digest_due(
+        &self,
+        last_digest_at: Option<chrono::NaiveDateTime>,
+        now: chrono::NaiveDateTime,
+    ) -> bool {
+        let digest_hour = self.digest_hour.unwrap_or(DEFAULT_DIGEST_HOUR);
+        let digest_at = match now.date().and_hms_opt(digest_hour, 0, 0) {
+            Some(digest_at) => digest_at,
+            None => return false,
+        };
+        now >= digest_at && last_digest_at.filter(|last| *last >= digest_at).is_none()
+    }
+
+    /// Days seen posts are kept, given the time periods of subscriptions. A post pruned while
+    /// still in a top list would be sent again, so posts are kept at least for the longest period
+    /// in use, and for the dedup window. None if posts are kept forever.
+    pub fn resolve_seen_retention_days(&self, subs: &[Subscription]) -> Option<u32> {
+        let retention_days = self.seen_retention_days?;
+        let longest_period_days = subs
+            .iter()
+            .map(|sub| self.resolve_time(sub.time).days())
+            .try_fold(0, |longest, days| days.map(|days| longest.max(days)))?;
+        let dedup_window_hours = self
+            .dedup_window_hours
+            .unwrap_or(DEFAULT_DEDUP_WINDOW_HOURS);
+        let dedup_window_days = dedup_window_hours.div_ceil(24);
+        Some(
+            retention_days
+                .max(longest_period_days)
+                .max(dedup_window_days),
+        )
+    }
+
+    /// How many posts are sent on the first check of a new subscription, None for every post.
+    /// Without initial_send_count, skip_initial_send sends none.
+    pub fn resolve_initial_send_count(&self) -> Option<u32> {
+        self.initial_send_count
+            .or(self.skip_initial_send.then_some(0))
+    }
+
+    /// Resolves the effective post limit, falling back to configured and built-in defaults.
+    /// `LIMIT_MAX` resolves to `max_limit` or Reddit's maximum page size.
+    pub fn resolve_limit(&self, limit: Option<u32>) -> u32 {
+        match limit.or(self.default_limit).unwrap_or(DEFAULT_LIMIT) {
+            LIMIT_MAX => self.max_limit.unwrap_or(REDDIT_MAX_LIMIT),
+            limit => limit,
+        }
+    }
+
+    /// Resolves which comments links are shown. A custom links_base_url usually points to a
+    /// frontend that has no old version, so only its link is shown by default.
+    pub fn resolve_comments_link_style(&self) -> CommentsLinkStyle {
+        self.comments_link_style
+            .unwrap_or(match self.links_base_url {
+                Some(_) => CommentsLinkStyle::New,
+                None => CommentsLinkStyle::Both,
+            })
+    }
+
+    /// Resolves the time period of a listing, falling back to configured and built-in defaults.
+    pub fn resolve_time(&self, time: Option<TopPostsTimePeriod>) -> TopPostsTimePeriod {
+        time.or(self.default_time).unwrap_or(DEFAULT_TIME_PERIOD)
+    }
+
+    /// Resolves the post type filter, falling back to the configured default.
+    pub fn resolve_filter(&self, filter: Option<PostType>) -> Option<PostType> {
+        filter.or(self.default_filter)
+    }
+
+    /// Whether the post links to a blocked domain, has a blocked keyword in its title, is by a
+    /// blocked author, or is a link post to a domain that's not allowed
+    pub fn is_post_blocked(&self, post: &Post) -> bool {
+        let title = post.title.to_lowercase();
+        // Unknown posts are sent as links too
+        let is_link = matches!(post.post_type, PostType::Link | PostType::Unknown);
+        let is_link_allowed = self.allowed_domains.is_empty()
+            || self
+                .allowed_domains
+                .iter()
+                .any(|domain| is_url_on_domain(&post.url, domain));
+
+        self.blocked_domains
+            .iter()
+            .any(|domain| is_url_on_domain(&post.url, domain))
+            || self
+                .blocked_title_keywords
+                .iter()
+                .any(|keyword| title.contains(&keyword.to_lowercase()))
+            || is_author_blocked(&self.blocked_authors, &post.author)
+            || (is_link && !is_link_allowed)
+    }
+
+    /// Resolves the maximum age of posts in hours, falling back to the configured default. None
+    /// means posts of any age are sent.
+    pub fn resolve_max_age_hours(&self, max_age_hours: Option<u32>) -> Option<u32> {
+        max_age_hours.or(self.max_age_hours)
+    }
+}
+
+/// Whether the host of the url is the domain or one of its subdomains. A www. prefix of the domain
+/// is ignored, so that www.example.com also matches example.com and its other subdomains.
+fn is_url_on_domain(url: &str, domain: &str) -> bool {
+    let host = match url::Url::parse(url) {
+        Ok(url) => match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+    let domain = domain.trim().to_lowercase();
+    let domain = domain.strip_prefix("www.").unwrap_or(&domain);
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+fn read_secret_file(key: &str, path: &Path) -> Result<SecretString, String> {
+    std::fs::read_to_string(path)
+        .map(|secret| SecretString(Secret::new(secret.trim().to_string())))
+        .map_err(|e| format!("{key}: could not read {}: {e}", path.display()))
+}
+
+/// The database is created on first start, so the directory it's in must exist and be writable
+fn validate_db_path(db_path: &Path) -> Result<(), String> {
+    let dir = match db_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match std::fs::metadata(dir) {
+        Ok(metadata) if !metadata.is_dir() => Err(format!(
+            "db_path {} is not in a directory, {} is not a directory",
+            db_path.display(),
+            dir.display()
+        )),
+        Ok(metadata) if metadata.permissions().readonly() => Err(format!(
+            "db_path {} is in a read-only directory, make {} writable",
+            db_path.display(),
+            dir.display()
+        )),
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "db_path {} is in a directory that can't be accessed, create {} or mount a volume there: {err}",
+            db_path.display(),
+            dir.display()
+        )),
+    }
+}
+
+/// Whether the author is in the list of blocked authors, ignoring case and any u/ prefix in the
+/// list
+pub fn is_author_blocked(blocked_authors: &[String], author: &str) -> bool {
+    blocked_authors.iter().any(|blocked| {
+        let blocked = blocked.trim_start_matches('/');
+        let blocked = blocked.strip_prefix("u/").unwrap_or(blocked);
+        blocked.eq_ignore_ascii_case(author)
+    })
+}
+
+/// Checks that downloads can be written in the directory by creating a temporary directory there
+fn validate_temp_dir(temp_dir: &Path) -> Result<(), String> {
+    match tempdir::TempDir::new_in(temp_dir, "tgreddit") {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "temp_dir {} is not a writable directory, create it or mount a volume there: {err}",
+            temp_dir.display()
+        )),
+    }
+}
+
+/// Parses the value of an override environment variable as a TOML value, e.g. 600 or [1, 2].
+/// Anything else is taken as a string, as are values of string options unless quoted.
+fn parse_env_value(key: &str, value: &str) -> toml::Value {
+    let parsed = toml::from_str::<toml::value::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"));
+    match parsed {
+        Some(parsed @ toml::Value::String(_)) => parsed,
+        Some(_) if ENV_OVERRIDE_STRING_KEYS.contains(&key) => {
+            toml::Value::String(value.to_string())
+        }
+        Some(parsed) => parsed,
+        None => toml::Value::String(value.to_string()),
+    }
+}
+
+/// Sets options from TGREDDIT_ prefixed environment variables, which take precedence over the
+/// config file. Returns whether any were set.
+fn apply_env_overrides(
+    table: &mut toml::value::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> bool {
+    let mut applied = false;
+    for (name, value) in vars {
+        if let Some(key) = name.strip_prefix(ENV_OVERRIDE_PREFIX) {
+            let key = key.to_lowercase();
+            let value = parse_env_value(&key, &value);
+            table.insert(key, value);
+            applied = true;
+        }
+    }
+    applied
+}
+
+/// Reads the config file at CONFIG_PATH, if set, and applies overrides from the environment.
+/// Without a config file, the whole config can be given as environment variables.
+fn read_config_table() -> Result<toml::value::Table, String> {
+    let mut table = match env::var(CONFIG_PATH_ENV) {
+        Ok(config_path) => std::fs::read(&config_path)
+            .map_err(|e| format!("could not read {config_path}: {e}"))
+            .and_then(|bytes| {
+                toml::from_slice::<toml::value::Table>(&bytes).map_err(|e| e.to_string())
+            })?,
+        Err(_) => toml::value::Table::new(),
+    };
+    if !apply_env_overrides(&mut table, env::vars()) && table.is_empty() {
+        return Err(format!(
+            "{CONFIG_PATH_ENV} environment variable not set, and no {ENV_OVERRIDE_PREFIX} prefixed environment variables either"
+        ));
+    }
+    Ok(table)
 }
 
 pub fn read_config() -> Config {
-    env::var(CONFIG_PATH_ENV)
-        .map_err(|_| format!("{CONFIG_PATH_ENV} environment variable not set"))
-        .and_then(|config_path| std::fs::read(config_path).map_err(|e| e.to_string()))
-        .and_then(|bytes| toml::from_slice(&bytes).map_err(|e| e.to_string()))
-        .unwrap_or_else(|err| {
-            error!("failed to read config: {err}");
-            std::process::exit(1);
+    try_read_config().unwrap_or_else(|err| {
+        error!("failed to read config: {err}");
+        std::process::exit(1);
+    })
+}
+
+pub fn try_read_config() -> Result<Config, String> {
+    read_config_table()
+        .and_then(|table| {
+            toml::Value::Table(table)
+                .try_into::<Config>()
+                .map_err(|e| e.to_string())
         })
+        .and_then(|mut config| config.read_secret_files().map(|_| config))
+        .and_then(|config| config.validate().map(|_| config))
 }
 
 fn default_db_path() -> PathBuf {
@@ -61,3 +801,611 @@ fn default_db_path() -> PathBuf {
 fn default_skip_initial_send() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let tmp_dir = tempdir::TempDir::new("tgreddit").unwrap();
+        let config = Config {
+            authorized_user_ids: vec![1],
+            db_path: tmp_dir.path().join("data.db3"),
+            telegram_bot_token: SecretString(Secret::new("x".to_string())),
+            check_interval_secs: 60,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+
+        let config = Config {
+            db_path: tmp_dir.path().join("missing").join("data.db3"),
+            ..Default::default()
+        };
+        let errors = config.validate().unwrap_err();
+        let errors = errors.lines().collect::<Vec<_>>();
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].starts_with("telegram_bot_token"));
+        assert!(errors[1].starts_with("authorized_user_ids"));
+        assert!(errors[2].starts_with("check_interval_secs"));
+        assert!(errors[3].starts_with("db_path"));
+    }
+
+    #[test]
+    fn test_validate_temp_dir() {
+        let tmp_dir = tempdir::TempDir::new("tgreddit").unwrap();
+        assert_eq!(validate_temp_dir(tmp_dir.path()), Ok(()));
+        assert!(validate_temp_dir(&tmp_dir.path().join("missing"))
+            .unwrap_err()
+            .starts_with("temp_dir"));
+    }
+
+    #[test]
+    fn test_ytdlp_timeout() {
+        assert_eq!(
+            Config::default().ytdlp_timeout(),
+            Duration::from_secs(DEFAULT_YTDLP_TIMEOUT_SECS)
+        );
+        let tmp_dir = tempdir::TempDir::new("tgreddit").unwrap();
+        let config = Config {
+            authorized_user_ids: vec![1],
+            db_path: tmp_dir.path().join("data.db3"),
+            telegram_bot_token: SecretString(Secret::new("x".to_string())),
+            check_interval_secs: 60,
+            ytdlp_timeout_secs: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("ytdlp_timeout_secs must be greater than 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_apply_env_overrides() {
+        let mut table = toml::from_str::<toml::value::Table>(
+            r#"
+            authorized_user_ids = [1]
+            telegram_bot_token = "x"
+            check_interval_secs = 60
+            "#,
+        )
+        .unwrap();
+        let vars = [
+            ("TGREDDIT_CHECK_INTERVAL_SECS", "600"),
+            ("TGREDDIT_AUTHORIZED_USER_IDS", "[1, 2]"),
+            ("TGREDDIT_TELEGRAM_BOT_TOKEN", "123:abc"),
+            (
+                "TGREDDIT_YTDLP_FORMAT",
+                "\"bestvideo[height<=720]+bestaudio/best\"",
+            ),
+            ("HOME", "/root"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        assert!(apply_env_overrides(&mut table, vars));
+        assert!(!table.contains_key("home"));
+        let config = toml::Value::Table(table).try_into::<Config>().unwrap();
+        assert_eq!(config.check_interval_secs, 600);
+        assert_eq!(config.authorized_user_ids, vec![1, 2]);
+        assert_eq!(config.telegram_bot_token.expose_secret(), "123:abc");
+        assert_eq!(
+            config.ytdlp_format.as_deref(),
+            Some("bestvideo[height<=720]+bestaudio/best")
+        );
+
+        let mut table = toml::value::Table::new();
+        assert!(!apply_env_overrides(
+            &mut table,
+            [("HOME".to_string(), "/root".to_string())].into_iter()
+        ));
+    }
+
+    #[test]
+    fn test_apply_env_overrides_string_options() {
+        let mut table = toml::from_str::<toml::value::Table>(
+            r#"
+            authorized_user_ids = [1]
+            telegram_bot_token = "x"
+            check_interval_secs = 60
+            "#,
+        )
+        .unwrap();
+        let vars = [
+            ("TGREDDIT_REDDIT_CLIENT_SECRET", "123456"),
+            ("TGREDDIT_REDDIT_CLIENT_ID", "true"),
+            ("TGREDDIT_TELEGRAM_BOT_TOKEN", "\"1e5\""),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        assert!(apply_env_overrides(&mut table, vars));
+        let config = toml::Value::Table(table).try_into::<Config>().unwrap();
+        assert_eq!(
+            config.reddit_client_secret.unwrap().expose_secret(),
+            "123456"
+        );
+        assert_eq!(config.reddit_client_id.as_deref(), Some("true"));
+        assert_eq!(config.telegram_bot_token.expose_secret(), "1e5");
+    }
+
+    #[test]
+    fn test_read_secret_files() {
+        let tmp_dir = tempdir::TempDir::new("tgreddit").unwrap();
+        let token_path = tmp_dir.path().join("telegram_bot_token");
+        let secret_path = tmp_dir.path().join("reddit_client_secret");
+        std::fs::write(&token_path, "123:abc\n").unwrap();
+        std::fs::write(&secret_path, "secret").unwrap();
+
+        let mut config = Config {
+            telegram_bot_token_file: Some(token_path.clone()),
+            reddit_client_secret_file: Some(secret_path),
+            ..Default::default()
+        };
+        config.read_secret_files().unwrap();
+        assert_eq!(config.telegram_bot_token.expose_secret(), "123:abc");
+        assert_eq!(
+            config.reddit_client_secret.unwrap().expose_secret(),
+            "secret"
+        );
+
+        let mut config = Config {
+            telegram_bot_token: SecretString(Secret::new("x".to_string())),
+            telegram_bot_token_file: Some(token_path),
+            ..Default::default()
+        };
+        assert!(config.read_secret_files().is_err());
+
+        let mut config = Config {
+            telegram_bot_token_file: Some(tmp_dir.path().join("missing")),
+            ..Default::default()
+        };
+        assert!(config
+            .read_secret_files()
+            .unwrap_err()
+            .starts_with("telegram_bot_token_file"));
+    }
+
+    #[test]
+    fn test_resolve_initial_send_count() {
+        let config = Config {
+            skip_initial_send: true,
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_initial_send_count(), Some(0));
+        let config = Config {
+            skip_initial_send: false,
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_initial_send_count(), None);
+        let config = Config {
+            skip_initial_send: true,
+            initial_send_count: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_initial_send_count(), Some(3));
+    }
+
+    #[test]
+    fn test_resolve_limit() {
+        let config = Config {
+            default_limit: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_limit(None), 5);
+        assert_eq!(config.resolve_limit(Some(3)), 3);
+        assert_eq!(config.resolve_limit(Some(LIMIT_MAX)), REDDIT_MAX_LIMIT);
+
+        let config = Config {
+            max_limit: Some(25),
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_limit(None), DEFAULT_LIMIT);
+        assert_eq!(config.resolve_limit(Some(LIMIT_MAX)), 25);
+    }
+
+    #[test]
+    fn test_is_url_on_domain() {
+        assert!(is_url_on_domain("https://spam.com/page", "spam.com"));
+        assert!(is_url_on_domain("https://www.spam.com/page", "spam.com"));
+        assert!(is_url_on_domain("https://i.spam.com/a.jpg", "spam.com"));
+        assert!(is_url_on_domain("https://spam.com/page", "www.spam.com"));
+        assert!(is_url_on_domain("https://WWW.Spam.com/page", "SPAM.com"));
+        assert!(!is_url_on_domain("https://notspam.com/page", "spam.com"));
+        assert!(!is_url_on_domain(
+            "https://spam.com.example.org/",
+            "spam.com"
+        ));
+        assert!(!is_url_on_domain(
+            "https://example.org/?ref=spam.com",
+            "spam.com"
+        ));
+        assert!(!is_url_on_domain("not a url", "spam.com"));
+    }
+
+    #[test]
+    fn test_is_post_blocked() {
+        let post = |title: &str, url: &str| -> Post {
+            serde_json::from_value(serde_json::json!({
+                "id": "abc123",
+                "created": 1654581100.0,
+                "subreddit": "pics",
+                "author": "someone",
+                "title": title,
+                "is_video": false,
+                "ups": 10,
+                "num_comments": 2,
+                "permalink": "/r/pics/comments/abc123/a_post/",
+                "url": url,
+                "is_self": false,
+            }))
+            .unwrap()
+        };
+        let config = Config {
+            blocked_domains: vec!["spam.com".into()],
+            blocked_title_keywords: vec!["Giveaway".into()],
+            blocked_authors: vec!["u/SomeOne".into()],
+            ..Default::default()
+        };
+
+        assert!(config.is_post_blocked(&post("A picture", "https://www.spam.com/a")));
+        assert!(config.is_post_blocked(&post("Huge GIVEAWAY today", "https://i.redd.it/a.jpg")));
+        assert!(config.is_post_blocked(&post("A picture", "https://i.redd.it/a.jpg")));
+        assert!(!Config::default().is_post_blocked(&post("Giveaway", "https://spam.com")));
+        assert!(!is_author_blocked(&["someone_else".into()], "someone"));
+    }
+
+    #[test]
+    fn test_is_post_blocked_with_allowed_domains() {
+        let post = |url: &str, post_hint: &str| -> Post {
+            serde_json::from_value(serde_json::json!({
+                "id": "abc123",
+                "created": 1654581100.0,
+                "subreddit": "worldnews",
+                "author": "someone",
+                "title": "A post",
+                "is_video": false,
+                "ups": 10,
+                "num_comments": 2,
+                "permalink": "/r/worldnews/comments/abc123/a_post/",
+                "url": url,
+                "post_hint": post_hint,
+                "is_self": false,
+            }))
+            .unwrap()
+        };
+        let config = Config {
+            allowed_domains: vec!["reuters.com".into()],
+            ..Default::default()
+        };
+
+        assert!(!config.is_post_blocked(&post("https://www.reuters.com/world/a", "link")));
+        assert!(config.is_post_blocked(&post("https://tabloid.example/a", "link")));
+        // Media posts are not affected
+        assert!(!config.is_post_blocked(&post("https://i.redd.it/a.jpg", "image")));
+    }
+
+    #[test]
+    fn test_resolve_time_and_filter() {
+        let config = Config::default();
+        assert_eq!(config.resolve_time(None), DEFAULT_TIME_PERIOD);
+        assert_eq!(config.resolve_filter(None), None);
+
+        let config = Config {
+            default_time: Some(TopPostsTimePeriod::Week),
+            default_filter: Some(PostType::Video),
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_time(None), TopPostsTimePeriod::Week);
+        assert_eq!(
+            config.resolve_time(Some(TopPostsTimePeriod::Month)),
+            TopPostsTimePeriod::Month
+        );
+        assert_eq!(config.resolve_filter(None), Some(PostType::Video));
+        assert_eq!(
+            config.resolve_filter(Some(PostType::Image)),
+            Some(PostType::Image)
+        );
+    }
+
+    #[test]
+    fn test_webhook_urls_are_not_logged() {
+        let config: Config = toml::from_str(
+            r#"
+            authorized_user_ids = [1]
+            telegram_bot_token = "token"
+            check_interval_secs = 60
+            slack_webhook_url = "https://hooks.slack.com/services/T0/B0/slacksecret"
+            webhook_urls = ["https://example.com/hook?token=hooksecret"]
+
+            [webhooks.team]
+            url = "https://hooks.slack.com/services/T0/B1/teamsecret"
+            format = "slack"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.slack_webhook_url.as_ref().unwrap().expose_secret(),
+            "https://hooks.slack.com/services/T0/B0/slacksecret"
+        );
+        assert_eq!(
+            config.webhook_urls[0].expose_secret(),
+            "https://example.com/hook?token=hooksecret"
+        );
+        let team = &config.webhooks["team"];
+        assert_eq!(team.format, WebhookFormat::Slack);
+        assert!(!team.global);
+        let logged = format!("{config:#?}");
+        assert!(!logged.contains("slacksecret"));
+        assert!(!logged.contains("hooksecret"));
+        assert!(!logged.contains("teamsecret"));
+    }
+
+    #[test]
+    fn test_parse_post_type_behavior() {
+        let config: Config = toml::from_str(
+            r#"
+            authorized_user_ids = [1]
+            telegram_bot_token = "token"
+            check_interval_secs = 60
+
+            [post_type_behavior]
+            video = "link"
+            self_text = "ignore"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.post_type_behavior.get(PostType::Video),
+            Some(&PostTypeBehavior::Link)
+        );
+        assert_eq!(
+            config.post_type_behavior.get(PostType::SelfText),
+            Some(&PostTypeBehavior::Ignore)
+        );
+        assert_eq!(config.post_type_behavior.get(PostType::Image), None);
+    }
+
+    #[test]
+    fn test_web_preview_enabled() {
+        let config = Config::default();
+        assert!(config.web_preview_enabled(PostType::Link));
+        assert!(config.web_preview_enabled(PostType::Unknown));
+        assert!(!config.web_preview_enabled(PostType::SelfText));
+
+        let config = Config {
+            web_preview: PerPostType {
+                link: Some(false),
+                self_text: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(!config.web_preview_enabled(PostType::Link));
+        assert!(config.web_preview_enabled(PostType::Unknown));
+        assert!(config.web_preview_enabled(PostType::SelfText));
+    }
+
+    #[test]
+    fn test_title_transforms_are_validated() {
+        let config: Config = toml::from_str(
+            r#"
+            authorized_user_ids = []
+            db_path = "/tmp/data.db3"
+            telegram_bot_token = "x"
+            check_interval_secs = 60
+
+            [[title_transforms]]
+            pattern = '^\[OC\]\s*'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.title_transforms.len(), 1);
+        assert_eq!(config.title_transforms[0].replacement, "");
+
+        let err = toml::from_str::<Config>(
+            r#"
+            authorized_user_ids = []
+            db_path = "/tmp/data.db3"
+            telegram_bot_token = "x"
+            check_interval_secs = 60
+
+            [[title_transforms]]
+            pattern = '(unclosed'
+            replacement = ""
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unclosed group"));
+    }
+
+    #[test]
+    fn test_resolve_media_options() {
+        let config = Config {
+            max_media_mb: Some(50),
+            ..Default::default()
+        };
+        let chat_settings = ChatSettings {
+            max_media_mb: Some(20),
+            ..Default::default()
+        };
+        let no_chat_settings = ChatSettings::default();
+
+        assert_eq!(
+            config
+                .resolve_media_options(Some(10), None, None, &chat_settings)
+                .max_media_mb,
+            Some(10)
+        );
+        assert_eq!(
+            config
+                .resolve_media_options(None, None, None, &chat_settings)
+                .max_media_mb,
+            Some(20)
+        );
+        assert_eq!(
+            config
+                .resolve_media_options(None, None, None, &no_chat_settings)
+                .max_media_mb,
+            Some(50)
+        );
+        assert_eq!(
+            Config::default()
+                .resolve_media_options(None, None, None, &no_chat_settings)
+                .max_media_mb,
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve_media_options_ytdlp_format() {
+        let config = Config {
+            ytdlp_format: Some("bestvideo[height<=720]+bestaudio/best".to_string()),
+            ..Default::default()
+        };
+        let chat_settings = ChatSettings::default();
+
+        assert_eq!(
+            config
+                .resolve_media_options(None, Some("worst"), None, &chat_settings)
+                .ytdlp_format
+                .as_deref(),
+            Some("worst")
+        );
+        assert_eq!(
+            config
+                .resolve_media_options(None, None, None, &chat_settings)
+                .ytdlp_format
+                .as_deref(),
+            Some("bestvideo[height<=720]+bestaudio/best")
+        );
+    }
+
+    #[test]
+    fn test_is_digest_due() {
+        let time =
+            |time: &str| chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        let config = Config {
+            digest_hour: Some(8),
+            ..Default::default()
+        };
+
+        assert!(!config.is_digest_due(None, time("2022-06-07 07:59")));
+        assert!(config.is_digest_due(None, time("2022-06-07 08:00")));
+        assert!(config.is_digest_due(Some(time("2022-06-06 08:05")), time("2022-06-07 09:30")));
+        // Already sent today
+        assert!(!config.is_digest_due(Some(time("2022-06-07 08:05")), time("2022-06-07 21:00")));
+        // Sent yesterday, but today's digest hour hasn't been reached yet
+        assert!(!config.is_digest_due(Some(time("2022-06-06 08:05")), time("2022-06-07 07:00")));
+    }
+
+    #[test]
+    fn test_is_quiet_time() {
+        let time = |time: &str| chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let config = Config {
+            quiet_hours: Some("23:00-07:00".parse().unwrap()),
+            ..Default::default()
+        };
+        let chat_settings = ChatSettings {
+            quiet_hours: Some("12:00-13:00".parse().unwrap()),
+            ..Default::default()
+        };
+
+        assert!(config.is_quiet_time(&ChatSettings::default(), time("01:00")));
+        assert!(!config.is_quiet_time(&ChatSettings::default(), time("12:30")));
+        // Chat's quiet hours replace the configured ones
+        assert!(config.is_quiet_time(&chat_settings, time("12:30")));
+        assert!(!config.is_quiet_time(&chat_settings, time("01:00")));
+        assert!(!Config::default().is_quiet_time(&ChatSettings::default(), time("01:00")));
+    }
+
+    #[test]
+    fn test_quiet_hours_from_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            authorized_user_ids = []
+            db_path = "/tmp/data.db3"
+            telegram_bot_token = "x"
+            check_interval_secs = 60
+            quiet_hours = "23:00-07:00"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.quiet_hours, Some("23:00-07:00".parse().unwrap()));
+
+        let err = toml::from_str::<Config>(
+            r#"
+            authorized_user_ids = []
+            db_path = "/tmp/data.db3"
+            telegram_bot_token = "x"
+            check_interval_secs = 60
+            quiet_hours = "late"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("quiet hours"));
+    }
+
+    #[test]
+    fn test_resolve_seen_retention_days() {
+        let sub = |time: Option<TopPostsTimePeriod>| Subscription {
+            chat_id: 1,
+            source_type: Default::default(),
+            subreddit: "pics".to_string(),
+            time,
+            ..Subscription::test_default()
+        };
+        let config = Config {
+            seen_retention_days: Some(14),
+            ..Default::default()
+        };
+
+        assert_eq!(Config::default().resolve_seen_retention_days(&[]), None);
+        assert_eq!(config.resolve_seen_retention_days(&[]), Some(14));
+        assert_eq!(
+            config.resolve_seen_retention_days(&[sub(None), sub(Some(TopPostsTimePeriod::Week))]),
+            Some(14)
+        );
+        assert_eq!(
+            config.resolve_seen_retention_days(&[sub(Some(TopPostsTimePeriod::Month))]),
+            Some(31)
+        );
+        // Posts of all time top lists are never pruned
+        assert_eq!(
+            config.resolve_seen_retention_days(&[
+                sub(Some(TopPostsTimePeriod::Day)),
+                sub(Some(TopPostsTimePeriod::All))
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_video_size_cap_mb() {
+        let config = Config::default();
+        assert_eq!(
+            config.video_size_cap_mb(&MediaOptions::default()),
+            DEFAULT_MAX_VIDEO_MB
+        );
+        assert_eq!(
+            config.video_size_cap_mb(&MediaOptions {
+                max_media_mb: Some(20),
+                ..Default::default()
+            }),
+            20
+        );
+
+        let config = Config {
+            max_video_mb: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.video_size_cap_mb(&MediaOptions {
+                max_media_mb: Some(100),
+                ..Default::default()
+            }),
+            30
+        );
+    }
+}
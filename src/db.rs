@@ -1,7 +1,8 @@
 use crate::{config::*, reddit::*, types::*};
 use anyhow::{Context, Result};
+use itertools::Itertools;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef};
-use rusqlite::{named_params, Connection, Row};
+use rusqlite::{named_params, Connection, OptionalExtension, Row};
 use rusqlite_migration::{Migrations, M};
 use std::convert::TryFrom;
 use std::path::Path;
@@ -29,8 +30,131 @@ const MIGRATIONS: &[&str] = &[
         primary key (subreddit, chat_id)
     ) strict;
 ",
+    "
+    alter table subscription add column source_type text not null default 'subreddit';
+    alter table post add column author text;
+    ",
+    "
+    create table chat_settings(
+        chat_id       integer primary key,
+        max_media_mb  integer
+    ) strict;
+    alter table subscription add column max_media_mb integer;
+    ",
+    "
+    alter table subscription add column ytdlp_format text;
+    ",
+    "
+    create table feedback(
+        id          integer primary key,
+        chat_id     integer not null,
+        user_id     integer not null,
+        text        text not null,
+        created_at  text not null
+    ) strict;
+    ",
+    "
+    alter table post add column content_id text;
+    create index post_chat_id_content_id on post (chat_id, content_id);
+    ",
+    "
+    alter table subscription add column group_images integer;
+    ",
+    "
+    alter table chat_settings add column quiet_hours text;
+    create table pending_post(
+        post_id       text not null,
+        chat_id       integer not null,
+        max_media_mb  integer,
+        ytdlp_format  text,
+        queued_at     text not null,
+        primary key (post_id, chat_id)
+    ) strict;
+    ",
+    "
+    alter table subscription add column digest text;
+    alter table post add column delivered integer not null default 1;
+    alter table chat_settings add column last_digest_at text;
+    ",
+    "
+    alter table subscription add column message_thread_id integer;
+    alter table pending_post add column message_thread_id integer;
+    ",
+    "
+    alter table subscription add column include_top_comment integer;
+    alter table pending_post add column include_top_comment integer not null default 0;
+    ",
+    "
+    alter table subscription add column max_age_hours integer;
+    ",
+    "
+    create table failed_post(
+        post_id              text not null,
+        chat_id              integer not null,
+        message_thread_id    integer,
+        max_media_mb         integer,
+        ytdlp_format         text,
+        include_top_comment  integer not null,
+        attempts             integer not null,
+        failed_at            text not null,
+        primary key (post_id, chat_id)
+    ) strict;
+    ",
+    "
+    alter table subscription add column paused integer not null default 0;
+    ",
+    "
+    create table cached_file(
+        key         text primary key,
+        file_id     text not null,
+        size        integer not null,
+        created_at  text not null
+    ) strict;
+    ",
+    "
+    create table blocked_author(
+        chat_id     integer not null,
+        author      text not null collate nocase,
+        primary key (chat_id, author)
+    ) strict;
+    ",
+    "
+    alter table subscription add column keyword text;
+    ",
+    "
+    alter table subscription add column initialized integer not null default 0;
+    ",
+    "
+    alter table post add column title text;
+    alter table post add column permalink text;
+    alter table post add column url text;
+    alter table chat_settings add column feed_token text;
+    create unique index chat_settings_feed_token on chat_settings (feed_token);
+    ",
+    "
+    alter table subscription add column sort text;
+    ",
+    "
+    alter table subscription add column last_seen_fullname text;
+    ",
+    "
+    alter table subscription add column webhook text;
+    alter table pending_post add column webhook text;
+    alter table failed_post add column webhook text;
+    ",
+    "
+    create table webhook_post(
+        webhook     text not null,
+        post_id     text not null,
+        pushed_at   text not null,
+        primary key (webhook, post_id)
+    ) strict;
+    ",
 ];
 
+/// Prefix of the ids of comments in the post table
+const COMMENT_ID_PREFIX: &str = "t1_";
+
 #[derive(Debug)]
 pub struct Database {
     pub conn: Connection,
@@ -43,9 +167,15 @@ impl Database {
         Ok(Database { conn })
     }
 
+    /// Tests get an in-memory database unless they give a db_path, e.g. to check what the
+    /// polling loop stored
     #[cfg(test)]
-    fn get_conn(_db_path: &Path) -> Result<Connection, rusqlite::Error> {
-        Connection::open_in_memory()
+    fn get_conn(db_path: &Path) -> Result<Connection, rusqlite::Error> {
+        if db_path.as_os_str().is_empty() {
+            Connection::open_in_memory()
+        } else {
+            Connection::open(db_path)
+        }
     }
 
     #[cfg(not(test))]
@@ -59,22 +189,142 @@ impl Database {
     }
 
     pub fn mark_post_seen(&self, chat_id: i64, post: &Post) -> Result<()> {
+        self.insert_post(chat_id, post, true)
+    }
+
+    /// Marks a post of a digest subscription seen, to be delivered with the next digest
+    pub fn add_digest_post(&self, chat_id: i64, post: &Post) -> Result<()> {
+        self.insert_post(chat_id, post, false)
+    }
+
+    fn insert_post(&self, chat_id: i64, post: &Post, delivered: bool) -> Result<()> {
         let mut stmt = self.conn.prepare(
             "
-            insert into post (post_id, chat_id, subreddit, seen_at)
-            values (:post_id, :chat_id, :subreddit, :seen_at)
+            insert into post (post_id, chat_id, subreddit, author, content_id, title, permalink, url, delivered, seen_at)
+            values (:post_id, :chat_id, :subreddit, :author, :content_id, :title, :permalink, :url, :delivered, :seen_at)
             ",
         )?;
         stmt.execute(named_params! {
             ":post_id": post.id,
             ":chat_id": chat_id,
             ":subreddit": &post.subreddit,
+            ":author": &post.author,
+            ":content_id": post.content_id(),
+            ":title": &post.title,
+            ":permalink": &post.permalink,
+            ":url": &post.url,
+            ":delivered": delivered,
             ":seen_at": chrono::Utc::now()
         })
         .context("could not mark post seen")
         .map(|_| ())
     }
 
+    /// Chats that have posts waiting for a digest
+    pub fn get_digest_chat_ids(&self) -> Result<Vec<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("select distinct chat_id from post where delivered = 0")?;
+        let chat_ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chat_ids)
+    }
+
+    /// Ids of the posts waiting for the next digest of a chat, in the order they were seen
+    pub fn get_digest_post_ids(&self, chat_id: i64) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "
+            select post_id
+              from post
+             where chat_id = :chat_id and delivered = 0
+             order by seen_at
+            ",
+        )?;
+        let post_ids = stmt
+            .query_map(named_params! { ":chat_id": chat_id }, |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(post_ids)
+    }
+
+    /// Marks the posts of a chat delivered and records when the digest was sent
+    pub fn mark_digest_delivered(
+        &self,
+        chat_id: i64,
+        post_ids: &[String],
+        delivered_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "
+            update post set delivered = 1
+             where chat_id = :chat_id and post_id = :post_id
+            ",
+        )?;
+        for post_id in post_ids {
+            stmt.execute(named_params! { ":chat_id": chat_id, ":post_id": post_id })
+                .context("could not mark post delivered")?;
+        }
+        self.conn
+            .execute(
+                "
+                insert into chat_settings (chat_id, last_digest_at)
+                values (:chat_id, :last_digest_at)
+                on conflict (chat_id) do update set last_digest_at = excluded.last_digest_at
+                ",
+                named_params! { ":chat_id": chat_id, ":last_digest_at": delivered_at },
+            )
+            .context("could not set last digest time")?;
+        Ok(())
+    }
+
+    pub fn get_last_digest_at(
+        &self,
+        chat_id: i64,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let last_digest_at = self
+            .conn
+            .query_row(
+                "select last_digest_at from chat_settings where chat_id = :chat_id",
+                named_params! { ":chat_id": chat_id },
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(last_digest_at)
+    }
+
+    /// Whether a post with the same content, e.g. the original of a crosspost, has been seen in the
+    /// chat since the given time
+    pub fn is_content_seen(
+        &self,
+        chat_id: i64,
+        post: &Post,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool> {
+        let mut stmt = self.conn.prepare(
+            "
+            select exists(
+                select 1
+                  from post
+                 where chat_id = :chat_id and content_id = :content_id and seen_at >= :since
+            );
+            ",
+        )?;
+        let exists: bool = stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":content_id": post.content_id(),
+                ":since": since,
+            },
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    /// Whether the post has been recorded for the chat. The post table is the only record of sent
+    /// posts, there is no in-memory cache, so a post is not sent again however many posts a
+    /// subscription's limit lets through. Posts are forgotten only when pruned by
+    /// seen_retention_days.
     pub fn is_post_seen(&self, chat_id: i64, post: &Post) -> Result<bool> {
         let mut stmt = self.conn.prepare(
             "
@@ -96,6 +346,8 @@ impl Database {
         .map_err(anyhow::Error::from)
     }
 
+    /// Posts are stored with the subreddit they were posted in, so for a multireddit like
+    /// `rust+golang` this checks each of the subreddits.
     pub fn existing_posts_for_subreddit(&self, chat_id: i64, subreddit: &str) -> Result<bool> {
         let mut stmt = self.conn.prepare(
             "
@@ -103,6 +355,75 @@ impl Database {
                 select 1
                   from post
                  where chat_id = :chat_id and subreddit = :subreddit
+                   and substr(post_id, 1, 3) != :comment_prefix
+            );
+            ",
+        )?;
+
+        for subreddit in split_multireddit(subreddit) {
+            let exists: bool = stmt.query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":subreddit": subreddit,
+                    ":comment_prefix": COMMENT_ID_PREFIX,
+                },
+                |row| row.get(0),
+            )?;
+            if exists {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Id of the post from the subreddit that was most recently sent to the chat. For a
+    /// multireddit like `rust+golang` this considers each of the subreddits.
+    pub fn latest_seen_post(&self, chat_id: i64, subreddit: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare(
+            "
+            select post_id, seen_at
+              from post
+             where chat_id = :chat_id and subreddit = :subreddit collate nocase and delivered = 1
+               and substr(post_id, 1, 3) != :comment_prefix
+             order by seen_at desc, rowid desc
+             limit 1
+            ",
+        )?;
+
+        let mut latest: Option<(String, chrono::DateTime<chrono::Utc>)> = None;
+        for subreddit in split_multireddit(subreddit) {
+            let row = stmt
+                .query_row(
+                    named_params! {
+                        ":chat_id": chat_id,
+                        ":subreddit": subreddit,
+                        ":comment_prefix": COMMENT_ID_PREFIX,
+                    },
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            if let Some((post_id, seen_at)) = row {
+                if latest
+                    .as_ref()
+                    .filter(|(_, latest_at)| seen_at <= *latest_at)
+                    .is_none()
+                {
+                    latest = Some((post_id, seen_at));
+                }
+            }
+        }
+
+        Ok(latest.map(|(post_id, _)| post_id))
+    }
+
+    pub fn existing_posts_for_user(&self, chat_id: i64, user: &str) -> Result<bool> {
+        let mut stmt = self.conn.prepare(
+            "
+            select exists(
+                select 1
+                  from post
+                 where chat_id = :chat_id and author = :author
             );
             ",
         )?;
@@ -110,74 +431,234 @@ impl Database {
         stmt.query_row(
             named_params! {
                 ":chat_id": chat_id,
-                ":subreddit": subreddit,
+                ":author": user,
             },
             |row| row.get(0),
         )
         .map_err(anyhow::Error::from)
     }
 
+    /// Whether any posts have been seen for the subscription, i.e. it has been checked before.
+    /// `posts` are the posts just fetched for the subscription. Since the subreddits of a saved
+    /// multireddit aren't stored, the subreddits of those posts are checked instead.
+    pub fn existing_posts_for_subscription(
+        &self,
+        sub: &Subscription,
+        posts: &[Post],
+    ) -> Result<bool> {
+        match sub.source_type {
+            SourceType::Subreddit => self.existing_posts_for_subreddit(sub.chat_id, &sub.subreddit),
+            SourceType::User => self.existing_posts_for_user(sub.chat_id, &sub.subreddit),
+            SourceType::Multireddit => {
+                let subreddits = posts.iter().map(|p| p.subreddit.as_str()).join("+");
+                self.existing_posts_for_subreddit(sub.chat_id, &subreddits)
+            }
+            // Comments are checked against the time the subscription was created instead
+            SourceType::Comments => Ok(true),
+        }
+    }
+
     pub fn subscribe(&self, chat_id: i64, args: &SubscriptionArgs) -> Result<()> {
         let mut stmt = self.conn.prepare(
             "
-            insert into subscription (chat_id, subreddit, post_limit, time, filter, created_at)
-            values (:chat_id, :subreddit, :limit, :time, :filter, :created_at)
+            insert into subscription (chat_id, source_type, subreddit, post_limit, time, sort, filter, max_media_mb, ytdlp_format, group_images, include_top_comment, max_age_hours, digest, message_thread_id, keyword, webhook, created_at)
+            values (:chat_id, :source_type, :subreddit, :limit, :time, :sort, :filter, :max_media_mb, :ytdlp_format, :group_images, :include_top_comment, :max_age_hours, :digest, :message_thread_id, :keyword, :webhook, :created_at)
             ",
         )?;
         stmt.execute(named_params! {
             ":chat_id": chat_id,
+            ":source_type": args.source_type,
             ":subreddit": args.subreddit,
             ":limit": args.limit,
             ":time": args.time,
+            ":sort": args.sort,
             ":filter": args.filter,
+            ":max_media_mb": args.max_media_mb,
+            ":ytdlp_format": args.ytdlp_format,
+            ":group_images": args.group_images,
+            ":include_top_comment": args.include_top_comment,
+            ":max_age_hours": args.max_age_hours,
+            ":digest": args.digest,
+            ":message_thread_id": args.message_thread_id,
+            ":keyword": args.keyword,
+            ":webhook": args.webhook,
             ":created_at": chrono::Utc::now()
         })
         .context("could not add subscription")?;
         Ok(())
     }
 
-    pub fn unsubscribe(&self, chat_id: i64, subreddit: &str) -> Result<String> {
+    pub fn unsubscribe(&self, chat_id: i64, source_type: SourceType, name: &str) -> Result<String> {
         let mut stmt = self.conn.prepare(
             "
             delete from subscription
-            where chat_id = :chat_id and subreddit LIKE :subreddit
+            where chat_id = :chat_id and source_type = :source_type and subreddit LIKE :subreddit
             returning subreddit
             ",
         )?;
-        let deleted_subreddit: String = stmt
+        let deleted_name: String = stmt
             .query_row(
                 named_params! {
                     ":chat_id": chat_id,
-                    ":subreddit": subreddit,
+                    ":source_type": source_type,
+                    ":subreddit": name,
                 },
                 |row| row.get("subreddit"),
             )
             .context("could not delete subscription")?;
 
         // Delete posts so that if subreddit is subscribed to later, the first posts seen won't be
-        // considered new.
-        let mut stmt = self.conn.prepare(
-            "
-            delete from post
-            where chat_id = :chat_id and subreddit = :subreddit
-            ",
-        )?;
-        stmt.execute(named_params! {
-            ":chat_id": chat_id,
-            ":subreddit": deleted_subreddit,
-        })
-        .context("could not delete posts")?;
+        // considered new. The subreddits of a saved multireddit aren't stored, so its posts are
+        // left as they are.
+        let (sql, names) = match source_type {
+            SourceType::Subreddit => (
+                "
+                delete from post
+                where chat_id = :chat_id and subreddit = :name and substr(post_id, 1, 3) != 't1_'
+                ",
+                split_multireddit(&deleted_name),
+            ),
+            SourceType::Comments => (
+                "
+                delete from post
+                where chat_id = :chat_id and subreddit = :name and substr(post_id, 1, 3) = 't1_'
+                ",
+                split_multireddit(&deleted_name),
+            ),
+            SourceType::User => (
+                "
+                delete from post
+                where chat_id = :chat_id and author = :name
+                ",
+                vec![deleted_name.as_str()],
+            ),
+            SourceType::Multireddit => return Ok(deleted_name),
+        };
+        let mut stmt = self.conn.prepare(sql)?;
+        for name in names {
+            stmt.execute(named_params! {
+                ":chat_id": chat_id,
+                ":name": name,
+            })
+            .context("could not delete posts")?;
+        }
+
+        Ok(deleted_name)
+    }
+
+    /// Removes all subscriptions of the chat along with its seen, pending and failed posts. Returns
+    /// the number of subscriptions removed.
+    pub fn unsubscribe_all(&self, chat_id: i64) -> Result<usize> {
+        let count = self
+            .conn
+            .execute(
+                "delete from subscription where chat_id = :chat_id",
+                named_params! { ":chat_id": chat_id },
+            )
+            .context("could not delete subscriptions")?;
+        self.conn
+            .execute(
+                "delete from post where chat_id = :chat_id",
+                named_params! { ":chat_id": chat_id },
+            )
+            .context("could not delete posts")?;
+        self.conn
+            .execute(
+                "delete from pending_post where chat_id = :chat_id",
+                named_params! { ":chat_id": chat_id },
+            )
+            .context("could not delete pending posts")?;
+        self.conn
+            .execute(
+                "delete from failed_post where chat_id = :chat_id",
+                named_params! { ":chat_id": chat_id },
+            )
+            .context("could not delete failed posts")?;
+        Ok(count)
+    }
+
+    /// Stops checking a subscription until it's subscribed to again
+    pub fn pause_subscription(
+        &self,
+        chat_id: i64,
+        source_type: SourceType,
+        subreddit: &str,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "
+                update subscription
+                   set paused = 1
+                 where chat_id = :chat_id and source_type = :source_type and subreddit = :subreddit
+                ",
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":source_type": source_type,
+                    ":subreddit": subreddit,
+                },
+            )
+            .context("could not pause subscription")?;
+        Ok(())
+    }
+
+    /// Records that the subscription has been checked, see Subscription::initialized
+    pub fn mark_subscription_initialized(
+        &self,
+        chat_id: i64,
+        source_type: SourceType,
+        subreddit: &str,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "
+                update subscription
+                   set initialized = 1
+                 where chat_id = :chat_id and source_type = :source_type and subreddit = :subreddit
+                ",
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":source_type": source_type,
+                    ":subreddit": subreddit,
+                },
+            )
+            .context("could not mark subscription initialized")?;
+        Ok(())
+    }
 
-        Ok(deleted_subreddit)
+    /// Records the newest post seen by a sort=new subscription, see
+    /// Subscription::last_seen_fullname
+    pub fn set_last_seen_fullname(
+        &self,
+        chat_id: i64,
+        source_type: SourceType,
+        subreddit: &str,
+        fullname: &str,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "
+                update subscription
+                   set last_seen_fullname = :fullname
+                 where chat_id = :chat_id and source_type = :source_type and subreddit = :subreddit
+                ",
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":source_type": source_type,
+                    ":subreddit": subreddit,
+                    ":fullname": fullname,
+                },
+            )
+            .context("could not set last seen post of subscription")?;
+        Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn get_subscriptions_for_chat(&self, chat_id: i64) -> Result<Vec<Subscription>> {
         let mut stmt = self.conn.prepare(
             "
-            select chat_id, subreddit, post_limit, time, filter, created_at
+            select chat_id, source_type, subreddit, post_limit, time, sort, filter, max_media_mb, ytdlp_format, group_images, include_top_comment, max_age_hours, digest, message_thread_id, keyword, webhook, created_at, paused, initialized, last_seen_fullname
             from subscription
             where chat_id = ?
+            order by subreddit collate nocase
             ",
         )?;
 
@@ -191,7 +672,7 @@ impl Database {
     pub fn get_all_subscriptions(&self) -> Result<Vec<Subscription>> {
         let mut stmt = self.conn.prepare(
             "
-            select chat_id, subreddit, post_limit, time, filter, created_at
+            select chat_id, source_type, subreddit, post_limit, time, sort, filter, max_media_mb, ytdlp_format, group_images, include_top_comment, max_age_hours, digest, message_thread_id, keyword, webhook, created_at, paused, initialized, last_seen_fullname
             from subscription
             ",
         )?;
@@ -202,83 +683,734 @@ impl Database {
 
         Ok(subs)
     }
-}
 
-impl ToSql for TopPostsTimePeriod {
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
-        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    pub fn get_chat_settings(&self, chat_id: i64) -> Result<ChatSettings> {
+        let mut stmt = self.conn.prepare(
+            "
+            select max_media_mb, quiet_hours
+              from chat_settings
+             where chat_id = :chat_id
+            ",
+        )?;
+        let settings = stmt
+            .query_row(named_params! { ":chat_id": chat_id }, |row| {
+                Ok(ChatSettings {
+                    max_media_mb: row.get("max_media_mb")?,
+                    quiet_hours: row.get("quiet_hours")?,
+                })
+            })
+            .optional()?
+            .unwrap_or_default();
+
+        Ok(settings)
     }
-}
 
-impl ToSql for PostType {
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
-        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    /// Token of the chat's RSS feed, created on first use. The token is random so that the feed
+    /// url can't be guessed from the chat id.
+    pub fn get_or_create_feed_token(&self, chat_id: i64) -> Result<String> {
+        self.conn
+            .query_row(
+                "
+                insert into chat_settings (chat_id, feed_token)
+                values (:chat_id, lower(hex(randomblob(16))))
+                on conflict (chat_id) do update
+                   set feed_token = coalesce(feed_token, excluded.feed_token)
+                returning feed_token
+                ",
+                named_params! { ":chat_id": chat_id },
+                |row| row.get(0),
+            )
+            .context("could not get feed token")
     }
-}
 
-impl FromSql for TopPostsTimePeriod {
-    fn column_result(value: ValueRef) -> FromSqlResult<TopPostsTimePeriod> {
-        let str = String::column_result(value)?;
-        TopPostsTimePeriod::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    pub fn get_feed_chat_id(&self, feed_token: &str) -> Result<Option<i64>> {
+        self.conn
+            .query_row(
+                "select chat_id from chat_settings where feed_token = :feed_token",
+                named_params! { ":feed_token": feed_token },
+                |row| row.get(0),
+            )
+            .optional()
+            .context("could not query feed token")
     }
-}
 
-impl FromSql for PostType {
-    fn column_result(value: ValueRef) -> FromSqlResult<PostType> {
-        let str = String::column_result(value)?;
-        PostType::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    /// Posts most recently delivered to the chat, newest first. Posts seen before titles were
+    /// stored are left out.
+    pub fn get_feed_items(&self, chat_id: i64, limit: u32) -> Result<Vec<FeedItem>> {
+        let mut stmt = self.conn.prepare(
+            "
+            select post_id, subreddit, title, permalink, url, seen_at
+              from post
+             where chat_id = :chat_id and delivered = 1 and title is not null
+             order by seen_at desc, rowid desc
+             limit :limit
+            ",
+        )?;
+        let items = stmt
+            .query_map(
+                named_params! { ":chat_id": chat_id, ":limit": limit },
+                |row| {
+                    Ok(FeedItem {
+                        post_id: row.get("post_id")?,
+                        subreddit: row.get("subreddit")?,
+                        title: row.get("title")?,
+                        permalink: row.get("permalink")?,
+                        url: row.get("url")?,
+                        seen_at: row.get("seen_at")?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(items)
     }
-}
 
-impl TryFrom<&Row<'_>> for Subscription {
-    type Error = rusqlite::Error;
+    pub fn set_chat_max_media_mb(&self, chat_id: i64, max_media_mb: Option<u32>) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "
+            insert into chat_settings (chat_id, max_media_mb)
+            values (:chat_id, :max_media_mb)
+            on conflict (chat_id) do update set max_media_mb = excluded.max_media_mb
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":max_media_mb": max_media_mb,
+        })
+        .context("could not update chat settings")?;
+        Ok(())
+    }
 
-    fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
-        Ok(Self {
-            subreddit: row.get_unwrap("subreddit"),
-            chat_id: row.get_unwrap("chat_id"),
-            limit: row.get_unwrap("post_limit"),
-            time: row.get_unwrap("time"),
-            filter: row.get_unwrap("filter"),
+    pub fn set_chat_quiet_hours(
+        &self,
+        chat_id: i64,
+        quiet_hours: Option<QuietHours>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "
+            insert into chat_settings (chat_id, quiet_hours)
+            values (:chat_id, :quiet_hours)
+            on conflict (chat_id) do update set quiet_hours = excluded.quiet_hours
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":quiet_hours": quiet_hours,
         })
+        .context("could not update chat settings")?;
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::reddit::PostType;
+    /// Holds back a post to be sent later. Queueing the same post again is a no-op.
+    pub fn add_pending_post(
+        &self,
+        chat_id: i64,
+        message_thread_id: Option<i32>,
+        post: &Post,
+        media_options: &MediaOptions,
+        webhook: Option<&str>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "
+            insert or ignore into pending_post (post_id, chat_id, message_thread_id, max_media_mb, ytdlp_format, include_top_comment, webhook, queued_at)
+            values (:post_id, :chat_id, :message_thread_id, :max_media_mb, :ytdlp_format, :include_top_comment, :webhook, :queued_at)
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":post_id": post.id,
+            ":chat_id": chat_id,
+            ":message_thread_id": message_thread_id,
+            ":max_media_mb": media_options.max_media_mb,
+            ":ytdlp_format": media_options.ytdlp_format,
+            ":include_top_comment": media_options.include_top_comment,
+            ":webhook": webhook,
+            ":queued_at": chrono::Utc::now()
+        })
+        .context("could not add pending post")?;
+        Ok(())
+    }
 
-    #[test]
-    fn test_db() {
-        let config = Config::default();
-        let mut db = Database::open(&config).unwrap();
-        db.migrate().unwrap();
-        let post = Post {
-            id: "v6nu75".into(),
-            created: 1654581100.0,
-            post_hint: Some("link".into()),
-            subreddit: "absoluteunit".into(),
-            title: "Tipping a cow to trim its hooves".into(),
-            is_self: false,
-            is_video: false,
-            is_gallery: Some(false),
-            gallery_data: None,
-            media_metadata: None,
-            ups: 469,
-            permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
-            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
-            post_type: PostType::Video,
-            crosspost_parent_list: None,
-        };
+    pub fn get_pending_posts(&self) -> Result<Vec<PendingPost>> {
+        let mut stmt = self.conn.prepare(
+            "
+            select post_id, chat_id, message_thread_id, max_media_mb, ytdlp_format, include_top_comment, webhook
+            from pending_post
+            order by queued_at
+            ",
+        )?;
+        let pending_posts = stmt
+            .query_map([], |row| {
+                Ok(PendingPost {
+                    chat_id: row.get("chat_id")?,
+                    post_id: row.get("post_id")?,
+                    message_thread_id: row.get("message_thread_id")?,
+                    media_options: MediaOptions {
+                        max_media_mb: row.get("max_media_mb")?,
+                        ytdlp_format: row.get("ytdlp_format")?,
+                        show_progress: false,
+                        include_top_comment: row.get("include_top_comment")?,
+                    },
+                    webhook: row.get("webhook")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(pending_posts)
+    }
 
-        assert!(!db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
-        db.mark_post_seen(1, &post).unwrap();
-        assert!(db.is_post_seen(1, &post).unwrap());
-        assert!(db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
+    pub fn delete_pending_post(&self, chat_id: i64, post_id: &str) -> Result<()> {
+        self.conn.execute(
+            "delete from pending_post where chat_id = :chat_id and post_id = :post_id",
+            named_params! { ":chat_id": chat_id, ":post_id": post_id },
+        )?;
+        Ok(())
     }
 
-    #[test]
+    /// Records a failed attempt to send a post. The attempts of an already failed post are
+    /// incremented.
+    pub fn add_failed_post(
+        &self,
+        chat_id: i64,
+        message_thread_id: Option<i32>,
+        post_id: &str,
+        media_options: &MediaOptions,
+        webhook: Option<&str>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "
+            insert into failed_post (post_id, chat_id, message_thread_id, max_media_mb, ytdlp_format, include_top_comment, webhook, attempts, failed_at)
+            values (:post_id, :chat_id, :message_thread_id, :max_media_mb, :ytdlp_format, :include_top_comment, :webhook, 1, :failed_at)
+            on conflict (post_id, chat_id) do update set attempts = attempts + 1, failed_at = :failed_at
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":post_id": post_id,
+            ":chat_id": chat_id,
+            ":message_thread_id": message_thread_id,
+            ":max_media_mb": media_options.max_media_mb,
+            ":ytdlp_format": media_options.ytdlp_format,
+            ":include_top_comment": media_options.include_top_comment,
+            ":webhook": webhook,
+            ":failed_at": chrono::Utc::now()
+        })
+        .context("could not add failed post")?;
+        Ok(())
+    }
+
+    pub fn get_failed_posts(&self) -> Result<Vec<FailedPost>> {
+        let mut stmt = self.conn.prepare(
+            "
+            select post_id, chat_id, message_thread_id, max_media_mb, ytdlp_format, include_top_comment, webhook, attempts
+            from failed_post
+            order by failed_at
+            ",
+        )?;
+        let failed_posts = stmt
+            .query_map([], |row| {
+                Ok(FailedPost {
+                    chat_id: row.get("chat_id")?,
+                    post_id: row.get("post_id")?,
+                    message_thread_id: row.get("message_thread_id")?,
+                    media_options: MediaOptions {
+                        max_media_mb: row.get("max_media_mb")?,
+                        ytdlp_format: row.get("ytdlp_format")?,
+                        show_progress: false,
+                        include_top_comment: row.get("include_top_comment")?,
+                    },
+                    webhook: row.get("webhook")?,
+                    attempts: row.get("attempts")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(failed_posts)
+    }
+
+    pub fn delete_failed_post(&self, chat_id: i64, post_id: &str) -> Result<()> {
+        self.conn
+            .execute(
+                "delete from failed_post where chat_id = :chat_id and post_id = :post_id",
+                named_params! { ":chat_id": chat_id, ":post_id": post_id },
+            )
+            .context("could not delete failed post")?;
+        Ok(())
+    }
+
+    pub fn add_feedback(&self, chat_id: i64, user_id: u64, text: &str) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "
+            insert into feedback (chat_id, user_id, text, created_at)
+            values (:chat_id, :user_id, :text, :created_at)
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":user_id": user_id,
+            ":text": text,
+            ":created_at": chrono::Utc::now()
+        })
+        .context("could not add feedback")?;
+        Ok(())
+    }
+
+    pub fn get_last_feedback_at(
+        &self,
+        user_id: u64,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let created_at = self
+            .conn
+            .query_row(
+                "
+                select created_at
+                from feedback
+                where user_id = :user_id
+                order by created_at desc
+                limit 1
+                ",
+                named_params! { ":user_id": user_id },
+                |row| row.get("created_at"),
+            )
+            .optional()?;
+        Ok(created_at)
+    }
+
+    /// Claims pushing the post to the webhook. Returns false when the post has been pushed to it
+    /// already, e.g. for another chat subscribed to the same subreddit.
+    pub fn claim_webhook_post(&self, webhook: &str, post_id: &str) -> Result<bool> {
+        let inserted = self
+            .conn
+            .execute(
+                "
+                insert or ignore into webhook_post (webhook, post_id, pushed_at)
+                values (:webhook, :post_id, :pushed_at)
+                ",
+                named_params! {
+                    ":webhook": webhook,
+                    ":post_id": post_id,
+                    ":pushed_at": chrono::Utc::now(),
+                },
+            )
+            .context("could not claim webhook post")?;
+        Ok(inserted == 1)
+    }
+
+    /// Deletes seen posts older than the given number of days. Returns the number of posts deleted.
+    pub fn prune_seen_posts(&self, older_than_days: u32) -> Result<usize> {
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(older_than_days.into());
+        let count = self
+            .conn
+            .execute(
+                // Posts waiting for a digest are kept until delivered
+                "delete from post where seen_at < :cutoff and delivered = 1",
+                named_params! { ":cutoff": cutoff },
+            )
+            .context("could not prune seen posts")?;
+        // Telegram's file_ids don't last forever either
+        self.conn
+            .execute(
+                "delete from cached_file where created_at < :cutoff",
+                named_params! { ":cutoff": cutoff },
+            )
+            .context("could not prune cached files")?;
+        self.conn
+            .execute(
+                "delete from webhook_post where pushed_at < :cutoff",
+                named_params! { ":cutoff": cutoff },
+            )
+            .context("could not prune webhook posts")?;
+        Ok(count)
+    }
+
+    /// Comments are recorded in the post table like posts, with their id in Reddit's t1_ namespace
+    /// so that they can't collide with post ids
+    pub fn mark_comment_seen(&self, chat_id: i64, comment: &Comment) -> Result<()> {
+        self.conn
+            .execute(
+                "
+                insert into post (post_id, chat_id, subreddit, author, content_id, delivered, seen_at)
+                values (:post_id, :chat_id, :subreddit, :author, :content_id, 1, :seen_at)
+                ",
+                named_params! {
+                    ":post_id": format!("{COMMENT_ID_PREFIX}{}", comment.id),
+                    ":chat_id": chat_id,
+                    ":subreddit": &comment.subreddit,
+                    ":author": &comment.author,
+                    ":content_id": &comment.permalink,
+                    ":seen_at": chrono::Utc::now(),
+                },
+            )
+            .context("could not mark comment seen")?;
+        Ok(())
+    }
+
+    pub fn is_comment_seen(&self, chat_id: i64, comment: &Comment) -> Result<bool> {
+        self.conn
+            .query_row(
+                "select exists(select 1 from post where post_id = :post_id and chat_id = :chat_id)",
+                named_params! {
+                    ":post_id": format!("{COMMENT_ID_PREFIX}{}", comment.id),
+                    ":chat_id": chat_id,
+                },
+                |row| row.get(0),
+            )
+            .context("could not query if comment is seen")
+    }
+
+    /// Blocks posts by the author in the chat. Returns false if the author was already blocked.
+    pub fn block_author(&self, chat_id: i64, author: &str) -> Result<bool> {
+        let inserted = self
+            .conn
+            .execute(
+                "
+                insert or ignore into blocked_author (chat_id, author)
+                values (:chat_id, :author)
+                ",
+                named_params! { ":chat_id": chat_id, ":author": author },
+            )
+            .context("could not block author")?;
+        Ok(inserted > 0)
+    }
+
+    pub fn get_blocked_authors(&self, chat_id: i64) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "
+            select author
+              from blocked_author
+             where chat_id = :chat_id
+             order by author
+            ",
+        )?;
+        let authors = stmt
+            .query_map(named_params! { ":chat_id": chat_id }, |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(authors)
+    }
+
+    pub fn get_cached_file(&self, key: &str) -> Result<Option<CachedFile>> {
+        self.conn
+            .query_row(
+                "select file_id, size from cached_file where key = :key",
+                named_params! { ":key": key },
+                |row| {
+                    Ok(CachedFile {
+                        file_id: row.get("file_id")?,
+                        size: row.get("size")?,
+                    })
+                },
+            )
+            .optional()
+            .context("could not get cached file")
+    }
+
+    pub fn set_cached_file(&self, key: &str, file: &CachedFile) -> Result<()> {
+        self.conn
+            .execute(
+                "
+                insert or replace into cached_file (key, file_id, size, created_at)
+                values (:key, :file_id, :size, :created_at)
+                ",
+                named_params! {
+                    ":key": key,
+                    ":file_id": file.file_id,
+                    ":size": file.size,
+                    ":created_at": chrono::Utc::now(),
+                },
+            )
+            .context("could not cache file")?;
+        Ok(())
+    }
+
+    pub fn delete_cached_file(&self, key: &str) -> Result<()> {
+        self.conn
+            .execute(
+                "delete from cached_file where key = :key",
+                named_params! { ":key": key },
+            )
+            .context("could not delete cached file")?;
+        Ok(())
+    }
+
+    /// Checks that the database can be queried
+    pub fn ping(&self) -> Result<()> {
+        self.conn.query_row("select 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    pub fn count_subscriptions(&self, chat_id: i64) -> Result<u64> {
+        let count = self.conn.query_row(
+            "select count(*) from subscription where chat_id = :chat_id",
+            named_params! { ":chat_id": chat_id },
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    pub fn count_seen_posts(&self, chat_id: i64) -> Result<u64> {
+        let count = self.conn.query_row(
+            "select count(*) from post where chat_id = :chat_id",
+            named_params! { ":chat_id": chat_id },
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    pub fn count_seen_posts_since(
+        &self,
+        chat_id: i64,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<u64> {
+        let count = self.conn.query_row(
+            "select count(*) from post where chat_id = :chat_id and seen_at >= :since",
+            named_params! { ":chat_id": chat_id, ":since": since },
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Subreddit with the most seen posts in the chat, along with the count
+    pub fn most_active_subreddit(&self, chat_id: i64) -> Result<Option<(String, u64)>> {
+        let subreddit = self
+            .conn
+            .query_row(
+                "
+                select subreddit, count(*) as count
+                from post
+                where chat_id = :chat_id
+                group by subreddit
+                order by count desc, subreddit
+                limit 1
+                ",
+                named_params! { ":chat_id": chat_id },
+                |row| Ok((row.get("subreddit")?, row.get("count")?)),
+            )
+            .optional()?;
+        Ok(subreddit)
+    }
+
+    pub fn get_chat_stats(&self, chat_id: i64) -> Result<ChatStats> {
+        let day_ago = chrono::Utc::now() - chrono::Duration::days(1);
+        Ok(ChatStats {
+            subscriptions: self.count_subscriptions(chat_id)?,
+            seen_posts: self.count_seen_posts(chat_id)?,
+            seen_posts_last_day: self.count_seen_posts_since(chat_id, day_ago)?,
+            most_active_subreddit: self.most_active_subreddit(chat_id)?,
+        })
+    }
+
+    pub fn get_stats(&self) -> Result<DatabaseStats> {
+        let stats = self.conn.query_row(
+            "
+            select (select count(*) from subscription) as subscriptions,
+                   (select count(distinct chat_id) from subscription) as chats,
+                   (select count(*) from post) as seen_posts
+            ",
+            [],
+            |row| {
+                Ok(DatabaseStats {
+                    subscriptions: row.get("subscriptions")?,
+                    chats: row.get("chats")?,
+                    seen_posts: row.get("seen_posts")?,
+                })
+            },
+        )?;
+
+        Ok(stats)
+    }
+}
+
+impl ToSql for TopPostsTimePeriod {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for SourceType {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for PostType {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for TopPostsTimePeriod {
+    fn column_result(value: ValueRef) -> FromSqlResult<TopPostsTimePeriod> {
+        let str = String::column_result(value)?;
+        TopPostsTimePeriod::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl FromSql for SourceType {
+    fn column_result(value: ValueRef) -> FromSqlResult<SourceType> {
+        let str = String::column_result(value)?;
+        SourceType::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for SortMode {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for SortMode {
+    fn column_result(value: ValueRef) -> FromSqlResult<SortMode> {
+        let str = String::column_result(value)?;
+        SortMode::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for QuietHours {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for QuietHours {
+    fn column_result(value: ValueRef) -> FromSqlResult<QuietHours> {
+        let str = String::column_result(value)?;
+        QuietHours::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for DigestMode {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for DigestMode {
+    fn column_result(value: ValueRef) -> FromSqlResult<DigestMode> {
+        let str = String::column_result(value)?;
+        DigestMode::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl FromSql for PostType {
+    fn column_result(value: ValueRef) -> FromSqlResult<PostType> {
+        let str = String::column_result(value)?;
+        PostType::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl TryFrom<&Row<'_>> for Subscription {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            source_type: row.get_unwrap("source_type"),
+            subreddit: row.get_unwrap("subreddit"),
+            chat_id: row.get_unwrap("chat_id"),
+            limit: row.get_unwrap("post_limit"),
+            time: row.get_unwrap("time"),
+            sort: row.get_unwrap("sort"),
+            filter: row.get_unwrap("filter"),
+            max_media_mb: row.get_unwrap("max_media_mb"),
+            ytdlp_format: row.get_unwrap("ytdlp_format"),
+            group_images: row.get_unwrap("group_images"),
+            include_top_comment: row.get_unwrap("include_top_comment"),
+            max_age_hours: row.get_unwrap("max_age_hours"),
+            digest: row.get_unwrap("digest"),
+            message_thread_id: row.get_unwrap("message_thread_id"),
+            keyword: row.get_unwrap("keyword"),
+            webhook: row.get_unwrap("webhook"),
+            created_at: row.get_unwrap("created_at"),
+            paused: row.get_unwrap("paused"),
+            initialized: row.get_unwrap("initialized"),
+            last_seen_fullname: row.get_unwrap("last_seen_fullname"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reddit::PostType;
+
+    #[test]
+    fn test_db() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            ..Post::test_default()
+        };
+
+        assert!(!db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
+        db.mark_post_seen(1, &post).unwrap();
+        assert!(db.is_post_seen(1, &post).unwrap());
+        assert!(db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
+    }
+
+    #[test]
+    fn test_db_latest_seen_post() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            subreddit: "rust".into(),
+            title: "A post".into(),
+            permalink: "/r/rust/comments/v6nu75/a_post/".into(),
+            ..Post::test_default()
+        };
+
+        assert_eq!(db.latest_seen_post(1, "rust").unwrap(), None);
+        db.mark_post_seen(1, &post).unwrap();
+        let newer_post = Post {
+            id: "v6nu76".into(),
+            ..post.clone()
+        };
+        db.mark_post_seen(1, &newer_post).unwrap();
+        // Posts waiting for a digest haven't been sent yet
+        let digest_post = Post {
+            id: "v6nu77".into(),
+            ..post.clone()
+        };
+        db.add_digest_post(1, &digest_post).unwrap();
+
+        assert_eq!(
+            db.latest_seen_post(1, "Rust").unwrap().as_deref(),
+            Some("v6nu76")
+        );
+        assert_eq!(
+            db.latest_seen_post(1, "golang+rust").unwrap().as_deref(),
+            Some("v6nu76")
+        );
+        assert_eq!(db.latest_seen_post(2, "rust").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_is_content_seen() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let original = Post {
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: "/r/pics/comments/v6nu75/a_picture/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            post_type: PostType::Image,
+            ..Post::test_default()
+        };
+        let crosspost = Post {
+            id: "w7ov86".into(),
+            subreddit: "aww".into(),
+            permalink: "/r/aww/comments/w7ov86/a_picture/".into(),
+            crosspost_parent_list: Some(vec![original.clone()]),
+            ..original.clone()
+        };
+        let hour_ago = chrono::Utc::now() - chrono::Duration::hours(1);
+
+        assert!(!db.is_content_seen(1, &crosspost, hour_ago).unwrap());
+        db.mark_post_seen(1, &original).unwrap();
+        assert!(db.is_content_seen(1, &crosspost, hour_ago).unwrap());
+        assert!(!db.is_content_seen(2, &crosspost, hour_ago).unwrap());
+        let hour_from_now = chrono::Utc::now() + chrono::Duration::hours(1);
+        assert!(!db.is_content_seen(1, &crosspost, hour_from_now).unwrap());
+    }
+
+    #[test]
     fn test_db_subscribe() {
         let config = Config::default();
         let mut db = Database::open(&config).unwrap();
@@ -288,6 +1420,7 @@ mod tests {
             limit: Some(1),
             time: Some(TopPostsTimePeriod::Week),
             filter: Some(PostType::Video),
+            ..SubscriptionArgs::test_default()
         };
         db.subscribe(1, &subscription_args).unwrap();
 
@@ -300,10 +1433,84 @@ mod tests {
                 limit: Some(1),
                 time: Some(TopPostsTimePeriod::Week),
                 filter: Some(PostType::Video),
+                created_at: subs[0].created_at,
+                ..Subscription::test_default()
             }]
         );
     }
 
+    #[test]
+    fn test_db_subscriptions_for_chat_sorted_by_name() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        for name in ["rust", "Askreddit", "pics"] {
+            let args = SubscriptionArgs {
+                subreddit: name.to_string(),
+                ..SubscriptionArgs::test_default()
+            };
+            db.subscribe(1, &args).unwrap();
+        }
+
+        let names: Vec<_> = db
+            .get_subscriptions_for_chat(1)
+            .unwrap()
+            .into_iter()
+            .map(|sub| sub.subreddit)
+            .collect();
+        assert_eq!(names, vec!["Askreddit", "pics", "rust"]);
+    }
+
+    #[test]
+    fn test_db_pause_subscription() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "secret".to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        assert!(!db.get_all_subscriptions().unwrap()[0].paused);
+
+        db.pause_subscription(1, SourceType::Subreddit, "secret")
+            .unwrap();
+        assert!(db.get_all_subscriptions().unwrap()[0].paused);
+    }
+
+    #[test]
+    fn test_db_mark_subscription_initialized() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "quiet".to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        assert!(!db.get_all_subscriptions().unwrap()[0].initialized);
+
+        db.mark_subscription_initialized(1, SourceType::Subreddit, "quiet")
+            .unwrap();
+        assert!(db.get_all_subscriptions().unwrap()[0].initialized);
+    }
+
+    #[test]
+    fn test_db_subscribe_in_topic() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            message_thread_id: Some(42),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(subs[0].message_thread_id, Some(42));
+    }
+
     #[test]
     fn test_db_unsubscribe() {
         let config = Config::default();
@@ -314,11 +1521,12 @@ mod tests {
             limit: Some(1),
             time: Some(TopPostsTimePeriod::Week),
             filter: Some(PostType::Video),
+            ..SubscriptionArgs::test_default()
         };
         db.subscribe(1, &subscription_args).unwrap();
         let subs = db.get_subscriptions_for_chat(1).unwrap();
         assert_eq!(subs.len(), 1);
-        let deleted = db.unsubscribe(1, "test").unwrap();
+        let deleted = db.unsubscribe(1, SourceType::Subreddit, "test").unwrap();
         assert_eq!(deleted, "test");
         let subs = db.get_subscriptions_for_chat(1).unwrap();
         assert_eq!(subs, vec![]);
@@ -334,28 +1542,558 @@ mod tests {
             limit: Some(1),
             time: Some(TopPostsTimePeriod::Week),
             filter: Some(PostType::Video),
+            ..SubscriptionArgs::test_default()
         };
         db.subscribe(1, &subscription_args).unwrap();
         let post = Post {
-            id: "v6nu75".into(),
-            created: 1654581100.0,
-            post_hint: Some("link".into()),
             subreddit: "test".into(),
-            title: "Tipping a cow to trim its hooves".into(),
-            is_self: false,
-            is_gallery: Some(false),
-            is_video: false,
-            gallery_data: None,
-            media_metadata: None,
-            ups: 469,
             permalink: "/r/test/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
-            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
-            post_type: PostType::Video,
-            crosspost_parent_list: None,
+            ..Post::test_default()
         };
         db.mark_post_seen(1, &post).unwrap();
         assert!(db.is_post_seen(1, &post).unwrap());
-        db.unsubscribe(1, "test").unwrap();
+        db.unsubscribe(1, SourceType::Subreddit, "test").unwrap();
+        assert!(!db.is_post_seen(1, &post).unwrap());
+    }
+
+    #[test]
+    fn test_db_comment_seen() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            source_type: SourceType::Comments,
+            subreddit: "rust".to_string(),
+            keyword: Some("tokio".to_string()),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(subs[0].keyword.as_deref(), Some("tokio"));
+
+        let comment = Comment {
+            id: "ibz1s2x".into(),
+            author: "someone".into(),
+            body: "Have you tried tokio?".into(),
+            score: 3,
+            subreddit: "rust".into(),
+            permalink: "/r/rust/comments/v6nu75/a_post/ibz1s2x/".into(),
+            stickied: false,
+            created: 1654581100.0,
+        };
+        assert!(!db.is_comment_seen(1, &comment).unwrap());
+        db.mark_comment_seen(1, &comment).unwrap();
+        assert!(db.is_comment_seen(1, &comment).unwrap());
+        assert!(!db.is_comment_seen(2, &comment).unwrap());
+        // Comments are not taken as posts seen in the subreddit
+        assert_eq!(db.latest_seen_post(1, "rust").unwrap(), None);
+
+        db.unsubscribe(1, SourceType::Comments, "rust").unwrap();
+        assert!(!db.is_comment_seen(1, &comment).unwrap());
+    }
+
+    #[test]
+    fn test_db_user_subscription() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            source_type: SourceType::User,
+            subreddit: "spez".to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let post = Post {
+            subreddit: "announcements".into(),
+            author: "spez".into(),
+            permalink: "/r/announcements/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            ..Post::test_default()
+        };
+
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(subs[0].source_type, SourceType::User);
+        assert!(!db.existing_posts_for_subscription(&subs[0], &[]).unwrap());
+        db.mark_post_seen(1, &post).unwrap();
+        assert!(db.existing_posts_for_subscription(&subs[0], &[]).unwrap());
+        assert!(db.unsubscribe(1, SourceType::Subreddit, "spez").is_err());
+        db.unsubscribe(1, SourceType::User, "spez").unwrap();
+        assert!(!db.is_post_seen(1, &post).unwrap());
+    }
+
+    #[test]
+    fn test_db_multireddit_subscription() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "rust+golang".to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let post = Post {
+            subreddit: "golang".into(),
+            permalink: "/r/golang/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            ..Post::test_default()
+        };
+
+        assert!(!db.existing_posts_for_subreddit(1, "rust+golang").unwrap());
+        db.mark_post_seen(1, &post).unwrap();
+        assert!(db.existing_posts_for_subreddit(1, "rust+golang").unwrap());
+        assert!(!db.existing_posts_for_subreddit(1, "rust").unwrap());
+
+        db.unsubscribe(1, SourceType::Subreddit, "rust+golang")
+            .unwrap();
+        assert!(!db.is_post_seen(1, &post).unwrap());
+    }
+
+    #[test]
+    fn test_db_get_stats() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(
+            db.get_stats().unwrap(),
+            DatabaseStats {
+                subscriptions: 0,
+                chats: 0,
+                seen_posts: 0,
+            }
+        );
+
+        for (chat_id, subreddit) in [(1, "foo"), (1, "bar"), (2, "foo")] {
+            let subscription_args = SubscriptionArgs {
+                subreddit: subreddit.to_string(),
+                ..SubscriptionArgs::test_default()
+            };
+            db.subscribe(chat_id, &subscription_args).unwrap();
+        }
+        let post = Post {
+            subreddit: "foo".into(),
+            permalink: "/r/foo/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            ..Post::test_default()
+        };
+        db.mark_post_seen(1, &post).unwrap();
+        db.mark_post_seen(2, &post).unwrap();
+
+        assert_eq!(
+            db.get_stats().unwrap(),
+            DatabaseStats {
+                subscriptions: 3,
+                chats: 2,
+                seen_posts: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_db_saved_multireddit_subscription() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            source_type: SourceType::Multireddit,
+            subreddit: "someone/m/mymulti".to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let post = Post {
+            ..Post::test_default()
+        };
+
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        let posts = vec![post];
+        assert!(!db
+            .existing_posts_for_subscription(&subs[0], &posts)
+            .unwrap());
+        db.mark_post_seen(1, &posts[0]).unwrap();
+        assert!(db
+            .existing_posts_for_subscription(&subs[0], &posts)
+            .unwrap());
+        assert!(!db.existing_posts_for_subscription(&subs[0], &[]).unwrap());
+
+        let deleted = db
+            .unsubscribe(1, SourceType::Multireddit, "someone/m/mymulti")
+            .unwrap();
+        assert_eq!(deleted, "someone/m/mymulti");
+    }
+
+    #[test]
+    fn test_db_chat_settings() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_chat_settings(1).unwrap(), ChatSettings::default());
+        db.set_chat_max_media_mb(1, Some(20)).unwrap();
+        assert_eq!(db.get_chat_settings(1).unwrap().max_media_mb, Some(20));
+        assert_eq!(db.get_chat_settings(2).unwrap().max_media_mb, None);
+        db.set_chat_max_media_mb(1, None).unwrap();
+        assert_eq!(db.get_chat_settings(1).unwrap().max_media_mb, None);
+    }
+
+    #[test]
+    fn test_add_feedback() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+
+        assert_eq!(db.get_last_feedback_at(123).unwrap(), None);
+        db.add_feedback(1, 123, "great bot").unwrap();
+        assert!(db.get_last_feedback_at(123).unwrap().is_some());
+        assert_eq!(db.get_last_feedback_at(456).unwrap(), None);
+
+        let text: String = db
+            .conn
+            .query_row("select text from feedback where user_id = 123", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(text, "great bot");
+    }
+
+    #[test]
+    fn test_db_get_chat_stats() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: "/r/pics/comments/v6nu75/a_picture/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            post_type: PostType::Image,
+            ..Post::test_default()
+        };
+        let args = SubscriptionArgs {
+            subreddit: "pics".to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+
+        assert_eq!(
+            db.get_chat_stats(1).unwrap(),
+            ChatStats {
+                subscriptions: 0,
+                seen_posts: 0,
+                seen_posts_last_day: 0,
+                most_active_subreddit: None,
+            }
+        );
+
+        db.subscribe(1, &args).unwrap();
+        db.mark_post_seen(1, &post).unwrap();
+        db.mark_post_seen(
+            1,
+            &Post {
+                id: "w7ov86".into(),
+                ..post.clone()
+            },
+        )
+        .unwrap();
+        db.mark_post_seen(
+            1,
+            &Post {
+                id: "x8pw97".into(),
+                subreddit: "aww".into(),
+                ..post.clone()
+            },
+        )
+        .unwrap();
+        db.mark_post_seen(2, &post).unwrap();
+
+        assert_eq!(
+            db.get_chat_stats(1).unwrap(),
+            ChatStats {
+                subscriptions: 1,
+                seen_posts: 3,
+                seen_posts_last_day: 3,
+                most_active_subreddit: Some(("pics".to_string(), 2)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_db_pending_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: "/r/pics/comments/v6nu75/a_picture/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            post_type: PostType::Image,
+            ..Post::test_default()
+        };
+        let media_options = MediaOptions {
+            max_media_mb: Some(10),
+            ytdlp_format: None,
+            show_progress: false,
+            include_top_comment: true,
+        };
+
+        db.add_pending_post(1, Some(3), &post, &media_options, Some("archive"))
+            .unwrap();
+        // Queueing again on the next check doesn't duplicate the post
+        db.add_pending_post(1, Some(3), &post, &media_options, Some("archive"))
+            .unwrap();
+        assert_eq!(
+            db.get_pending_posts().unwrap(),
+            vec![PendingPost {
+                chat_id: 1,
+                post_id: "v6nu75".into(),
+                message_thread_id: Some(3),
+                media_options,
+                webhook: Some("archive".into()),
+            }]
+        );
         assert!(!db.is_post_seen(1, &post).unwrap());
+
+        db.delete_pending_post(1, "v6nu75").unwrap();
+        assert_eq!(db.get_pending_posts().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_db_failed_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let media_options = MediaOptions {
+            max_media_mb: Some(10),
+            ytdlp_format: None,
+            show_progress: false,
+            include_top_comment: false,
+        };
+
+        db.add_failed_post(1, None, "v6nu75", &media_options, None)
+            .unwrap();
+        // Failing again counts as another attempt
+        db.add_failed_post(1, None, "v6nu75", &media_options, None)
+            .unwrap();
+        assert_eq!(
+            db.get_failed_posts().unwrap(),
+            vec![FailedPost {
+                chat_id: 1,
+                post_id: "v6nu75".into(),
+                message_thread_id: None,
+                media_options,
+                webhook: None,
+                attempts: 2,
+            }]
+        );
+
+        db.delete_failed_post(1, "v6nu75").unwrap();
+        assert_eq!(db.get_failed_posts().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_db_claim_webhook_post() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert!(db.claim_webhook_post("webhooks.archive", "v6nu75").unwrap());
+        assert!(!db.claim_webhook_post("webhooks.archive", "v6nu75").unwrap());
+        assert!(db
+            .claim_webhook_post("slack_webhook_url", "v6nu75")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_db_blocked_authors() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert!(db.block_author(1, "someone").unwrap());
+        assert!(db.block_author(1, "another").unwrap());
+        // Usernames are case-insensitive
+        assert!(!db.block_author(1, "SomeOne").unwrap());
+        assert!(db.block_author(2, "someone").unwrap());
+
+        assert_eq!(db.get_blocked_authors(1).unwrap(), ["another", "someone"]);
+        assert!(db.get_blocked_authors(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_db_cached_files() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let key = "https://i.redd.it/abcdef.jpg";
+        assert_eq!(db.get_cached_file(key).unwrap(), None);
+
+        let file = CachedFile {
+            file_id: "AgACAgQAAxkBAAI".into(),
+            size: 1234,
+        };
+        db.set_cached_file(key, &file).unwrap();
+        assert_eq!(db.get_cached_file(key).unwrap(), Some(file));
+
+        // A new upload replaces the earlier one
+        let file = CachedFile {
+            file_id: "AgACAgQAAxkBAAJ".into(),
+            size: 1234,
+        };
+        db.set_cached_file(key, &file).unwrap();
+        assert_eq!(db.get_cached_file(key).unwrap(), Some(file));
+
+        db.delete_cached_file(key).unwrap();
+        assert_eq!(db.get_cached_file(key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_chat_quiet_hours() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let quiet_hours: QuietHours = "23:00-07:00".parse().unwrap();
+
+        db.set_chat_quiet_hours(1, Some(quiet_hours)).unwrap();
+        db.set_chat_max_media_mb(1, Some(20)).unwrap();
+        assert_eq!(
+            db.get_chat_settings(1).unwrap(),
+            ChatSettings {
+                max_media_mb: Some(20),
+                quiet_hours: Some(quiet_hours),
+            }
+        );
+        db.set_chat_quiet_hours(1, None).unwrap();
+        assert_eq!(db.get_chat_settings(1).unwrap().quiet_hours, None);
+    }
+
+    #[test]
+    fn test_db_unsubscribe_all() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = |subreddit: &str| SubscriptionArgs {
+            subreddit: subreddit.to_string(),
+            ..SubscriptionArgs::test_default()
+        };
+
+        db.subscribe(1, &args("pics")).unwrap();
+        db.subscribe(1, &args("aww")).unwrap();
+        db.subscribe(2, &args("pics")).unwrap();
+
+        assert_eq!(db.unsubscribe_all(1).unwrap(), 2);
+        assert!(db.get_subscriptions_for_chat(1).unwrap().is_empty());
+        assert_eq!(db.get_subscriptions_for_chat(2).unwrap().len(), 1);
+        assert_eq!(db.unsubscribe_all(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_db_prune_seen_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: "/r/pics/comments/v6nu75/a_picture/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            post_type: PostType::Image,
+            ..Post::test_default()
+        };
+        let old_post = Post {
+            id: "w7ov86".into(),
+            ..post.clone()
+        };
+
+        db.mark_post_seen(1, &post).unwrap();
+        db.mark_post_seen(1, &old_post).unwrap();
+        db.conn
+            .execute(
+                "update post set seen_at = :seen_at where post_id = :post_id",
+                named_params! {
+                    ":seen_at": chrono::Utc::now() - chrono::Duration::days(40),
+                    ":post_id": old_post.id,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(db.prune_seen_posts(30).unwrap(), 1);
+        assert!(db.is_post_seen(1, &post).unwrap());
+        assert!(!db.is_post_seen(1, &old_post).unwrap());
+    }
+
+    #[test]
+    fn test_db_digest_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: "/r/pics/comments/v6nu75/a_picture/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            post_type: PostType::Image,
+            ..Post::test_default()
+        };
+        let digest_post = Post {
+            id: "w7ov86".into(),
+            ..post.clone()
+        };
+
+        db.mark_post_seen(1, &post).unwrap();
+        db.add_digest_post(1, &digest_post).unwrap();
+        assert!(db.is_post_seen(1, &digest_post).unwrap());
+        assert_eq!(db.get_digest_chat_ids().unwrap(), vec![1]);
+        assert_eq!(db.get_digest_post_ids(1).unwrap(), vec!["w7ov86"]);
+        assert_eq!(db.get_last_digest_at(1).unwrap(), None);
+
+        // Posts waiting for a digest aren't pruned
+        db.conn
+            .execute(
+                "update post set seen_at = :seen_at",
+                named_params! { ":seen_at": chrono::Utc::now() - chrono::Duration::days(40) },
+            )
+            .unwrap();
+        assert_eq!(db.prune_seen_posts(30).unwrap(), 1);
+        assert!(db.is_post_seen(1, &digest_post).unwrap());
+
+        let delivered_at = chrono::Utc::now();
+        db.mark_digest_delivered(1, &["w7ov86".to_string()], delivered_at)
+            .unwrap();
+        assert!(db.get_digest_chat_ids().unwrap().is_empty());
+        assert!(db.get_digest_post_ids(1).unwrap().is_empty());
+        assert_eq!(db.get_last_digest_at(1).unwrap(), Some(delivered_at));
+    }
+
+    #[test]
+    fn test_db_feed() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let token = db.get_or_create_feed_token(1).unwrap();
+        assert_eq!(token.len(), 32);
+        assert_eq!(db.get_or_create_feed_token(1).unwrap(), token);
+        assert_ne!(db.get_or_create_feed_token(2).unwrap(), token);
+        assert_eq!(db.get_feed_chat_id(&token).unwrap(), Some(1));
+        assert_eq!(db.get_feed_chat_id("nope").unwrap(), None);
+
+        let post = Post {
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: "/r/pics/comments/v6nu75/a_picture/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            post_type: PostType::Image,
+            ..Post::test_default()
+        };
+        let digest_post = Post {
+            id: "w7ov86".into(),
+            ..post.clone()
+        };
+        db.mark_post_seen(1, &post).unwrap();
+        db.add_digest_post(1, &digest_post).unwrap();
+
+        let items = db.get_feed_items(1, 10).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].post_id, "v6nu75");
+        assert_eq!(items[0].title, "A picture");
+        assert_eq!(items[0].permalink, "/r/pics/comments/v6nu75/a_picture/");
+        assert_eq!(items[0].url, "https://i.redd.it/abcdef.jpg");
+        assert!(db.get_feed_items(2, 10).unwrap().is_empty());
     }
 }
@@ -1,33 +1,439 @@
 use anyhow::{Context, Result};
+use lazy_static::lazy_static;
 use log::*;
 
 use std::io::Write;
 use std::{
     fs::File,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
 };
 use tempdir::TempDir;
+use thiserror::Error;
+use tokio::sync::{Semaphore, SemaphorePermit};
 use url::Url;
 
-/// Downloads url to a file and returns the path along with handle to temp dir in which the file is.
+/// How many posts may be downloading and uploading media at the same time by default. One at a
+/// time keeps memory, disk and bandwidth use predictable on small hosts, at the cost of a slow
+/// download holding up every other post and command.
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 1;
+
+/// How long downloading a single file may take, so that a stalled host can't hold the download
+/// permit forever
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub const DEFAULT_MAX_DOWNLOAD_MB: u32 = 200;
+
+/// Downloads larger than this are aborted, so that a huge file can't fill the disk
+static MAX_DOWNLOAD_BYTES: AtomicU64 = AtomicU64::new(DEFAULT_MAX_DOWNLOAD_MB as u64 * 1024 * 1024);
+
+/// A download aborted for exceeding the size limit
+#[derive(Error, Debug)]
+#[error("{url} is larger than the limit of {max_bytes} bytes")]
+pub struct TooLargeError {
+    pub url: String,
+    pub max_bytes: u64,
+}
+
+pub fn set_max_download_mb(max_mb: u32) {
+    MAX_DOWNLOAD_BYTES.store(u64::from(max_mb) * 1024 * 1024, Ordering::Relaxed);
+}
+
+lazy_static! {
+    static ref TEMP_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Sets the directory in which downloads are written instead of the system's temporary directory
+pub fn set_temp_dir(dir: PathBuf) {
+    *TEMP_DIR.lock().unwrap() = Some(dir);
+}
+
+/// Directory in which temporary directories for downloads are created
+pub fn temp_dir() -> PathBuf {
+    TEMP_DIR
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+lazy_static! {
+    static ref REDDIT_SESSION: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Sets the reddit_session cookie sent with downloads from Reddit, for media of age-gated or
+/// quarantined subreddits
+pub fn set_reddit_session(session: String) {
+    *REDDIT_SESSION.lock().unwrap() = Some(session);
+}
+
+/// Whether the url is hosted by Reddit, so that the session cookie may be sent there and nowhere
+/// else
+fn is_reddit_url(url: &Url) -> bool {
+    url.host_str().is_some_and(|host| {
+        ["reddit.com", "redd.it", "redditmedia.com"]
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+    })
+}
+
+/// Creates a temporary directory for a download, which is deleted when dropped
+pub fn create_tmp_dir() -> std::io::Result<TempDir> {
+    TempDir::new_in(temp_dir(), "tgreddit")
+}
+
+// The check loop and bot commands (e.g. a /get with several videos) both download media. Rather
+// than letting them contend for bandwidth, CPU and the database, all media handling goes through a
+// single semaphore. tokio's semaphore is fair: permits are handed out in the order they were
+// requested, so a command that arrives while the check loop is busy is served next instead of
+// being starved by the remaining posts of the cycle, and vice versa.
+lazy_static! {
+    static ref DOWNLOAD_PERMITS: DownloadPermits =
+        DownloadPermits::new(DEFAULT_MAX_CONCURRENT_DOWNLOADS);
+}
+
+/// A semaphore whose number of permits can be changed while they are in use
+struct DownloadPermits {
+    semaphore: Semaphore,
+    /// The number of permits in circulation, including the ones that are still owed
+    max: AtomicUsize,
+    /// Permits to take out of circulation when they are returned, after the max was lowered
+    /// while they were in use
+    owed: AtomicUsize,
+}
+
+impl DownloadPermits {
+    fn new(max: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(max),
+            max: AtomicUsize::new(max),
+            owed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Changes the number of permits without waiting, so that it can be called from outside of
+    /// the runtime, e.g. when the config is reloaded
+    fn set_max(&self, max: usize) {
+        let max = max.max(1);
+        let previous = self.max.swap(max, Ordering::Relaxed);
+        if max > previous {
+            let added = max - previous;
+            self.semaphore.add_permits(added - self.pay_owed(added));
+        } else if max < previous {
+            let mut removed = previous - max;
+            while removed > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                removed -= 1;
+            }
+            self.owed.fetch_add(removed, Ordering::Relaxed);
+        }
+    }
+
+    /// Cancels up to n owed permits, returning how many were owed
+    fn pay_owed(&self, n: usize) -> usize {
+        let owed = self
+            .owed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |owed| {
+                Some(owed - owed.min(n))
+            })
+            .unwrap_or_else(|owed| owed);
+        owed.min(n)
+    }
+
+    async fn acquire(&self) -> DownloadPermit<'_> {
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("download semaphore is never closed");
+        DownloadPermit {
+            permits: self,
+            permit: Some(permit),
+        }
+    }
+}
+
+/// A turn to download and send media, which lasts until it is dropped
+pub struct DownloadPermit<'a> {
+    permits: &'a DownloadPermits,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for DownloadPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            if self.permits.pay_owed(1) == 1 {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Changes how many posts may be downloading media at the same time. When lowered, permits that
+/// are in use are taken out of circulation as they are returned.
+pub fn set_max_concurrent_downloads(max: usize) {
+    DOWNLOAD_PERMITS.set_max(max);
+}
+
+/// Waits for a turn to download and send media. The turn lasts until the permit is dropped.
+pub async fn acquire_download_permit() -> DownloadPermit<'static> {
+    DOWNLOAD_PERMITS.acquire().await
+}
+
+pub fn size_exceeds_mb(size: u64, max_mb: u32) -> bool {
+    size > u64::from(max_mb) * 1024 * 1024
+}
+
+/// Whether the file is larger than max_mb megabytes. Without a cap, any size is fine.
+pub fn exceeds_size_cap(path: &Path, max_mb: Option<u32>) -> Result<bool> {
+    match max_mb {
+        Some(max_mb) => {
+            let size = std::fs::metadata(path)
+                .with_context(|| format!("failed to read metadata of {path:?}"))?
+                .len();
+            Ok(size_exceeds_mb(size, max_mb))
+        }
+        None => Ok(false),
+    }
+}
+
+/// File extensions for the media types that are sent to Telegram. The first one is used when the
+/// url doesn't have a matching extension.
+fn extensions_for_mime(mime: &str) -> Option<&'static [&'static str]> {
+    match mime {
+        "image/jpeg" => Some(&["jpg", "jpeg"]),
+        "image/png" => Some(&["png"]),
+        "image/gif" => Some(&["gif"]),
+        "image/webp" => Some(&["webp"]),
+        "video/mp4" => Some(&["mp4"]),
+        "video/webm" => Some(&["webm"]),
+        "video/quicktime" => Some(&["mov"]),
+        _ => None,
+    }
+}
+
+/// Name for the downloaded file, from the basename of the url with an extension matching the
+/// content type. Telegram relies on the extension, which e.g. preview urls of gifs converted to
+/// mp4 get wrong.
+fn download_file_name(url: &Url, mime: Option<&str>) -> PathBuf {
+    let mut file_name = PathBuf::from(
+        Path::new(url.path())
+            .file_name()
+            .unwrap_or_else(|| "download".as_ref()),
+    );
+    if let Some(extensions) = mime.and_then(extensions_for_mime) {
+        let extension = file_name
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if !matches!(extension, Some(extension) if extensions.contains(&extension.as_str())) {
+            file_name.set_extension(extensions[0]);
+        }
+    }
+    file_name
+}
+
+/// Downloads url to a file and returns the path along with handle to temp dir in which the file is,
+/// and the media type of the file when the response has one. Only images and videos are accepted.
 /// Whe the temp dir value is dropped, the contents in file system are deleted.
-pub async fn download_url_to_tmp(url: &str) -> Result<(PathBuf, TempDir)> {
+pub async fn download_url_to_tmp(url: &str) -> Result<(PathBuf, TempDir, Option<String>)> {
+    download_url_to_tmp_with_limit(url, MAX_DOWNLOAD_BYTES.load(Ordering::Relaxed)).await
+}
+
+/// Like download_url_to_tmp, but fails once the download exceeds max_bytes. The partial file is
+/// deleted along with the temp dir.
+async fn download_url_to_tmp_with_limit(
+    url: &str,
+    max_bytes: u64,
+) -> Result<(PathBuf, TempDir, Option<String>)> {
     info!("downloading {url}");
-    let mut res = reqwest::get(url).await?;
-    let tmp_dir = TempDir::new("tgreddit")?;
-    let parsed_url = Url::parse(url)?;
-    let tmp_filename = Path::new(parsed_url.path())
-        .file_name()
-        .context("could not get basename from url")?;
-    let tmp_path = tmp_dir.path().join(tmp_filename);
+    let client = reqwest::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()?;
+    let mut req = client.get(url);
+    if is_reddit_url(&Url::parse(url)?) {
+        if let Some(session) = REDDIT_SESSION.lock().unwrap().as_deref() {
+            req = req.header(reqwest::header::COOKIE, format!("reddit_session={session}"));
+        }
+    }
+    let mut res = req.send().await?;
+    let mime = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|mime| mime.trim().to_lowercase());
+    if let Some(mime) = &mime {
+        if !mime.starts_with("image/") && !mime.starts_with("video/") {
+            anyhow::bail!("{url} is not an image or a video but {mime}");
+        }
+    }
+    if let Some(content_length) = res.content_length() {
+        if content_length > max_bytes {
+            info!("{url} is {content_length} bytes, more than the limit of {max_bytes}");
+            return Err(TooLargeError {
+                url: url.to_string(),
+                max_bytes,
+            }
+            .into());
+        }
+    }
+    let tmp_dir = create_tmp_dir()?;
+    let tmp_path = tmp_dir
+        .path()
+        .join(download_file_name(&Url::parse(url)?, mime.as_deref()));
     let mut file = File::create(&tmp_path)
         .map_err(|_| anyhow::anyhow!("failed to create file {:?}", tmp_path))?;
 
+    let mut written = 0;
     while let Some(bytes) = res.chunk().await? {
-        file.write(&bytes)
+        written += bytes.len() as u64;
+        // Content-Length may be missing or wrong, so the limit is also checked while streaming
+        if written > max_bytes {
+            return Err(TooLargeError {
+                url: url.to_string(),
+                max_bytes,
+            }
+            .into());
+        }
+        file.write_all(&bytes)
             .map_err(|_| anyhow::anyhow!("error writing to file {:?}", tmp_path))?;
     }
 
     info!("downloaded {url} to {}", tmp_path.to_string_lossy());
-    Ok((tmp_path, tmp_dir))
+    Ok((tmp_path, tmp_dir, mime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_is_reddit_url() {
+        let is_reddit = |url: &str| is_reddit_url(&Url::parse(url).unwrap());
+        assert!(is_reddit("https://i.redd.it/foo.jpg"));
+        assert!(is_reddit("https://preview.redd.it/foo.jpg?width=640"));
+        assert!(is_reddit("https://www.reddit.com/gallery/foo"));
+        assert!(!is_reddit("https://i.imgur.com/foo.jpg"));
+        assert!(!is_reddit("https://notreddit.com/foo.jpg"));
+        assert!(!is_reddit("https://redd.it.example.com/foo.jpg"));
+    }
+
+    #[tokio::test]
+    async fn test_download_permits_are_granted_in_request_order() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let held = acquire_download_permit().await;
+
+        let spawn_waiter = |name: &'static str| {
+            let order = order.clone();
+            tokio::spawn(async move {
+                let _permit = acquire_download_permit().await;
+                order.lock().unwrap().push(name);
+            })
+        };
+
+        let check_loop = spawn_waiter("check_loop");
+        tokio::task::yield_now().await;
+        let command = spawn_waiter("command");
+        tokio::task::yield_now().await;
+
+        drop(held);
+        check_loop.await.unwrap();
+        command.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), vec!["check_loop", "command"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_max_download_permits_while_in_use() {
+        let permits = Arc::new(DownloadPermits::new(2));
+        let first = permits.acquire().await;
+        let second = permits.acquire().await;
+
+        // Config reloads happen on a thread without a runtime
+        let reload = permits.clone();
+        std::thread::spawn(move || reload.set_max(1))
+            .join()
+            .unwrap();
+        drop(first);
+        assert_eq!(permits.semaphore.available_permits(), 0);
+        drop(second);
+        assert_eq!(permits.semaphore.available_permits(), 1);
+
+        let held = permits.acquire().await;
+        permits.set_max(0);
+        permits.set_max(3);
+        drop(held);
+        assert_eq!(permits.semaphore.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_download_url_to_tmp_with_limit() {
+        use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(path("/image.jpg"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(vec![0; 100], "image/jpeg"))
+            .mount(&server)
+            .await;
+        let url = format!("{}/image.jpg", server.uri());
+
+        let (path, _tmp_dir, _) = download_url_to_tmp_with_limit(&url, 100).await.unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 100);
+
+        assert!(download_url_to_tmp_with_limit(&url, 99)
+            .await
+            .unwrap_err()
+            .is::<TooLargeError>());
+    }
+
+    #[tokio::test]
+    async fn test_download_url_to_tmp_content_type() {
+        use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let respond =
+            |content_type: &str| ResponseTemplate::new(200).set_body_raw(vec![0; 10], content_type);
+        Mock::given(path("/abcdef"))
+            .respond_with(respond("image/png"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/abc.gif"))
+            .respond_with(respond("video/mp4"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/photo.JPEG"))
+            .respond_with(respond("image/jpeg; charset=binary"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/removed"))
+            .respond_with(respond("text/html; charset=utf-8"))
+            .mount(&server)
+            .await;
+
+        let download = |path: &str| {
+            let url = format!("{}{path}", server.uri());
+            async move { download_url_to_tmp_with_limit(&url, 100).await }
+        };
+
+        let (path, _tmp_dir, mime) = download("/abcdef").await.unwrap();
+        assert_eq!(path.file_name().unwrap(), "abcdef.png");
+        assert_eq!(mime.as_deref(), Some("image/png"));
+
+        let (path, _tmp_dir, mime) = download("/abc.gif").await.unwrap();
+        assert_eq!(path.file_name().unwrap(), "abc.mp4");
+        assert_eq!(mime.as_deref(), Some("video/mp4"));
+
+        let (path, _tmp_dir, mime) = download("/photo.JPEG").await.unwrap();
+        assert_eq!(path.file_name().unwrap(), "photo.JPEG");
+        assert_eq!(mime.as_deref(), Some("image/jpeg"));
+
+        assert!(download("/removed").await.is_err());
+    }
 }
@@ -0,0 +1,115 @@
+use crate::{config::Config, db, reddit, types::FeedItem};
+use anyhow::Result;
+
+pub const FEED_PATH_PREFIX: &str = "/feed/";
+/// How many of the most recently delivered posts a feed lists
+const FEED_MAX_ITEMS: u32 = 50;
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Formats the posts delivered to a chat as an RSS 2.0 feed
+pub fn format_feed(chat_id: i64, items: &[FeedItem], links_base_url: Option<&str>) -> String {
+    let items = items
+        .iter()
+        .map(|item| {
+            let permalink = reddit::format_url_from_path(&item.permalink, links_base_url);
+            format!(
+                "<item>\
+                 <title>{}</title>\
+                 <link>{}</link>\
+                 <description>{}</description>\
+                 <category>r/{}</category>\
+                 <guid isPermaLink=\"false\">{}</guid>\
+                 <pubDate>{}</pubDate>\
+                 </item>",
+                escape_xml(&item.title),
+                escape_xml(&permalink),
+                escape_xml(&item.url),
+                escape_xml(&item.subreddit),
+                escape_xml(&item.post_id),
+                item.seen_at.to_rfc2822(),
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <rss version=\"2.0\"><channel>\
+         <title>tgreddit</title>\
+         <link>{}</link>\
+         <description>Posts delivered to chat {chat_id}</description>\
+         {items}\
+         </channel></rss>",
+        escape_xml(&reddit::format_url_from_path("/", links_base_url)),
+    )
+}
+
+/// The feed of the chat that has the token. None if no chat has it.
+pub fn render_feed(config: &Config, feed_token: &str) -> Result<Option<String>> {
+    let db = db::Database::open(config)?;
+    let chat_id = match db.get_feed_chat_id(feed_token)? {
+        Some(chat_id) => chat_id,
+        None => return Ok(None),
+    };
+    let items = db.get_feed_items(chat_id, FEED_MAX_ITEMS)?;
+    Ok(Some(format_feed(
+        chat_id,
+        &items,
+        config.links_base_url.as_deref(),
+    )))
+}
+
+/// Url of a chat's feed, served at base_url
+pub fn format_feed_url(base_url: &str, feed_token: &str) -> String {
+    format!(
+        "{}{FEED_PATH_PREFIX}{feed_token}",
+        base_url.trim_end_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_format_feed() {
+        let items = [FeedItem {
+            post_id: "v6nu75".into(),
+            subreddit: "pics".into(),
+            title: "Cats & <dogs>".into(),
+            permalink: "/r/pics/comments/v6nu75/cats_dogs/".into(),
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            seen_at: chrono::Utc.with_ymd_and_hms(2022, 6, 7, 6, 0, 0).unwrap(),
+        }];
+        assert_eq!(
+            format_feed(1, &items, None),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <rss version=\"2.0\"><channel>\
+             <title>tgreddit</title>\
+             <link>https://www.reddit.com/</link>\
+             <description>Posts delivered to chat 1</description>\
+             <item>\
+             <title>Cats &amp; &lt;dogs&gt;</title>\
+             <link>https://www.reddit.com/r/pics/comments/v6nu75/cats_dogs/</link>\
+             <description>https://i.redd.it/abcdef.jpg</description>\
+             <category>r/pics</category>\
+             <guid isPermaLink=\"false\">v6nu75</guid>\
+             <pubDate>Tue, 07 Jun 2022 06:00:00 +0000</pubDate>\
+             </item>\
+             </channel></rss>"
+        );
+    }
+
+    #[test]
+    fn test_format_feed_url() {
+        assert_eq!(
+            format_feed_url("https://bot.example.com/", "abc"),
+            "https://bot.example.com/feed/abc"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{config::Config, db, feed};
+use anyhow::Result;
+use log::*;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+
+pub const HEALTH_PATH: &str = "/healthz";
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds since the Unix epoch, the unit of the last check timestamp
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// The check loop is considered stuck if an iteration hasn't completed within two check intervals
+fn is_check_recent(last_check_at: u64, now: u64, check_interval_secs: u64) -> bool {
+    now.saturating_sub(last_check_at) <= check_interval_secs.saturating_mul(2)
+}
+
+fn is_healthy(config: &Config, last_check_at: u64) -> bool {
+    if !is_check_recent(last_check_at, unix_now(), config.check_interval_secs) {
+        warn!("health check failed, last check completed at {last_check_at}");
+        return false;
+    }
+    match db::Database::open(config).and_then(|db| db.ping()) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("health check failed, database not reachable: {err}");
+            false
+        }
+    }
+}
+
+/// Path of a GET or HEAD request from its request line, e.g. `GET /healthz HTTP/1.1`
+fn parse_request_path(request: &str) -> Option<&str> {
+    let mut parts = request.lines().next()?.split_whitespace();
+    match (parts.next()?, parts.next()?) {
+        ("GET" | "HEAD", path) => Some(path),
+        _ => None,
+    }
+}
+
+fn format_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn format_status_response(status: &str) -> String {
+    format_response(status, "text/plain", status)
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    config: &Config,
+    last_check_at: &AtomicU64,
+) -> Result<()> {
+    let mut buf = [0; 1024];
+    let len = tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await??;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let response = match parse_request_path(&request) {
+        Some(HEALTH_PATH) if is_healthy(config, last_check_at.load(Ordering::Acquire)) => {
+            format_status_response("200 OK")
+        }
+        Some(HEALTH_PATH) => format_status_response("503 Service Unavailable"),
+        Some(path) if path.starts_with(feed::FEED_PATH_PREFIX) => {
+            // Feed readers may add a query string, e.g. to bust caches
+            let feed_token = path[feed::FEED_PATH_PREFIX.len()..]
+                .split('?')
+                .next()
+                .unwrap_or_default();
+            match feed::render_feed(config, feed_token) {
+                Ok(Some(feed)) => {
+                    format_response("200 OK", "application/rss+xml; charset=utf-8", &feed)
+                }
+                Ok(None) => format_status_response("404 Not Found"),
+                Err(err) => {
+                    warn!("failed to render feed: {err}");
+                    format_status_response("500 Internal Server Error")
+                }
+            }
+        }
+        Some(_) => format_status_response("404 Not Found"),
+        None => format_status_response("400 Bad Request"),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Serves the health check endpoint and the RSS feeds of chats until shutdown. Healthy means that
+/// the database is reachable and that the check loop has completed recently.
+pub async fn serve(
+    addr: SocketAddr,
+    config: Arc<Config>,
+    last_check_at: Arc<AtomicU64>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("serving health check at http://{addr}{HEALTH_PATH}");
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let config = config.clone();
+                    let last_check_at = last_check_at.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(stream, &config, &last_check_at).await {
+                            warn!("failed to handle health check request: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("failed to accept health check connection: {err}"),
+            },
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_check_recent() {
+        assert!(is_check_recent(1000, 1000, 60));
+        assert!(is_check_recent(1000, 1120, 60));
+        assert!(!is_check_recent(1000, 1121, 60));
+        // Clock going backwards shouldn't make the bot unhealthy
+        assert!(is_check_recent(1000, 900, 60));
+    }
+
+    #[test]
+    fn test_parse_request_path() {
+        assert_eq!(
+            parse_request_path("GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Some("/healthz")
+        );
+        assert_eq!(
+            parse_request_path("HEAD /healthz HTTP/1.1\r\n"),
+            Some("/healthz")
+        );
+        assert_eq!(parse_request_path("GET / HTTP/1.1\r\n"), Some("/"));
+        assert_eq!(parse_request_path("POST /healthz HTTP/1.1\r\n"), None);
+        assert_eq!(parse_request_path(""), None);
+    }
+}
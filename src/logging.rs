@@ -0,0 +1,99 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::json;
+use std::{env, io::Write};
+
+const LOG_FORMAT_ENV: &str = "LOG_FORMAT";
+
+/// Initializes logging. Logs are human-readable by default, or JSON objects one per line when
+/// LOG_FORMAT=json. As with env_logger, the level is set with RUST_LOG.
+pub fn init() {
+    let mut builder = env_logger::Builder::from_default_env();
+    if env::var(LOG_FORMAT_ENV).as_deref() == Ok("json") {
+        builder.format(|buf, record| {
+            let message = record.args().to_string();
+            writeln!(
+                buf,
+                "{}",
+                format_json_record(
+                    &chrono::Utc::now().to_rfc3339(),
+                    &record.level().to_string(),
+                    record.target(),
+                    &message,
+                )
+            )
+        });
+    }
+    builder.init();
+}
+
+/// Formats a log record as a JSON object. The key=value pairs that log messages include for
+/// chat_id, post_id and subreddit are also added as fields, so that they can be queried.
+fn format_json_record(timestamp: &str, level: &str, target: &str, message: &str) -> String {
+    lazy_static! {
+        static ref FIELD_RE: Regex =
+            Regex::new(r"(?:^|\s)(?P<key>chat_id|post_id|subreddit)=(?P<value>[^\s:,]+)").unwrap();
+    }
+
+    let mut record = json!({
+        "timestamp": timestamp,
+        "level": level,
+        "target": target,
+        "message": message,
+    });
+    for caps in FIELD_RE.captures_iter(message) {
+        let value = &caps["value"];
+        record[&caps["key"]] = match value.parse::<i64>() {
+            Ok(number) => json!(number),
+            Err(_) => json!(value),
+        };
+    }
+    record.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json_record() {
+        let record: serde_json::Value = serde_json::from_str(&format_json_record(
+            "2022-06-07T05:51:40+00:00",
+            "INFO",
+            "tgreddit",
+            "image uploaded post_id=v6nu75 chat_id=-100123",
+        ))
+        .unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "timestamp": "2022-06-07T05:51:40+00:00",
+                "level": "INFO",
+                "target": "tgreddit",
+                "message": "image uploaded post_id=v6nu75 chat_id=-100123",
+                "post_id": "v6nu75",
+                "chat_id": -100123,
+            })
+        );
+
+        let record: serde_json::Value = serde_json::from_str(&format_json_record(
+            "2022-06-07T05:51:40+00:00",
+            "ERROR",
+            "tgreddit",
+            "failed to check post newness post_id=v6nu75 chat_id=1: database is locked",
+        ))
+        .unwrap();
+        assert_eq!(record["post_id"], "v6nu75");
+        assert_eq!(record["chat_id"], 1);
+
+        // Messages without fields have none
+        let record: serde_json::Value = serde_json::from_str(&format_json_record(
+            "2022-06-07T05:51:40+00:00",
+            "INFO",
+            "tgreddit",
+            "checking subscriptions for new posts",
+        ))
+        .unwrap();
+        assert!(record.get("chat_id").is_none());
+    }
+}
@@ -1,55 +1,75 @@
 use crate::{download::*, types::*};
 use anyhow::{Context, Result};
+use futures::StreamExt;
+use itertools::Itertools;
 use log::*;
-use reddit::{PostType, TopPostsTimePeriod};
+use reddit::{PostType, SourceType, TopPostsTimePeriod};
 use signal_hook::{
-    consts::signal::{SIGINT, SIGTERM},
+    consts::signal::{SIGHUP, SIGINT, SIGTERM},
     iterator::Signals,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::string::ToString;
 use std::{
     borrow::Cow,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     time::Duration,
 };
 use teloxide::types::InputFile;
 use teloxide::{
-    payloads::{SendMessageSetters, SendPhotoSetters, SendVideoSetters},
+    payloads::{SendAnimationSetters, SendMessageSetters, SendPhotoSetters, SendVideoSetters},
     types::InputMediaPhoto,
 };
 use teloxide::{prelude::*, types::InputMedia};
 use tempdir::TempDir;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 
 mod args;
 mod bot;
 mod config;
 mod db;
 mod download;
+mod feed;
+mod health;
+mod logging;
 mod messages;
+mod opengraph;
 mod reddit;
+mod sink;
+mod slack;
 mod types;
+mod webhook;
 mod ytdlp;
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+/// Telegram's limit for the number of items in a media group
+const MEDIA_GROUP_MAX_ITEMS: usize = 10;
+/// How many images of a gallery are downloaded at the same time
+const GALLERY_DOWNLOAD_CONCURRENCY: usize = 4;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    logging::init();
     let config = Arc::new(config::read_config());
     info!("starting with config: {config:#?}");
     let mut db = db::Database::open(&config)?;
     db.migrate()?;
     drop(db);
+    match ytdlp::check_installed(&config) {
+        Ok(version) => info!("using yt-dlp version {version}"),
+        Err(err) => warn!("{err}, video posts will be sent as links"),
+    }
+    apply_global_settings(&config);
 
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<()>(1);
+    // Config reloaded on SIGHUP, used by the check loop and the bot's handlers
+    let (config_tx, config_rx) = watch::channel(config.clone());
     let shutdown = Arc::new(AtomicBool::new(false));
-    let bot = bot::MyBot::new(config.clone()).await?;
+    let bot = bot::MyBot::new(config_rx.clone()).await?;
 
     // Any arguments are for things that help with debugging and development
     // Not optimized for usability.
@@ -61,19 +81,41 @@ async fn main() -> Result<()> {
         let post = reddit::get_link(&post_id).await.unwrap();
         info!("{:#?}", post);
         if let Some(chat_id) = opts.opt_str("chat-id") {
-            return handle_new_post(&config, &bot.tg, chat_id.parse().unwrap(), &post).await;
+            let chat_id = chat_id.parse().unwrap();
+            let chat_settings = db::Database::open(&config)?.get_chat_settings(chat_id)?;
+            let media_options = config.resolve_media_options(None, None, None, &chat_settings);
+            handle_new_post(&config, &bot.tg, chat_id, None, &post, &media_options).await?;
         }
         return Ok(());
     }
 
+    if config.notify_on_start {
+        notify_start(&config, &bot.tg).await;
+    }
+
+    // Starts from now so that the bot is healthy while the first check is running
+    let last_check_at = Arc::new(AtomicU64::new(health::unix_now()));
+    let health_handle = config.health_addr.map(|addr| {
+        tokio::task::spawn(health::serve(
+            addr,
+            config.clone(),
+            last_check_at.clone(),
+            shutdown_tx.subscribe(),
+        ))
+    });
+
     let sub_check_loop_handle = {
         let shutdown = shutdown.clone();
         let tg = bot.tg.clone();
         tokio::task::spawn(async move {
             while !shutdown.load(Ordering::Acquire) {
-                check_new_posts(&config, &tg).await.unwrap_or_else(|err| {
-                    error!("failed to check for new posts: {err}");
-                });
+                let config = config_rx.borrow().clone();
+                match check_new_posts(&config, &reddit::ApiClient, &tg, &shutdown, &shutdown_rx)
+                    .await
+                {
+                    Ok(()) => last_check_at.store(health::unix_now(), Ordering::Release),
+                    Err(err) => error!("failed to check for new posts: {err}"),
+                }
 
                 tokio::select! {
                    _ = tokio::time::sleep(Duration::from_secs(config.check_interval_secs)) => {}
@@ -90,11 +132,27 @@ async fn main() -> Result<()> {
         let shutdown = shutdown.clone();
         std::thread::spawn(move || {
             let mut forward_signals =
-                Signals::new([SIGINT, SIGTERM]).expect("unable to watch for signals");
+                Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("unable to watch for signals");
 
             for signal in forward_signals.forever() {
+                if signal == SIGHUP {
+                    // A broken config shouldn't take down a running bot
+                    match config::try_read_config() {
+                        Ok(config) => {
+                            info!("got signal {signal}, reloaded config: {config:#?}");
+                            apply_global_settings(&config);
+                            config_tx.send_replace(Arc::new(config));
+                        }
+                        Err(err) => {
+                            error!("failed to reload config, keeping the current one: {err}")
+                        }
+                    }
+                    continue;
+                }
+
                 info!("got signal {signal}, shutting down...");
                 shutdown.swap(true, Ordering::Relaxed);
+                ytdlp::cancel_downloads();
                 let _res = bot_shutdown_token.shutdown();
                 let _res = shutdown_tx.send(()).unwrap_or_else(|_| {
                     // Makes the second Ctrl-C exit instantly
@@ -107,26 +165,302 @@ async fn main() -> Result<()> {
     if let Err(err) = tokio::try_join!(bot_handle, sub_check_loop_handle) {
         panic!("{err}")
     }
+    if let Some(health_handle) = health_handle {
+        health_handle
+            .await?
+            .unwrap_or_else(|err| error!("failed to serve health check: {err}"));
+    }
 
     Ok(())
 }
 
+fn apply_global_settings(config: &config::Config) {
+    reddit::set_min_request_interval(Duration::from_millis(config.reddit_request_interval_ms));
+    if let Some(user_agent) = &config.reddit_user_agent {
+        reddit::set_user_agent(user_agent.clone());
+    }
+    if let (Some(client_id), Some(client_secret)) =
+        (&config.reddit_client_id, &config.reddit_client_secret)
+    {
+        reddit::set_credentials(client_id.clone(), client_secret.expose_secret().to_string());
+    }
+    reddit::set_max_retries(
+        config
+            .reddit_max_retries
+            .unwrap_or(reddit::DEFAULT_MAX_RETRIES),
+    );
+    reddit::set_request_timeout(
+        config
+            .reddit_request_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(reddit::DEFAULT_REQUEST_TIMEOUT),
+    );
+    reddit::set_subreddit_cache_ttl(
+        config
+            .subreddit_cache_ttl_secs
+            .map(Duration::from_secs)
+            .unwrap_or(reddit::DEFAULT_SUBREDDIT_CACHE_TTL),
+    );
+    webhook::set_timeout(
+        config
+            .webhook_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(webhook::DEFAULT_TIMEOUT),
+    );
+    download::set_max_download_mb(
+        config
+            .max_download_mb
+            .unwrap_or(download::DEFAULT_MAX_DOWNLOAD_MB),
+    );
+    download::set_max_concurrent_downloads(
+        config
+            .max_concurrent_downloads
+            .unwrap_or(download::DEFAULT_MAX_CONCURRENT_DOWNLOADS),
+    );
+    if let Some(session) = &config.reddit_session_cookie {
+        download::set_reddit_session(session.expose_secret().to_string());
+    }
+    if let Some(temp_dir) = &config.temp_dir {
+        download::set_temp_dir(temp_dir.clone());
+    }
+    info!(
+        "downloading media to {}",
+        download::temp_dir().to_string_lossy()
+    );
+}
+
+/// Lets authorized users know that the bot is up, e.g. after a deploy. Failures are only logged.
+async fn notify_start(config: &config::Config, tg: &Bot) {
+    let subscriptions = match db::Database::open(config).and_then(|db| db.get_stats()) {
+        Ok(stats) => stats.subscriptions,
+        Err(err) => {
+            error!("failed to get stats for startup notification: {err}");
+            return;
+        }
+    };
+    let text = messages::format_startup_message(env!("CARGO_PKG_VERSION"), subscriptions);
+    for user_id in &config.authorized_user_ids {
+        if let Err(err) = tg
+            .send_message(teloxide::types::UserId(*user_id), &text)
+            .await
+        {
+            error!("failed to send startup notification to user {user_id}: {err}");
+        }
+    }
+}
+
+/// Buttons for opening the post, if enabled with use_inline_buttons
+fn post_keyboard(
+    config: &config::Config,
+    post: &reddit::Post,
+) -> Option<teloxide::types::InlineKeyboardMarkup> {
+    config
+        .use_inline_buttons
+        .then(|| messages::build_post_keyboard(post, config.links_base_url.as_deref()))
+}
+
+/// Progress is shown in steps of this many percent, as Telegram limits how often a message can be
+/// edited
+const PROGRESS_STEP_PERCENT: f32 = 10.0;
+
+/// Sends a status message that shows the progress received from progress_rx until the sender is
+/// dropped. Failing to send the status message is not worth failing the post for.
+async fn start_progress_message(
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    mut progress_rx: watch::Receiver<f32>,
+) -> Option<(teloxide::types::MessageId, tokio::task::JoinHandle<()>)> {
+    let mut request = tg.send_message(ChatId(chat_id), "Downloading video…");
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
+    let message_id = match request.await {
+        Ok(message) => message.id,
+        Err(e) => {
+            warn!("failed to send progress message chat_id={chat_id}: {e}");
+            return None;
+        }
+    };
+
+    let tg = tg.clone();
+    let updates = tokio::spawn(async move {
+        let mut shown = 0.0;
+        while progress_rx.changed().await.is_ok() {
+            let percent = *progress_rx.borrow();
+            if (percent - shown).abs() < PROGRESS_STEP_PERCENT {
+                continue;
+            }
+            shown = percent;
+            let text = format!("Downloading video… {percent:.0}%");
+            if let Err(e) = tg
+                .edit_message_text(ChatId(chat_id), message_id, text)
+                .await
+            {
+                debug!("failed to update progress message chat_id={chat_id}: {e}");
+            }
+        }
+    });
+    Some((message_id, updates))
+}
+
 async fn handle_new_video_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<i32>,
     post: &reddit::Post,
+    media_options: &MediaOptions,
 ) -> Result<()> {
+    let (progress_tx, progress_rx) = watch::channel(0.0);
+    let progress_message = if media_options.show_progress {
+        start_progress_message(tg, chat_id, thread_id, progress_rx).await
+    } else {
+        None
+    };
+
+    let result = download_and_send_video(
+        config,
+        tg,
+        chat_id,
+        thread_id,
+        post,
+        media_options,
+        &progress_tx,
+    )
+    .await;
+
+    // Closing the channel ends the updates
+    drop(progress_tx);
+    if let Some((message_id, updates)) = progress_message {
+        let _ = updates.await;
+        if let Err(e) = tg.delete_message(ChatId(chat_id), message_id).await {
+            warn!("failed to delete progress message chat_id={chat_id}: {e}");
+        }
+    }
+    result
+}
+
+async fn download_and_send_video(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    post: &reddit::Post,
+    media_options: &MediaOptions,
+    progress_tx: &watch::Sender<f32>,
+) -> Result<()> {
+    let on_progress = |percent| {
+        // Nobody listens unless progress is shown
+        let _ = progress_tx.send(percent);
+    };
+    let max_mb = config.video_size_cap_mb(media_options);
+    let caption = messages::format_media_caption(post, config);
+    let video_request = |video: InputFile| {
+        let mut request = tg
+            .send_video(ChatId(chat_id), video)
+            .parse_mode(config.parse_mode.into())
+            .caption(&caption);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Some(keyboard) = post_keyboard(config, post) {
+            request = request.reply_markup(keyboard);
+        }
+        request
+    };
+
+    // The format is part of the key as it decides which video is downloaded
+    let cache_key = format!(
+        "{}#{}",
+        post.url,
+        media_options.ytdlp_format.as_deref().unwrap_or_default()
+    );
+    if let Some(file_id) = get_cached_file_id(config, &cache_key, Some(max_mb)) {
+        match video_request(InputFile::file_id(file_id)).await {
+            Ok(_) => {
+                info!(
+                    "video sent from cache post_id={} chat_id={chat_id}",
+                    post.id
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                warn!(
+                    "failed to send cached video, uploading it again post_id={}: {e}",
+                    post.id
+                );
+                forget_cached_file(config, &cache_key);
+            }
+        }
+    }
+
     // The temporary directory will be deleted when _tmp_dir is dropped
-    let (video, _tmp_dir) = tokio::task::block_in_place(|| ytdlp::download(&post.url))?;
-    info!("got a video: {video:?}");
-    let caption = messages::format_media_caption_html(post, config.links_base_url.as_deref());
-    tg.send_video(ChatId(chat_id), InputFile::file(&video.path))
-        .parse_mode(teloxide::types::ParseMode::Html)
-        .caption(&caption)
+    let (mut video, mut _tmp_dir) = match tokio::task::block_in_place(|| {
+        ytdlp::download(
+            config,
+            &post.url,
+            media_options.ytdlp_format.as_deref(),
+            on_progress,
+        )
+    }) {
+        Ok(download) => download,
+        Err(err) if err.is::<ytdlp::NotInstalledError>() || err.is::<ytdlp::TimeoutError>() => {
+            warn!("{err}, sending video as link post_id={}", post.id);
+            return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+        }
+        Err(err) => return Err(err),
+    };
+    info!(
+        "got a video post_id={} chat_id={chat_id}: {video:?}",
+        post.id
+    );
+    if size_exceeds_mb(video.size, max_mb) {
+        info!(
+            "video of {} bytes exceeds {max_mb} MB, retrying in lower resolution post_id={}",
+            video.size, post.id
+        );
+        match tokio::task::block_in_place(|| {
+            ytdlp::download(config, &post.url, Some(ytdlp::REDUCED_FORMAT), on_progress)
+        }) {
+            Ok((reduced_video, reduced_tmp_dir))
+                if !size_exceeds_mb(reduced_video.size, max_mb) =>
+            {
+                info!("got a video in lower resolution: {reduced_video:?}");
+                (video, _tmp_dir) = (reduced_video, reduced_tmp_dir);
+            }
+            Ok((reduced_video, _)) => {
+                info!(
+                    "video of {} bytes still exceeds {max_mb} MB, sending as link post_id={}",
+                    reduced_video.size, post.id
+                );
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+            Err(err) => {
+                warn!(
+                    "failed to download video in lower resolution, sending as link post_id={}: {err}",
+                    post.id
+                );
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+        }
+    }
+    if let Err(e) = tg
+        .send_chat_action(ChatId(chat_id), teloxide::types::ChatAction::UploadVideo)
+        .await
+    {
+        debug!("failed to send chat action chat_id={chat_id}: {e}");
+    }
+    let mut request = video_request(InputFile::file(&video.path))
         .height(video.height.into())
-        .width(video.width.into())
-        .await?;
+        .width(video.width.into());
+    if let Some(thumbnail) = &video.thumbnail {
+        request = request.thumb(InputFile::file(thumbnail));
+    }
+    let message = request.await?;
+    if let Some(sent_video) = message.video() {
+        cache_file(config, &cache_key, &sent_video.file);
+    }
     info!(
         "video uploaded post_id={} chat_id={chat_id} video={video:?}",
         post.id
@@ -134,130 +468,755 @@ async fn handle_new_video_post(
     Ok(())
 }
 
+/// Sends gifs and gif-like videos with send_animation, so that Telegram autoplays and loops them
+async fn handle_new_animation_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    post: &reddit::Post,
+    media_options: &MediaOptions,
+) -> Result<()> {
+    let caption = messages::format_media_caption(post, config);
+    match post.post_type {
+        // Videos such as imgur's .gifv need yt-dlp, which also gives the dimensions
+        reddit::PostType::Video => {
+            let max_mb = config.video_size_cap_mb(media_options);
+            // The temporary directory will be deleted when _tmp_dir is dropped
+            let (video, _tmp_dir) = match tokio::task::block_in_place(|| {
+                ytdlp::download(
+                    config,
+                    &post.url,
+                    media_options.ytdlp_format.as_deref(),
+                    |_| {},
+                )
+            }) {
+                Ok(download) => download,
+                Err(err)
+                    if err.is::<ytdlp::NotInstalledError>() || err.is::<ytdlp::TimeoutError>() =>
+                {
+                    warn!("{err}, sending animation as link post_id={}", post.id);
+                    return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+                }
+                Err(err) => return Err(err),
+            };
+            info!(
+                "got an animation post_id={} chat_id={chat_id}: {video:?}",
+                post.id
+            );
+            if size_exceeds_mb(video.size, max_mb) {
+                info!(
+                    "animation of {} bytes exceeds {max_mb} MB, sending as link post_id={}",
+                    video.size, post.id
+                );
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+            let mut request = tg
+                .send_animation(ChatId(chat_id), InputFile::file(&video.path))
+                .parse_mode(config.parse_mode.into())
+                .caption(&caption)
+                .height(video.height.into())
+                .width(video.width.into());
+            if let Some(thread_id) = thread_id {
+                request = request.message_thread_id(thread_id);
+            }
+            if let Some(thumbnail) = &video.thumbnail {
+                request = request.thumb(InputFile::file(thumbnail));
+            }
+            request.await?;
+        }
+        // Plain gifs are downloaded as is, Telegram reads their dimensions from the file
+        _ => {
+            let (path, _tmp_dir, _) = download_url_to_tmp(&post.url).await?;
+            if exceeds_size_cap(&path, media_options.max_media_mb)? {
+                info!(
+                    "animation exceeds max_media_mb={:?}, sending as link post_id={}",
+                    media_options.max_media_mb, post.id
+                );
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+            let mut request = tg
+                .send_animation(ChatId(chat_id), InputFile::file(path))
+                .parse_mode(config.parse_mode.into())
+                .caption(&caption);
+            if let Some(thread_id) = thread_id {
+                request = request.message_thread_id(thread_id);
+            }
+            request.await?;
+        }
+    }
+    info!("animation uploaded post_id={} chat_id={chat_id}", post.id);
+    Ok(())
+}
+
+/// Sends several image posts as one album, each image with the caption of its post. Images that
+/// can't be included, e.g. due to the size cap, are sent individually. Returns the posts that were
+/// sent.
+async fn handle_new_image_album<'a>(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    posts: &'a [reddit::Post],
+    media_options: &MediaOptions,
+) -> Result<Vec<&'a reddit::Post>> {
+    let permit = acquire_download_permit().await;
+    let mut media_group = vec![];
+    let mut album_posts = vec![];
+    let mut individual_posts = vec![];
+    // Keeps the downloaded files around until the album has been sent
+    let mut tmp_dirs = vec![];
+
+    for post in posts {
+        match download_url_to_tmp(&post.url).await {
+            // Images that turn out to be videos are sent individually as animations
+            Ok((_, _, Some(mime))) if mime.starts_with("video/") => {
+                individual_posts.push(post);
+            }
+            Ok((path, _, _)) if exceeds_size_cap(&path, media_options.max_media_mb)? => {
+                individual_posts.push(post);
+            }
+            Ok((path, tmp_dir, _)) => {
+                let caption = messages::format_media_caption(post, config);
+                media_group.push(InputMedia::Photo(
+                    InputMediaPhoto::new(InputFile::file(path))
+                        .caption(caption)
+                        .parse_mode(config.parse_mode.into()),
+                ));
+                album_posts.push(post);
+                tmp_dirs.push(tmp_dir);
+            }
+            Err(e) => {
+                error!(
+                    "failed to download image for album post_id={}: {e}",
+                    post.id
+                );
+                individual_posts.push(post);
+            }
+        }
+    }
+
+    let mut sent_posts = vec![];
+    // An album needs at least two items
+    if media_group.len() > 1 {
+        let mut request = tg.send_media_group(ChatId(chat_id), media_group);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        request.await?;
+        info!(
+            "album of {} images uploaded chat_id={chat_id}",
+            tmp_dirs.len()
+        );
+        sent_posts = album_posts;
+    } else {
+        individual_posts = posts.iter().collect();
+    }
+    drop(permit);
+
+    for post in individual_posts {
+        match handle_new_post(config, tg, chat_id, thread_id, post, media_options).await {
+            Ok(true) => sent_posts.push(post),
+            Ok(false) => {}
+            Err(e) => error!(
+                "failed to handle new post post_id={} chat_id={chat_id}: {e}",
+                post.id
+            ),
+        }
+    }
+
+    Ok(sent_posts)
+}
+
+/// The post with its url pointing to media_proxy_base_url, if it's set and the url is on a host the
+/// proxy serves
+fn with_media_proxy_url<'a>(
+    config: &config::Config,
+    post: &'a reddit::Post,
+) -> Cow<'a, reddit::Post> {
+    match config
+        .media_proxy_base_url
+        .as_deref()
+        .and_then(|base_url| reddit::to_media_proxy_url(&post.url, base_url))
+    {
+        Some(url) => Cow::Owned(reddit::Post {
+            url,
+            ..post.clone()
+        }),
+        None => Cow::Borrowed(post),
+    }
+}
+
+/// file_id of an earlier upload of the media with the given key, so that it can be sent without
+/// downloading and uploading it again. None if it's not cached or is larger than max_mb. Failing
+/// to use the cache is not worth failing the post for.
+fn get_cached_file_id(config: &config::Config, key: &str, max_mb: Option<u32>) -> Option<String> {
+    match db::Database::open(config).and_then(|db| db.get_cached_file(key)) {
+        Ok(Some(file)) if !max_mb.is_some_and(|max_mb| size_exceeds_mb(file.size, max_mb)) => {
+            Some(file.file_id)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            warn!("failed to get cached file key={key}: {e}");
+            None
+        }
+    }
+}
+
+fn cache_file(config: &config::Config, key: &str, file: &teloxide::types::FileMeta) {
+    let file = CachedFile {
+        file_id: file.id.clone(),
+        size: file.size.into(),
+    };
+    if let Err(e) = db::Database::open(config).and_then(|db| db.set_cached_file(key, &file)) {
+        warn!("failed to cache file key={key}: {e}");
+    }
+}
+
+/// Forgets a file_id that Telegram no longer accepts
+fn forget_cached_file(config: &config::Config, key: &str) {
+    if let Err(e) = db::Database::open(config).and_then(|db| db.delete_cached_file(key)) {
+        warn!("failed to delete cached file key={key}: {e}");
+    }
+}
+
 async fn handle_new_image_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<i32>,
     post: &reddit::Post,
+    media_options: &MediaOptions,
 ) -> Result<()> {
-    match download_url_to_tmp(&post.url).await {
-        Ok((path, _tmp_dir)) => {
+    // An album is several images that can't be downloaded as a single file
+    if reddit::is_imgur_album_url(&post.url) {
+        return handle_new_album_post(config, tg, chat_id, thread_id, post, media_options).await;
+    }
+
+    let caption = messages::format_media_caption(post, config);
+    let photo_request = |photo: InputFile| {
+        let mut request = tg
+            .send_photo(ChatId(chat_id), photo)
+            .parse_mode(config.parse_mode.into())
+            .caption(&caption);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Some(keyboard) = post_keyboard(config, post) {
+            request = request.reply_markup(keyboard);
+        }
+        request
+    };
+
+    if let Some(file_id) = get_cached_file_id(config, &post.url, media_options.max_media_mb) {
+        match photo_request(InputFile::file_id(file_id)).await {
+            Ok(_) => {
+                info!(
+                    "image sent from cache post_id={} chat_id={chat_id}",
+                    post.id
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                warn!(
+                    "failed to send cached image, uploading it again post_id={}: {e}",
+                    post.id
+                );
+                forget_cached_file(config, &post.url);
+            }
+        }
+    }
+
+    match download_url_to_tmp(&with_media_proxy_url(config, post).url).await {
+        // Image hosts may serve e.g. an mp4 for a gif, which can't be sent as a photo
+        Ok((_, _, Some(mime))) if mime.starts_with("video/") => {
+            info!(
+                "image is a video ({mime}), sending as animation post_id={}",
+                post.id
+            );
+            handle_new_animation_post(config, tg, chat_id, thread_id, post, media_options).await
+        }
+        Ok((path, _tmp_dir, _)) => {
+            if exceeds_size_cap(&path, media_options.max_media_mb)? {
+                info!(
+                    "image exceeds max_media_mb={:?}, sending as link post_id={}",
+                    media_options.max_media_mb, post.id
+                );
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+
             // path will be deleted when _tmp_dir when goes out of scope
-            let caption =
-                messages::format_media_caption_html(post, config.links_base_url.as_deref());
-            tg.send_photo(ChatId(chat_id), InputFile::file(path))
-                .parse_mode(teloxide::types::ParseMode::Html)
-                .caption(&caption)
-                .await?;
+            let message = photo_request(InputFile::file(path)).await?;
+            // Telegram returns the photo in several sizes, the largest last
+            if let Some(photo) = message.photo().and_then(|sizes| sizes.last()) {
+                cache_file(config, &post.url, &photo.file);
+            }
             info!("image uploaded post_id={} chat_id={chat_id}", post.id);
             Ok(())
         }
         Err(e) => {
-            error!("failed to download image: {e}");
+            error!(
+                "failed to download image post_id={} chat_id={chat_id}: {e}",
+                post.id
+            );
             Err(e)
         }
     }
 }
 
+/// Sends the images of an album as media groups. Sent as a link if the album can't be downloaded
+/// or any of its images is too large.
+async fn handle_new_album_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    post: &reddit::Post,
+    media_options: &MediaOptions,
+) -> Result<()> {
+    // The temporary directory will be deleted when _tmp_dir is dropped
+    let (image_paths, _tmp_dir) =
+        match tokio::task::block_in_place(|| ytdlp::download_album(config, &post.url)) {
+            Ok((image_paths, tmp_dir)) if !image_paths.is_empty() => (image_paths, tmp_dir),
+            Ok(_) => {
+                info!("album has no images, sending as link post_id={}", post.id);
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+            Err(err) => {
+                warn!(
+                    "failed to download album, sending as link post_id={}: {err}",
+                    post.id
+                );
+                return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+            }
+        };
+    for path in &image_paths {
+        if exceeds_size_cap(path, media_options.max_media_mb)? {
+            info!(
+                "album image exceeds max_media_mb={:?}, sending as link post_id={}",
+                media_options.max_media_mb, post.id
+            );
+            return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+        }
+    }
+
+    let image_paths = image_paths.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+    let caption = messages::format_media_caption(post, config);
+    send_images(
+        tg,
+        chat_id,
+        thread_id,
+        &image_paths,
+        &caption,
+        config.parse_mode.into(),
+    )
+    .await?;
+    info!(
+        "album uploaded post_id={} chat_id={chat_id} images={}",
+        post.id,
+        image_paths.len()
+    );
+    Ok(())
+}
+
 async fn handle_new_link_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<i32>,
     post: &reddit::Post,
 ) -> Result<()> {
-    let message_html = messages::format_link_message_html(post, config.links_base_url.as_deref());
-    tg.send_message(ChatId(chat_id), message_html)
-        .parse_mode(teloxide::types::ParseMode::Html)
-        .disable_web_page_preview(false)
-        .await?;
+    if config.fetch_link_metadata && post.post_type == PostType::Link {
+        match opengraph::fetch_og_metadata(&post.url).await {
+            Ok(Some(og)) => match send_link_card(config, tg, chat_id, thread_id, post, &og).await {
+                Ok(()) => {
+                    info!("link card sent post_id={} chat_id={chat_id}", post.id);
+                    return Ok(());
+                }
+                Err(err) => warn!(
+                    "failed to send link card, sending without it post_id={}: {err}",
+                    post.id
+                ),
+            },
+            Ok(None) => info!("linked page has no metadata post_id={}", post.id),
+            Err(err) => warn!(
+                "failed to fetch metadata of linked page post_id={}: {err}",
+                post.id
+            ),
+        }
+    }
+
+    let post = &with_media_proxy_url(config, post);
+    let message = messages::format_link_message(post, config);
+    let mut request = tg
+        .send_message(ChatId(chat_id), message)
+        .parse_mode(config.parse_mode.into())
+        .disable_web_page_preview(!config.web_preview_enabled(post.post_type));
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
+    if let Some(keyboard) = post_keyboard(config, post) {
+        request = request.reply_markup(keyboard);
+    }
+    request.await?;
     info!("message sent post_id={} chat_id={chat_id}", post.id);
     Ok(())
 }
 
+/// Sends a link post with the OpenGraph metadata of the linked page, as a photo when the page has
+/// an image. Telegram's own preview is disabled as it would repeat the card.
+async fn send_link_card(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    post: &reddit::Post,
+    og: &opengraph::OgMetadata,
+) -> Result<()> {
+    let keyboard = post_keyboard(config, post);
+    if let Some(image) = &og.image {
+        let mut request = tg
+            .send_photo(ChatId(chat_id), InputFile::url(image.parse()?))
+            .parse_mode(config.parse_mode.into())
+            .caption(messages::format_link_card_caption(post, config, og));
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Some(keyboard) = keyboard {
+            request = request.reply_markup(keyboard);
+        }
+        request.await?;
+    } else {
+        let mut request = tg
+            .send_message(
+                ChatId(chat_id),
+                messages::format_link_card_message(post, config, og),
+            )
+            .parse_mode(config.parse_mode.into())
+            .disable_web_page_preview(true);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Some(keyboard) = keyboard {
+            request = request.reply_markup(keyboard);
+        }
+        request.await?;
+    }
+    Ok(())
+}
+
 async fn handle_new_self_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<i32>,
     post: &reddit::Post,
 ) -> Result<()> {
-    let message_html = messages::format_media_caption_html(post, config.links_base_url.as_deref());
-    tg.send_message(ChatId(chat_id), message_html)
-        .parse_mode(teloxide::types::ParseMode::Html)
-        .disable_web_page_preview(true)
-        .await?;
+    let message = messages::format_self_message(post, config);
+    let mut request = tg
+        .send_message(ChatId(chat_id), message)
+        .parse_mode(config.parse_mode.into())
+        .disable_web_page_preview(!config.web_preview_enabled(post.post_type));
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
+    request.await?;
     info!("message sent post_id={} chat_id={chat_id}", post.id);
     Ok(())
 }
 
-async fn download_gallery(post: &reddit::Post) -> Result<HashMap<String, (PathBuf, TempDir)>> {
-    let media_metadata_map = post
+/// Sends images with the caption on the first one, in media groups of at most
+/// MEDIA_GROUP_MAX_ITEMS images. A group of one image is sent as a photo instead, as Telegram
+/// requires at least two items in a media group.
+async fn send_images(
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    image_paths: &[&Path],
+    caption: &str,
+    parse_mode: teloxide::types::ParseMode,
+) -> Result<()> {
+    for (chunk_index, chunk) in image_paths.chunks(MEDIA_GROUP_MAX_ITEMS).enumerate() {
+        let caption = (chunk_index == 0).then_some(caption);
+        match chunk {
+            [image_path] => {
+                let mut request = tg
+                    .send_photo(ChatId(chat_id), InputFile::file(image_path))
+                    .parse_mode(parse_mode);
+                if let Some(caption) = caption {
+                    request = request.caption(caption);
+                }
+                if let Some(thread_id) = thread_id {
+                    request = request.message_thread_id(thread_id);
+                }
+                request.await?;
+            }
+            _ => {
+                let media_group = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, image_path)| {
+                        let input_media_photo = InputMediaPhoto::new(InputFile::file(*image_path));
+                        // The first InputMediaPhoto of the first group needs to contain the
+                        // caption and parse_mode
+                        match caption {
+                            Some(caption) if i == 0 => InputMedia::Photo(
+                                input_media_photo.caption(caption).parse_mode(parse_mode),
+                            ),
+                            _ => InputMedia::Photo(input_media_photo),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let mut request = tg.send_media_group(ChatId(chat_id), media_group);
+                if let Some(thread_id) = thread_id {
+                    request = request.message_thread_id(thread_id);
+                }
+                request.await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Self posts may have images inline in the text, which are sent along with the text rather than
+/// dropped
+async fn handle_new_self_post_with_images(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    post: &reddit::Post,
+    media_options: &MediaOptions,
+) -> Result<()> {
+    let images = post
         .media_metadata
-        .as_ref()
-        .expect("expected media_metadata to exist in gallery post");
+        .iter()
+        .flatten()
+        .filter(|(_, media_metadata)| media_metadata.e == "Image")
+        .map(|(id, media_metadata)| (id.clone(), media_metadata.clone()))
+        .collect::<HashMap<_, _>>();
+    let image_files_map = download_gallery(&images).await?;
 
-    let mut map: HashMap<String, (PathBuf, TempDir)> = HashMap::new();
-    for (id, media_metadata) in media_metadata_map {
-        let s = &media_metadata.s;
-        let url = &s.url.replace("&amp;", "&");
-        info!("got media id={id} x={} y={} url={}", &s.x, &s.y, url);
-        map.insert(id.to_string(), download_url_to_tmp(url).await?);
+    // Reddit doesn't give the order in which images appear in the text, so sort by id to at least
+    // be consistent
+    let mut image_paths = vec![];
+    for (id, (image_path, _tempdir)) in image_files_map.iter().sorted_by_key(|(id, _)| *id) {
+        if exceeds_size_cap(image_path, media_options.max_media_mb)? {
+            info!(
+                "self post image exceeds max_media_mb={:?}, skipping id={id}",
+                media_options.max_media_mb
+            );
+        } else {
+            image_paths.push(image_path.as_path());
+        }
+    }
+
+    if image_paths.is_empty() {
+        return handle_new_self_post(config, tg, chat_id, thread_id, post).await;
     }
 
+    let caption = messages::format_self_caption(post, config);
+    send_images(
+        tg,
+        chat_id,
+        thread_id,
+        &image_paths,
+        &caption,
+        config.parse_mode.into(),
+    )
+    .await?;
+    info!(
+        "self post with images uploaded post_id={} chat_id={chat_id}",
+        post.id
+    );
+    Ok(())
+}
+
+/// Downloads the images of a gallery by media id, a few at a time. Images that fail to download
+/// are left out, so that the rest can still be sent. The files are deleted when the returned temp
+/// dirs are dropped.
+async fn download_gallery(
+    media_metadata_map: &HashMap<String, reddit::MediaMetadata>,
+) -> Result<HashMap<String, (PathBuf, TempDir)>> {
+    // Resolved to owned urls up front, as a stream over borrowed items makes the future
+    // not Send for the bot's handlers
+    let urls: Vec<(String, String)> = media_metadata_map
+        .iter()
+        .filter_map(|(id, media_metadata)| {
+            let media = media_metadata.best_variant(reddit::MAX_IMAGE_DIMENSION);
+            let Some(url) = &media.url else {
+                error!("gallery image has no url id={id}");
+                return None;
+            };
+            let url = url.replace("&amp;", "&");
+            info!(
+                "got media id={id} x={} y={} url={}",
+                &media.x, &media.y, url
+            );
+            Some((id.to_string(), url))
+        })
+        .collect();
+
+    let map = futures::stream::iter(urls)
+        .map(|(id, url)| async move {
+            match download_url_to_tmp(&url).await {
+                Ok((path, tmp_dir, _)) => Some((id, (path, tmp_dir))),
+                Err(e) => {
+                    error!("failed to download gallery image id={id}: {e}");
+                    None
+                }
+            }
+        })
+        .buffer_unordered(GALLERY_DOWNLOAD_CONCURRENCY)
+        .filter_map(|download| async move { download })
+        .collect::<HashMap<_, _>>()
+        .await;
+
     Ok(map)
 }
 
+/// Sends an animated gallery item
+async fn send_gallery_animation(
+    tg: &Bot,
+    chat_id: i64,
+    thread_id: Option<i32>,
+    url: &str,
+    caption: Option<&str>,
+    parse_mode: teloxide::types::ParseMode,
+    media_options: &MediaOptions,
+) -> Result<()> {
+    let url = url.replace("&amp;", "&");
+    let (path, _tmp_dir, _) = download_url_to_tmp(&url).await?;
+    if exceeds_size_cap(&path, media_options.max_media_mb)? {
+        info!(
+            "gallery animation exceeds max_media_mb={:?}, skipping url={url}",
+            media_options.max_media_mb
+        );
+        return Ok(());
+    }
+
+    let mut request = tg.send_animation(ChatId(chat_id), InputFile::file(path));
+    if let Some(caption) = caption {
+        request = request.caption(caption).parse_mode(parse_mode);
+    }
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
+    request.await?;
+    Ok(())
+}
+
+/// Sends the images of a gallery in gallery order, with the caption on the first one. Animated
+/// items can't be part of a group of photos, so they are sent individually after the images. When
+/// a gallery has only animated items, the caption goes on the first one.
 async fn handle_new_gallery_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<i32>,
     post: &reddit::Post,
+    media_options: &MediaOptions,
 ) -> Result<()> {
     // post.gallery_data is an array that describes the order of photos in the gallery, while
     // post.media_metadata is a map that contains the URL for each photo
-    let gallery_data_items = &post
-        .gallery_data
-        .as_ref()
-        .expect("expected media_metadata to exist in gallery post")
-        .items;
-    let gallery_files_map = download_gallery(post).await?;
-    let mut media_group = vec![];
-    let mut first = true;
+    let (gallery_data, media_metadata_map) = match post.gallery_media() {
+        Some(gallery_media) => gallery_media,
+        None => {
+            warn!(
+                "gallery post has no media metadata, sending as link post_id={}",
+                post.id
+            );
+            return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+        }
+    };
+    let (animations, images): (HashMap<_, _>, HashMap<_, _>) = media_metadata_map
+        .iter()
+        .map(|(id, media_metadata)| (id.clone(), media_metadata.clone()))
+        .partition(|(_, media_metadata)| media_metadata.is_animated());
+    let gallery_files_map = download_gallery(&images).await?;
+    let mut image_paths = vec![];
+    let mut animation_urls = vec![];
+
+    for item in &gallery_data.items {
+        if let Some(media_metadata) = animations.get(&item.media_id) {
+            match media_metadata.animation_url() {
+                Some(url) => animation_urls.push(url),
+                None => error!("animated gallery item has no url: {item:?}"),
+            }
+            continue;
+        }
 
-    for item in gallery_data_items {
         let file = gallery_files_map.get(&item.media_id);
         match file {
-            Some((image_path, _tempdir)) => {
-                let mut input_media_photo = InputMediaPhoto::new(InputFile::file(image_path));
-                // The first InputMediaPhoto in the vector needs to contain the caption and parse_mode;
-                if first {
-                    let caption =
-                        messages::format_media_caption_html(post, config.links_base_url.as_deref());
-                    input_media_photo = input_media_photo
-                        .caption(&caption)
-                        .parse_mode(teloxide::types::ParseMode::Html);
-                    first = false;
-                }
-
-                media_group.push(InputMedia::Photo(input_media_photo))
+            Some((image_path, _tempdir))
+                if exceeds_size_cap(image_path, media_options.max_media_mb)? =>
+            {
+                info!(
+                    "gallery image exceeds max_media_mb={:?}, skipping: {item:?}",
+                    media_options.max_media_mb
+                );
             }
+            Some((image_path, _tempdir)) => image_paths.push(image_path.as_path()),
             None => {
-                error!("could not find downloaded image for gallery data item: {item:?}");
+                info!("gallery image was not downloaded, skipping: {item:?}");
             }
         }
     }
 
-    tg.send_media_group(ChatId(chat_id), media_group).await?;
+    if image_paths.is_empty() && animation_urls.is_empty() {
+        info!(
+            "no gallery images to send, sending as link post_id={}",
+            post.id
+        );
+        return handle_new_link_post(config, tg, chat_id, thread_id, post).await;
+    }
+
+    let caption = messages::format_media_caption(post, config);
+    if !image_paths.is_empty() {
+        send_images(
+            tg,
+            chat_id,
+            thread_id,
+            &image_paths,
+            &caption,
+            config.parse_mode.into(),
+        )
+        .await?;
+    }
+    for (i, url) in animation_urls.into_iter().enumerate() {
+        let caption = (image_paths.is_empty() && i == 0).then_some(caption.as_str());
+        if let Err(e) = send_gallery_animation(
+            tg,
+            chat_id,
+            thread_id,
+            url,
+            caption,
+            config.parse_mode.into(),
+            media_options,
+        )
+        .await
+        {
+            error!(
+                "failed to send gallery animation post_id={} url={url}: {e}",
+                post.id
+            );
+        }
+    }
     info!("gallery uploaded post_id={} chat_id={chat_id}", post.id);
 
     Ok(())
 }
 
+/// Sends the post to the chat. Returns whether it was sent, as posts that have been deleted or are
+/// ignored by post_type_behavior are skipped.
 async fn handle_new_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    thread_id: Option<i32>,
     post: &reddit::Post,
-) -> Result<()> {
+    media_options: &MediaOptions,
+) -> Result<bool> {
     info!("got new {post:#?}");
     let mut post = Cow::Borrowed(post);
 
@@ -265,38 +1224,126 @@ async fn handle_new_post(
     // but not always
     // TODO: It appears that post with is_gallery=true will never have post_hint set
     if post.post_hint.is_none() {
-        info!("post missing post_hint, getting like directly");
+        info!(
+            "post missing post_hint, getting like directly post_id={}",
+            post.id
+        );
         post = Cow::Owned(reddit::get_link(&post.id).await.unwrap());
     }
 
+    // The listing may be stale, so the post can have been deleted after it was fetched. It's
+    // still marked as seen so that it's not checked again.
+    if post.is_deleted() {
+        info!(
+            "post has been deleted or removed, skipping it post_id={}",
+            post.id
+        );
+        return Ok(false);
+    }
+
+    let behavior = config.post_type_behavior.get(post.post_type).copied();
+    if behavior == Some(config::PostTypeBehavior::Ignore) {
+        info!(
+            "ignoring {} post due to post_type_behavior post_id={}",
+            post.post_type, post.id
+        );
+        return Ok(false);
+    }
+
+    if media_options.include_top_comment {
+        match reddit::get_top_comment(&post.id).await {
+            Ok(top_comment) => post.to_mut().top_comment = top_comment,
+            // The post is still worth sending without the comment
+            Err(err) => warn!("failed to get top comment post_id={}: {err:?}", post.id),
+        }
+    }
+
+    if behavior == Some(config::PostTypeBehavior::Link) {
+        handle_new_link_post(config, tg, chat_id, thread_id, &post).await?;
+        return Ok(true);
+    }
+
+    // Held until the post has been sent so that the check loop and commands take turns
+    let _permit = acquire_download_permit().await;
     match post.post_type {
-        reddit::PostType::Image => handle_new_image_post(config, tg, chat_id, &post).await,
-        reddit::PostType::Video => handle_new_video_post(config, tg, chat_id, &post).await,
-        reddit::PostType::Link => handle_new_link_post(config, tg, chat_id, &post).await,
-        reddit::PostType::SelfText => handle_new_self_post(config, tg, chat_id, &post).await,
-        reddit::PostType::Gallery => handle_new_gallery_post(config, tg, chat_id, &post).await,
+        reddit::PostType::Image | reddit::PostType::Video
+            if reddit::is_animation_url(&post.url) =>
+        {
+            handle_new_animation_post(config, tg, chat_id, thread_id, &post, media_options).await
+        }
+        reddit::PostType::Image => {
+            handle_new_image_post(config, tg, chat_id, thread_id, &post, media_options).await
+        }
+        reddit::PostType::Video => {
+            handle_new_video_post(config, tg, chat_id, thread_id, &post, media_options).await
+        }
+        reddit::PostType::Link => handle_new_link_post(config, tg, chat_id, thread_id, &post).await,
+        reddit::PostType::SelfText => match &post.media_metadata {
+            Some(media_metadata) if !media_metadata.is_empty() => {
+                handle_new_self_post_with_images(
+                    config,
+                    tg,
+                    chat_id,
+                    thread_id,
+                    &post,
+                    media_options,
+                )
+                .await
+            }
+            _ => handle_new_self_post(config, tg, chat_id, thread_id, &post).await,
+        },
+        reddit::PostType::Gallery => {
+            handle_new_gallery_post(config, tg, chat_id, thread_id, &post, media_options).await
+        }
         // /r/bestof posts have no characteristics like post_hint that could be used to
         // determine them as a type of Link; as a workaround, post Unknown post types the same way
         // as a link
         reddit::PostType::Unknown => {
             warn!("unknown post type, post={post:?}");
-            handle_new_link_post(config, tg, chat_id, &post).await
+            handle_new_link_post(config, tg, chat_id, thread_id, &post).await
         }
+    }?;
+    Ok(true)
+}
+
+/// Sorts the posts found on a check into the order they are sent in. Without a delivery order,
+/// Reddit's listing order is kept.
+fn sort_for_delivery(posts: &mut [reddit::Post], delivery_order: Option<config::DeliveryOrder>) {
+    match delivery_order {
+        Some(config::DeliveryOrder::Chronological) => {
+            posts.sort_by(|a, b| a.created.total_cmp(&b.created))
+        }
+        Some(config::DeliveryOrder::Score) => posts.sort_by_key(|post| std::cmp::Reverse(post.ups)),
+        None => {}
     }
 }
 
-async fn check_post_newness(
+/// Ids of the count most recently created posts, which are sent on the first check of a new
+/// subscription
+fn freshest_post_ids<'a>(
+    posts: impl Iterator<Item = &'a reddit::Post>,
+    count: u32,
+) -> HashSet<String> {
+    posts
+        .sorted_by(|a, b| b.created.total_cmp(&a.created))
+        .take(count as usize)
+        .map(|post| post.id.clone())
+        .collect()
+}
+
+/// Whether the post matches the filter and hasn't been seen in the chat. Posts whose content has
+/// already been sent are marked seen.
+fn is_post_new(
+    db: &db::Database,
     config: &config::Config,
-    tg: &Bot,
     chat_id: i64,
     filter: Option<reddit::PostType>,
     post: &reddit::Post,
     only_mark_seen: bool,
-) -> Result<()> {
-    let db = db::Database::open(config)?;
+) -> Result<bool> {
     if filter.is_some() && filter.as_ref() != Some(&post.post_type) {
         debug!("filter set and post does not match filter, skipping");
-        return Ok(());
+        return Ok(false);
     }
 
     if db
@@ -304,85 +1351,1062 @@ async fn check_post_newness(
         .expect("failed to query if post is seen")
     {
         debug!("post already seen, skipping...");
-        return Ok(());
+        return Ok(false);
+    }
+
+    // Marked seen so that the post isn't checked again while it stays in the listing
+    if config.is_post_blocked(post) {
+        info!(
+            "post blocked by domain, keyword or author lists, skipping post_id={} chat_id={chat_id}",
+            post.id
+        );
+        db.mark_post_seen(chat_id, post)?;
+        return Ok(false);
+    }
+    if config::is_author_blocked(&db.get_blocked_authors(chat_id)?, &post.author) {
+        info!(
+            "author blocked in chat, skipping post_id={} chat_id={chat_id}",
+            post.id
+        );
+        db.mark_post_seen(chat_id, post)?;
+        return Ok(false);
+    }
+
+    let dedup_window_hours = config
+        .dedup_window_hours
+        .unwrap_or(config::DEFAULT_DEDUP_WINDOW_HOURS);
+    if !only_mark_seen && dedup_window_hours > 0 {
+        let since = chrono::Utc::now() - chrono::Duration::hours(dedup_window_hours.into());
+        if db.is_content_seen(chat_id, post, since)? {
+            info!(
+                "content of post already sent to chat, skipping post_id={} chat_id={chat_id}",
+                post.id
+            );
+            db.mark_post_seen(chat_id, post)?;
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns whether the post was new and sending it was attempted.
+async fn check_post_newness(
+    config: &config::Config,
+    tg: &Bot,
+    sub: &Subscription,
+    filter: Option<reddit::PostType>,
+    post: &reddit::Post,
+    only_mark_seen: bool,
+    media_options: &MediaOptions,
+) -> Result<bool> {
+    let (chat_id, thread_id) = (sub.chat_id, sub.message_thread_id);
+    let db = db::Database::open(config)?;
+    if !is_post_new(&db, config, chat_id, filter, post, only_mark_seen)? {
+        return Ok(false);
     }
 
     if !only_mark_seen {
         // Intentionally marking post as seen if handling it fails. It's preferable to not have it
-        // fail continuously.
-        if let Err(e) = handle_new_post(config, tg, chat_id, post).await {
-            error!("failed to handle new post: {e}");
+        // fail continuously, so it's only retried a limited number of times if retry_failed is set.
+        let telegram = sink::TelegramSink {
+            config,
+            tg,
+            chat_id,
+            thread_id,
+            media_options,
+        };
+        let webhooks = sink::webhook_sinks(config, sub.webhook.as_deref());
+        if let Err(e) = sink::deliver_post(&telegram, &webhooks, post).await {
+            error!(
+                "failed to handle new post post_id={} chat_id={chat_id}: {e}",
+                post.id
+            );
+            if config.retry_failed.is_some() {
+                db.add_failed_post(
+                    chat_id,
+                    thread_id,
+                    &post.id,
+                    media_options,
+                    sub.webhook.as_deref(),
+                )?;
+            }
         }
     }
 
     db.mark_post_seen(chat_id, post)?;
-    info!("marked post seen: {}", post.id);
+    info!("marked post seen post_id={} chat_id={chat_id}", post.id);
+
+    Ok(!only_mark_seen)
+}
+
+/// Sends posts held back during quiet hours, once the quiet hours of the chat are over
+async fn send_pending_posts(
+    config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
+    tg: &Bot,
+) -> Result<()> {
+    let db = db::Database::open(config)?;
+    let now = chrono::Local::now().time();
+
+    for pending in db.get_pending_posts()? {
+        let chat_id = pending.chat_id;
+        if config.is_quiet_time(&db.get_chat_settings(chat_id)?, now) {
+            continue;
+        }
+
+        // Kept pending so that sending is attempted again on the next check
+        let post = match reddit_client.get_link(&pending.post_id).await {
+            Ok(post) => post,
+            Err(e) => {
+                error!(
+                    "failed to get pending post post_id={}: {e}",
+                    pending.post_id
+                );
+                continue;
+            }
+        };
+
+        if !db.is_post_seen(chat_id, &post)? {
+            info!(
+                "sending post held back during quiet hours post_id={} chat_id={chat_id}",
+                post.id
+            );
+            let telegram = sink::TelegramSink {
+                config,
+                tg,
+                chat_id,
+                thread_id: pending.message_thread_id,
+                media_options: &pending.media_options,
+            };
+            let webhooks = sink::webhook_sinks(config, pending.webhook.as_deref());
+            if let Err(e) = sink::deliver_post(&telegram, &webhooks, &post).await {
+                error!(
+                    "failed to handle new post post_id={} chat_id={chat_id}: {e}",
+                    post.id
+                );
+            }
+            db.mark_post_seen(chat_id, &post)?;
+        }
+        db.delete_pending_post(chat_id, &pending.post_id)?;
+    }
+
+    Ok(())
+}
+
+/// Attempts to send posts that failed to be sent on earlier checks again, until they have been
+/// retried retry_failed times
+async fn retry_failed_posts(
+    config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
+    tg: &Bot,
+) -> Result<()> {
+    let Some(max_retries) = config.retry_failed else {
+        return Ok(());
+    };
+    let db = db::Database::open(config)?;
+
+    for failed in db.get_failed_posts()? {
+        let chat_id = failed.chat_id;
+        let result = match reddit_client.get_link(&failed.post_id).await {
+            Ok(post) => {
+                info!(
+                    "retrying failed post post_id={} chat_id={chat_id} attempts={}",
+                    post.id, failed.attempts
+                );
+                let telegram = sink::TelegramSink {
+                    config,
+                    tg,
+                    chat_id,
+                    thread_id: failed.message_thread_id,
+                    media_options: &failed.media_options,
+                };
+                let webhooks = sink::webhook_sinks(config, failed.webhook.as_deref());
+                sink::deliver_post(&telegram, &webhooks, &post).await
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(_) => db.delete_failed_post(chat_id, &failed.post_id)?,
+            Err(e) if failed.attempts >= max_retries => {
+                warn!(
+                    "giving up on failed post post_id={} chat_id={chat_id} attempts={}: {e}",
+                    failed.post_id, failed.attempts
+                );
+                db.delete_failed_post(chat_id, &failed.post_id)?;
+            }
+            Err(e) => {
+                error!(
+                    "failed to retry post post_id={} chat_id={chat_id}: {e}",
+                    failed.post_id
+                );
+                db.add_failed_post(
+                    chat_id,
+                    failed.message_thread_id,
+                    &failed.post_id,
+                    &failed.media_options,
+                    failed.webhook.as_deref(),
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the posts collected for digests, once a day at digest_hour
+async fn send_digests(
+    config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
+    tg: &Bot,
+) -> Result<()> {
+    let db = db::Database::open(config)?;
+    let now = chrono::Local::now();
+
+    for chat_id in db.get_digest_chat_ids()? {
+        let last_digest_at = db
+            .get_last_digest_at(chat_id)?
+            .map(|last| last.with_timezone(&chrono::Local).naive_local());
+        if !config.is_digest_due(last_digest_at, now.naive_local())
+            || config.is_quiet_time(&db.get_chat_settings(chat_id)?, now.time())
+        {
+            continue;
+        }
+
+        // Kept undelivered so that sending is attempted again on the next check
+        let post_ids = db.get_digest_post_ids(chat_id)?;
+        match send_digest(config, reddit_client, tg, chat_id, &post_ids).await {
+            Ok(()) => db.mark_digest_delivered(chat_id, &post_ids, chrono::Utc::now())?,
+            Err(e) => error!("failed to send digest chat_id={chat_id}: {e}"),
+        }
+    }
 
     Ok(())
 }
 
-async fn check_new_posts(config: &config::Config, tg: &Bot) -> Result<()> {
+async fn send_digest(
+    config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
+    tg: &Bot,
+    chat_id: i64,
+    post_ids: &[String],
+) -> Result<()> {
+    let mut posts = vec![];
+    for post_id in post_ids {
+        // Posts that can't be fetched, e.g. because they were deleted, are left out
+        match reddit_client.get_link(post_id).await {
+            Ok(post) => posts.push(post),
+            Err(e) => error!("failed to get digest post post_id={post_id}: {e}"),
+        }
+    }
+
+    if !posts.is_empty() {
+        info!(
+            "sending digest of {} post(s) chat_id={chat_id}",
+            posts.len()
+        );
+        for message in messages::format_digest(&posts, config) {
+            tg.send_message(ChatId(chat_id), message)
+                .parse_mode(config.parse_mode.into())
+                .disable_web_page_preview(true)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn check_new_posts(
+    config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
+    tg: &Bot,
+    shutdown: &AtomicBool,
+    shutdown_rx: &broadcast::Receiver<()>,
+) -> Result<()> {
+    send_pending_posts(config, reddit_client, tg)
+        .await
+        .unwrap_or_else(|err| error!("failed to send pending posts: {err}"));
+    retry_failed_posts(config, reddit_client, tg)
+        .await
+        .unwrap_or_else(|err| error!("failed to retry failed posts: {err}"));
+    send_digests(config, reddit_client, tg)
+        .await
+        .unwrap_or_else(|err| error!("failed to send digests: {err}"));
+
     info!("checking subscriptions for new posts");
     let db = db::Database::open(config)?;
     let subs = db.get_all_subscriptions()?;
-    for sub in subs {
-        check_new_posts_for_subscription(config, tg, &sub)
-            .await
-            .unwrap_or_else(|err| {
-                error!("failed to check subscription for new posts: {err}");
-            });
+    if let Some(retention_days) = config.resolve_seen_retention_days(&subs) {
+        match db.prune_seen_posts(retention_days) {
+            Ok(count) => info!("pruned {count} seen post(s) older than {retention_days} days"),
+            Err(err) => error!("failed to prune seen posts: {err}"),
+        }
     }
+    let max_concurrent_fetches = config
+        .max_concurrent_fetches
+        .unwrap_or(config::DEFAULT_MAX_CONCURRENT_FETCHES);
+
+    futures::stream::iter(subs.into_iter().filter(|sub| !sub.paused))
+        .map(|sub| {
+            let mut shutdown_rx = shutdown_rx.resubscribe();
+            async move {
+                // The shutdown message may have been consumed while waiting between posts
+                if shutdown.load(Ordering::Acquire) {
+                    return;
+                }
+
+                check_new_posts_for_subscription(config, reddit_client, tg, &sub, &mut shutdown_rx)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!(
+                            "failed to check subscription for new posts subreddit={} chat_id={}: {err}",
+                            sub.subreddit, sub.chat_id
+                        );
+                    });
+            }
+        })
+        .buffer_unordered(max_concurrent_fetches.max(1))
+        .collect::<Vec<()>>()
+        .await;
 
     Ok(())
 }
 
+/// Sends the comments of the subreddit that contain the subscription's keyword. Comments made
+/// before the subscription are skipped, so there's no initial batch like with posts.
+async fn check_new_comments_for_subscription(
+    config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
+    tg: &Bot,
+    sub: &Subscription,
+) -> Result<()> {
+    let db = db::Database::open(config)?;
+    let subreddit = &sub.subreddit;
+    let chat_id = sub.chat_id;
+    let Some(keyword) = sub.keyword.as_deref().map(str::to_lowercase) else {
+        warn!("comments subscription has no keyword subreddit={subreddit} chat_id={chat_id}");
+        return Ok(());
+    };
+    info!("checking r/{subreddit} for new comments subreddit={subreddit} chat_id={chat_id}");
+
+    let limit = sub.limit.unwrap_or(config::REDDIT_MAX_LIMIT);
+    let comments = reddit_client
+        .get_subreddit_comments(subreddit, limit)
+        .await?;
+    let subscribed_at = sub.created_at.timestamp() as f32;
+    // Listed newest first, but sent in the order they were made
+    for comment in comments.iter().rev() {
+        if comment.created < subscribed_at
+            || !comment.body.to_lowercase().contains(&keyword)
+            || db.is_comment_seen(chat_id, comment)?
+        {
+            continue;
+        }
+
+        info!(
+            "got new comment comment_id={} subreddit={subreddit} chat_id={chat_id}",
+            comment.id
+        );
+        let mut request = tg
+            .send_message(ChatId(chat_id), messages::format_comment(comment, config))
+            .parse_mode(config.parse_mode.into())
+            .disable_web_page_preview(true);
+        if let Some(thread_id) = sub.message_thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Err(err) = request.await {
+            error!(
+                "failed to send comment comment_id={} chat_id={chat_id}: {err}",
+                comment.id
+            );
+        }
+        db.mark_comment_seen(chat_id, comment)?;
+    }
+
+    Ok(())
+}
+
+/// Spaces out a burst of new posts. Returns true if shutdown was requested while waiting.
+async fn inter_post_delay(
+    config: &config::Config,
+    shutdown_rx: &mut broadcast::Receiver<()>,
+) -> bool {
+    if config.inter_post_delay_ms == 0 {
+        return false;
+    }
+    tokio::select! {
+        _ = tokio::time::sleep(Duration::from_millis(config.inter_post_delay_ms)) => false,
+        _ = shutdown_rx.recv() => true,
+    }
+}
+
+/// Gets the posts of a listing in the given order, the top posts when no order is given
+async fn get_sorted_posts(
+    reddit_client: &impl reddit::RedditClient,
+    source_type: SourceType,
+    name: &str,
+    sort: Option<reddit::SortMode>,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<reddit::Post>> {
+    match sort {
+        Some(sort) => {
+            reddit_client
+                .get_subreddit_posts(name, sort, limit, time, None)
+                .await
+        }
+        None => {
+            reddit_client
+                .get_top_posts(source_type, name, limit, time)
+                .await
+        }
+    }
+}
+
+/// Gets the posts of the subscription's listing, as checked for new posts. Subscriptions with
+/// sort=new only fetch the posts newer than the newest one of the previous check. As a post
+/// removed since then makes for an empty listing, an empty result falls back to the whole listing
+/// if the post is gone.
+async fn get_subscription_posts(
+    reddit_client: &impl reddit::RedditClient,
+    sub: &Subscription,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<reddit::Post>> {
+    let get_all_posts = || {
+        get_sorted_posts(
+            reddit_client,
+            sub.source_type,
+            &sub.subreddit,
+            sub.sort,
+            limit,
+            time,
+        )
+    };
+    let before = sub
+        .last_seen_fullname
+        .as_deref()
+        .filter(|_| sub.sort == Some(reddit::SortMode::New));
+    let Some(before) = before else {
+        return get_all_posts().await;
+    };
+    let posts = reddit_client
+        .get_subreddit_posts(
+            &sub.subreddit,
+            reddit::SortMode::New,
+            limit,
+            time,
+            Some(before),
+        )
+        .await?;
+    if !posts.is_empty() {
+        return Ok(posts);
+    }
+    // No new posts is the usual outcome, so the whole listing is only fetched when looking up the
+    // single cursor post shows it's gone
+    let cursor_listed = reddit_client
+        .get_link(before.trim_start_matches("t3_"))
+        .await
+        .is_ok_and(|post| !post.is_deleted());
+    if cursor_listed {
+        Ok(posts)
+    } else {
+        get_all_posts().await
+    }
+}
+
 async fn check_new_posts_for_subscription(
     config: &config::Config,
+    reddit_client: &impl reddit::RedditClient,
     tg: &Bot,
     sub: &Subscription,
+    shutdown_rx: &mut broadcast::Receiver<()>,
 ) -> Result<()> {
+    if sub.source_type == SourceType::Comments {
+        return check_new_comments_for_subscription(config, reddit_client, tg, sub).await;
+    }
+
     let db = db::Database::open(config)?;
     let subreddit = &sub.subreddit;
-    let limit = sub
-        .limit
-        .or(config.default_limit)
-        .unwrap_or(config::DEFAULT_LIMIT);
-    let time = sub
-        .time
-        .or(config.default_time)
-        .unwrap_or(config::DEFAULT_TIME_PERIOD);
-    let filter = sub.filter.or(config.default_filter);
+    let limit = config.resolve_limit(sub.limit);
+    let time = config.resolve_time(sub.time);
+    let filter = config.resolve_filter(sub.filter);
+    let max_age_hours = config.resolve_max_age_hours(sub.max_age_hours);
     let chat_id = sub.chat_id;
-    info!(
-        "checking subreddit /r/{subreddit} for new posts for user {chat_id}",
-        subreddit = subreddit,
-        chat_id = chat_id
+    let thread_id = sub.message_thread_id;
+    let chat_settings = db.get_chat_settings(chat_id)?;
+    let media_options = config.resolve_media_options(
+        sub.max_media_mb,
+        sub.ytdlp_format.as_deref(),
+        sub.include_top_comment,
+        &chat_settings,
     );
+    let prefix = sub.source_type.prefix();
+    info!("checking /{prefix}{subreddit} for new posts subreddit={subreddit} chat_id={chat_id}");
 
-    match reddit::get_subreddit_top_posts(subreddit, limit, &time).await {
-        Ok(posts) => {
-            debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
+    match get_subscription_posts(reddit_client, sub, limit, &time).await {
+        Ok(mut posts) => {
+            debug!(
+                "got {} post(s) for /{prefix}{subreddit} subreddit={subreddit} chat_id={chat_id}",
+                posts.len()
+            );
+            // Taken before the posts are sorted for delivery, and stored once they've been handled
+            let newest_fullname = (sub.sort == Some(reddit::SortMode::New))
+                .then(|| {
+                    posts
+                        .iter()
+                        .max_by(|a, b| a.created.total_cmp(&b.created))
+                        .map(|post| format!("t3_{}", post.id))
+                })
+                .flatten();
+            // Every post is still checked, so the order doesn't affect which are marked seen
+            sort_for_delivery(&mut posts, config.delivery_order);
 
-            // First run should not send anything to telegram but the post should be marked
-            // as seen, unless skip_initial_send is enabled
-            let is_new_subreddit = !db
-                .existing_posts_for_subreddit(chat_id, subreddit)
-                .context("failed to query if subreddit has existing posts")?;
-            let only_mark_seen = is_new_subreddit && config.skip_initial_send;
+            // On the first run, only up to initial_send_count of the freshest posts are sent to
+            // Telegram and the rest are just marked as seen. Subscriptions from before the
+            // initialized flag are recognized by their seen posts.
+            let is_new_subreddit = !sub.initialized
+                && !db
+                    .existing_posts_for_subscription(sub, &posts)
+                    .context("failed to query if subreddit has existing posts")?;
+            let initial_send_count = config.resolve_initial_send_count();
+            let only_mark_seen = is_new_subreddit && initial_send_count == Some(0);
+            let now = chrono::Utc::now();
+            let initial_send_ids = match initial_send_count {
+                Some(count) if is_new_subreddit && count > 0 => Some(freshest_post_ids(
+                    posts.iter().filter(|post| {
+                        !filter.is_some_and(|filter| post.post_type != filter)
+                            && !max_age_hours.is_some_and(|hours| post.is_older_than(hours, now))
+                    }),
+                    count,
+                )),
+                _ => None,
+            };
+            let is_digest = !only_mark_seen && sub.digest.is_some();
+            let group_images = !only_mark_seen && sub.group_images.unwrap_or(config.group_images);
+            let is_quiet_time = !only_mark_seen
+                && config.is_quiet_time(&chat_settings, chrono::Local::now().time());
+            let mut album_posts = vec![];
 
             for post in posts {
                 debug!("got {post:?}");
-                check_post_newness(config, tg, chat_id, filter, &post, only_mark_seen)
+                if max_age_hours.is_some_and(|hours| post.is_older_than(hours, now)) {
+                    debug!(
+                        "post older than max_age_hours, skipping post_id={}",
+                        post.id
+                    );
+                    continue;
+                }
+                let only_mark_seen = only_mark_seen
+                    || initial_send_ids
+                        .as_ref()
+                        .is_some_and(|ids| !ids.contains(&post.id));
+
+                // New posts are sent later in a digest, so they aren't held back by quiet hours
+                if is_digest && !only_mark_seen {
+                    match is_post_new(&db, config, chat_id, filter, &post, only_mark_seen) {
+                        Ok(true) => {
+                            db.add_digest_post(chat_id, &post)?;
+                            info!("added post to digest post_id={} chat_id={chat_id}", post.id);
+                        }
+                        Ok(false) => {}
+                        Err(err) => error!(
+                            "failed to check post newness post_id={} chat_id={chat_id}: {err}",
+                            post.id
+                        ),
+                    }
+                    continue;
+                }
+
+                // New posts are held back rather than marked seen, and sent when quiet hours end
+                if is_quiet_time && !only_mark_seen {
+                    match is_post_new(&db, config, chat_id, filter, &post, only_mark_seen) {
+                        Ok(true) => {
+                            db.add_pending_post(
+                                chat_id,
+                                thread_id,
+                                &post,
+                                &media_options,
+                                sub.webhook.as_deref(),
+                            )?;
+                            info!(
+                                "quiet hours, holding back post_id={} chat_id={chat_id}",
+                                post.id
+                            );
+                        }
+                        Ok(false) => {}
+                        Err(err) => error!(
+                            "failed to check post newness post_id={} chat_id={chat_id}: {err}",
+                            post.id
+                        ),
+                    }
+                    continue;
+                }
+
+                if group_images
+                    && !only_mark_seen
+                    && post.post_type == PostType::Image
+                    && !reddit::is_animation_url(&post.url)
+                    && config.post_type_behavior.get(PostType::Image).is_none()
+                {
+                    match is_post_new(&db, config, chat_id, filter, &post, only_mark_seen) {
+                        Ok(true) => album_posts.push(post),
+                        Ok(false) => {}
+                        Err(err) => error!(
+                            "failed to check post newness post_id={} chat_id={chat_id}: {err}",
+                            post.id
+                        ),
+                    }
+                    continue;
+                }
+
+                let sent = check_post_newness(
+                    config,
+                    tg,
+                    sub,
+                    filter,
+                    &post,
+                    only_mark_seen,
+                    &media_options,
+                )
+                .await
+                .unwrap_or_else(|err| {
+                    error!(
+                        "failed to check post newness post_id={} chat_id={chat_id}: {err}",
+                        post.id
+                    );
+                    false
+                });
+
+                if sent && inter_post_delay(config, shutdown_rx).await {
+                    return Ok(());
+                }
+            }
+
+            for album in album_posts.chunks(MEDIA_GROUP_MAX_ITEMS) {
+                // Like single posts, the posts are marked seen even if sending fails
+                match handle_new_image_album(config, tg, chat_id, thread_id, album, &media_options)
                     .await
-                    .unwrap_or_else(|err| {
-                        error!("failed to check post newness: {err}");
-                    });
+                {
+                    Ok(sent_posts) => {
+                        let webhooks = sink::webhook_sinks(config, sub.webhook.as_deref());
+                        for post in sent_posts {
+                            sink::send_to_webhooks(config, &webhooks, post).await;
+                        }
+                    }
+                    Err(e) => error!(
+                        "failed to handle new image album subreddit={subreddit} chat_id={chat_id}: {e}"
+                    ),
+                }
+                for post in album {
+                    db.mark_post_seen(chat_id, post)?;
+                    info!("marked post seen post_id={} chat_id={chat_id}", post.id);
+                }
+
+                if inter_post_delay(config, shutdown_rx).await {
+                    return Ok(());
+                }
+            }
+
+            // Even with no posts, as posts appearing later are new rather than initial ones
+            if !sub.initialized {
+                db.mark_subscription_initialized(chat_id, sub.source_type, subreddit)?;
+            }
+            if let Some(fullname) = newest_fullname {
+                db.set_last_seen_fullname(chat_id, sub.source_type, subreddit, &fullname)?;
             }
         }
-        Err(e) => {
-            error!("failed to get posts for {}: {e}", subreddit)
-        }
+        Err(e) => match reddit::inaccessible_listing_reason(&e) {
+            // Paused rather than failing on every check. Being paused also keeps the chat from
+            // being notified more than once.
+            Some(reason) => {
+                warn!(
+                    "/{prefix}{subreddit} is inaccessible, pausing subscription chat_id={chat_id}: {e}"
+                );
+                db.pause_subscription(chat_id, sub.source_type, subreddit)?;
+                let mut request = tg.send_message(
+                    ChatId(chat_id),
+                    messages::format_subscription_paused(sub, reason),
+                );
+                if let Some(thread_id) = thread_id {
+                    request = request.message_thread_id(thread_id);
+                }
+                request.await?;
+            }
+            None => error!("failed to get posts for /{prefix}{subreddit}: {e}"),
+        },
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the given posts for every listing, or an error if there are none
+    struct MockRedditClient {
+        posts: Option<Vec<reddit::Post>>,
+    }
+
+    impl reddit::RedditClient for MockRedditClient {
+        async fn get_top_posts(
+            &self,
+            _source_type: SourceType,
+            _name: &str,
+            _limit: u32,
+            _time: &TopPostsTimePeriod,
+        ) -> Result<Vec<reddit::Post>> {
+            self.posts.clone().context("failed to get posts")
+        }
+
+        async fn get_subreddit_posts(
+            &self,
+            _subreddit: &str,
+            _sort: reddit::SortMode,
+            _limit: u32,
+            _time: &TopPostsTimePeriod,
+            before: Option<&str>,
+        ) -> Result<Vec<reddit::Post>> {
+            let posts = self.posts.clone().context("failed to get posts")?;
+            // Like Reddit, only the posts listed before the cursor, or none if it's not listed
+            Ok(match before {
+                Some(before) => posts
+                    .iter()
+                    .position(|post| format!("t3_{}", post.id) == before)
+                    .map(|i| posts[..i].to_vec())
+                    .unwrap_or_default(),
+                None => posts,
+            })
+        }
+
+        async fn get_link(&self, link_id: &str) -> Result<reddit::Post> {
+            self.posts
+                .iter()
+                .flatten()
+                .find(|post| post.id == link_id)
+                .cloned()
+                .context("no such post")
+        }
+
+        async fn get_subreddit_comments(
+            &self,
+            _subreddit: &str,
+            _limit: u32,
+        ) -> Result<Vec<reddit::Comment>> {
+            Ok(vec![])
+        }
+    }
+
+    fn make_post(id: &str) -> reddit::Post {
+        reddit::Post {
+            id: id.into(),
+            post_hint: Some("image".into()),
+            subreddit: "pics".into(),
+            title: "A picture".into(),
+            permalink: format!("/r/pics/comments/{id}/a_picture/"),
+            url: format!("https://i.redd.it/{id}.jpg"),
+            post_type: PostType::Image,
+            ..reddit::Post::test_default()
+        }
+    }
+
+    fn make_config(tmp_dir: &TempDir) -> config::Config {
+        let config = config::Config {
+            db_path: tmp_dir.path().join("data.db3"),
+            skip_initial_send: true,
+            ..Default::default()
+        };
+        db::Database::open(&config).unwrap().migrate().unwrap();
+        config
+    }
+
+    #[test]
+    fn test_sort_for_delivery() {
+        let post = |id: &str, created: f32, ups: u32| reddit::Post {
+            created,
+            ups,
+            ..make_post(id)
+        };
+        let ids = |posts: &[reddit::Post]| posts.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        let mut posts = vec![
+            post("a", 1654588300.0, 900),
+            post("b", 1654581100.0, 500),
+            post("c", 1654584700.0, 700),
+        ];
+
+        sort_for_delivery(&mut posts, None);
+        assert_eq!(ids(&posts), ["a", "b", "c"]);
+
+        sort_for_delivery(&mut posts, Some(config::DeliveryOrder::Chronological));
+        assert_eq!(ids(&posts), ["b", "c", "a"]);
+
+        sort_for_delivery(&mut posts, Some(config::DeliveryOrder::Score));
+        assert_eq!(ids(&posts), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn test_freshest_post_ids() {
+        let post = |id: &str, created: f32| reddit::Post {
+            created,
+            ..make_post(id)
+        };
+        let posts = [
+            post("a", 1654581100.0),
+            post("b", 1654588300.0),
+            post("c", 1654584700.0),
+        ];
+        assert_eq!(
+            freshest_post_ids(posts.iter(), 2),
+            HashSet::from(["b".to_string(), "c".to_string()])
+        );
+        assert_eq!(freshest_post_ids(posts.iter(), 5).len(), 3);
+        assert!(freshest_post_ids(posts.iter(), 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_for_new_subscription_marks_posts_seen() {
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let posts = vec![make_post("v6nu75"), make_post("w7ov86")];
+        let client = MockRedditClient {
+            posts: Some(posts.clone()),
+        };
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &Subscription::test_default(),
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        let db = db::Database::open(&config).unwrap();
+        for post in &posts {
+            assert!(db.is_post_seen(1, post).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_for_empty_subscription_marks_initialized() {
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let sub = Subscription::test_default();
+        let db = db::Database::open(&config).unwrap();
+        db.subscribe(
+            sub.chat_id,
+            &SubscriptionArgs {
+                source_type: sub.source_type,
+                subreddit: sub.subreddit.clone(),
+                ..SubscriptionArgs::test_default()
+            },
+        )
+        .unwrap();
+        let client = MockRedditClient {
+            posts: Some(vec![]),
+        };
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &sub,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        assert!(db.get_all_subscriptions().unwrap()[0].initialized);
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_for_new_sort_fetches_posts_after_cursor() {
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let sub = Subscription {
+            sort: Some(reddit::SortMode::New),
+            last_seen_fullname: Some("t3_b".into()),
+            ..Subscription::test_default()
+        };
+        let db = db::Database::open(&config).unwrap();
+        db.subscribe(
+            sub.chat_id,
+            &SubscriptionArgs {
+                source_type: sub.source_type,
+                subreddit: sub.subreddit.clone(),
+                sort: sub.sort,
+                ..SubscriptionArgs::test_default()
+            },
+        )
+        .unwrap();
+        let post = |id: &str, created: f32| reddit::Post {
+            created,
+            ..make_post(id)
+        };
+        // Newest first, like Reddit's new listing
+        let posts = vec![
+            post("c", 1654588300.0),
+            post("b", 1654584700.0),
+            post("a", 1654581100.0),
+        ];
+        let client = MockRedditClient {
+            posts: Some(posts.clone()),
+        };
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &sub,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        assert!(db.is_post_seen(1, &posts[0]).unwrap());
+        assert!(!db.is_post_seen(1, &posts[1]).unwrap());
+        assert!(!db.is_post_seen(1, &posts[2]).unwrap());
+        assert_eq!(
+            db.get_all_subscriptions().unwrap()[0].last_seen_fullname,
+            Some("t3_c".to_string())
+        );
+
+        // A cursor that is no longer listed falls back to the whole listing
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let sub = Subscription {
+            last_seen_fullname: Some("t3_removed".into()),
+            ..sub
+        };
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &sub,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+        let db = db::Database::open(&config).unwrap();
+        for post in &posts {
+            assert!(db.is_post_seen(1, post).unwrap());
+        }
+
+        // Without newer posts than a cursor that is still listed, the whole listing isn't fetched
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let sub = Subscription {
+            last_seen_fullname: Some("t3_c".into()),
+            ..sub
+        };
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &sub,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+        let db = db::Database::open(&config).unwrap();
+        for post in &posts {
+            assert!(!db.is_post_seen(1, post).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_skipped_posts_are_not_pushed_to_webhooks() {
+        let server = wiremock::MockServer::start().await;
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = config::Config {
+            webhook_urls: vec![server.uri().into()],
+            ..make_config(&tmp_dir)
+        };
+        let deleted_post = reddit::Post {
+            author: "[deleted]".into(),
+            ..make_post("v6nu75")
+        };
+        let client = MockRedditClient {
+            posts: Some(vec![deleted_post.clone()]),
+        };
+        let sub = Subscription {
+            initialized: true,
+            ..Subscription::test_default()
+        };
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &sub,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        let db = db::Database::open(&config).unwrap();
+        assert!(db.is_post_seen(1, &deleted_post).unwrap());
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_for_subscription_with_failing_request() {
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let client = MockRedditClient { posts: None };
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        // Failing to get posts of one subscription shouldn't fail the whole check
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &Subscription::test_default(),
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        let db = db::Database::open(&config).unwrap();
+        assert_eq!(db.count_seen_posts(1).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_for_digest_subscription() {
+        let tmp_dir = TempDir::new("tgreddit").unwrap();
+        let config = make_config(&tmp_dir);
+        let seen_post = make_post("v6nu75");
+        let new_post = make_post("w7ov86");
+        db::Database::open(&config)
+            .unwrap()
+            .mark_post_seen(1, &seen_post)
+            .unwrap();
+        let client = MockRedditClient {
+            posts: Some(vec![seen_post, new_post]),
+        };
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+        let sub = Subscription {
+            digest: Some(DigestMode::Daily),
+            ..Subscription::test_default()
+        };
+
+        check_new_posts_for_subscription(
+            &config,
+            &client,
+            &Bot::new("token"),
+            &sub,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        let db = db::Database::open(&config).unwrap();
+        assert_eq!(db.get_digest_post_ids(1).unwrap(), vec!["w7ov86"]);
+    }
+}
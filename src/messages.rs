@@ -1,46 +1,494 @@
 use crate::reddit;
 use crate::*;
 use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+/// Telegram's limit for the length of a message
+const MESSAGE_MAX_CHARS: usize = 4096;
+/// Telegram's limit for the length of a media caption
+const CAPTION_MAX_CHARS: usize = 1024;
+
+/// Longest top comment shown below a post, so that it doesn't crowd out the post itself
+const TOP_COMMENT_MAX_CHARS: usize = 500;
+
+/// Descriptions of linked pages are cut to this so that the card stays short
+const LINK_DESCRIPTION_MAX_CHARS: usize = 300;
+/// Top comments with less room than this are left out rather than cut to a few words
+const TOP_COMMENT_MIN_CHARS: usize = 50;
+
+/// Caption layout used when `caption_template` is not configured
+pub const DEFAULT_CAPTION_TEMPLATE: &str = "{title}\n{meta}";
+const CAPTION_PLACEHOLDERS: &[&str] = &[
+    "title",
+    "subreddit",
+    "score",
+    "comments",
+    "url",
+    "permalink",
+    "meta",
+];
+
+lazy_static! {
+    static ref PLACEHOLDER_RE: Regex = Regex::new(r"\{(\w+)\}").unwrap();
+    static ref PARTIAL_ENTITY_RE: Regex = Regex::new(r"&[#\w]*$").unwrap();
+}
 
 fn escape(html: &str) -> String {
     html.replace('<', "&lt;").replace('>', "&gt;")
 }
 
+/// Characters that must be escaped everywhere in MarkdownV2 text
+const MARKDOWN_V2_RESERVED: &str = r"\_*[]()~`>#+-=|{}.!";
+
+/// Escapes text for MarkdownV2, where any of a number of punctuation characters would otherwise
+/// start an entity or be rejected by Telegram
+pub fn escape_markdown_v2(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_V2_RESERVED.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes the URL of a MarkdownV2 inline link, where only ) and \ are special
+fn escape_markdown_v2_url(url: &str) -> String {
+    url.replace('\\', "\\\\").replace(')', "\\)")
+}
+
+/// Reddit escapes &, < and > in titles, text and URLs as HTML entities, which Telegram's HTML
+/// understands but MarkdownV2 would show as is
+fn decode_reddit_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Escapes text from Reddit for the parse mode
+fn escape_text(text: &str, parse_mode: config::ParseMode) -> String {
+    match parse_mode {
+        config::ParseMode::Html => escape(text),
+        config::ParseMode::MarkdownV2 => escape_markdown_v2(&decode_reddit_entities(text)),
+    }
+}
+
+fn format_anchor(href: &str, text: &str, parse_mode: config::ParseMode) -> String {
+    match parse_mode {
+        config::ParseMode::Html => format_html_anchor(href, text),
+        config::ParseMode::MarkdownV2 => format!(
+            "[{}]({})",
+            escape_text(text, parse_mode),
+            escape_markdown_v2_url(&decode_reddit_entities(href))
+        ),
+    }
+}
+
+fn format_bold(text: &str, parse_mode: config::ParseMode) -> String {
+    match parse_mode {
+        config::ParseMode::Html => format!("<b>{}</b>", escape(text)),
+        config::ParseMode::MarkdownV2 => format!("*{}*", escape_text(text, parse_mode)),
+    }
+}
+
+/// The parts of a post message that don't depend on the platform it's sent to
+#[derive(Debug, PartialEq, Eq)]
+pub struct PostSummary {
+    pub title: String,
+    pub url: String,
+    pub permalink: String,
+    pub subreddit: String,
+    pub subreddit_url: String,
+    pub thumbnail_url: Option<String>,
+}
+
+impl PostSummary {
+    pub fn new(post: &reddit::Post, links_base_url: Option<&str>) -> Self {
+        PostSummary {
+            title: post.title.clone(),
+            url: post.url.clone(),
+            permalink: post.format_permalink_url(links_base_url),
+            subreddit: format!("/r/{}", post.subreddit),
+            subreddit_url: reddit::format_subreddit_url(&post.subreddit, links_base_url),
+            // Posts without a thumbnail have values like "self", "default" or "nsfw"
+            thumbnail_url: post
+                .thumbnail
+                .as_ref()
+                .filter(|thumbnail| thumbnail.starts_with("http"))
+                .cloned(),
+        }
+    }
+}
+
 fn format_html_anchor(href: &str, text: &str) -> String {
     format!(r#"<a href="{href}">{}</a>"#, escape(text))
 }
 
-fn format_subreddit_link(subreddit: &str, base_url: Option<&str>) -> String {
-    format_html_anchor(
+fn format_subreddit_link(
+    subreddit: &str,
+    base_url: Option<&str>,
+    parse_mode: config::ParseMode,
+) -> String {
+    format_anchor(
         &reddit::format_subreddit_url(subreddit, base_url),
         &format!("/r/{}", &subreddit),
+        parse_mode,
     )
 }
 
-fn format_meta_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let subreddit_link = format_subreddit_link(&post.subreddit, links_base_url);
-    let comments_link = format_html_anchor(&post.format_permalink_url(links_base_url), "comments");
+/// Formats a count compactly for display, e.g. 1500 => 1.5k
+fn format_count(n: u32) -> String {
+    fn with_suffix(n: f64, suffix: &str) -> String {
+        let s = format!("{n:.1}");
+        format!("{}{suffix}", s.strip_suffix(".0").unwrap_or(&s))
+    }
+
+    match n {
+        0..=999 => n.to_string(),
+        // Values that would round up to 1000.0k are shown in millions instead
+        1_000..=999_949 => with_suffix(n as f64 / 1_000.0, "k"),
+        _ => with_suffix(n as f64 / 1_000_000.0, "m"),
+    }
+}
+
+fn format_score(post: &reddit::Post) -> String {
+    format!(
+        "👍 {} · 💬 {}",
+        format_count(post.ups),
+        format_count(post.num_comments)
+    )
+}
+
+fn format_meta(post: &reddit::Post, config: &config::Config) -> String {
+    let links_base_url = config.links_base_url.as_deref();
+    let parse_mode = config.parse_mode;
+    let subreddit_link = format_subreddit_link(&post.subreddit, links_base_url, parse_mode);
+    let comments_link = || {
+        format_anchor(
+            &post.format_permalink_url(links_base_url),
+            "comments",
+            parse_mode,
+        )
+    };
+    let old_comments_link = || format_anchor(&post.format_old_permalink_url(), "old", parse_mode);
+
+    let links = match config.resolve_comments_link_style() {
+        config::CommentsLinkStyle::New => vec![comments_link()],
+        config::CommentsLinkStyle::Old => vec![old_comments_link()],
+        config::CommentsLinkStyle::Both => vec![comments_link(), old_comments_link()],
+        config::CommentsLinkStyle::None => vec![],
+    };
+    let meta = if links.is_empty() {
+        subreddit_link
+    } else {
+        format!(
+            "{subreddit_link} {}{}{}",
+            escape_text("[", parse_mode),
+            links.join(", "),
+            escape_text("]", parse_mode)
+        )
+    };
 
-    // If using custom links base url, the old reddit link doesn't make sense.
-    match links_base_url {
-        Some(_) => format!("{subreddit_link} [{comments_link}]"),
-        None => {
-            let old_comments_link = format_html_anchor(&post.format_old_permalink_url(), "old");
-            format!("{subreddit_link} [{comments_link}, {old_comments_link}]")
+    if config.show_score {
+        format!("{meta}\n{}", escape_text(&format_score(post), parse_mode))
+    } else {
+        meta
+    }
+}
+
+fn format_post_type_label(post: &reddit::Post, config: &config::Config) -> String {
+    match config
+        .post_type_labels
+        .get(post.post_type)
+        .map(String::as_str)
+    {
+        Some(label) => format!("{} ", escape_text(label, config.parse_mode)),
+        None => "".to_string(),
+    }
+}
+
+/// Checks that a caption template only uses known placeholders.
+pub fn validate_caption_template(template: &str) -> Result<(), String> {
+    let unknown = PLACEHOLDER_RE
+        .captures_iter(template)
+        .filter(|caps| !CAPTION_PLACEHOLDERS.contains(&&caps[1]))
+        .map(|caps| caps[0].to_string())
+        .collect::<Vec<_>>();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "unknown placeholder(s) in caption_template: {}; supported are {}",
+            unknown.join(", "),
+            CAPTION_PLACEHOLDERS
+                .iter()
+                .map(|p| format!("{{{p}}}"))
+                .join(", ")
+        ))
+    }
+}
+
+/// Expands the configured caption template for a post. `title` is the already formatted title,
+/// which differs between media captions and link messages.
+fn format_caption_template(post: &reddit::Post, config: &config::Config, title: &str) -> String {
+    let links_base_url = config.links_base_url.as_deref();
+    let parse_mode = config.parse_mode;
+    let template = config
+        .caption_template
+        .as_deref()
+        .unwrap_or(DEFAULT_CAPTION_TEMPLATE);
+    let label = format_post_type_label(post, config);
+    let caption = PLACEHOLDER_RE.replace_all(template, |caps: &Captures| match &caps[1] {
+        "title" => title.to_string(),
+        "subreddit" => format_subreddit_link(&post.subreddit, links_base_url, parse_mode),
+        "score" => escape_text(&format_count(post.ups), parse_mode),
+        "comments" => escape_text(&format_count(post.num_comments), parse_mode),
+        "url" => escape_text(&post.url, parse_mode),
+        "permalink" => escape_text(&post.format_permalink_url(links_base_url), parse_mode),
+        "meta" => format_meta(post, config),
+        // Rejected when config is read, but leave as is rather than panic
+        _ => caps[0].to_string(),
+    });
+    format!("{label}{caption}")
+}
+
+/// Applies the configured title transforms in order.
+fn transform_title(title: &str, transforms: &[config::TitleTransform]) -> String {
+    transforms
+        .iter()
+        .fold(title.to_string(), |title, transform| {
+            transform
+                .pattern
+                .replace_all(&title, transform.replacement.as_str())
+                .into_owned()
+        })
+        .trim()
+        .to_string()
+}
+
+/// Formats the top comment of a post with its body truncated to fit in max_chars. None if the
+/// post has no top comment or there's too little room for it.
+fn format_top_comment(
+    post: &reddit::Post,
+    config: &config::Config,
+    max_chars: usize,
+) -> Option<String> {
+    let comment = post.top_comment.as_ref()?;
+    let parse_mode = config.parse_mode;
+    let header = format!(
+        "💬 {}: ",
+        format_bold(&format!("u/{}", comment.author), parse_mode)
+    );
+    let max_body_chars = max_chars
+        .min(TOP_COMMENT_MAX_CHARS)
+        .saturating_sub(header.chars().count());
+    if max_body_chars < TOP_COMMENT_MIN_CHARS {
+        return None;
+    }
+
+    let permalink =
+        reddit::format_url_from_path(&comment.permalink, config.links_base_url.as_deref());
+    let body = truncate_self_text(comment.body.trim(), max_body_chars, &permalink, parse_mode);
+    Some(format!("{header}{body}"))
+}
+
+/// Appends the top comment of a post to a formatted message if it fits in max_chars
+fn append_top_comment(
+    message: String,
+    post: &reddit::Post,
+    config: &config::Config,
+    max_chars: usize,
+) -> String {
+    let max_comment_chars = max_chars.saturating_sub(message.chars().count() + 2);
+    match format_top_comment(post, config, max_comment_chars) {
+        Some(top_comment) => format!("{message}\n\n{top_comment}"),
+        None => message,
+    }
+}
+
+pub fn format_media_caption(post: &reddit::Post, config: &config::Config) -> String {
+    let title = transform_title(&post.title, &config.title_transforms);
+    let title = escape_text(&title, config.parse_mode);
+    let caption = format_caption_template(post, config, &title);
+    append_top_comment(caption, post, config, CAPTION_MAX_CHARS)
+}
+
+pub fn format_link_message(post: &reddit::Post, config: &config::Config) -> String {
+    let title = transform_title(&post.title, &config.title_transforms);
+    let title = format_anchor(&post.url, &title, config.parse_mode);
+    let message = format_caption_template(post, config, &title);
+    append_top_comment(message, post, config, MESSAGE_MAX_CHARS)
+}
+
+/// Formats a link post with the OpenGraph title and description of the linked page, which are
+/// truncated to fit in max_chars along with the rest of the message
+fn format_link_card_text(
+    post: &reddit::Post,
+    config: &config::Config,
+    og: &opengraph::OgMetadata,
+    max_chars: usize,
+) -> String {
+    let parse_mode = config.parse_mode;
+    let title = transform_title(&post.title, &config.title_transforms);
+    let title = format_anchor(&post.url, &title, parse_mode);
+    let message = format_caption_template(post, config, &title);
+    let og_title = format_bold(&og.title, parse_mode);
+    let mut card = format!("{message}\n\n{og_title}");
+    if let Some(description) = &og.description {
+        let room = max_chars
+            .saturating_sub(card.chars().count() + 1)
+            .min(LINK_DESCRIPTION_MAX_CHARS);
+        let description = if description.chars().count() > room {
+            let truncated = description
+                .chars()
+                .take(room.saturating_sub(1))
+                .collect::<String>();
+            format!("{}…", truncated.trim_end())
+        } else {
+            description.clone()
+        };
+        if !description.is_empty() {
+            card = format!("{card}\n{}", escape_text(&description, parse_mode));
         }
     }
+    append_top_comment(card, post, config, max_chars)
+}
+
+pub fn format_link_card_message(
+    post: &reddit::Post,
+    config: &config::Config,
+    og: &opengraph::OgMetadata,
+) -> String {
+    format_link_card_text(post, config, og, MESSAGE_MAX_CHARS)
+}
+
+pub fn format_link_card_caption(
+    post: &reddit::Post,
+    config: &config::Config,
+    og: &opengraph::OgMetadata,
+) -> String {
+    format_link_card_text(post, config, og, CAPTION_MAX_CHARS)
+}
+
+/// Formats a self post with its body and top comment, which are truncated to fit in max_chars
+/// along with the rest of the message
+fn format_self_text(post: &reddit::Post, config: &config::Config, max_chars: usize) -> String {
+    let links_base_url = config.links_base_url.as_deref();
+    let title = transform_title(&post.title, &config.title_transforms);
+    let title = escape_text(&title, config.parse_mode);
+    let without_body = format_caption_template(post, config, &title);
+    // The length of the markup overestimates the length of the visible text Telegram counts,
+    // which leaves some slack
+    let room = max_chars.saturating_sub(without_body.chars().count() + 2);
+    // The top comment may take at most half of the room so that the body is not cut short
+    let top_comment = format_top_comment(post, config, room / 2);
+    let selftext = post.selftext.trim();
+    let message = if selftext.is_empty() {
+        without_body
+    } else {
+        let max_body_chars = room.saturating_sub(
+            top_comment
+                .as_ref()
+                .map_or(0, |top_comment| top_comment.chars().count() + 2),
+        );
+        let permalink = post.format_permalink_url(links_base_url);
+        let body = truncate_self_text(selftext, max_body_chars, &permalink, config.parse_mode);
+        format_caption_template(post, config, &format!("{title}\n\n{body}"))
+    };
+
+    match top_comment {
+        Some(top_comment) => format!("{message}\n\n{top_comment}"),
+        None => message,
+    }
+}
+
+/// Truncates the body of a self post to max_chars, ending with a link to the post when truncated
+fn truncate_self_text(
+    selftext: &str,
+    max_chars: usize,
+    permalink: &str,
+    parse_mode: config::ParseMode,
+) -> String {
+    if selftext.chars().count() <= max_chars {
+        return escape_text(selftext, parse_mode);
+    }
+
+    let read_more = "… read more";
+    let truncated = selftext
+        .chars()
+        .take(max_chars.saturating_sub(read_more.chars().count()))
+        .collect::<String>();
+    // Reddit escapes &, < and > as entities, which must not be cut in half
+    let truncated = PARTIAL_ENTITY_RE.replace(&truncated, "");
+    format!(
+        "{}… {}",
+        escape_text(truncated.trim_end(), parse_mode),
+        format_anchor(permalink, "read more", parse_mode)
+    )
+}
+
+pub fn format_self_message(post: &reddit::Post, config: &config::Config) -> String {
+    format_self_text(post, config, MESSAGE_MAX_CHARS)
 }
 
-pub fn format_media_caption_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let title = &post.title;
-    let meta = format_meta_html(post, links_base_url);
-    format!("{title}\n{meta}")
+/// Self post formatted to fit in a caption, for self posts sent along with their images
+pub fn format_self_caption(post: &reddit::Post, config: &config::Config) -> String {
+    format_self_text(post, config, CAPTION_MAX_CHARS)
 }
 
-pub fn format_link_message_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let title = format_html_anchor(&post.url, &post.title);
-    let meta = format_meta_html(post, links_base_url);
-    format!("{title}\n{meta}")
+/// Formats posts as a numbered list of links with scores, for previewing a listing.
+pub fn format_post_list(posts: &[reddit::Post], links_base_url: Option<&str>) -> String {
+    if posts.is_empty() {
+        return "No posts found".to_owned();
+    }
+
+    posts
+        .iter()
+        .enumerate()
+        .map(|(i, post)| {
+            let title = format_html_anchor(&post.url, &post.title);
+            let comments_link =
+                format_html_anchor(&post.format_permalink_url(links_base_url), "comments");
+            format!(
+                "{}. {title}\n{} [{comments_link}]",
+                i + 1,
+                format_score(post)
+            )
+        })
+        .join("\n\n")
+}
+
+/// Summary of the posts a subscription's check would currently find, for /testsub. `matching` are
+/// the posts that pass the filter along with whether they have been seen. Sent as plain text.
+pub fn format_subscription_test(
+    name: &str,
+    limit: u32,
+    time: reddit::TopPostsTimePeriod,
+    filter: Option<reddit::PostType>,
+    fetched: usize,
+    matching: &[(&reddit::Post, bool)],
+) -> String {
+    let filter = filter.map_or("any".to_string(), |filter| filter.to_string());
+    let new = matching.iter().filter(|(_, seen)| !seen).count();
+    let mut lines = vec![
+        format!("{name}: limit={limit}, time={time}, filter={filter}"),
+        format!(
+            "Fetched {fetched} post(s), {} match the filter, {new} not seen yet",
+            matching.len()
+        ),
+    ];
+    if !matching.is_empty() {
+        lines.push("".to_string());
+    }
+    lines.extend(matching.iter().enumerate().map(|(i, (post, seen))| {
+        let status = if *seen { "seen" } else { "new" };
+        format!("{}. [{status}] {}", i + 1, post.title)
+    }));
+
+    lines.join("\n").chars().take(MESSAGE_MAX_CHARS).collect()
 }
 
 pub fn format_subscription_list(post: &[Subscription]) -> String {
@@ -49,12 +497,41 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
         if let Some(time) = sub.time {
             args.push(format!("time={}", time));
         }
-        if let Some(limit) = sub.limit {
-            args.push(format!("limit={}", limit));
+        if let Some(sort) = sub.sort {
+            args.push(format!("sort={}", sort));
+        }
+        match sub.limit {
+            Some(config::LIMIT_MAX) => args.push("limit=max".to_string()),
+            Some(limit) => args.push(format!("limit={}", limit)),
+            None => {}
         }
         if let Some(filter) = sub.filter {
             args.push(format!("filter={}", filter));
         }
+        if let Some(max_media_mb) = sub.max_media_mb {
+            args.push(format!("max_media_mb={}", max_media_mb));
+        }
+        if let Some(ytdlp_format) = &sub.ytdlp_format {
+            args.push(format!("format={}", ytdlp_format));
+        }
+        if let Some(group_images) = sub.group_images {
+            args.push(format!("group_images={}", group_images));
+        }
+        if let Some(include_top_comment) = sub.include_top_comment {
+            args.push(format!("include_top_comment={}", include_top_comment));
+        }
+        if let Some(max_age_hours) = sub.max_age_hours {
+            args.push(format!("max_age_hours={}", max_age_hours));
+        }
+        if let Some(digest) = sub.digest {
+            args.push(format!("digest={}", digest));
+        }
+        if let Some(keyword) = &sub.keyword {
+            args.push(format!("keyword={}", keyword));
+        }
+        if let Some(webhook) = &sub.webhook {
+            args.push(format!("webhook={}", webhook));
+        }
 
         let args_str = if !args.is_empty() {
             format!("({})", args.join(", "))
@@ -62,10 +539,18 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
             "".to_string()
         };
 
-        [sub.subreddit.to_owned(), args_str]
-            .join(" ")
-            .trim_end()
-            .to_string()
+        let name = match sub.source_type {
+            SourceType::Subreddit => sub.subreddit.to_owned(),
+            SourceType::Comments => format!("{} comments", sub.subreddit),
+            source_type => format!("{}{}", source_type.prefix(), sub.subreddit),
+        };
+
+        let paused = if sub.paused { ", paused" } else { "" };
+        format!(
+            "{}, since {}{paused}",
+            [name, args_str].join(" ").trim_end(),
+            sub.created_at.format("%Y-%m-%d")
+        )
     }
 
     if post.is_empty() {
@@ -75,10 +560,332 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
     }
 }
 
+/// Notice that a subscription was paused because its source can no longer be accessed
+pub fn format_subscription_paused(
+    sub: &Subscription,
+    reason: reddit::InaccessibleReason,
+) -> String {
+    let reason = match reason {
+        reddit::InaccessibleReason::Private => "it's private",
+        reddit::InaccessibleReason::BannedOrMissing => "it's banned or no longer exists",
+    };
+    format!(
+        "{}{} is no longer accessible as {reason}, so the subscription is paused. Unsubscribe and \
+         subscribe again to resume it.",
+        sub.source_type.prefix(),
+        sub.subreddit
+    )
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{days}d {hours}h {mins}m")
+    } else if hours > 0 {
+        format!("{hours}h {mins}m")
+    } else {
+        format!("{mins}m")
+    }
+}
+
+pub fn format_admin_stats(
+    stats: &DatabaseStats,
+    db_size: Option<u64>,
+    uptime: std::time::Duration,
+) -> String {
+    let db_size = db_size
+        .map(format_bytes)
+        .unwrap_or_else(|| "unknown".to_owned());
+    [
+        format!("Subscriptions: {}", stats.subscriptions),
+        format!("Chats: {}", stats.chats),
+        format!("Seen posts: {}", stats.seen_posts),
+        format!("Database size: {db_size}"),
+        format!("Uptime: {}", format_duration(uptime)),
+    ]
+    .join("\n")
+}
+
+/// Versions of tgreddit and yt-dlp and the relevant configuration, to be pasted in bug reports
+pub fn format_version(
+    ytdlp_version: Option<&str>,
+    db_path: &std::path::Path,
+    db_size: Option<u64>,
+    reddit_auth: bool,
+) -> String {
+    let db_size = db_size
+        .map(format_bytes)
+        .unwrap_or_else(|| "unknown".to_owned());
+    [
+        format!("tgreddit: {}", env!("CARGO_PKG_VERSION")),
+        format!("yt-dlp: {}", ytdlp_version.unwrap_or("not found")),
+        format!("Database: {} ({db_size})", db_path.display()),
+        format!(
+            "Reddit auth: {}",
+            if reddit_auth {
+                "configured"
+            } else {
+                "anonymous"
+            }
+        ),
+    ]
+    .join("\n")
+}
+
+/// Inline keyboard with buttons for the comments, the link of the post and the subreddit. Buttons
+/// with urls Telegram wouldn't accept are left out.
+pub fn build_post_keyboard(
+    post: &reddit::Post,
+    base_url: Option<&str>,
+) -> teloxide::types::InlineKeyboardMarkup {
+    let buttons = [
+        ("Comments", post.format_permalink_url(base_url)),
+        ("Open", post.url.clone()),
+        (
+            "Subreddit",
+            reddit::format_subreddit_url(&post.subreddit, base_url),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(text, url)| {
+        url::Url::parse(&url)
+            .ok()
+            .map(|url| teloxide::types::InlineKeyboardButton::url(text, url))
+    })
+    .collect::<Vec<_>>();
+    teloxide::types::InlineKeyboardMarkup::new([buttons])
+}
+
+pub fn format_chat_stats(stats: &ChatStats) -> String {
+    let most_active_subreddit = stats
+        .most_active_subreddit
+        .as_ref()
+        .map(|(subreddit, count)| format!("r/{subreddit} ({count} posts)"))
+        .unwrap_or_else(|| "none".to_owned());
+    [
+        format!("Subscriptions: {}", stats.subscriptions),
+        format!("Posts delivered: {}", stats.seen_posts),
+        format!(
+            "Posts delivered in the last 24h: {}",
+            stats.seen_posts_last_day
+        ),
+        format!("Most active subreddit: {most_active_subreddit}"),
+    ]
+    .join("\n")
+}
+
+/// Formats posts collected for a daily digest as a list of titles, scores and links. Media is
+/// linked rather than sent, to keep the digest compact. Split into several messages if the list
+/// doesn't fit in one.
+pub fn format_digest(posts: &[reddit::Post], config: &config::Config) -> Vec<String> {
+    let base_url = config.links_base_url.as_deref();
+    let parse_mode = config.parse_mode;
+    let items = posts.iter().map(|post| {
+        let title = transform_title(&post.title, &config.title_transforms);
+        format!(
+            "{}\n{} · {} · {}",
+            format_anchor(&post.url, &title, parse_mode),
+            format_subreddit_link(&post.subreddit, base_url, parse_mode),
+            escape_text(&format_score(post), parse_mode),
+            format_anchor(&post.format_permalink_url(base_url), "comments", parse_mode),
+        )
+    });
+
+    let mut messages = vec![format!(
+        "{}, {} posts",
+        format_bold("Daily digest", parse_mode),
+        posts.len()
+    )];
+    for item in items {
+        let message = messages.last_mut().unwrap();
+        if message.chars().count() + item.chars().count() + 2 > MESSAGE_MAX_CHARS {
+            messages.push(item);
+        } else {
+            message.push_str("\n\n");
+            message.push_str(&item);
+        }
+    }
+    messages
+}
+
+pub fn format_startup_message(version: &str, subscriptions: u64) -> String {
+    format!("tgreddit started (version {version})\nActive subscriptions: {subscriptions}")
+}
+
+pub fn format_feedback(user: &teloxide::types::User, chat_id: i64, text: &str) -> String {
+    let username = user
+        .username
+        .as_ref()
+        .map(|username| format!(" (@{username})"))
+        .unwrap_or_default();
+    format!(
+        "Feedback from {}{username}, user id {}, chat id {chat_id}:\n\n{text}",
+        user.full_name(),
+        user.id.0
+    )
+}
+
+pub fn format_comment(comment: &reddit::Comment, config: &config::Config) -> String {
+    let links_base_url = config.links_base_url.as_deref();
+    let parse_mode = config.parse_mode;
+    let permalink = reddit::format_url_from_path(&comment.permalink, links_base_url);
+    let score = match u32::try_from(comment.score) {
+        Ok(score) => format_count(score),
+        Err(_) => comment.score.to_string(),
+    };
+    let header = format!(
+        "{} in {} · 👍 {}",
+        format_bold(&format!("u/{}", comment.author), parse_mode),
+        format_subreddit_link(&comment.subreddit, links_base_url, parse_mode),
+        escape_text(&score, parse_mode)
+    );
+    let footer = format_anchor(&permalink, "comment", parse_mode);
+    let read_more = format_anchor(&permalink, "read more", parse_mode);
+    // The length of the markup overestimates the length of the visible text, which leaves some
+    // slack. Room is also left for the markup of the read more link of a truncated body.
+    let max_body_chars = MESSAGE_MAX_CHARS.saturating_sub(
+        header.chars().count() + footer.chars().count() + read_more.chars().count() + 4,
+    );
+    let body = truncate_self_text(comment.body.trim(), max_body_chars, &permalink, parse_mode);
+    format!("{header}\n\n{body}\n\n{footer}")
+}
+
+/// Summary of how a post was deserialized and classified, for /debug. Sent as plain text.
+pub fn format_post_debug(post: &reddit::Post) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    let message = [
+        format!("Post {} in r/{}", post.id, post.subreddit),
+        post.title.clone(),
+        "".to_string(),
+        format!("post_type: {:?}", post.post_type),
+        format!("post_hint: {}", optional(post.post_hint.clone())),
+        format!("is_video: {}", post.is_video),
+        format!(
+            "is_gallery: {}",
+            optional(post.is_gallery.map(|g| g.to_string()))
+        ),
+        format!("is_self: {}", post.is_self),
+        format!("is_crosspost: {}", post.crosspost_parent_list.is_some()),
+        format!("url: {}", post.url),
+    ]
+    .join("\n");
+    message.chars().take(MESSAGE_MAX_CHARS).collect()
+}
+
+/// The reqwest error behind a failed request, if any. The about errors wrap it transparently, so
+/// it doesn't appear in the error chain by itself.
+fn find_reqwest_error(err: &anyhow::Error) -> Option<&reqwest::Error> {
+    err.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return Some(err);
+        }
+        match cause.downcast_ref::<reddit::SubredditAboutError>() {
+            Some(reddit::SubredditAboutError::Reqwest(err)) => return Some(err),
+            Some(_) => return None,
+            None => {}
+        }
+        match cause.downcast_ref::<reddit::UserAboutError>() {
+            Some(reddit::UserAboutError::Reqwest(err)) => return Some(err),
+            Some(_) => return None,
+            None => {}
+        }
+        match cause.downcast_ref::<reddit::MultiredditAboutError>() {
+            Some(reddit::MultiredditAboutError::Reqwest(err)) => Some(err),
+            _ => None,
+        }
+    })
+}
+
+/// Reply to a command that failed, explaining the cause when it's something the user can act on.
+/// The full error is logged separately.
+pub fn format_error_reply(err: &anyhow::Error) -> String {
+    if err
+        .chain()
+        .any(|cause| cause.is::<ytdlp::NotInstalledError>())
+    {
+        return "yt-dlp is not installed, so videos can't be downloaded".to_string();
+    }
+
+    match find_reqwest_error(err) {
+        Some(err) if err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) => {
+            "Reddit is rate limiting requests, try again in a few minutes".to_string()
+        }
+        Some(err) if err.status() == Some(reqwest::StatusCode::FORBIDDEN) => {
+            "Reddit denied access, the subreddit may be private or banned".to_string()
+        }
+        Some(err) if err.status().is_some_and(|status| status.is_server_error()) => {
+            "Reddit is having problems, try again later".to_string()
+        }
+        Some(err) if err.is_timeout() || err.is_connect() => {
+            "Could not reach Reddit, try again later".to_string()
+        }
+        _ => "Something went wrong".to_string(),
+    }
+}
+
+/// Short reason for a post that could not be sent, for the summary of /get
+pub fn format_send_failure_reason(err: &anyhow::Error) -> String {
+    for cause in err.chain() {
+        if cause.is::<download::TooLargeError>() {
+            return "media too large".to_string();
+        }
+        if cause.is::<ytdlp::TimeoutError>() {
+            return "video download timed out".to_string();
+        }
+        if let Some(teloxide::RequestError::Api(api_err)) =
+            cause.downcast_ref::<teloxide::RequestError>()
+        {
+            return format!("Telegram: {api_err}");
+        }
+        if cause.is::<teloxide::RequestError>() {
+            return "could not reach Telegram".to_string();
+        }
+    }
+
+    match find_reqwest_error(err) {
+        Some(_) => "download failed".to_string(),
+        None => "unexpected error".to_string(),
+    }
+}
+
+/// Summary of a /get where some posts failed, e.g. "Sent 3/5, failed 2 (media too large)". Each
+/// distinct reason is listed once.
+pub fn format_get_summary(total: usize, failure_reasons: &[String]) -> String {
+    let failed = failure_reasons.len();
+    format!(
+        "Sent {}/{total}, failed {failed} ({})",
+        total - failed,
+        failure_reasons.iter().unique().join(", ")
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn test_post() -> reddit::Post {
+        reddit::Post {
+            ups: 1234,
+            ..reddit::Post::test_default()
+        }
+    }
+
     #[test]
     fn test_format_html_anchor() {
         assert_eq!(
@@ -94,19 +901,681 @@ mod tests {
                 Subscription {
                     chat_id: 1,
                     subreddit: "foo".to_owned(),
-                    limit: None,
-                    time: None,
-                    filter: None,
+                    created_at: chrono::TimeZone::timestamp_opt(&chrono::Utc, 1654581100, 0)
+                        .unwrap(),
+                    ..Subscription::test_default()
                 },
                 Subscription {
                     chat_id: 1,
                     subreddit: "bar".to_owned(),
                     limit: Some(1),
                     time: Some(TopPostsTimePeriod::Week),
-                    filter: None,
+                    created_at: chrono::TimeZone::timestamp_opt(&chrono::Utc, 1654581100, 0)
+                        .unwrap(),
+                    ..Subscription::test_default()
                 },
             ]),
-            "foo\nbar (time=week, limit=1)"
+            "foo, since 2022-06-07\nbar (time=week, limit=1), since 2022-06-07"
         )
     }
+
+    #[test]
+    fn test_format_post_type_label() {
+        let config = config::Config {
+            post_type_labels: config::PerPostType {
+                video: Some("🎥".to_string()),
+                gallery: Some("🖼".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut post = test_post();
+        assert_eq!(format_post_type_label(&post, &config), "🎥 ");
+        post.post_type = PostType::Gallery;
+        assert_eq!(format_post_type_label(&post, &config), "🖼 ");
+        post.post_type = PostType::Link;
+        assert_eq!(format_post_type_label(&post, &config), "");
+        assert_eq!(
+            format_post_type_label(&post, &config::Config::default()),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_format_admin_stats() {
+        let stats = DatabaseStats {
+            subscriptions: 3,
+            chats: 2,
+            seen_posts: 120,
+        };
+        assert_eq!(
+            format_admin_stats(
+                &stats,
+                Some(1536 * 1024),
+                std::time::Duration::from_secs(90061)
+            ),
+            "Subscriptions: 3\nChats: 2\nSeen posts: 120\nDatabase size: 1.5 MiB\nUptime: 1d 1h 1m"
+        );
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_duration(std::time::Duration::from_secs(59)), "0m");
+    }
+
+    #[test]
+    fn test_format_version() {
+        assert_eq!(
+            format_version(
+                Some("2023.03.04"),
+                std::path::Path::new("/data/data.db3"),
+                Some(2048),
+                true
+            ),
+            format!(
+                "tgreddit: {}\nyt-dlp: 2023.03.04\nDatabase: /data/data.db3 (2.0 KiB)\nReddit auth: configured",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert!(
+            format_version(None, std::path::Path::new("data.db3"), None, false).ends_with(
+                "yt-dlp: not found\nDatabase: data.db3 (unknown)\nReddit auth: anonymous"
+            )
+        );
+    }
+
+    #[test]
+    fn test_build_post_keyboard() {
+        let post = test_post();
+        let keyboard = build_post_keyboard(&post, Some("https://teddit.net"));
+        let buttons = keyboard.inline_keyboard.concat();
+        let buttons = buttons
+            .iter()
+            .map(|button| match &button.kind {
+                teloxide::types::InlineKeyboardButtonKind::Url(url) => {
+                    (button.text.as_str(), url.as_str())
+                }
+                kind => panic!("unexpected button kind {kind:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buttons,
+            vec![
+                (
+                    "Comments",
+                    "https://teddit.net/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/"
+                ),
+                ("Open", "https://i.imgur.com/Zt6f5mB.gifv"),
+                ("Subreddit", "https://teddit.net/r/absoluteunit"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_chat_stats() {
+        let stats = ChatStats {
+            subscriptions: 2,
+            seen_posts: 40,
+            seen_posts_last_day: 3,
+            most_active_subreddit: Some(("pics".to_string(), 25)),
+        };
+        assert_eq!(
+            format_chat_stats(&stats),
+            "Subscriptions: 2\nPosts delivered: 40\nPosts delivered in the last 24h: 3\nMost active subreddit: r/pics (25 posts)"
+        );
+    }
+
+    #[test]
+    fn test_format_digest() {
+        let config = config::Config::default();
+        let posts = vec![
+            test_post(),
+            reddit::Post {
+                id: "w7ov86".into(),
+                title: "A <small> cow".into(),
+                ups: 10,
+                num_comments: 1,
+                permalink: "/r/absoluteunit/comments/w7ov86/a_small_cow/".into(),
+                url: "https://i.redd.it/abcdef.jpg".into(),
+                ..test_post()
+            },
+        ];
+
+        assert_eq!(
+            format_digest(&posts, &config),
+            vec![[
+                "<b>Daily digest</b>, 2 posts",
+                "",
+                r#"<a href="https://i.imgur.com/Zt6f5mB.gifv">Tipping a cow to trim its hooves</a>"#,
+                r#"<a href="https://www.reddit.com/r/absoluteunit">/r/absoluteunit</a> · 👍 1.2k · 💬 20 · <a href="https://www.reddit.com/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">comments</a>"#,
+                "",
+                r#"<a href="https://i.redd.it/abcdef.jpg">A &lt;small&gt; cow</a>"#,
+                r#"<a href="https://www.reddit.com/r/absoluteunit">/r/absoluteunit</a> · 👍 10 · 💬 1 · <a href="https://www.reddit.com/r/absoluteunit/comments/w7ov86/a_small_cow/">comments</a>"#,
+            ]
+            .join("\n")]
+        );
+    }
+
+    #[test]
+    fn test_format_digest_split_into_messages() {
+        let config = config::Config::default();
+        let posts = vec![test_post(); 50];
+
+        let messages = format_digest(&posts, &config);
+        assert!(messages.len() > 1);
+        assert!(messages
+            .iter()
+            .all(|message| message.chars().count() <= MESSAGE_MAX_CHARS));
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.matches(">comments</a>").count())
+                .sum::<usize>(),
+            50
+        );
+    }
+
+    #[test]
+    fn test_format_comment() {
+        let comment = reddit::Comment {
+            id: "def456".into(),
+            author: "someone".into(),
+            body: "Great &amp; <b>useful</b>".into(),
+            score: 1500,
+            subreddit: "rust".into(),
+            permalink: "/r/rust/comments/abc123/a_post/def456/".into(),
+            stickied: false,
+            created: 1654581100.0,
+        };
+        let config = config::Config::default();
+        assert_eq!(
+            format_comment(&comment, &config),
+            [
+                r#"<b>u/someone</b> in <a href="https://www.reddit.com/r/rust">/r/rust</a> · 👍 1.5k"#,
+                "",
+                "Great &amp; &lt;b&gt;useful&lt;/b&gt;",
+                "",
+                r#"<a href="https://www.reddit.com/r/rust/comments/abc123/a_post/def456/">comment</a>"#,
+            ]
+            .join("\n")
+        );
+
+        let comment = reddit::Comment {
+            body: "a".repeat(5000),
+            score: -3,
+            ..comment
+        };
+        let message = format_comment(&comment, &config);
+        assert!(message.contains("👍 -3"));
+        assert!(message.contains("read more"));
+        assert!(message.chars().count() <= MESSAGE_MAX_CHARS);
+    }
+
+    #[test]
+    fn test_format_comment_markdown_v2() {
+        let comment = reddit::Comment {
+            id: "def456".into(),
+            author: "some_one".into(),
+            body: "Great &amp; useful!".into(),
+            score: -3,
+            subreddit: "rust".into(),
+            permalink: "/r/rust/comments/abc123/a_post/def456/".into(),
+            stickied: false,
+            created: 1654581100.0,
+        };
+        let config = config::Config {
+            parse_mode: config::ParseMode::MarkdownV2,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_comment(&comment, &config),
+            [
+                r"*u/some\_one* in [/r/rust](https://www.reddit.com/r/rust) · 👍 \-3",
+                "",
+                r"Great & useful\!",
+                "",
+                "[comment](https://www.reddit.com/r/rust/comments/abc123/a_post/def456/)",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_format_post_debug() {
+        assert_eq!(
+            format_post_debug(&test_post()),
+            [
+                "Post v6nu75 in r/absoluteunit",
+                "Tipping a cow to trim its hooves",
+                "",
+                "post_type: Video",
+                "post_hint: link",
+                "is_video: false",
+                "is_gallery: false",
+                "is_self: false",
+                "is_crosspost: false",
+                "url: https://i.imgur.com/Zt6f5mB.gifv",
+            ]
+            .join("\n")
+        );
+
+        let post = reddit::Post {
+            title: "a".repeat(5000),
+            ..test_post()
+        };
+        assert_eq!(format_post_debug(&post).chars().count(), MESSAGE_MAX_CHARS);
+    }
+
+    #[test]
+    fn test_format_subscription_test() {
+        let seen = test_post();
+        let new = reddit::Post {
+            title: "A new post".into(),
+            ..test_post()
+        };
+        assert_eq!(
+            format_subscription_test(
+                "r/absoluteunit",
+                10,
+                reddit::TopPostsTimePeriod::Day,
+                Some(PostType::Video),
+                10,
+                &[(&seen, true), (&new, false)],
+            ),
+            [
+                "r/absoluteunit: limit=10, time=day, filter=video",
+                "Fetched 10 post(s), 2 match the filter, 1 not seen yet",
+                "",
+                "1. [seen] Tipping a cow to trim its hooves",
+                "2. [new] A new post",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            format_subscription_test(
+                "r/absoluteunit",
+                10,
+                reddit::TopPostsTimePeriod::Day,
+                None,
+                0,
+                &[]
+            ),
+            "r/absoluteunit: limit=10, time=day, filter=any\nFetched 0 post(s), 0 match the filter, 0 not seen yet"
+        );
+    }
+
+    #[test]
+    fn test_format_startup_message() {
+        assert_eq!(
+            format_startup_message("0.1.4", 3),
+            "tgreddit started (version 0.1.4)\nActive subscriptions: 3"
+        );
+    }
+
+    #[test]
+    fn test_format_self_message_html() {
+        let config = config::Config::default();
+        let post = reddit::Post {
+            post_type: reddit::PostType::SelfText,
+            selftext: "Body of the post &amp; more".into(),
+            ..test_post()
+        };
+        assert_eq!(
+            format_self_message(&post, &config),
+            format!(
+                "{}\n\nBody of the post &amp; more\n{}",
+                post.title,
+                format_meta(&post, &config)
+            )
+        );
+
+        let post = reddit::Post {
+            selftext: "".into(),
+            ..post
+        };
+        assert_eq!(
+            format_self_message(&post, &config),
+            format_media_caption(&post, &config)
+        );
+    }
+
+    #[test]
+    fn test_format_top_comment() {
+        let config = config::Config::default();
+        let comment = reddit::Comment {
+            id: "c1".into(),
+            author: "someone".into(),
+            body: "The best answer".into(),
+            score: 100,
+            subreddit: "AskReddit".into(),
+            permalink: "/r/AskReddit/comments/v6nu75/a_post/c1/".into(),
+            stickied: false,
+            created: 1654581100.0,
+        };
+        let post = reddit::Post {
+            top_comment: Some(comment.clone()),
+            ..test_post()
+        };
+        let top_comment = "💬 <b>u/someone</b>: The best answer";
+        assert_eq!(
+            format_link_message(&post, &config),
+            format!(
+                "{}\n\n{top_comment}",
+                format_link_message(&test_post(), &config)
+            )
+        );
+        assert_eq!(
+            format_media_caption(&post, &config),
+            format!(
+                "{}\n\n{top_comment}",
+                format_media_caption(&test_post(), &config)
+            )
+        );
+
+        // A long comment is truncated, and left out when there's no room for it
+        let post = reddit::Post {
+            top_comment: Some(reddit::Comment {
+                body: "a".repeat(2000),
+                ..comment
+            }),
+            ..post
+        };
+        let caption = format_media_caption(&post, &config);
+        assert!(caption.contains("read more"));
+        assert!(caption.chars().count() <= CAPTION_MAX_CHARS);
+        let post = reddit::Post {
+            title: "a".repeat(1000),
+            ..post
+        };
+        assert!(!format_media_caption(&post, &config).contains("u/someone"));
+    }
+
+    #[test]
+    fn test_truncate_self_text_html() {
+        let permalink = "https://www.reddit.com/r/test/comments/abc/";
+        let read_more = format!(r#"… <a href="{permalink}">read more</a>"#);
+
+        assert_eq!(
+            truncate_self_text(
+                "a".repeat(20).as_str(),
+                20,
+                permalink,
+                config::ParseMode::Html
+            ),
+            "a".repeat(20)
+        );
+        assert_eq!(
+            truncate_self_text(
+                "a".repeat(21).as_str(),
+                20,
+                permalink,
+                config::ParseMode::Html
+            ),
+            format!("{}{read_more}", "a".repeat(9))
+        );
+        // A cut entity is dropped rather than left broken
+        assert_eq!(
+            truncate_self_text(
+                "aaaaaa &amp; aaaaaaaa",
+                20,
+                permalink,
+                config::ParseMode::Html
+            ),
+            format!("aaaaaa{read_more}")
+        );
+    }
+
+    #[test]
+    fn test_format_send_failure_reason() {
+        let err = anyhow::Error::new(download::TooLargeError {
+            url: "https://i.redd.it/abcdef.jpg".into(),
+            max_bytes: 100,
+        })
+        .context("failed to download image");
+        assert_eq!(format_send_failure_reason(&err), "media too large");
+        assert_eq!(
+            format_send_failure_reason(&anyhow::anyhow!("oops")),
+            "unexpected error"
+        );
+    }
+
+    #[test]
+    fn test_format_get_summary() {
+        assert_eq!(
+            format_get_summary(
+                5,
+                &["media too large".to_string(), "media too large".to_string()]
+            ),
+            "Sent 3/5, failed 2 (media too large)"
+        );
+        assert_eq!(
+            format_get_summary(2, &["a".to_string(), "b".to_string()]),
+            "Sent 0/2, failed 2 (a, b)"
+        );
+    }
+
+    #[test]
+    fn test_format_count() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1000), "1k");
+        assert_eq!(format_count(1234), "1.2k");
+        assert_eq!(format_count(999_950), "1m");
+        assert_eq!(format_count(1500000), "1.5m");
+    }
+
+    #[test]
+    fn test_format_caption_template_default() {
+        let post = test_post();
+        let config = config::Config::default();
+        assert_eq!(
+            format_media_caption(&post, &config),
+            [
+                "Tipping a cow to trim its hooves",
+                r#"<a href="https://www.reddit.com/r/absoluteunit">/r/absoluteunit</a> [<a href="https://www.reddit.com/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">comments</a>, <a href="https://old.reddit.com/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">old</a>]"#,
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_format_meta_html_comments_link_style() {
+        let post = test_post();
+        let subreddit = r#"<a href="https://www.reddit.com/r/absoluteunit">/r/absoluteunit</a>"#;
+        let new = r#"<a href="https://www.reddit.com/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">comments</a>"#;
+        let old = r#"<a href="https://old.reddit.com/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">old</a>"#;
+        let meta = |style| {
+            let config = config::Config {
+                comments_link_style: Some(style),
+                ..Default::default()
+            };
+            format_meta(&post, &config)
+        };
+
+        assert_eq!(
+            meta(config::CommentsLinkStyle::New),
+            format!("{subreddit} [{new}]")
+        );
+        assert_eq!(
+            meta(config::CommentsLinkStyle::Old),
+            format!("{subreddit} [{old}]")
+        );
+        assert_eq!(
+            meta(config::CommentsLinkStyle::Both),
+            format!("{subreddit} [{new}, {old}]")
+        );
+        assert_eq!(meta(config::CommentsLinkStyle::None), subreddit);
+
+        // Only the custom frontend's link by default, but old can still be asked for
+        let config = config::Config {
+            links_base_url: Some("https://teddit.net".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_meta(&post, &config),
+            r#"<a href="https://teddit.net/r/absoluteunit">/r/absoluteunit</a> [<a href="https://teddit.net/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">comments</a>]"#
+        );
+        let config = config::Config {
+            comments_link_style: Some(config::CommentsLinkStyle::Both),
+            ..config
+        };
+        assert!(format_meta(&post, &config).ends_with(&format!(", {old}]")));
+    }
+
+    #[test]
+    fn test_format_caption_template_all_placeholders() {
+        let post = test_post();
+        let config = config::Config {
+            caption_template: Some(
+                "{title}|{subreddit}|{score}|{comments}|{url}|{permalink}".to_string(),
+            ),
+            links_base_url: Some("https://teddit.net".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_link_message(&post, &config),
+            [
+                r#"<a href="https://i.imgur.com/Zt6f5mB.gifv">Tipping a cow to trim its hooves</a>"#,
+                r#"<a href="https://teddit.net/r/absoluteunit">/r/absoluteunit</a>"#,
+                "1.2k",
+                "20",
+                "https://i.imgur.com/Zt6f5mB.gifv",
+                "https://teddit.net/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/",
+            ]
+            .join("|")
+        );
+    }
+
+    #[test]
+    fn test_escape_markdown_v2() {
+        assert_eq!(
+            escape_markdown_v2("1.5k_views (wow)! a*b [c]"),
+            r"1\.5k\_views \(wow\)\! a\*b \[c\]"
+        );
+        assert_eq!(escape_markdown_v2(r"a\b"), r"a\\b");
+        assert_eq!(escape_markdown_v2("plain text"), "plain text");
+    }
+
+    #[test]
+    fn test_format_link_message_markdown_v2() {
+        let post = reddit::Post {
+            title: "Q&amp;A: 1.5 cows!".into(),
+            ..test_post()
+        };
+        let config = config::Config {
+            parse_mode: config::ParseMode::MarkdownV2,
+            links_base_url: Some("https://teddit.net".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_link_message(&post, &config),
+            [
+                r"[Q&A: 1\.5 cows\!](https://i.imgur.com/Zt6f5mB.gifv)",
+                r"[/r/absoluteunit](https://teddit.net/r/absoluteunit) \[[comments](https://teddit.net/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/)\]",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_format_link_card() {
+        let post = test_post();
+        let config = config::Config::default();
+        let og = opengraph::OgMetadata {
+            title: "Cow <3".to_string(),
+            description: Some("x".repeat(2000)),
+            image: None,
+        };
+        let message = format_link_card_message(&post, &config, &og);
+        assert!(message.starts_with(&format_link_message(&post, &config)));
+        assert!(message.contains("\n\n<b>Cow &lt;3</b>\n"));
+        assert!(message.ends_with(&format!("{}…", "x".repeat(LINK_DESCRIPTION_MAX_CHARS - 1))));
+
+        let og = opengraph::OgMetadata {
+            description: None,
+            ..og
+        };
+        assert!(format_link_card_caption(&post, &config, &og).ends_with("<b>Cow &lt;3</b>"));
+    }
+
+    #[test]
+    fn test_format_caption_escapes_title() {
+        let post = reddit::Post {
+            title: "1 < 2 > 0".into(),
+            selftext: "a <b>".into(),
+            ..test_post()
+        };
+        let config = config::Config::default();
+        assert!(format_media_caption(&post, &config).starts_with("1 &lt; 2 &gt; 0\n"));
+        assert!(format_link_message(&post, &config)
+            .starts_with(r#"<a href="https://i.imgur.com/Zt6f5mB.gifv">1 &lt; 2 &gt; 0</a>"#));
+        assert!(format_self_message(&post, &config).starts_with("1 &lt; 2 &gt; 0\n\na &lt;b&gt;\n"));
+    }
+
+    #[test]
+    fn test_format_caption_template_missing_fields() {
+        let post = test_post();
+        let config = config::Config {
+            caption_template: Some("{title} ({score})".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_media_caption(&post, &config),
+            "Tipping a cow to trim its hooves (1.2k)"
+        );
+    }
+
+    #[test]
+    fn test_validate_caption_template() {
+        assert!(validate_caption_template(DEFAULT_CAPTION_TEMPLATE).is_ok());
+        assert!(validate_caption_template("{title} {upvotes} {author}")
+            .unwrap_err()
+            .starts_with("unknown placeholder(s) in caption_template: {upvotes}, {author};"));
+        assert!(validate_caption_template("{ title }").is_ok());
+    }
+
+    #[test]
+    fn test_format_post_list() {
+        assert_eq!(format_post_list(&[], None), "No posts found");
+        assert_eq!(
+            format_post_list(&[test_post(), test_post()], Some("https://teddit.net")),
+            [
+                r#"1. <a href="https://i.imgur.com/Zt6f5mB.gifv">Tipping a cow to trim its hooves</a>"#,
+                r#"👍 1.2k · 💬 20 [<a href="https://teddit.net/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">comments</a>]"#,
+                "",
+                r#"2. <a href="https://i.imgur.com/Zt6f5mB.gifv">Tipping a cow to trim its hooves</a>"#,
+                r#"👍 1.2k · 💬 20 [<a href="https://teddit.net/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/">comments</a>]"#,
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_transform_title() {
+        let transforms = vec![
+            config::TitleTransform {
+                pattern: Regex::new(r"^\[OC\]").unwrap(),
+                replacement: "".to_string(),
+            },
+            config::TitleTransform {
+                pattern: Regex::new(r"\s+").unwrap(),
+                replacement: " ".to_string(),
+            },
+        ];
+        assert_eq!(
+            transform_title("[OC]  My   cat, drawn", &transforms),
+            "My cat, drawn"
+        );
+        assert_eq!(transform_title("  Untouched  ", &[]), "Untouched");
+    }
+
+    #[test]
+    fn test_format_error_reply() {
+        let err = anyhow::Error::new(ytdlp::NotInstalledError).context("failed to download video");
+        assert_eq!(
+            format_error_reply(&err),
+            "yt-dlp is not installed, so videos can't be downloaded"
+        );
+        assert_eq!(
+            format_error_reply(&anyhow::anyhow!("database is locked")),
+            "Something went wrong"
+        );
+    }
 }
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::*;
+use regex::Regex;
+use std::time::Duration;
+use url::Url;
+
+/// How long fetching a linked page may take before the post is sent without its metadata
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OpenGraph tags are in the head of the page, so there's no need to read more than this
+const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+/// OpenGraph metadata of a linked page, used for a richer card than Telegram's own preview
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OgMetadata {
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
+
+/// Fetches the page and parses its OpenGraph metadata. None if the page is not HTML or has no
+/// og:title.
+pub async fn fetch_og_metadata(url: &str) -> Result<Option<OgMetadata>> {
+    info!("fetching metadata of {url}");
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let mut res = client.get(url).send().await?.error_for_status()?;
+    let is_html = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_lowercase().starts_with("text/html"));
+    if !is_html {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+    while let Some(bytes) = res.chunk().await? {
+        body.extend_from_slice(&bytes);
+        if body.len() >= MAX_PAGE_BYTES {
+            break;
+        }
+    }
+    let page_url = res.url().clone();
+    Ok(parse_og_metadata(
+        &String::from_utf8_lossy(&body),
+        &page_url,
+    ))
+}
+
+/// Parses OpenGraph meta tags from HTML. A relative og:image is resolved against the page url.
+fn parse_og_metadata(html: &str, page_url: &Url) -> Option<OgMetadata> {
+    lazy_static! {
+        static ref META_RE: Regex = Regex::new(r"(?i)<meta\s[^>]*>").unwrap();
+        static ref ATTR_RE: Regex =
+            Regex::new(r#"(?i)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    }
+
+    let mut title = None;
+    let mut description = None;
+    let mut image = None;
+    for tag in META_RE.find_iter(html) {
+        let mut property = None;
+        let mut content = None;
+        for caps in ATTR_RE.captures_iter(tag.as_str()) {
+            let value = caps.get(2).or_else(|| caps.get(3)).unwrap().as_str();
+            match caps[1].to_lowercase().as_str() {
+                // Some sites use name instead of property
+                "property" | "name" => property = Some(value.to_lowercase()),
+                "content" => content = Some(decode_entities(value).trim().to_string()),
+                _ => {}
+            }
+        }
+        let content = match content {
+            Some(content) if !content.is_empty() => content,
+            _ => continue,
+        };
+        match property.as_deref() {
+            Some("og:title") if title.is_none() => title = Some(content),
+            Some("og:description") if description.is_none() => description = Some(content),
+            Some("og:image") if image.is_none() => {
+                image = page_url
+                    .join(&content)
+                    .ok()
+                    .filter(|url| matches!(url.scheme(), "http" | "https"))
+                    .map(String::from)
+            }
+            _ => {}
+        }
+    }
+
+    Some(OgMetadata {
+        title: title?,
+        description,
+        image,
+    })
+}
+
+/// Decodes the entities commonly found in attribute values
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_og_metadata() {
+        let html = r#"<html><head>
+            <meta charset="utf-8">
+            <meta property="og:title" content="Tom &amp; Jerry &quot;returns&quot;" />
+            <meta content='A cat and a mouse' name="og:description">
+            <meta property="og:image" content="/images/cover.jpg">
+            </head></html>"#;
+        let page_url = Url::parse("https://example.com/articles/1").unwrap();
+        assert_eq!(
+            parse_og_metadata(html, &page_url),
+            Some(OgMetadata {
+                title: "Tom & Jerry \"returns\"".to_string(),
+                description: Some("A cat and a mouse".to_string()),
+                image: Some("https://example.com/images/cover.jpg".to_string()),
+            })
+        );
+
+        let html = r#"<meta property="og:description" content="No title">"#;
+        assert_eq!(parse_og_metadata(html, &page_url), None);
+    }
+}
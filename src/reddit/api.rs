@@ -1,18 +1,350 @@
 use super::*;
+use crate::config::REDDIT_MAX_LIMIT;
 use anyhow::{Context, Result};
-use log::{error, info};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use url::Url;
 
 static REDDIT_BASE_URL: &str = "https://www.reddit.com";
-static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+static REDDIT_OAUTH_BASE_URL: &str = "https://oauth.reddit.com";
+static REDDIT_ACCESS_TOKEN_URL: &str = "https://www.reddit.com/api/v1/access_token";
+pub static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+// Tokens are refreshed a bit before they expire so that requests in flight don't fail
+const ACCESS_TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
 
+lazy_static! {
+    // Shared by the check loop and bot commands so that they draw from the same request budget
+    static ref RATE_LIMITER: RateLimiter = RateLimiter::new(Duration::ZERO);
+    static ref CREDENTIALS: Mutex<Option<Credentials>> = Mutex::new(None);
+    static ref USER_AGENT: Mutex<String> = Mutex::new(APP_USER_AGENT.to_string());
+    static ref SUBREDDIT_ABOUT_CACHE: TtlCache<SubredditAbout> =
+        TtlCache::new(DEFAULT_SUBREDDIT_CACHE_TTL);
+    // An async lock so that concurrent requests wait for a single token request
+    static ref ACCESS_TOKEN: tokio::sync::Mutex<Option<AccessToken>> =
+        tokio::sync::Mutex::new(None);
+}
+
+/// Client id and secret of a Reddit "script" app
+#[derive(Clone)]
+struct Credentials {
+    client_id: String,
+    client_secret: Secret<String>,
+}
+
+#[derive(Debug)]
+struct AccessToken {
+    token: Secret<String>,
+    expires_at: Instant,
+}
+
+impl AccessToken {
+    fn is_valid(&self, now: Instant) -> bool {
+        now + ACCESS_TOKEN_EXPIRY_MARGIN < self.expires_at
+    }
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Makes requests to Reddit's OAuth API authenticated as the given app. Without credentials,
+/// requests are anonymous.
+pub fn set_credentials(client_id: String, client_secret: String) {
+    *CREDENTIALS.lock().unwrap() = Some(Credentials {
+        client_id,
+        client_secret: Secret::new(client_secret),
+    });
+}
+
+fn get_credentials() -> Option<Credentials> {
+    CREDENTIALS.lock().unwrap().clone()
+}
+
+/// Fetches an application-only access token with the app's credentials.
+async fn fetch_access_token(credentials: &Credentials) -> reqwest::Result<AccessToken> {
+    info!("getting reddit access token");
+    let requested_at = Instant::now();
+    let res = get_client()
+        .build()?
+        .post(REDDIT_ACCESS_TOKEN_URL)
+        .basic_auth(
+            &credentials.client_id,
+            Some(credentials.client_secret.expose_secret()),
+        )
+        .form(&[("grant_type", "client_credentials")])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<AccessTokenResponse>()
+        .await?;
+
+    Ok(AccessToken {
+        token: Secret::new(res.access_token),
+        expires_at: requested_at + Duration::from_secs(res.expires_in),
+    })
+}
+
+/// Returns a valid access token when credentials are configured, fetching a new one if the
+/// cached token has expired.
+async fn get_access_token() -> reqwest::Result<Option<Secret<String>>> {
+    let credentials = match get_credentials() {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+
+    let mut access_token = ACCESS_TOKEN.lock().await;
+    match access_token.as_ref() {
+        Some(token) if token.is_valid(Instant::now()) => {}
+        _ => *access_token = Some(fetch_access_token(&credentials).await?),
+    }
+
+    Ok(access_token.as_ref().map(|token| token.token.clone()))
+}
+
+async fn authorize(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::RequestBuilder> {
+    Ok(match get_access_token().await? {
+        Some(token) => request.bearer_auth(token.expose_secret()),
+        None => request,
+    })
+}
+
+/// Spaces out requests so that at most one starts per interval. Each caller reserves the next
+/// free slot, so waiting callers are served in order.
+struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    interval: Duration,
+    next_slot: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        RateLimiter {
+            state: Mutex::new(RateLimiterState {
+                interval,
+                next_slot: None,
+            }),
+        }
+    }
+
+    fn set_interval(&self, interval: Duration) {
+        self.state.lock().unwrap().interval = interval;
+    }
+
+    /// Reserves the next slot at or after `now` and returns when it starts.
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let slot = match state.next_slot {
+            Some(next_slot) if next_slot > now => next_slot,
+            _ => now,
+        };
+        state.next_slot = Some(slot + state.interval);
+        slot
+    }
+
+    async fn wait(&self) {
+        let slot = self.reserve(Instant::now());
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
+
+/// Sets the minimum interval between requests to Reddit.
+pub fn set_min_request_interval(interval: Duration) {
+    RATE_LIMITER.set_interval(interval);
+}
+
+pub const DEFAULT_SUBREDDIT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Remembers values for a while, so that e.g. a /sub right after /get doesn't request the same
+/// subreddit again.
+struct TtlCache<V> {
+    state: Mutex<TtlCacheState<V>>,
+}
+
+struct TtlCacheState<V> {
+    ttl: Duration,
+    entries: HashMap<String, (Instant, V)>,
+}
+
+impl<V: Clone> TtlCache<V> {
+    fn new(ttl: Duration) -> Self {
+        TtlCache {
+            state: Mutex::new(TtlCacheState {
+                ttl,
+                entries: HashMap::new(),
+            }),
+        }
+    }
+
+    fn set_ttl(&self, ttl: Duration) {
+        self.state.lock().unwrap().ttl = ttl;
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<V> {
+        let state = self.state.lock().unwrap();
+        match state.entries.get(key) {
+            Some((inserted_at, value)) if now < *inserted_at + state.ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: &str, value: V, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let ttl = state.ttl;
+        // Drop expired entries so that the cache doesn't grow with every name ever looked up
+        state
+            .entries
+            .retain(|_, (inserted_at, _)| now < *inserted_at + ttl);
+        state.entries.insert(key.to_string(), (now, value));
+    }
+}
+
+/// Sets how long subreddit lookups are cached. Zero disables caching.
+pub fn set_subreddit_cache_ttl(ttl: Duration) {
+    SUBREDDIT_ABOUT_CACHE.set_ttl(ttl);
+}
+
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// Keeps a failing subreddit from holding up the check loop for long
+const MAX_TOTAL_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+static MAX_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_MAX_RETRIES);
+
+/// Sets how many times a request that failed with a transient error is retried.
+pub fn set_max_retries(max_retries: u32) {
+    MAX_RETRIES.store(max_retries, Ordering::Relaxed);
+}
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+static REQUEST_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_REQUEST_TIMEOUT.as_millis() as u64);
+
+/// Sets how long a request may take in total, so that a hung connection can't stall the check
+/// loop. Timed out requests are retried like other transient errors.
+pub fn set_request_timeout(timeout: Duration) {
+    REQUEST_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Rate limiting and server errors are worth retrying, other errors like 404 are permanent.
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Timeouts, failed connections and transient statuses, e.g. of the access token request, are
+/// worth retrying
+fn is_transient_error(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.status().is_some_and(is_transient_status)
+}
+
+/// Exponential backoff starting from RETRY_BASE_DELAY, unless the server told how long to wait.
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after
+        .unwrap_or_else(|| RETRY_BASE_DELAY * 2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Parses the delay-seconds form of the Retry-After header.
+fn parse_retry_after(res: &reqwest::Response) -> Option<Duration> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Sends a request to Reddit once the rate limiter allows it. Requests failing with a transient
+/// error are retried with backoff. After the retries run out, the last response or error is
+/// returned.
+async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let max_retries = MAX_RETRIES.load(Ordering::Relaxed);
+    let mut total_delay = Duration::ZERO;
+    let mut attempt = 0;
+
+    let mut request = request;
+    loop {
+        // Requests without a streaming body can always be cloned
+        let retry_request = request.try_clone();
+        // Failing to get an access token is retried like a failed request
+        let result = match authorize(request).await {
+            Ok(authorized) => {
+                RATE_LIMITER.wait().await;
+                authorized.send().await
+            }
+            Err(err) => Err(err),
+        };
+        let (result, retry_after) = match result {
+            Ok(res) if is_transient_status(res.status()) => {
+                let retry_after = parse_retry_after(&res);
+                (Ok(res), retry_after)
+            }
+            Err(err) if is_transient_error(&err) => (Err(err), None),
+            result => return result,
+        };
+
+        let delay = retry_delay(attempt, retry_after);
+        let retry_request = match retry_request {
+            Some(retry_request)
+                if attempt < max_retries && total_delay + delay <= MAX_TOTAL_RETRY_DELAY =>
+            {
+                retry_request
+            }
+            _ => return result,
+        };
+
+        match &result {
+            Ok(res) => warn!(
+                "request failed with status {}, retrying in {delay:?}",
+                res.status()
+            ),
+            Err(err) => warn!("request failed: {err}, retrying in {delay:?}"),
+        }
+        tokio::time::sleep(delay).await;
+        total_delay += delay;
+        attempt += 1;
+        request = retry_request;
+    }
+}
+
+/// Base url for API requests. Authenticated requests must go to the OAuth domain.
 fn get_base_url() -> Url {
-    Url::parse(REDDIT_BASE_URL).unwrap()
+    match get_credentials() {
+        Some(_) => Url::parse(REDDIT_OAUTH_BASE_URL).unwrap(),
+        None => Url::parse(REDDIT_BASE_URL).unwrap(),
+    }
+}
+
+/// Sets the user agent of requests to Reddit, which asks for one that identifies the app and
+/// its operator
+pub fn set_user_agent(user_agent: String) {
+    *USER_AGENT.lock().unwrap() = user_agent;
 }
 
 fn get_client() -> reqwest::ClientBuilder {
-    reqwest::Client::builder().user_agent(APP_USER_AGENT)
+    reqwest::Client::builder()
+        .user_agent(USER_AGENT.lock().unwrap().as_str())
+        .timeout(Duration::from_millis(
+            REQUEST_TIMEOUT_MS.load(Ordering::Relaxed),
+        ))
 }
 
 pub fn format_url_from_path(path: &str, base_url: Option<&str>) -> String {
@@ -30,42 +362,383 @@ pub fn to_old_reddit_url(url: &str) -> String {
     url.to_string()
 }
 
+/// Rewrites the url of media on a host that privacy frontends proxy to go through the frontend at
+/// base_url. Paths of i.imgur.com are kept as is, as with rimgo, and i.redd.it is served under
+/// /img, as with libreddit. None for other hosts, which are left untouched.
+pub fn to_media_proxy_url(url: &str, base_url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let path = match url.host_str()? {
+        "i.imgur.com" => url.path().to_string(),
+        "i.redd.it" => format!("/img{}", url.path()),
+        _ => return None,
+    };
+    Some(format!("{}{path}", base_url.trim_end_matches('/')))
+}
+
+/// Whether the url points to a gif or gif-like video, such as imgur's .gifv, which Telegram should
+/// show as a looping animation
+pub fn is_animation_url(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(url) => {
+            let path = url.path().to_lowercase();
+            path.ends_with(".gif")
+                || path.ends_with(".gifv")
+                || url.host_str() == Some("gfycat.com")
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether the url is an imgur album or gallery, like imgur.com/a/xxxx, rather than a single image
+pub fn is_imgur_album_url(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(url) => {
+            matches!(
+                url.host_str(),
+                Some("imgur.com" | "www.imgur.com" | "m.imgur.com")
+            ) && (url.path().starts_with("/a/") || url.path().starts_with("/gallery/"))
+        }
+        Err(_) => false,
+    }
+}
+
 pub fn format_subreddit_url(subreddit: &str, base_url: Option<&str>) -> String {
     format_url_from_path(&format!("/r/{subreddit}"), base_url)
 }
 
+/// Splits a multireddit like `rust+golang` into its subreddits. A plain subreddit name yields
+/// itself.
+pub fn split_multireddit(subreddit: &str) -> Vec<&str> {
+    subreddit.split('+').filter(|s| !s.is_empty()).collect()
+}
+
+/// Parses a saved multireddit from a URL like `https://www.reddit.com/user/someone/m/mymulti` or
+/// a path like `u/someone/m/mymulti`. Returns it as `someone/m/mymulti`, which is how multireddits
+/// are identified in subscriptions.
+pub fn parse_multireddit(input: &str) -> Option<String> {
+    let path = match Url::parse(input) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => input.to_string(),
+    };
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+
+    match segments[..] {
+        ["user" | "u", user, "m", name, ..] => Some(format!("{user}/m/{name}")),
+        _ => None,
+    }
+}
+
+/// Parses a comment id from a comment permalink like
+/// `https://www.reddit.com/r/rust/comments/abc123/title/def456/`, a fullname like `t1_def456` or
+/// a plain id.
+pub fn parse_comment_id(input: &str) -> Option<String> {
+    let input = input.trim();
+    let id = match Url::parse(input) {
+        Ok(url) => {
+            let segments = url
+                .path_segments()?
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>();
+            match segments[..] {
+                [.., "comments", _, _, comment_id] => comment_id.to_string(),
+                _ => return None,
+            }
+        }
+        Err(_) => input.strip_prefix("t1_").unwrap_or(input).to_string(),
+    };
+    (!id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())).then_some(id)
+}
+
+/// Path of the top listing of a saved multireddit identified as `user/m/name`.
+pub fn format_multireddit_top_path(multireddit: &str) -> String {
+    format!("/user/{multireddit}/top.json")
+}
+
+async fn get_top_listing(
+    base_url: &Url,
+    path: &str,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+    extra_query: &[(&str, &str)],
+) -> Result<Vec<Post>> {
+    get_listing(base_url, path, limit, Some(time), None, extra_query).await
+}
+
+/// Gets a listing of posts. The time period is left out for listings that don't take one. With
+/// a before cursor, only the posts listed before that post are fetched, e.g. the posts newer than
+/// it in a new listing.
+async fn get_listing(
+    base_url: &Url,
+    path: &str,
+    limit: u32,
+    time: Option<&TopPostsTimePeriod>,
+    before: Option<&str>,
+    extra_query: &[(&str, &str)],
+) -> Result<Vec<Post>> {
+    let url = base_url.join(path)?;
+    let client = get_client().build()?;
+    let mut posts = Vec::new();
+    let mut before = before.map(str::to_string);
+    let mut after: Option<String> = None;
+    // Limits over REDDIT_MAX_LIMIT are fetched a page at a time, following the cursor of the
+    // previous page
+    while (posts.len() as u32) < limit {
+        let page_limit = (limit - posts.len() as u32).min(REDDIT_MAX_LIMIT);
+        let mut request = client
+            .get(url.clone())
+            .query(&[("limit", &page_limit.to_string())]);
+        if let Some(time) = time {
+            request = request.query(&[("t", &format!("{:?}", time).to_lowercase())]);
+        }
+        if let Some(before) = &before {
+            request = request.query(&[("before", before)]);
+        }
+        if let Some(after) = &after {
+            request = request.query(&[("after", after)]);
+        }
+        let request = request.query(extra_query);
+        // Checking the status keeps it in the error, e.g. 403 for a private subreddit
+        let res = send(request)
+            .await?
+            .error_for_status()?
+            .json::<ListingResponse>()
+            .await?;
+        let page_len = res.data.children.len();
+        let page = res.data.children.into_iter().map(|e| e.data);
+        let has_next_page = if before.is_some() {
+            // Each page of a before cursor comes before the ones fetched so far
+            posts.splice(0..0, page);
+            before = res.data.before;
+            before.is_some()
+        } else {
+            posts.extend(page);
+            after = res.data.after;
+            after.is_some()
+        };
+        if page_len == 0 || !has_next_page {
+            break;
+        }
+    }
+    Ok(posts)
+}
+
+/// Why a listing can't be accessed at all, as opposed to failing temporarily
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InaccessibleReason {
+    Private,
+    BannedOrMissing,
+}
+
+/// Whether an error from getting a listing means that it can't be accessed until something changes
+/// on Reddit. Reddit responds with 403 for private subreddits and 404 for banned subreddits and
+/// deleted users.
+pub fn inaccessible_listing_reason(err: &anyhow::Error) -> Option<InaccessibleReason> {
+    let status = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>())?
+        .status()?;
+    match status {
+        reqwest::StatusCode::FORBIDDEN => Some(InaccessibleReason::Private),
+        reqwest::StatusCode::NOT_FOUND => Some(InaccessibleReason::BannedOrMissing),
+        _ => None,
+    }
+}
+
 pub async fn get_subreddit_top_posts(
     subreddit: &str,
     limit: u32,
     time: &TopPostsTimePeriod,
 ) -> Result<Vec<Post>> {
-    info!("getting top posts for /r/{subreddit} limit={limit} time={time:?}");
-    let url = get_base_url()
-        .join(&format!("/r/{subreddit}/top.json"))
-        .unwrap();
+    get_subreddit_posts(subreddit, SortMode::Top, limit, time, None).await
+}
+
+/// Gets the posts of a subreddit in the given order. The time period only applies to the orders
+/// that take one. The before cursor, a post's fullname like t3_abc123, only applies to the new
+/// order, which it limits to the posts newer than that post.
+pub async fn get_subreddit_posts(
+    subreddit: &str,
+    sort: SortMode,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+    before: Option<&str>,
+) -> Result<Vec<Post>> {
+    get_subreddit_posts_from(&get_base_url(), subreddit, sort, limit, time, before).await
+}
+
+async fn get_subreddit_posts_from(
+    base_url: &Url,
+    subreddit: &str,
+    sort: SortMode,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+    before: Option<&str>,
+) -> Result<Vec<Post>> {
+    let before = before.filter(|_| sort == SortMode::New);
+    info!("getting {sort} posts for /r/{subreddit} limit={limit} time={time:?} before={before:?}");
+    let (path, time) = format_subreddit_listing(subreddit, sort, time);
+    get_listing(base_url, &path, limit, time, before, &[]).await
+}
+
+/// Path of a subreddit listing in the given order and the time period to request it with, if the
+/// order takes one. Best is only offered for the front page, so subreddits fall back to hot, which
+/// is also their default order on Reddit.
+fn format_subreddit_listing<'a>(
+    subreddit: &str,
+    sort: SortMode,
+    time: &'a TopPostsTimePeriod,
+) -> (String, Option<&'a TopPostsTimePeriod>) {
+    let sort = match sort {
+        SortMode::Best => SortMode::Hot,
+        sort => sort,
+    };
+    (
+        format!("/r/{subreddit}/{sort}.json"),
+        sort.uses_time_period().then_some(time),
+    )
+}
+
+pub async fn get_user_top_posts(
+    user: &str,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<Post>> {
+    info!("getting top posts for /u/{user} limit={limit} time={time:?}");
+    get_top_listing(
+        &get_base_url(),
+        &format!("/user/{user}/submitted.json"),
+        limit,
+        time,
+        &[("sort", "top")],
+    )
+    .await
+}
+
+pub async fn get_multireddit_top_posts(
+    multireddit: &str,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<Post>> {
+    info!("getting top posts for /u/{multireddit} limit={limit} time={time:?}");
+    get_top_listing(
+        &get_base_url(),
+        &format_multireddit_top_path(multireddit),
+        limit,
+        time,
+        &[],
+    )
+    .await
+}
+
+/// Gets top posts from a subreddit, a user's submissions or a saved multireddit, depending on
+/// source type.
+pub async fn get_top_posts(
+    source_type: SourceType,
+    name: &str,
+    limit: u32,
+    time: &TopPostsTimePeriod,
+) -> Result<Vec<Post>> {
+    match source_type {
+        SourceType::Subreddit => get_subreddit_top_posts(name, limit, time).await,
+        SourceType::User => get_user_top_posts(name, limit, time).await,
+        SourceType::Multireddit => get_multireddit_top_posts(name, limit, time).await,
+        SourceType::Comments => anyhow::bail!("r/{name} comments have no top posts"),
+    }
+}
+
+/// Gets the newest comments across all posts of a subreddit, newest first
+pub async fn get_subreddit_comments(subreddit: &str, limit: u32) -> Result<Vec<Comment>> {
+    get_subreddit_comments_from(&get_base_url(), subreddit, limit).await
+}
+
+async fn get_subreddit_comments_from(
+    base_url: &Url,
+    subreddit: &str,
+    limit: u32,
+) -> Result<Vec<Comment>> {
+    info!("getting comments of r/{subreddit}");
+    let url = base_url.join(&format!("/r/{subreddit}/comments.json"))?;
     let client = get_client().build()?;
-    let res = client
-        .get(url)
-        .query(&[
-            ("limit", &limit.to_string()),
-            ("t", &format!("{:?}", time).to_lowercase()),
-        ])
-        .send()
+    let res = send(
+        client
+            .get(url)
+            .query(&[("limit", &limit.min(REDDIT_MAX_LIMIT).to_string())]),
+    )
+    .await?
+    .error_for_status()?
+    .json::<ListingResponse<Comment>>()
+    .await?;
+    Ok(res.data.children.into_iter().map(|e| e.data).collect())
+}
+
+pub async fn get_comment(comment_id: &str) -> Result<Comment> {
+    get_comment_from(&get_base_url(), comment_id).await
+}
+
+async fn get_comment_from(base_url: &Url, comment_id: &str) -> Result<Comment> {
+    info!("getting comment id {comment_id}");
+    let url = base_url.join("/api/info.json")?;
+    let client = get_client().build()?;
+    let res = send(
+        client
+            .get(url)
+            .query(&[("id", &format!("t1_{comment_id}"))]),
+    )
+    .await?
+    .error_for_status()?
+    .json::<ListingResponse<Comment>>()
+    .await?;
+    res.data
+        .children
+        .into_iter()
+        .map(|e| e.data)
+        .next()
+        .context("no comment in response")
+}
+
+/// Gets the highest voted comment of a post, skipping comments pinned by moderators and removed
+/// ones. None when the post has no comments or they are disabled.
+pub async fn get_top_comment(post_id: &str) -> Result<Option<Comment>> {
+    get_top_comment_from(&get_base_url(), post_id).await
+}
+
+async fn get_top_comment_from(base_url: &Url, post_id: &str) -> Result<Option<Comment>> {
+    info!("getting top comment post_id={post_id}");
+    let url = base_url.join(&format!("/comments/{post_id}.json"))?;
+    let client = get_client().build()?;
+    // The response is the listing of the post itself followed by the listing of comments, which
+    // can end with a placeholder for loading more comments that doesn't parse as a comment
+    let (_, comments) =
+        send(
+            client
+                .get(url)
+                .query(&[("sort", "top"), ("limit", "5"), ("depth", "1")]),
+        )
         .await?
-        .json::<ListingResponse>()
+        .error_for_status()?
+        .json::<(serde::de::IgnoredAny, ListingResponse<serde_json::Value>)>()
         .await?;
-    let posts = res.data.children.into_iter().map(|e| e.data).collect();
-    Ok(posts)
+    Ok(comments
+        .data
+        .children
+        .into_iter()
+        .filter_map(|item| serde_json::from_value::<Comment>(item.data).ok())
+        .find(|comment| {
+            !comment.stickied && !matches!(comment.body.as_str(), "[deleted]" | "[removed]")
+        }))
 }
 
 pub async fn get_link(link_id: &str) -> Result<Post> {
+    get_link_from(&get_base_url(), link_id).await
+}
+
+async fn get_link_from(base_url: &Url, link_id: &str) -> Result<Post> {
     info!("getting link id {link_id}");
-    let url = get_base_url().join("/api/info.json")?;
+    let url = base_url.join("/api/info.json")?;
     let client = get_client().build()?;
-    let res = client
-        .get(url)
-        .query(&[("id", &format!("t3_{link_id}"))])
-        .send()
+    let res = send(client.get(url).query(&[("id", &format!("t3_{link_id}"))]))
         .await
         .context("failed to send request")?;
 
@@ -103,6 +776,8 @@ pub async fn get_link(link_id: &str) -> Result<Post> {
 pub enum SubredditAboutError {
     #[error("no such subreddit")]
     NoSuchSubreddit,
+    #[error("subreddit is private or banned")]
+    PrivateOrBanned,
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
     #[error(transparent)]
@@ -111,19 +786,849 @@ pub enum SubredditAboutError {
     IO(#[from] std::io::Error),
 }
 
+/// Gets information about a subreddit. Found subreddits are cached by name, errors are not.
 pub async fn get_subreddit_about(subreddit: &str) -> Result<SubredditAbout, SubredditAboutError> {
+    // Subreddit names are case insensitive
+    let cache_key = subreddit.to_lowercase();
+    if let Some(about) = SUBREDDIT_ABOUT_CACHE.get(&cache_key, Instant::now()) {
+        info!("using cached subreddit about for /r/{subreddit}");
+        return Ok(about);
+    }
+
     info!("getting subreddit about for /r/{subreddit}");
     let client = get_client()
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
     let url = get_base_url().join(&format!("/r/{subreddit}/about.json"))?;
-    let res = client.get(url).send().await?;
+    let res = send(client.get(url)).await?;
 
     match res.status() {
-        reqwest::StatusCode::FOUND => Err(SubredditAboutError::NoSuchSubreddit),
+        // www.reddit.com redirects to search, oauth.reddit.com responds with 404
+        reqwest::StatusCode::FOUND | reqwest::StatusCode::NOT_FOUND => {
+            Err(SubredditAboutError::NoSuchSubreddit)
+        }
+        reqwest::StatusCode::FORBIDDEN => Err(SubredditAboutError::PrivateOrBanned),
         _ => {
             let data = res.json::<SubredditAboutResponse>().await?.data;
+            SUBREDDIT_ABOUT_CACHE.insert(&cache_key, data.clone(), Instant::now());
+            Ok(data)
+        }
+    }
+}
+
+/// Searches subreddits by name and description, for suggesting alternatives to a mistyped name
+pub async fn search_subreddits(query: &str, limit: u32) -> Result<Vec<SubredditAbout>> {
+    info!("searching subreddits for {query}");
+    let client = get_client().build()?;
+    let url = get_base_url().join("/subreddits/search.json")?;
+    let res = send(
+        client
+            .get(url)
+            .query(&[("q", query), ("limit", &limit.to_string())]),
+    )
+    .await?
+    .error_for_status()?;
+    let subreddits = res
+        .json::<SubredditSearchResponse>()
+        .await?
+        .data
+        .children
+        .into_iter()
+        .map(|child| child.data)
+        .collect();
+    Ok(subreddits)
+}
+
+/// Searches posts across Reddit, or only in the subreddit when one is given
+pub async fn search_posts(query: &str, subreddit: Option<&str>, limit: u32) -> Result<Vec<Post>> {
+    search_posts_from(&get_base_url(), query, subreddit, limit).await
+}
+
+async fn search_posts_from(
+    base_url: &Url,
+    query: &str,
+    subreddit: Option<&str>,
+    limit: u32,
+) -> Result<Vec<Post>> {
+    info!("searching posts for {query} subreddit={subreddit:?}");
+    let client = get_client().build()?;
+    let path = match subreddit {
+        Some(subreddit) => format!("/r/{subreddit}/search.json"),
+        None => "/search.json".to_string(),
+    };
+    let url = base_url.join(&path)?;
+    let mut params = vec![("q", query.to_string()), ("limit", limit.to_string())];
+    if subreddit.is_some() {
+        params.push(("restrict_sr", "1".to_string()));
+    }
+    let res = send(client.get(url).query(&params))
+        .await?
+        .error_for_status()?
+        .json::<ListingResponse>()
+        .await?;
+    Ok(res.data.children.into_iter().map(|e| e.data).collect())
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Error, Debug)]
+pub enum UserAboutError {
+    #[error("no such user")]
+    NoSuchUser,
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlParseError(#[from] url::ParseError),
+}
+
+pub async fn get_user_about(user: &str) -> Result<UserAbout, UserAboutError> {
+    info!("getting user about for /u/{user}");
+    let client = get_client().build()?;
+    let url = get_base_url().join(&format!("/user/{user}/about.json"))?;
+    let res = send(client.get(url)).await?;
+
+    match res.status() {
+        reqwest::StatusCode::NOT_FOUND => Err(UserAboutError::NoSuchUser),
+        _ => {
+            let data = res.json::<UserAboutResponse>().await?.data;
+            Ok(data)
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Error, Debug)]
+pub enum MultiredditAboutError {
+    #[error("no such multireddit")]
+    NoSuchMultireddit,
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlParseError(#[from] url::ParseError),
+}
+
+/// Gets a saved multireddit identified as `user/m/name`.
+pub async fn get_multireddit_about(
+    multireddit: &str,
+) -> Result<MultiredditAbout, MultiredditAboutError> {
+    info!("getting multireddit about for /u/{multireddit}");
+    let client = get_client().build()?;
+    let url = get_base_url().join(&format!("/api/multi/user/{multireddit}"))?;
+    let res = send(client.get(url)).await?;
+
+    match res.status() {
+        reqwest::StatusCode::NOT_FOUND => Err(MultiredditAboutError::NoSuchMultireddit),
+        _ => {
+            let data = res.json::<MultiredditAboutResponse>().await?.data;
             Ok(data)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn post_json(id: &str, fields: serde_json::Value) -> serde_json::Value {
+        let mut post = serde_json::json!({
+            "id": id,
+            "created": 1654581100.0,
+            "subreddit": "pics",
+            "author": "someone",
+            "title": "A post",
+            "is_video": false,
+            "ups": 10,
+            "num_comments": 2,
+            "permalink": format!("/r/pics/comments/{id}/a_post/"),
+            "url": format!("https://www.reddit.com/r/pics/comments/{id}/a_post/"),
+            "is_self": false,
+        });
+        post.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::json!({ "kind": "t3", "data": post })
+    }
+
+    fn listing_json(children: Vec<serde_json::Value>) -> serde_json::Value {
+        serde_json::json!({ "kind": "Listing", "data": { "children": children } })
+    }
+
+    async fn start_mock_server() -> (MockServer, Url) {
+        let server = MockServer::start().await;
+        let base_url = Url::parse(&server.uri()).unwrap();
+        (server, base_url)
+    }
+
+    #[test]
+    fn test_format_subreddit_listing() {
+        let week = TopPostsTimePeriod::Week;
+        let cases = [
+            (SortMode::Top, "/r/rust/top.json", Some(&week)),
+            (
+                SortMode::Controversial,
+                "/r/rust/controversial.json",
+                Some(&week),
+            ),
+            (SortMode::Hot, "/r/rust/hot.json", None),
+            (SortMode::New, "/r/rust/new.json", None),
+            (SortMode::Rising, "/r/rust/rising.json", None),
+            (SortMode::Best, "/r/rust/hot.json", None),
+        ];
+        for (sort, path, time) in cases {
+            assert_eq!(
+                format_subreddit_listing("rust", sort, &week),
+                (path.to_string(), time),
+                "{sort}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_subreddit_posts_query() {
+        let server = MockServer::start().await;
+        let base_url = Url::parse(&server.uri()).unwrap();
+        Mock::given(method("GET"))
+            .and(path("/r/pics/controversial.json"))
+            .and(query_param("limit", "5"))
+            .and(query_param("t", "month"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(listing_json(vec![post_json(
+                    "image1",
+                    serde_json::json!({ "post_hint": "image" }),
+                )])),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/r/pics/new.json"))
+            .and(query_param("limit", "5"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing_json(vec![])))
+            .mount(&server)
+            .await;
+
+        let posts = get_subreddit_posts_from(
+            &base_url,
+            "pics",
+            SortMode::Controversial,
+            5,
+            &TopPostsTimePeriod::Month,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(posts.len(), 1);
+
+        get_subreddit_posts_from(
+            &base_url,
+            "pics",
+            SortMode::New,
+            5,
+            &TopPostsTimePeriod::Month,
+            None,
+        )
+        .await
+        .unwrap();
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests[1].url.query_pairs().any(|(key, _)| key == "t"));
+    }
+
+    #[tokio::test]
+    async fn test_get_listing_paginates() {
+        let (server, base_url) = start_mock_server().await;
+        let page = |ids: std::ops::Range<u32>, after: Option<&str>| {
+            let children = ids
+                .map(|i| post_json(&format!("post{i}"), serde_json::json!({})))
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "kind": "Listing",
+                "data": { "children": children, "after": after }
+            })
+        };
+        Mock::given(method("GET"))
+            .and(path("/r/pics/new.json"))
+            .and(query_param("limit", "100"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(0..100, Some("t3_post99"))))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/r/pics/new.json"))
+            .and(query_param("limit", "50"))
+            .and(query_param("after", "t3_post99"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(100..130, None)))
+            .mount(&server)
+            .await;
+
+        let posts = get_listing(&base_url, "/r/pics/new.json", 150, None, None, &[])
+            .await
+            .unwrap();
+        // The second page ran out before the limit, and has no cursor for a third
+        assert_eq!(posts.len(), 130);
+        assert_eq!(posts[0].id, "post0");
+        assert_eq!(posts[129].id, "post129");
+    }
+
+    #[tokio::test]
+    async fn test_get_subreddit_posts_before() {
+        let (server, base_url) = start_mock_server().await;
+        let page = |ids: &[u32], before: Option<&str>| {
+            let children = ids
+                .iter()
+                .map(|i| post_json(&format!("post{i}"), serde_json::json!({})))
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "kind": "Listing",
+                "data": { "children": children, "after": null, "before": before }
+            })
+        };
+        // Newest first, so the posts newer than post5 are on the pages before it
+        Mock::given(method("GET"))
+            .and(path("/r/pics/new.json"))
+            .and(query_param("before", "t3_post5"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&[3, 4], Some("t3_post3"))))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/r/pics/new.json"))
+            .and(query_param("before", "t3_post3"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&[1, 2], None)))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/r/pics/top.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&[0], None)))
+            .mount(&server)
+            .await;
+
+        let posts = get_subreddit_posts_from(
+            &base_url,
+            "pics",
+            SortMode::New,
+            10,
+            &TopPostsTimePeriod::Day,
+            Some("t3_post5"),
+        )
+        .await
+        .unwrap();
+        let ids = posts
+            .iter()
+            .map(|post| post.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["post1", "post2", "post3", "post4"]);
+
+        // Only the new listing is limited by the cursor
+        get_subreddit_posts_from(
+            &base_url,
+            "pics",
+            SortMode::Top,
+            10,
+            &TopPostsTimePeriod::Day,
+            Some("t3_post5"),
+        )
+        .await
+        .unwrap();
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests[2]
+            .url
+            .query_pairs()
+            .any(|(key, _)| key == "before"));
+    }
+
+    #[tokio::test]
+    async fn test_get_top_listing() {
+        let (server, base_url) = start_mock_server().await;
+        let listing = listing_json(vec![
+            post_json(
+                "image1",
+                serde_json::json!({
+                    "post_hint": "image",
+                    "url": "https://i.redd.it/abcdef.jpg",
+                }),
+            ),
+            post_json(
+                "video1",
+                serde_json::json!({
+                    "post_hint": "hosted:video",
+                    "is_video": true,
+                    "url": "https://v.redd.it/abcdef",
+                }),
+            ),
+            post_json(
+                "gallery1",
+                serde_json::json!({
+                    "is_gallery": true,
+                    "url": "https://www.reddit.com/gallery/gallery1",
+                    "gallery_data": { "items": [{ "media_id": "abc", "id": 1 }] },
+                    "media_metadata": {
+                        "abc": {
+                            "status": "valid",
+                            "e": "Image",
+                            "m": "image/jpg",
+                            "s": { "x": 640, "y": 480, "u": "https://i.redd.it/abc.jpg" }
+                        }
+                    },
+                }),
+            ),
+            post_json(
+                "self1",
+                serde_json::json!({ "is_self": true, "selftext": "Hello" }),
+            ),
+            post_json(
+                "link1",
+                serde_json::json!({
+                    "post_hint": "link",
+                    "url": "https://example.com/article",
+                }),
+            ),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/r/pics/top.json"))
+            .and(query_param("limit", "5"))
+            .and(query_param("t", "week"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing))
+            .mount(&server)
+            .await;
+
+        let posts = get_top_listing(
+            &base_url,
+            "/r/pics/top.json",
+            5,
+            &TopPostsTimePeriod::Week,
+            &[],
+        )
+        .await
+        .unwrap();
+
+        let post_types = posts
+            .iter()
+            .map(|post| (post.id.as_str(), post.post_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            post_types,
+            vec![
+                ("image1", PostType::Image),
+                ("video1", PostType::Video),
+                ("gallery1", PostType::Gallery),
+                ("self1", PostType::SelfText),
+                ("link1", PostType::Link),
+            ]
+        );
+        assert!(posts[2].gallery_media().is_some());
+        assert_eq!(posts[3].selftext, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_get_link() {
+        let (server, base_url) = start_mock_server().await;
+        let listing = listing_json(vec![post_json(
+            "abc123",
+            serde_json::json!({ "post_hint": "image", "url": "https://i.redd.it/abc123.jpg" }),
+        )]);
+        Mock::given(method("GET"))
+            .and(path("/api/info.json"))
+            .and(query_param("id", "t3_abc123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing))
+            .mount(&server)
+            .await;
+
+        let post = get_link_from(&base_url, "abc123").await.unwrap();
+        assert_eq!(post.id, "abc123");
+        assert_eq!(post.post_type, PostType::Image);
+
+        // Not mocked, so the mock server responds with 404
+        assert!(get_link_from(&base_url, "def456").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_posts() {
+        let (server, base_url) = start_mock_server().await;
+        Mock::given(method("GET"))
+            .and(path("/search.json"))
+            .and(query_param("q", "cats"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing_json(vec![
+                post_json("abc123", serde_json::json!({})),
+                post_json("def456", serde_json::json!({})),
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/r/rust/search.json"))
+            .and(query_param("q", "async"))
+            .and(query_param("restrict_sr", "1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(listing_json(vec![post_json(
+                    "ghi789",
+                    serde_json::json!({}),
+                )])),
+            )
+            .mount(&server)
+            .await;
+
+        let posts = search_posts_from(&base_url, "cats", None, 5).await.unwrap();
+        assert_eq!(
+            posts.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+            ["abc123", "def456"]
+        );
+        let posts = search_posts_from(&base_url, "async", Some("rust"), 5)
+            .await
+            .unwrap();
+        assert_eq!(posts[0].id, "ghi789");
+    }
+
+    #[tokio::test]
+    async fn test_get_top_comment() {
+        let (server, base_url) = start_mock_server().await;
+        let comment = |id: &str, body: &str, stickied: bool| {
+            serde_json::json!({ "kind": "t1", "data": {
+                "id": id,
+                "author": "someone",
+                "body": body,
+                "score": 100,
+                "subreddit": "AskReddit",
+                "permalink": format!("/r/AskReddit/comments/abc123/a_post/{id}/"),
+                "stickied": stickied,
+            }})
+        };
+        let more = serde_json::json!({ "kind": "more", "data": { "count": 10, "children": [] } });
+        Mock::given(method("GET"))
+            .and(path("/comments/abc123.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                listing_json(vec![post_json("abc123", serde_json::json!({}))]),
+                listing_json(vec![
+                    comment("c1", "Please follow the rules", true),
+                    comment("c2", "[removed]", false),
+                    comment("c3", "The best answer", false),
+                    more,
+                ]),
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/comments/def456.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                listing_json(vec![post_json("def456", serde_json::json!({}))]),
+                listing_json(vec![]),
+            ])))
+            .mount(&server)
+            .await;
+
+        let top_comment = get_top_comment_from(&base_url, "abc123")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(top_comment.id, "c3");
+        assert_eq!(top_comment.body, "The best answer");
+        assert!(get_top_comment_from(&base_url, "def456")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_subreddit_comments() {
+        let (server, base_url) = start_mock_server().await;
+        let comment = |id: &str, created: f32| {
+            serde_json::json!({ "kind": "t1", "data": {
+                "id": id,
+                "author": "someone",
+                "body": "I like async",
+                "score": 1,
+                "subreddit": "rust",
+                "permalink": format!("/r/rust/comments/abc123/a_post/{id}/"),
+                "created": created,
+            }})
+        };
+        Mock::given(method("GET"))
+            .and(path("/r/rust/comments.json"))
+            .and(query_param("limit", "100"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing_json(vec![
+                comment("c2", 1654584700.0),
+                comment("c1", 1654581100.0),
+            ])))
+            .mount(&server)
+            .await;
+
+        let comments = get_subreddit_comments_from(&base_url, "rust", 500)
+            .await
+            .unwrap();
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0].id, "c2");
+        assert_eq!(comments[1].created, 1654581100.0);
+    }
+
+    #[tokio::test]
+    async fn test_get_link_with_empty_listing() {
+        let (server, base_url) = start_mock_server().await;
+        Mock::given(method("GET"))
+            .and(path("/api/info.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing_json(vec![])))
+            .mount(&server)
+            .await;
+
+        assert!(get_link_from(&base_url, "abc123").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_top_listing_private_subreddit() {
+        let (server, base_url) = start_mock_server().await;
+        Mock::given(method("GET"))
+            .and(path("/r/secret/top.json"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .set_body_json(serde_json::json!({ "reason": "private" })),
+            )
+            .mount(&server)
+            .await;
+
+        let err = get_top_listing(
+            &base_url,
+            "/r/secret/top.json",
+            10,
+            &TopPostsTimePeriod::Day,
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<reqwest::Error>()
+                .and_then(|err| err.status()),
+            Some(reqwest::StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            inaccessible_listing_reason(&err),
+            Some(InaccessibleReason::Private)
+        );
+
+        // Not mocked, so the mock server responds with 404 like for a banned subreddit
+        let err = get_top_listing(
+            &base_url,
+            "/r/banned/top.json",
+            10,
+            &TopPostsTimePeriod::Day,
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            inaccessible_listing_reason(&err),
+            Some(InaccessibleReason::BannedOrMissing)
+        );
+        assert_eq!(inaccessible_listing_reason(&anyhow::anyhow!("oops")), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_comment() {
+        let (server, base_url) = start_mock_server().await;
+        Mock::given(method("GET"))
+            .and(path("/api/info.json"))
+            .and(query_param("id", "t1_def456"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(listing_json(vec![
+                serde_json::json!({
+                    "kind": "t1",
+                    "data": {
+                        "id": "def456",
+                        "author": "someone",
+                        "body": "This is the best comment",
+                        "score": 1234,
+                        "subreddit": "rust",
+                        "permalink": "/r/rust/comments/abc123/a_post/def456/",
+                    }
+                }),
+            ])))
+            .mount(&server)
+            .await;
+
+        let comment = get_comment_from(&base_url, "def456").await.unwrap();
+        assert_eq!(comment.author, "someone");
+        assert_eq!(comment.score, 1234);
+        assert_eq!(comment.permalink, "/r/rust/comments/abc123/a_post/def456/");
+    }
+
+    #[test]
+    fn test_parse_comment_id() {
+        assert_eq!(
+            parse_comment_id("https://www.reddit.com/r/rust/comments/abc123/a_post/def456/"),
+            Some("def456".to_string())
+        );
+        assert_eq!(
+            parse_comment_id(
+                "https://old.reddit.com/r/rust/comments/abc123/a_post/def456/?context=3"
+            ),
+            Some("def456".to_string())
+        );
+        assert_eq!(parse_comment_id("t1_def456"), Some("def456".to_string()));
+        assert_eq!(parse_comment_id("def456"), Some("def456".to_string()));
+        // A link to a post rather than a comment
+        assert_eq!(
+            parse_comment_id("https://www.reddit.com/r/rust/comments/abc123/a_post/"),
+            None
+        );
+        assert_eq!(parse_comment_id(""), None);
+    }
+
+    #[test]
+    fn test_ttl_cache() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(cache.get("pics", now), None);
+
+        cache.insert("pics", 1, now);
+        assert_eq!(cache.get("pics", now + Duration::from_secs(59)), Some(1));
+        assert_eq!(cache.get("pics", now + Duration::from_secs(60)), None);
+
+        // Expired entries are dropped on insert
+        cache.insert("rust", 2, now + Duration::from_secs(61));
+        assert_eq!(cache.state.lock().unwrap().entries.len(), 1);
+
+        cache.set_ttl(Duration::ZERO);
+        assert_eq!(cache.get("rust", now + Duration::from_secs(61)), None);
+    }
+
+    #[test]
+    fn test_rate_limiter_reserve() {
+        let limiter = RateLimiter::new(Duration::from_millis(100));
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(now), now);
+        assert_eq!(limiter.reserve(now), now + Duration::from_millis(100));
+        assert_eq!(limiter.reserve(now), now + Duration::from_millis(200));
+
+        // A request after the reserved slots have passed goes immediately
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.reserve(later), later);
+    }
+
+    #[test]
+    fn test_access_token_is_valid() {
+        let now = Instant::now();
+        let token = AccessToken {
+            token: Secret::new("token".to_string()),
+            expires_at: now + Duration::from_secs(3600),
+        };
+        assert!(token.is_valid(now));
+        assert!(!token.is_valid(now + Duration::from_secs(3590)));
+        assert!(!token.is_valid(now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0, None), Duration::from_secs(1));
+        assert_eq!(retry_delay(1, None), Duration::from_secs(2));
+        assert_eq!(retry_delay(2, None), Duration::from_secs(4));
+        assert_eq!(retry_delay(10, None), MAX_RETRY_DELAY);
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(7))),
+            Duration::from_secs(7)
+        );
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(3600))),
+            MAX_RETRY_DELAY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_transient_status() {
+        let (server, base_url) = start_mock_server().await;
+        Mock::given(method("GET"))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let client = get_client().build().unwrap();
+        let res = send(client.get(base_url.join("/flaky").unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_is_transient_status() {
+        assert!(is_transient_status(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient_status(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert!(!is_transient_status(reqwest::StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(reqwest::StatusCode::FORBIDDEN));
+        assert!(!is_transient_status(reqwest::StatusCode::OK));
+    }
+
+    #[test]
+    fn test_rate_limiter_zero_interval() {
+        let limiter = RateLimiter::new(Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(now), now);
+        assert_eq!(limiter.reserve(now), now);
+    }
+
+    #[test]
+    fn test_parse_multireddit() {
+        let expected = Some("someone/m/mymulti".to_string());
+        assert_eq!(
+            parse_multireddit("https://www.reddit.com/user/someone/m/mymulti"),
+            expected
+        );
+        assert_eq!(
+            parse_multireddit("https://old.reddit.com/user/someone/m/mymulti/top/?t=week"),
+            expected
+        );
+        assert_eq!(parse_multireddit("/u/someone/m/mymulti"), expected);
+        assert_eq!(parse_multireddit("u/someone/m/mymulti"), expected);
+        assert_eq!(parse_multireddit("u/someone"), None);
+        assert_eq!(parse_multireddit("https://www.reddit.com/r/rust"), None);
+    }
+
+    #[test]
+    fn test_is_imgur_album_url() {
+        assert!(is_imgur_album_url("https://imgur.com/a/abc123"));
+        assert!(is_imgur_album_url("https://m.imgur.com/gallery/abc123"));
+        assert!(!is_imgur_album_url("https://imgur.com/abc123"));
+        assert!(!is_imgur_album_url("https://i.imgur.com/abc123.jpg"));
+        assert!(!is_imgur_album_url("https://example.com/a/abc123"));
+    }
+
+    #[test]
+    fn test_is_animation_url() {
+        assert!(is_animation_url("https://i.imgur.com/abcdef.gifv"));
+        assert!(is_animation_url("https://i.redd.it/abcdef.gif"));
+        assert!(is_animation_url("https://gfycat.com/someanimal"));
+        assert!(!is_animation_url("https://i.redd.it/abcdef.jpg"));
+        assert!(!is_animation_url("https://v.redd.it/abcdef"));
+        assert!(!is_animation_url("not a url"));
+    }
+
+    #[test]
+    fn test_to_media_proxy_url() {
+        let base_url = "https://proxy.example/";
+        assert_eq!(
+            to_media_proxy_url("https://i.imgur.com/abcdef.jpg", base_url).as_deref(),
+            Some("https://proxy.example/abcdef.jpg")
+        );
+        assert_eq!(
+            to_media_proxy_url("https://i.redd.it/abcdef.jpg", base_url).as_deref(),
+            Some("https://proxy.example/img/abcdef.jpg")
+        );
+        assert_eq!(
+            to_media_proxy_url("https://example.com/abcdef.jpg", base_url),
+            None
+        );
+        assert_eq!(to_media_proxy_url("not a url", base_url), None);
+    }
+
+    #[test]
+    fn test_format_multireddit_top_path() {
+        assert_eq!(
+            format_multireddit_top_path("someone/m/mymulti"),
+            "/user/someone/m/mymulti/top.json"
+        );
+    }
+}
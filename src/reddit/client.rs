@@ -0,0 +1,67 @@
+use super::*;
+use anyhow::Result;
+use std::future::Future;
+
+/// The Reddit requests made when checking subscriptions for new posts. Abstracted so that tests
+/// can check subscriptions against posts of their own.
+pub trait RedditClient: Sync {
+    fn get_top_posts(
+        &self,
+        source_type: SourceType,
+        name: &str,
+        limit: u32,
+        time: &TopPostsTimePeriod,
+    ) -> impl Future<Output = Result<Vec<Post>>> + Send;
+
+    /// Posts of a subreddit in the given order, see api::get_subreddit_posts
+    fn get_subreddit_posts(
+        &self,
+        subreddit: &str,
+        sort: SortMode,
+        limit: u32,
+        time: &TopPostsTimePeriod,
+        before: Option<&str>,
+    ) -> impl Future<Output = Result<Vec<Post>>> + Send;
+
+    fn get_link(&self, link_id: &str) -> impl Future<Output = Result<Post>> + Send;
+
+    fn get_subreddit_comments(
+        &self,
+        subreddit: &str,
+        limit: u32,
+    ) -> impl Future<Output = Result<Vec<Comment>>> + Send;
+}
+
+/// Client for the Reddit API, see api.rs
+pub struct ApiClient;
+
+impl RedditClient for ApiClient {
+    async fn get_top_posts(
+        &self,
+        source_type: SourceType,
+        name: &str,
+        limit: u32,
+        time: &TopPostsTimePeriod,
+    ) -> Result<Vec<Post>> {
+        get_top_posts(source_type, name, limit, time).await
+    }
+
+    async fn get_subreddit_posts(
+        &self,
+        subreddit: &str,
+        sort: SortMode,
+        limit: u32,
+        time: &TopPostsTimePeriod,
+        before: Option<&str>,
+    ) -> Result<Vec<Post>> {
+        get_subreddit_posts(subreddit, sort, limit, time, before).await
+    }
+
+    async fn get_link(&self, link_id: &str) -> Result<Post> {
+        get_link(link_id).await
+    }
+
+    async fn get_subreddit_comments(&self, subreddit: &str, limit: u32) -> Result<Vec<Comment>> {
+        get_subreddit_comments(subreddit, limit).await
+    }
+}
@@ -1,4 +1,6 @@
 mod api;
+mod client;
 mod types;
 pub use api::*;
+pub use client::*;
 pub use types::*;
@@ -30,19 +30,98 @@ pub enum TopPostsTimePeriod {
     All,
 }
 
+impl TopPostsTimePeriod {
+    /// Length of the period in days, rounded up. None for all time.
+    pub fn days(&self) -> Option<u32> {
+        match self {
+            TopPostsTimePeriod::Hour | TopPostsTimePeriod::Day => Some(1),
+            TopPostsTimePeriod::Week => Some(7),
+            TopPostsTimePeriod::Month => Some(31),
+            TopPostsTimePeriod::Year => Some(366),
+            TopPostsTimePeriod::All => None,
+        }
+    }
+}
+
+/// Order of the posts in a subreddit listing
+#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SortMode {
+    Hot,
+    New,
+    Rising,
+    Top,
+    Controversial,
+    Best,
+}
+
+impl SortMode {
+    /// Whether the listing is limited to a time period, which is given with the `t` parameter.
+    /// Other listings ignore the parameter.
+    pub fn uses_time_period(&self) -> bool {
+        matches!(self, SortMode::Top | SortMode::Controversial)
+    }
+}
+
+/// Where a subscription's posts come from.
+#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SourceType {
+    #[default]
+    Subreddit,
+    User,
+    /// A saved multireddit, identified as `user/m/name`
+    Multireddit,
+    /// New comments of a subreddit that contain the subscription's keyword
+    Comments,
+}
+
+impl SourceType {
+    /// The prefix Reddit uses when referring to a source of this type, e.g. r/rust or u/spez.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            SourceType::Subreddit | SourceType::Comments => "r/",
+            SourceType::User | SourceType::Multireddit => "u/",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
-pub struct ListingResponse {
-    pub data: ListingResponseData,
+pub struct ListingResponse<T = Post> {
+    pub data: ListingResponseData<T>,
 }
 
 #[derive(Deserialize, Debug)]
-pub struct ListingResponseData {
-    pub children: Vec<ListingItem>,
+pub struct ListingResponseData<T = Post> {
+    pub children: Vec<ListingItem<T>>,
+    /// Cursor for the next page of the listing, None on the last page
+    pub after: Option<String>,
+    /// Cursor for the previous page of the listing, i.e. newer posts of new listings
+    #[serde(default)]
+    pub before: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
-pub struct ListingItem {
-    pub data: Post,
+pub struct ListingItem<T = Post> {
+    pub data: T,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Comment {
+    pub id: String,
+    pub author: String,
+    /// Body of the comment in markdown, with &, < and > escaped as HTML entities
+    pub body: String,
+    pub score: i32,
+    pub subreddit: String,
+    pub permalink: String,
+    /// Pinned by a moderator, e.g. an automod notice
+    #[serde(default)]
+    pub stickied: bool,
+    #[serde(default)]
+    pub created: f32,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -61,8 +140,11 @@ pub struct GalleryData {
 pub struct Media {
     pub x: u16,
     pub y: u16,
+    /// Url of a static image. Animated gallery items have gif and mp4 instead.
     #[serde(rename = "u")]
-    pub url: String,
+    pub url: Option<String>,
+    pub gif: Option<String>,
+    pub mp4: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -71,7 +153,42 @@ pub struct MediaMetadata {
     pub e: String,
     #[serde(rename = "m")]
     pub mime: String,
+    /// The source image
     pub s: Media,
+    /// Resized previews of the source image, smallest first
+    #[serde(default)]
+    pub p: Vec<Media>,
+}
+
+/// Largest width or height of a gallery image that is downloaded as is. Larger source images can
+/// exceed Telegram's 10 MB limit for photos.
+pub const MAX_IMAGE_DIMENSION: u16 = 4096;
+
+impl MediaMetadata {
+    /// The source image when it's within max_dimension, otherwise the largest preview that is.
+    /// Falls back to the source when there's no such preview.
+    pub fn best_variant(&self, max_dimension: u16) -> &Media {
+        let fits = |media: &&Media| media.x <= max_dimension && media.y <= max_dimension;
+        if fits(&&self.s) {
+            return &self.s;
+        }
+        self.p
+            .iter()
+            .filter(|media| media.url.is_some())
+            .filter(fits)
+            .max_by_key(|media| u32::from(media.x) * u32::from(media.y))
+            .unwrap_or(&self.s)
+    }
+
+    /// Whether the item is a gif, which Reddit also provides as mp4
+    pub fn is_animated(&self) -> bool {
+        self.e == "AnimatedImage"
+    }
+
+    /// Url of an animated item, preferring mp4 as it's smaller than the gif
+    pub fn animation_url(&self) -> Option<&str> {
+        self.s.mp4.as_deref().or(self.s.gif.as_deref())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -79,9 +196,11 @@ pub struct Post {
     pub id: String,
     pub created: f32,
     pub subreddit: String,
+    pub author: String,
     pub title: String,
     pub is_video: bool,
     pub ups: u32,
+    pub num_comments: u32,
     pub permalink: String,
     pub url: String,
     pub post_hint: Option<String>,
@@ -91,6 +210,15 @@ pub struct Post {
     pub crosspost_parent_list: Option<Vec<Post>>,
     pub gallery_data: Option<GalleryData>,
     pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+    /// Thumbnail image url, or a placeholder like "self" or "default" when there's none
+    pub thumbnail: Option<String>,
+    /// Body of a self post in markdown, with &, < and > escaped as HTML entities
+    pub selftext: String,
+    /// Who removed the post, e.g. "moderator" or "deleted". None if the post hasn't been removed.
+    pub removed_by_category: Option<String>,
+    /// Not part of the post listing; fetched separately when the top comment is included in
+    /// the message
+    pub top_comment: Option<Comment>,
 }
 
 impl<'de> Deserialize<'de> for Post {
@@ -103,9 +231,11 @@ impl<'de> Deserialize<'de> for Post {
             pub id: String,
             pub created: f32,
             pub subreddit: String,
+            pub author: String,
             pub title: String,
             pub is_video: bool,
             pub ups: u32,
+            pub num_comments: u32,
             pub permalink: String,
             pub url: String,
             pub post_hint: Option<String>,
@@ -114,6 +244,10 @@ impl<'de> Deserialize<'de> for Post {
             pub crosspost_parent_list: Option<Vec<Post>>,
             pub gallery_data: Option<GalleryData>,
             pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+            pub thumbnail: Option<String>,
+            #[serde(default)]
+            pub selftext: String,
+            pub removed_by_category: Option<String>,
         }
 
         impl PostHelper {
@@ -124,7 +258,11 @@ impl<'de> Deserialize<'de> for Post {
                     let path = url.path();
                     let is_imgur_gif = host == "i.imgur.com" && path.ends_with(".gifv");
                     let is_gfycat_gif = host == "gfycat.com";
-                    Ok(is_imgur_gif || is_gfycat_gif)
+                    // Reddit hosted videos, which yt-dlp downloads with the separate audio stream
+                    // merged in. Crossposts and some older posts have is_video=false even though
+                    // the url points to v.redd.it.
+                    let is_reddit_video = host == "v.redd.it";
+                    Ok(is_imgur_gif || is_gfycat_gif || is_reddit_video)
                 };
 
                 // If the post is a crosspost with a video, it can be downloaded with post.url as
@@ -164,9 +302,11 @@ impl<'de> Deserialize<'de> for Post {
             id: helper.id,
             created: helper.created,
             subreddit: helper.subreddit,
+            author: helper.author,
             title: helper.title,
             is_video: helper.is_video,
             ups: helper.ups,
+            num_comments: helper.num_comments,
             permalink: helper.permalink,
             url: helper.url,
             post_hint: helper.post_hint,
@@ -176,6 +316,10 @@ impl<'de> Deserialize<'de> for Post {
             post_type,
             gallery_data: helper.gallery_data,
             media_metadata: helper.media_metadata,
+            thumbnail: helper.thumbnail,
+            selftext: helper.selftext,
+            removed_by_category: helper.removed_by_category,
+            top_comment: None,
         })
     }
 }
@@ -188,6 +332,44 @@ impl Post {
     pub(crate) fn format_old_permalink_url(&self) -> String {
         to_old_reddit_url(&format_url_from_path(&self.permalink, None))
     }
+
+    /// Identifies the content of the post, so that crossposts and reposts of the same link can be
+    /// recognized. Crossposts resolve to the content of the original post, others to their
+    /// normalized url.
+    pub fn content_id(&self) -> String {
+        match self
+            .crosspost_parent_list
+            .as_ref()
+            .and_then(|list| list.first())
+        {
+            Some(parent) => parent.content_id(),
+            None => normalize_url(&self.url),
+        }
+    }
+
+    /// Whether the post has been deleted by its author or removed by moderators since it was
+    /// posted. Reddit keeps such posts around with their text replaced.
+    pub fn is_deleted(&self) -> bool {
+        self.author == "[deleted]"
+            || self.removed_by_category.is_some()
+            || matches!(self.selftext.as_str(), "[deleted]" | "[removed]")
+    }
+
+    /// Whether the post was created more than max_age_hours before now
+    pub fn is_older_than(&self, max_age_hours: u32, now: chrono::DateTime<chrono::Utc>) -> bool {
+        now.timestamp() as f64 - self.created as f64 > f64::from(max_age_hours) * 3600.0
+    }
+
+    /// Gallery order and media of a gallery post. None when Reddit hasn't provided them, which
+    /// happens with galleries that are deleted or still processing.
+    pub fn gallery_media(&self) -> Option<(&GalleryData, &HashMap<String, MediaMetadata>)> {
+        match (&self.gallery_data, &self.media_metadata) {
+            (Some(gallery_data), Some(media_metadata)) if !gallery_data.items.is_empty() => {
+                Some((gallery_data, media_metadata))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -196,7 +378,391 @@ pub struct SubredditAboutResponse {
 }
 
 #[derive(Deserialize, Debug)]
+pub struct SubredditSearchResponse {
+    pub data: SubredditSearchResponseData,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubredditSearchResponseData {
+    pub children: Vec<SubredditAboutResponse>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct SubredditAbout {
     pub display_name: String,
     pub display_name_prefixed: String,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct UserAboutResponse {
+    pub data: UserAbout,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserAbout {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MultiredditAboutResponse {
+    pub data: MultiredditAbout,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MultiredditAbout {
+    /// e.g. `/user/someone/m/mymulti/`
+    pub path: String,
+}
+
+/// Drops the fragment, a trailing slash and the www. prefix, which don't change what the url
+/// points to
+fn normalize_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            if let Some(host) = url.host_str().and_then(|h| h.strip_prefix("www.")) {
+                let host = host.to_string();
+                // Fails only for urls that can't have a host, which is not the case here
+                let _ = url.set_host(Some(&host));
+            }
+            url.to_string().trim_end_matches('/').to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+impl Post {
+    /// A video post for tests, which set the fields they care about with struct update syntax
+    pub fn test_default() -> Post {
+        Post {
+            id: "v6nu75".into(),
+            created: 1654581100.0,
+            post_hint: Some("link".into()),
+            subreddit: "absoluteunit".into(),
+            author: "someone".into(),
+            title: "Tipping a cow to trim its hooves".into(),
+            is_self: false,
+            is_video: false,
+            is_gallery: Some(false),
+            gallery_data: None,
+            media_metadata: None,
+            ups: 469,
+            num_comments: 20,
+            permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
+            post_type: PostType::Video,
+            crosspost_parent_list: None,
+            thumbnail: None,
+            selftext: "".into(),
+            removed_by_category: None,
+            top_comment: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize_url("https://www.reddit.com/r/pics/comments/abc123/title/"),
+            "https://reddit.com/r/pics/comments/abc123/title"
+        );
+        assert_eq!(
+            normalize_url("https://i.redd.it/abcdef.jpg#fragment"),
+            "https://i.redd.it/abcdef.jpg"
+        );
+        assert_eq!(
+            normalize_url("https://www.youtube.com/watch?v=abc"),
+            "https://youtube.com/watch?v=abc"
+        );
+    }
+
+    #[test]
+    fn test_gallery_post_without_media_metadata() {
+        let post: Post = serde_json::from_value(serde_json::json!({
+            "id": "abc123",
+            "created": 1654581100.0,
+            "subreddit": "pics",
+            "author": "someone",
+            "title": "A gallery",
+            "is_video": false,
+            "ups": 10,
+            "num_comments": 2,
+            "permalink": "/r/pics/comments/abc123/a_gallery/",
+            "url": "https://www.reddit.com/gallery/abc123",
+            "is_self": false,
+            "is_gallery": true,
+            "gallery_data": null,
+            "media_metadata": null
+        }))
+        .unwrap();
+
+        assert_eq!(post.post_type, PostType::Gallery);
+        assert!(post.gallery_media().is_none());
+    }
+
+    #[test]
+    fn test_media_metadata_best_variant() {
+        let media = |x: u16, y: u16| serde_json::json!({ "x": x, "y": y, "u": format!("https://preview.redd.it/{x}x{y}.jpg") });
+        let media_metadata = |source: serde_json::Value| -> MediaMetadata {
+            serde_json::from_value(serde_json::json!({
+                "status": "valid",
+                "e": "Image",
+                "m": "image/jpg",
+                "s": source,
+                "p": [media(108, 72), media(640, 426), media(1080, 720)],
+            }))
+            .unwrap()
+        };
+
+        let small = media_metadata(media(2000, 1333));
+        assert_eq!(small.best_variant(MAX_IMAGE_DIMENSION).x, 2000);
+
+        let huge = media_metadata(media(9000, 6000));
+        assert_eq!(huge.best_variant(MAX_IMAGE_DIMENSION).x, 1080);
+        assert_eq!(huge.best_variant(700).x, 640);
+        // No preview is small enough
+        assert_eq!(huge.best_variant(100).x, 9000);
+
+        // Previews are missing from older posts
+        let without_previews: MediaMetadata = serde_json::from_value(serde_json::json!({
+            "status": "valid",
+            "e": "Image",
+            "m": "image/jpg",
+            "s": media(9000, 6000),
+        }))
+        .unwrap();
+        assert_eq!(without_previews.best_variant(MAX_IMAGE_DIMENSION).x, 9000);
+    }
+
+    #[test]
+    fn test_media_metadata_animated() {
+        let media_metadata: MediaMetadata = serde_json::from_value(serde_json::json!({
+            "status": "valid",
+            "e": "AnimatedImage",
+            "m": "image/gif",
+            "s": {
+                "x": 480,
+                "y": 270,
+                "gif": "https://i.redd.it/abc.gif",
+                "mp4": "https://preview.redd.it/abc.gif?format=mp4&amp;s=def"
+            },
+            "p": [{ "x": 108, "y": 60, "u": "https://preview.redd.it/abc.gif?width=108" }],
+        }))
+        .unwrap();
+
+        assert!(media_metadata.is_animated());
+        assert_eq!(
+            media_metadata.animation_url(),
+            Some("https://preview.redd.it/abc.gif?format=mp4&amp;s=def")
+        );
+    }
+
+    #[test]
+    fn test_content_id_of_crosspost() {
+        let post = |id: &str, url: &str, parents: Option<Vec<Post>>| -> Post {
+            serde_json::from_value::<Post>(serde_json::json!({
+                "id": id,
+                "created": 1654581100.0,
+                "subreddit": "pics",
+                "author": "someone",
+                "title": "A picture",
+                "is_video": false,
+                "ups": 10,
+                "num_comments": 2,
+                "permalink": format!("/r/pics/comments/{id}/a_picture/"),
+                "url": url,
+                "is_self": false,
+            }))
+            .map(|mut post| {
+                post.crosspost_parent_list = parents;
+                post
+            })
+            .unwrap()
+        };
+
+        let original = post("abc123", "https://i.redd.it/abcdef.jpg", None);
+        let crosspost = post(
+            "def456",
+            "https://i.redd.it/abcdef.jpg",
+            Some(vec![original.clone()]),
+        );
+        let other = post("ghi789", "https://i.redd.it/other.jpg", None);
+
+        assert_eq!(original.content_id(), "https://i.redd.it/abcdef.jpg");
+        assert_eq!(crosspost.content_id(), original.content_id());
+        assert_ne!(other.content_id(), original.content_id());
+    }
+
+    #[test]
+    fn test_reddit_hosted_video_post() {
+        let post: Post = serde_json::from_value(serde_json::json!({
+            "id": "xk3d2a",
+            "created": 1663329211.0,
+            "subreddit": "aww",
+            "author": "someone",
+            "title": "A video",
+            "is_video": false,
+            "ups": 5421,
+            "num_comments": 87,
+            "permalink": "/r/aww/comments/xk3d2a/a_video/",
+            "url": "https://v.redd.it/3mb6ql0xk3o91",
+            "is_self": false,
+            "thumbnail": "https://b.thumbs.redditmedia.com/abcdef.jpg"
+        }))
+        .unwrap();
+
+        assert_eq!(post.post_type, PostType::Video);
+        assert_eq!(post.url, "https://v.redd.it/3mb6ql0xk3o91");
+    }
+
+    #[test]
+    fn test_post_is_older_than() {
+        let post: Post = serde_json::from_value(serde_json::json!({
+            "id": "xk3d2a",
+            "created": 1663329600.0,
+            "subreddit": "aww",
+            "author": "someone",
+            "title": "A post",
+            "is_video": false,
+            "ups": 5421,
+            "num_comments": 87,
+            "permalink": "/r/aww/comments/xk3d2a/a_post/",
+            "url": "https://www.reddit.com/r/aww/comments/xk3d2a/a_post/",
+            "is_self": true,
+        }))
+        .unwrap();
+        let created = chrono::TimeZone::timestamp_opt(&chrono::Utc, 1663329600, 0).unwrap();
+
+        assert!(!post.is_older_than(24, created + chrono::Duration::hours(23)));
+        assert!(post.is_older_than(24, created + chrono::Duration::hours(25)));
+        assert!(post.is_older_than(0, created + chrono::Duration::hours(1)));
+    }
+
+    #[test]
+    fn test_post_is_deleted() {
+        let post: Post = serde_json::from_value(serde_json::json!({
+            "id": "xk3d2a",
+            "created": 1663329600.0,
+            "subreddit": "aww",
+            "author": "[deleted]",
+            "title": "A post",
+            "is_video": false,
+            "ups": 5421,
+            "num_comments": 87,
+            "permalink": "/r/aww/comments/xk3d2a/a_post/",
+            "url": "https://www.reddit.com/r/aww/comments/xk3d2a/a_post/",
+            "is_self": true,
+            "selftext": "[deleted]",
+        }))
+        .unwrap();
+        assert!(post.is_deleted());
+
+        let post = Post {
+            author: "someone".into(),
+            selftext: "[removed]".into(),
+            ..post
+        };
+        assert!(post.is_deleted());
+
+        let post = Post {
+            selftext: "Hello".into(),
+            ..post
+        };
+        assert!(!post.is_deleted());
+
+        // Link posts keep their url when removed, which only removed_by_category tells
+        let post = Post {
+            removed_by_category: Some("moderator".into()),
+            ..post
+        };
+        assert!(post.is_deleted());
+    }
+
+    #[test]
+    fn test_post_type() {
+        let post_type = |fields: serde_json::Value| -> PostType {
+            let mut post = serde_json::json!({
+                "id": "abc123",
+                "created": 1654581100.0,
+                "subreddit": "pics",
+                "author": "someone",
+                "title": "A post",
+                "is_video": false,
+                "ups": 10,
+                "num_comments": 2,
+                "permalink": "/r/pics/comments/abc123/a_post/",
+                "url": "https://www.reddit.com/r/pics/comments/abc123/a_post/",
+                "is_self": false,
+            });
+            post.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            serde_json::from_value::<Post>(post).unwrap().post_type
+        };
+
+        assert_eq!(
+            post_type(serde_json::json!({ "post_hint": "image" })),
+            PostType::Image
+        );
+        assert_eq!(
+            post_type(serde_json::json!({ "post_hint": "hosted:video", "is_video": true })),
+            PostType::Video
+        );
+        assert_eq!(
+            post_type(serde_json::json!({
+                "post_hint": "link",
+                "url": "https://i.imgur.com/abcdef.gifv"
+            })),
+            PostType::Video
+        );
+        assert_eq!(
+            post_type(serde_json::json!({ "url": "https://gfycat.com/abcdef" })),
+            PostType::Video
+        );
+        assert_eq!(
+            post_type(serde_json::json!({ "post_hint": "link" })),
+            PostType::Link
+        );
+        // YouTube videos are too long to be worth downloading
+        assert_eq!(
+            post_type(serde_json::json!({
+                "post_hint": "rich:video",
+                "url": "https://www.youtube.com/watch?v=abc"
+            })),
+            PostType::Link
+        );
+        assert_eq!(
+            post_type(serde_json::json!({ "is_self": true, "post_hint": "self" })),
+            PostType::SelfText
+        );
+        assert_eq!(
+            post_type(serde_json::json!({ "is_gallery": true })),
+            PostType::Gallery
+        );
+        assert_eq!(post_type(serde_json::json!({})), PostType::Unknown);
+        // Crossposts of videos are downloaded through the crosspost's url
+        assert_eq!(
+            post_type(serde_json::json!({
+                "crosspost_parent_list": [{
+                    "id": "def456",
+                    "created": 1654581000.0,
+                    "subreddit": "videos",
+                    "author": "someone_else",
+                    "title": "A video",
+                    "is_video": true,
+                    "ups": 100,
+                    "num_comments": 20,
+                    "permalink": "/r/videos/comments/def456/a_video/",
+                    "url": "https://v.redd.it/abcdef",
+                    "is_self": false,
+                }]
+            })),
+            PostType::Video
+        );
+    }
+}
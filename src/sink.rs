@@ -0,0 +1,264 @@
+use crate::{
+    config::{Config, WebhookFormat},
+    reddit,
+    types::MediaOptions,
+};
+use anyhow::Result;
+use log::*;
+use std::future::Future;
+use teloxide::Bot;
+
+/// Somewhere new posts are delivered to
+pub trait PostSink: Sync {
+    /// Returns whether the post was delivered, as a post may be skipped, e.g. when it has been
+    /// deleted in the meantime
+    fn send_post(&self, post: &reddit::Post) -> impl Future<Output = Result<bool>> + Send;
+}
+
+/// Sends posts to a chat, or a forum topic of it
+pub struct TelegramSink<'a> {
+    pub config: &'a Config,
+    pub tg: &'a Bot,
+    pub chat_id: i64,
+    pub thread_id: Option<i32>,
+    pub media_options: &'a MediaOptions,
+}
+
+impl PostSink for TelegramSink<'_> {
+    fn send_post(&self, post: &reddit::Post) -> impl Future<Output = Result<bool>> + Send {
+        crate::handle_new_post(
+            self.config,
+            self.tg,
+            self.chat_id,
+            self.thread_id,
+            post,
+            self.media_options,
+        )
+    }
+}
+
+/// Pushes posts to a webhook, Slack's included
+pub struct WebhookSink<'a> {
+    /// Identifies the webhook in logs without revealing its url
+    pub name: String,
+    pub url: &'a str,
+    pub format: WebhookFormat,
+    pub links_base_url: Option<&'a str>,
+}
+
+impl PostSink for WebhookSink<'_> {
+    async fn send_post(&self, post: &reddit::Post) -> Result<bool> {
+        match self.format {
+            WebhookFormat::Json => {
+                crate::webhook::send_post(self.url, post, self.links_base_url).await?
+            }
+            WebhookFormat::Slack => {
+                crate::slack::send_post(self.url, post, self.links_base_url).await?
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The webhooks that the posts of a subscription are pushed to: the global ones, and the one
+/// given to the subscription with webhook=<name>
+pub fn webhook_sinks<'a>(config: &'a Config, webhook: Option<&str>) -> Vec<WebhookSink<'a>> {
+    let links_base_url = config.links_base_url.as_deref();
+    let mut sinks = vec![];
+    if let Some(url) = &config.slack_webhook_url {
+        sinks.push(WebhookSink {
+            name: "slack_webhook_url".into(),
+            url: url.expose_secret(),
+            format: WebhookFormat::Slack,
+            links_base_url,
+        });
+    }
+    for (i, url) in config.webhook_urls.iter().enumerate() {
+        sinks.push(WebhookSink {
+            name: format!("webhook_urls.{i}"),
+            url: url.expose_secret(),
+            format: WebhookFormat::Json,
+            links_base_url,
+        });
+    }
+    if let Some(name) = webhook {
+        if !config.webhooks.contains_key(name) {
+            // The webhook may have been removed from the config after subscribing
+            warn!("subscription has a webhook that is not in the config name={name}");
+        }
+    }
+    for (name, webhook_config) in &config.webhooks {
+        if webhook_config.global || webhook == Some(name.as_str()) {
+            sinks.push(WebhookSink {
+                name: format!("webhooks.{name}"),
+                url: webhook_config.url.expose_secret(),
+                format: webhook_config.format,
+                links_base_url,
+            });
+        }
+    }
+    sinks
+}
+
+/// Pushes the post to the webhooks that haven't got it yet, as the same post may be found by the
+/// subscriptions of several chats. Failures are only logged, so that one failing webhook doesn't
+/// affect the others or Telegram.
+pub async fn send_to_webhooks(config: &Config, sinks: &[WebhookSink<'_>], post: &reddit::Post) {
+    for sink in sinks {
+        let claimed = crate::db::Database::open(config)
+            .and_then(|db| db.claim_webhook_post(&sink.name, &post.id));
+        match claimed {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!(
+                    "post already pushed to webhook name={} post_id={}",
+                    sink.name, post.id
+                );
+                continue;
+            }
+            Err(e) => {
+                error!(
+                    "failed to claim webhook post name={} post_id={}: {e}",
+                    sink.name, post.id
+                );
+                continue;
+            }
+        }
+        if let Err(e) = sink.send_post(post).await {
+            error!(
+                "failed to push post to webhook name={} post_id={}: {e}",
+                sink.name, post.id
+            );
+        }
+    }
+}
+
+/// Sends the post to the chat and, once it has been delivered there, pushes it to the webhooks.
+/// Returns whether the post was delivered to the chat.
+pub async fn deliver_post(
+    telegram: &TelegramSink<'_>,
+    webhooks: &[WebhookSink<'_>],
+    post: &reddit::Post,
+) -> Result<bool> {
+    if !telegram.send_post(post).await? {
+        return Ok(false);
+    }
+    send_to_webhooks(telegram.config, webhooks, post).await;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WebhookConfig;
+    use wiremock::{
+        matchers::{body_partial_json, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn webhook_config(url: &str, format: WebhookFormat, global: bool) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string().into(),
+            format,
+            global,
+        }
+    }
+
+    #[test]
+    fn test_webhook_sinks() {
+        let config = Config {
+            slack_webhook_url: Some("https://hooks.slack.com/services/x".to_string().into()),
+            webhooks: [
+                (
+                    "archive".to_string(),
+                    webhook_config("https://example.com/archive", WebhookFormat::Json, false),
+                ),
+                (
+                    "team".to_string(),
+                    webhook_config("https://example.com/team", WebhookFormat::Slack, false),
+                ),
+                (
+                    "all".to_string(),
+                    webhook_config("https://example.com/all", WebhookFormat::Json, true),
+                ),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let names = |webhook| {
+            let mut names = webhook_sinks(&config, webhook)
+                .into_iter()
+                .map(|sink| sink.name)
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(names(None), ["slack_webhook_url", "webhooks.all"]);
+        assert_eq!(
+            names(Some("archive")),
+            ["slack_webhook_url", "webhooks.all", "webhooks.archive"]
+        );
+        // A webhook removed from the config is skipped
+        assert_eq!(names(Some("gone")), ["slack_webhook_url", "webhooks.all"]);
+
+        let team = webhook_sinks(&config, Some("team"))
+            .into_iter()
+            .find(|sink| sink.name == "webhooks.team")
+            .unwrap();
+        assert_eq!(team.url, "https://example.com/team");
+        assert_eq!(team.format, WebhookFormat::Slack);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_sink_send_post() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({ "id": "abc" })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let sink = WebhookSink {
+            name: "webhooks.archive".into(),
+            url: &server.uri(),
+            format: WebhookFormat::Json,
+            links_base_url: None,
+        };
+        let post = reddit::Post {
+            id: "abc".into(),
+            ..reddit::Post::test_default()
+        };
+        assert!(sink.send_post(&post).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_send_to_webhooks_pushes_post_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let tmp_dir = tempdir::TempDir::new("tgreddit").unwrap();
+        let config = Config {
+            db_path: tmp_dir.path().join("data.db3"),
+            ..Default::default()
+        };
+        crate::db::Database::open(&config)
+            .unwrap()
+            .migrate()
+            .unwrap();
+        let sinks = [WebhookSink {
+            name: "webhook_urls.0".into(),
+            url: &server.uri(),
+            format: WebhookFormat::Json,
+            links_base_url: None,
+        }];
+        let post = reddit::Post::test_default();
+
+        // As when two chats are subscribed to the subreddit the post is in
+        send_to_webhooks(&config, &sinks, &post).await;
+        send_to_webhooks(&config, &sinks, &post).await;
+    }
+}
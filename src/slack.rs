@@ -0,0 +1,108 @@
+use crate::messages::PostSummary;
+use crate::reddit;
+use anyhow::Result;
+use log::*;
+use serde_json::json;
+
+/// Slack's mrkdwn treats these characters as control characters.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn format_link(url: &str, text: &str) -> String {
+    format!("<{url}|{}>", escape(text))
+}
+
+/// Formats a post as an incoming webhook payload. Media is linked rather than uploaded.
+pub fn format_payload(post: &reddit::Post, links_base_url: Option<&str>) -> serde_json::Value {
+    let summary = PostSummary::new(post, links_base_url);
+    let text = format!(
+        "*{}*\n{} · {}",
+        format_link(&summary.url, &summary.title),
+        format_link(&summary.subreddit_url, &summary.subreddit),
+        format_link(&summary.permalink, "comments"),
+    );
+    let mut section = json!({
+        "type": "section",
+        "text": { "type": "mrkdwn", "text": text },
+    });
+    if let Some(thumbnail_url) = &summary.thumbnail_url {
+        section["accessory"] = json!({
+            "type": "image",
+            "image_url": thumbnail_url,
+            "alt_text": summary.title,
+        });
+    }
+
+    json!({
+        // Shown in notifications
+        "text": summary.title,
+        "blocks": [section],
+    })
+}
+
+pub async fn send_post(
+    webhook_url: &str,
+    post: &reddit::Post,
+    links_base_url: Option<&str>,
+) -> Result<()> {
+    let payload = format_payload(post, links_base_url);
+    crate::webhook::client()?
+        .post(webhook_url)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    info!("post sent to slack post_id={}", post.id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_post() -> reddit::Post {
+        reddit::Post {
+            title: "Cows & <hooves>".into(),
+            permalink: "/r/absoluteunit/comments/v6nu75/cows/".into(),
+            thumbnail: Some("https://b.thumbs.redditmedia.com/abc.jpg".into()),
+            ..reddit::Post::test_default()
+        }
+    }
+
+    #[test]
+    fn test_format_payload() {
+        assert_eq!(
+            format_payload(&test_post(), None),
+            json!({
+                "text": "Cows & <hooves>",
+                "blocks": [{
+                    "type": "section",
+                    "text": {
+                        "type": "mrkdwn",
+                        "text": "*<https://i.imgur.com/Zt6f5mB.gifv|Cows &amp; &lt;hooves&gt;>*\n<https://www.reddit.com/r/absoluteunit|/r/absoluteunit> · <https://www.reddit.com/r/absoluteunit/comments/v6nu75/cows/|comments>",
+                    },
+                    "accessory": {
+                        "type": "image",
+                        "image_url": "https://b.thumbs.redditmedia.com/abc.jpg",
+                        "alt_text": "Cows & <hooves>",
+                    },
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_format_payload_without_thumbnail() {
+        let mut post = test_post();
+        post.thumbnail = Some("self".into());
+        let payload = format_payload(&post, Some("https://teddit.net"));
+        assert!(payload["blocks"][0].get("accessory").is_none());
+        assert_eq!(
+            payload["blocks"][0]["text"]["text"],
+            "*<https://i.imgur.com/Zt6f5mB.gifv|Cows &amp; &lt;hooves&gt;>*\n<https://teddit.net/r/absoluteunit|/r/absoluteunit> · <https://teddit.net/r/absoluteunit/comments/v6nu75/cows/|comments>"
+        );
+    }
+}
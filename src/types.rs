@@ -1,26 +1,305 @@
-use crate::reddit::{PostType, TopPostsTimePeriod};
-use std::path::PathBuf;
+use crate::reddit::{PostType, SortMode, SourceType, TopPostsTimePeriod};
+use chrono::NaiveTime;
+use std::{fmt, path::PathBuf, str::FromStr};
+use strum_macros::{Display, EnumString};
 
 #[derive(Debug)]
 pub struct Video {
     pub path: PathBuf,
     pub width: u16,
     pub height: u16,
+    /// File size in bytes
+    pub size: u64,
+    pub thumbnail: Option<PathBuf>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Subscription {
     pub chat_id: i64,
+    pub source_type: SourceType,
+    /// Name of the subreddit, or the username when source_type is User
     pub subreddit: String,
     pub limit: Option<u32>,
     pub time: Option<TopPostsTimePeriod>,
+    /// Order of the subreddit listing that is checked, top when not given
+    pub sort: Option<SortMode>,
     pub filter: Option<PostType>,
+    pub max_media_mb: Option<u32>,
+    pub ytdlp_format: Option<String>,
+    pub group_images: Option<bool>,
+    pub include_top_comment: Option<bool>,
+    pub max_age_hours: Option<u32>,
+    pub digest: Option<DigestMode>,
+    /// Forum topic of the chat that posts are sent to
+    pub message_thread_id: Option<i32>,
+    /// Only comments containing this, ignoring case, are sent. Used with SourceType::Comments.
+    pub keyword: Option<String>,
+    /// Name of a webhook of the config that posts are also pushed to
+    pub webhook: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Set when the source became inaccessible, after which it's no longer checked
+    pub paused: bool,
+    /// Set after the first successful check, so that a subscription whose listing was empty then
+    /// doesn't treat its first posts as the initial ones
+    pub initialized: bool,
+    /// Fullname of the newest post seen with sort=new, from which the next check fetches only
+    /// newer posts
+    pub last_seen_fullname: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SubscriptionArgs {
+    pub source_type: SourceType,
+    /// Name of the subreddit, or the username when source_type is User
     pub subreddit: String,
     pub limit: Option<u32>,
     pub time: Option<TopPostsTimePeriod>,
+    /// Order of the subreddit listing that is checked, top when not given
+    pub sort: Option<SortMode>,
     pub filter: Option<PostType>,
+    pub max_media_mb: Option<u32>,
+    pub ytdlp_format: Option<String>,
+    /// Send new image posts found on one check as albums
+    pub group_images: Option<bool>,
+    /// Append the top comment of a post to its message
+    pub include_top_comment: Option<bool>,
+    /// Skip posts created longer than this many hours ago
+    pub max_age_hours: Option<u32>,
+    /// Collect new posts into a digest instead of sending them as they are found
+    pub digest: Option<DigestMode>,
+    /// Forum topic the subscription was created in. Not part of the command syntax.
+    pub message_thread_id: Option<i32>,
+    /// Only comments containing this are sent, with SourceType::Comments
+    pub keyword: Option<String>,
+    /// Name of a webhook of the config that posts are also pushed to
+    pub webhook: Option<String>,
+}
+
+#[cfg(test)]
+impl Subscription {
+    /// A subscription to r/pics without options, for tests to set the fields they care about
+    /// with struct update syntax
+    pub fn test_default() -> Subscription {
+        Subscription {
+            chat_id: 1,
+            source_type: SourceType::Subreddit,
+            subreddit: "pics".into(),
+            limit: None,
+            time: None,
+            sort: None,
+            filter: None,
+            max_media_mb: None,
+            ytdlp_format: None,
+            group_images: None,
+            include_top_comment: None,
+            max_age_hours: None,
+            digest: None,
+            message_thread_id: None,
+            keyword: None,
+            webhook: None,
+            created_at: Default::default(),
+            paused: false,
+            initialized: false,
+            last_seen_fullname: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl SubscriptionArgs {
+    /// Arguments of /sub pics without options, for tests to set the fields they care about with
+    /// struct update syntax
+    pub fn test_default() -> SubscriptionArgs {
+        SubscriptionArgs {
+            source_type: SourceType::Subreddit,
+            subreddit: "pics".into(),
+            limit: None,
+            time: None,
+            sort: None,
+            filter: None,
+            max_media_mb: None,
+            ytdlp_format: None,
+            group_images: None,
+            include_top_comment: None,
+            max_age_hours: None,
+            digest: None,
+            message_thread_id: None,
+            keyword: None,
+            webhook: None,
+        }
+    }
+}
+
+/// How often the posts of a digest subscription are sent, as a single message
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum DigestMode {
+    Daily,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub subscriptions: u64,
+    pub chats: u64,
+    pub seen_posts: u64,
+}
+
+/// Activity of a single chat
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChatStats {
+    pub subscriptions: u64,
+    pub seen_posts: u64,
+    pub seen_posts_last_day: u64,
+    /// Subreddit with the most seen posts along with the count
+    pub most_active_subreddit: Option<(String, u64)>,
+}
+
+/// Settings that apply to all subscriptions and commands in a chat
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ChatSettings {
+    pub max_media_mb: Option<u32>,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// Daily window like 23:00-07:00 during which new posts are held back. The window may wrap
+/// around midnight. The end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time in quiet hours: {time}"))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("quiet hours should be like 23:00-07:00, got {s}"))?;
+        Ok(QuietHours {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// A post held back during quiet hours, to be sent when they end
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingPost {
+    pub chat_id: i64,
+    pub post_id: String,
+    pub message_thread_id: Option<i32>,
+    pub media_options: MediaOptions,
+    /// The webhook of the subscription that found the post
+    pub webhook: Option<String>,
+}
+
+/// A post that failed to be sent, to be retried on the following checks
+#[derive(Debug, PartialEq, Eq)]
+pub struct FailedPost {
+    pub chat_id: i64,
+    pub post_id: String,
+    pub message_thread_id: Option<i32>,
+    pub media_options: MediaOptions,
+    /// The webhook of the subscription that found the post
+    pub webhook: Option<String>,
+    /// How many times sending has failed so far
+    pub attempts: u32,
+}
+
+/// A post delivered to a chat, as listed in the chat's RSS feed
+#[derive(Debug, PartialEq, Eq)]
+pub struct FeedItem {
+    pub post_id: String,
+    pub subreddit: String,
+    pub title: String,
+    pub permalink: String,
+    pub url: String,
+    pub seen_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Media uploaded to Telegram earlier, which can be sent again by its file_id
+#[derive(Debug, PartialEq, Eq)]
+pub struct CachedFile {
+    pub file_id: String,
+    /// File size in bytes
+    pub size: u64,
+}
+
+/// Options that affect how a post's media is sent, resolved from subscription, chat and global
+/// settings
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MediaOptions {
+    pub max_media_mb: Option<u32>,
+    /// yt-dlp format selector used for downloading videos
+    pub ytdlp_format: Option<String>,
+    /// Show the progress of downloading a video in a status message, for commands whose reply is
+    /// waited for
+    pub show_progress: bool,
+    /// Append the top comment of the post to its message
+    pub include_top_comment: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_quiet_hours_parse() {
+        let quiet_hours: QuietHours = "23:00-07:30".parse().unwrap();
+        assert_eq!(quiet_hours.start, time("23:00"));
+        assert_eq!(quiet_hours.end, time("07:30"));
+        assert_eq!(quiet_hours.to_string(), "23:00-07:30");
+        assert!("23:00".parse::<QuietHours>().is_err());
+        assert!("25:00-07:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn test_quiet_hours_contains() {
+        let quiet_hours: QuietHours = "13:00-15:00".parse().unwrap();
+        assert!(quiet_hours.contains(time("13:00")));
+        assert!(quiet_hours.contains(time("14:59")));
+        assert!(!quiet_hours.contains(time("15:00")));
+        assert!(!quiet_hours.contains(time("12:59")));
+    }
+
+    #[test]
+    fn test_quiet_hours_contains_wrapping_midnight() {
+        let quiet_hours: QuietHours = "23:00-07:00".parse().unwrap();
+        assert!(quiet_hours.contains(time("23:00")));
+        assert!(quiet_hours.contains(time("00:00")));
+        assert!(quiet_hours.contains(time("06:59")));
+        assert!(!quiet_hours.contains(time("07:00")));
+        assert!(!quiet_hours.contains(time("12:00")));
+        assert!(!quiet_hours.contains(time("22:59")));
+    }
 }
@@ -0,0 +1,142 @@
+use crate::reddit::{self, PostType};
+use anyhow::Result;
+use log::*;
+use serde_json::json;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT.as_millis() as u64);
+
+/// Sets how long pushing a post to a webhook may take, so that a slow endpoint can't hold up
+/// delivery to the other chats for long
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Client for pushing posts to webhooks, Slack's included
+pub fn client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_millis(TIMEOUT_MS.load(Ordering::Relaxed)))
+        .build()?)
+}
+
+/// Urls of the post's media, in gallery order for galleries. Empty for link and self posts.
+fn media_urls(post: &reddit::Post) -> Vec<String> {
+    match post.post_type {
+        PostType::Image | PostType::Video => vec![post.url.clone()],
+        PostType::Gallery => match post.gallery_media() {
+            Some((gallery_data, media_metadata_map)) => gallery_data
+                .items
+                .iter()
+                .filter_map(|item| media_metadata_map.get(&item.media_id))
+                .filter_map(|media_metadata| {
+                    if media_metadata.is_animated() {
+                        media_metadata.animation_url()
+                    } else {
+                        media_metadata.s.url.as_deref()
+                    }
+                })
+                .map(|url| url.replace("&amp;", "&"))
+                .collect(),
+            None => vec![],
+        },
+        PostType::Link | PostType::SelfText | PostType::Unknown => vec![],
+    }
+}
+
+/// Formats a post as a generic JSON payload for services like Discord, n8n or custom endpoints
+pub fn format_payload(post: &reddit::Post, links_base_url: Option<&str>) -> serde_json::Value {
+    json!({
+        "id": post.id,
+        "subreddit": post.subreddit,
+        "author": post.author,
+        "title": post.title,
+        "post_type": post.post_type.to_string(),
+        "url": post.url,
+        "permalink": post.format_permalink_url(links_base_url),
+        "score": post.ups,
+        "num_comments": post.num_comments,
+        "created": post.created as i64,
+        "media_urls": media_urls(post),
+    })
+}
+
+pub async fn send_post(
+    webhook_url: &str,
+    post: &reddit::Post,
+    links_base_url: Option<&str>,
+) -> Result<()> {
+    let payload = format_payload(post, links_base_url);
+    client()?
+        .post(webhook_url)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    info!("post sent to webhook post_id={}", post.id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_post() -> reddit::Post {
+        reddit::Post {
+            post_hint: Some("image".into()),
+            title: "Cows & <hooves>".into(),
+            permalink: "/r/absoluteunit/comments/v6nu75/cows/".into(),
+            url: "https://i.redd.it/abc.jpg".into(),
+            post_type: PostType::Image,
+            ..reddit::Post::test_default()
+        }
+    }
+
+    #[test]
+    fn test_format_payload() {
+        assert_eq!(
+            format_payload(&test_post(), None),
+            json!({
+                "id": "v6nu75",
+                "subreddit": "absoluteunit",
+                "author": "someone",
+                "title": "Cows & <hooves>",
+                "post_type": "image",
+                "url": "https://i.redd.it/abc.jpg",
+                "permalink": "https://www.reddit.com/r/absoluteunit/comments/v6nu75/cows/",
+                "score": 469,
+                "num_comments": 20,
+                "created": 1654581120,
+                "media_urls": ["https://i.redd.it/abc.jpg"],
+            })
+        );
+
+        let post = reddit::Post {
+            post_type: PostType::Link,
+            url: "https://example.com/article".into(),
+            ..test_post()
+        };
+        assert_eq!(format_payload(&post, None)["media_urls"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_send_post_times_out() {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        set_timeout(Duration::from_millis(100));
+        let err = send_post(&server.uri(), &test_post(), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast::<reqwest::Error>().unwrap().is_timeout());
+    }
+}
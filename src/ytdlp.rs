@@ -1,77 +1,362 @@
 use anyhow::Result;
 use duct::cmd;
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::{debug, error, info};
 use std::{
-    ffi::OsString,
+    ffi::{OsStr, OsString},
     fs,
-    io::{BufRead, BufReader},
-    path::Path,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::types::*;
+use crate::{config::Config, types::*};
 
 use regex::Regex;
 use tempdir::TempDir;
+use thiserror::Error;
 
-fn make_ytdlp_args(output: &Path, url: &str) -> Vec<OsString> {
-    vec![
+/// Format selector for retrying a video that was too large: prefer at most 480p, otherwise the
+/// worst quality available
+pub const REDUCED_FORMAT: &str = "bv*[height<=480]+ba/b[height<=480]/wv*+ba/w";
+
+const THUMBNAIL_FILE_STEM: &str = "thumbnail";
+
+/// Options that would break finding the downloaded files and parse_dimensions_from_path
+const RESERVED_ARGS: &[&str] = &["-o", "--output", "-P", "--paths"];
+
+#[derive(Error, Debug)]
+#[error("yt-dlp is not installed or not in PATH")]
+pub struct NotInstalledError;
+
+#[derive(Error, Debug)]
+#[error("yt-dlp did not finish in {0:?} and was killed")]
+pub struct TimeoutError(Duration);
+
+#[derive(Error, Debug)]
+#[error("yt-dlp was killed because the bot is shutting down")]
+pub struct CancelledError;
+
+/// How often a running download checks whether the bot is shutting down
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set on shutdown. Running downloads are killed and new ones aren't started.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Kills running yt-dlp processes, so that shutdown doesn't wait for them or leave them behind
+pub fn cancel_downloads() {
+    CANCELLED.store(true, Ordering::Relaxed);
+}
+
+/// Why a download was stopped before yt-dlp exited
+#[derive(Debug, PartialEq, Eq)]
+enum Stop {
+    TimedOut,
+    Cancelled,
+}
+
+/// Waits until the download is done, or returns why it should be stopped
+fn watch_download(done_rx: &mpsc::Receiver<()>, deadline: Instant) -> Option<Stop> {
+    loop {
+        if CANCELLED.load(Ordering::Relaxed) {
+            return Some(Stop::Cancelled);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Some(Stop::TimedOut);
+        }
+        match done_rx.recv_timeout(remaining.min(CANCEL_POLL_INTERVAL)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            // Done, or the downloading thread is gone
+            _ => return None,
+        }
+    }
+}
+
+/// Checks that yt-dlp can be run by running `yt-dlp --version`
+pub fn check_installed(config: &Config) -> Result<String> {
+    let version = cmd(config.ytdlp_path(), ["--version"])
+        .stderr_null()
+        .read()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => anyhow::Error::new(NotInstalledError),
+            _ => anyhow::Error::new(err),
+        })?;
+    Ok(version)
+}
+
+/// Checks that extra arguments configured for yt-dlp don't change where and with what name the
+/// files are written
+pub fn validate_extra_args(extra_args: &[String]) -> Result<(), String> {
+    match extra_args.iter().find(|arg| {
+        RESERVED_ARGS
+            .iter()
+            .any(|reserved| arg == reserved || arg.starts_with(&format!("{reserved}=")))
+    }) {
+        Some(arg) => Err(format!(
+            "ytdlp_extra_args must not contain {arg}, the output of yt-dlp is set by tgreddit"
+        )),
+        None => Ok(()),
+    }
+}
+
+fn make_ytdlp_args(
+    output: &Path,
+    url: &str,
+    format: Option<&str>,
+    cookies_file: Option<&Path>,
+    extra_args: &[String],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![];
+    if let Some(format) = format {
+        args.push("-f".into());
+        args.push(format.into());
+    }
+    if let Some(cookies_file) = cookies_file {
+        args.push("--cookies".into());
+        args.push(cookies_file.into());
+    }
+    args.extend(extra_args.iter().map(OsString::from));
+    args.extend([
         "--paths".into(),
         output.into(),
         "--output".into(),
         // To get telegram show correct aspect ratio for video, we need the dimensions and simplest
         // way to make that happens is have yt-dlp write them in the filename.
         "video_%(width)sx%(height)s.%(ext)s".into(),
+        // Telegram's automatic thumbnail is often a black frame, so send the one Reddit has
+        "--write-thumbnail".into(),
+        // Progress on separate lines rather than updated in place, for parse_progress
+        "--newline".into(),
+        "--output".into(),
+        format!("thumbnail:{THUMBNAIL_FILE_STEM}.%(ext)s").into(),
         url.into(),
-    ]
+    ]);
+    args
 }
 
-/// Downloads given url with yt-dlp and returns path to video
-pub fn download(url: &str) -> Result<(Video, TempDir)> {
-    let tmp_dir = TempDir::new("tgreddit")?;
-    let tmp_path = tmp_dir.path();
-    let ytdlp_args = make_ytdlp_args(tmp_dir.path(), url);
+fn make_album_args(
+    output: &Path,
+    url: &str,
+    cookies_file: Option<&Path>,
+    extra_args: &[String],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![];
+    if let Some(cookies_file) = cookies_file {
+        args.push("--cookies".into());
+        args.push(cookies_file.into());
+    }
+    args.extend(extra_args.iter().map(OsString::from));
+    args.extend([
+        "--paths".into(),
+        output.into(),
+        "--output".into(),
+        // The index keeps the images in album order when the files are sorted by name
+        "%(playlist_index)03d_%(id)s.%(ext)s".into(),
+        "--newline".into(),
+        url.into(),
+    ]);
+    args
+}
 
-    info!("running yt-dlp with arguments {:?}", ytdlp_args);
-    let duct_exp = cmd("yt-dlp", ytdlp_args).stderr_to_stdout();
-    let reader = match duct_exp.reader() {
-        Ok(child) => child,
-        Err(err) => {
-            error!("failed to run yt-dlp:\n{}", err);
-            return Err(anyhow::anyhow!(err));
+/// The arguments with the path of the cookies file hidden, so that it doesn't end up in logs
+fn redact_args(args: &[OsString]) -> Vec<OsString> {
+    let mut redacted = args.to_vec();
+    for i in 1..redacted.len() {
+        if args[i - 1] == "--cookies" {
+            redacted[i] = "<redacted>".into();
         }
-    };
+    }
+    redacted
+}
 
-    let lines = BufReader::new(reader).lines();
-    for line_result in lines {
-        match line_result {
-            Ok(line) => info!("{line}"),
-            Err(_) => {
-                error!("failed to read yt-dlp output");
-                return Err(anyhow::anyhow!("failed to read yt-dlp output"));
-            }
-        }
+/// Download progress in percent from a line of yt-dlp output like
+/// `[download]  45.3% of 10.00MiB at 1.00MiB/s ETA 00:05`
+fn parse_progress(line: &str) -> Option<f32> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^\[download\]\s+(\d+(?:\.\d+)?)%").unwrap();
+    }
+
+    RE.captures(line)?.get(1)?.as_str().parse().ok()
+}
+
+/// Downloads given url with yt-dlp and returns path to video. The format selector, if given, is
+/// passed to yt-dlp as is. on_progress is called with the download progress in percent. Videos
+/// with separate audio go from 0 to 100 once for each stream.
+pub fn download(
+    config: &Config,
+    url: &str,
+    format: Option<&str>,
+    on_progress: impl FnMut(f32),
+) -> Result<(Video, TempDir)> {
+    if CANCELLED.load(Ordering::Relaxed) {
+        return Err(CancelledError.into());
     }
+    let tmp_dir = crate::download::create_tmp_dir()?;
+    let tmp_path = tmp_dir.path();
+    let ytdlp_args = make_ytdlp_args(
+        tmp_dir.path(),
+        url,
+        format,
+        config.ytdlp_cookies_file.as_deref(),
+        &config.ytdlp_extra_args,
+    );
+    run(config, ytdlp_args, on_progress)?;
 
-    // yt-dlp is expected to write a single file, which is the video, to tmp_path
-    let video_path = fs::read_dir(tmp_path)
+    // yt-dlp is expected to write the video and possibly a thumbnail to tmp_path
+    let (thumbnail_paths, video_paths): (Vec<PathBuf>, Vec<PathBuf>) = fs::read_dir(tmp_path)
         .expect("could not read files in temp dir")
         .map(|de| de.unwrap().path())
+        .partition(|path| path.file_stem() == Some(OsStr::new(THUMBNAIL_FILE_STEM)));
+    let video_path = video_paths
+        .into_iter()
         .next()
         .expect("video file in temp dir");
+    let thumbnail = thumbnail_paths.into_iter().find(|path| is_jpeg_path(path));
+    if thumbnail.is_none() {
+        info!("no usable thumbnail for video, uploading without one");
+    }
 
     let dimensions =
         parse_dimensions_from_path(&video_path).expect("video filename should have dimensions");
 
+    let size = fs::metadata(&video_path)?.len();
+
     let video = Video {
         path: video_path,
         width: dimensions.0,
         height: dimensions.1,
+        size,
+        thumbnail,
     };
 
     Ok((video, tmp_dir))
 }
 
+/// Downloads the images of an album, such as imgur.com/a/xxxx, and returns their paths in album
+/// order. Videos in the album are left out.
+pub fn download_album(config: &Config, url: &str) -> Result<(Vec<PathBuf>, TempDir)> {
+    if CANCELLED.load(Ordering::Relaxed) {
+        return Err(CancelledError.into());
+    }
+    let tmp_dir = crate::download::create_tmp_dir()?;
+    let ytdlp_args = make_album_args(
+        tmp_dir.path(),
+        url,
+        config.ytdlp_cookies_file.as_deref(),
+        &config.ytdlp_extra_args,
+    );
+    run(config, ytdlp_args, |_| {})?;
+
+    let mut image_paths = fs::read_dir(tmp_dir.path())?
+        .map(|de| de.map(|de| de.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| is_image_path(path))
+        .collect::<Vec<_>>();
+    // File names start with the zero padded index of the image in the album
+    image_paths.sort();
+    Ok((image_paths, tmp_dir))
+}
+
+/// Runs yt-dlp until it exits, reporting progress of downloads as it goes. yt-dlp is killed if it
+/// takes longer than the configured timeout or the bot shuts down.
+fn run(config: &Config, ytdlp_args: Vec<OsString>, mut on_progress: impl FnMut(f32)) -> Result<()> {
+    info!(
+        "running yt-dlp with arguments {:?}",
+        redact_args(&ytdlp_args)
+    );
+    let duct_exp = cmd(config.ytdlp_path(), ytdlp_args).stderr_to_stdout();
+    let reader = match duct_exp.reader() {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            error!(
+                "failed to run yt-dlp, install it and make sure it is in PATH of tgreddit or set ytdlp_path"
+            );
+            return Err(NotInstalledError.into());
+        }
+        Err(err) => {
+            error!("failed to run yt-dlp:\n{}", err);
+            return Err(anyhow::anyhow!(err));
+        }
+    };
+
+    // Kills yt-dlp unless reading its output finishes, i.e. it exits, within the timeout or
+    // before the bot shuts down
+    let timeout = config.ytdlp_timeout();
+    let reader = Arc::new(reader);
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let watchdog = {
+        let reader = reader.clone();
+        thread::spawn(move || {
+            let stop = watch_download(&done_rx, Instant::now() + timeout);
+            if stop.is_some() {
+                if let Err(err) = reader.kill() {
+                    error!("failed to kill yt-dlp: {err}");
+                }
+            }
+            stop
+        })
+    };
+
+    let lines = BufReader::new(&*reader).lines();
+    let mut read_result = Ok(());
+    for line_result in lines {
+        match line_result {
+            Ok(line) => match parse_progress(&line) {
+                Some(percent) => {
+                    debug!("{line}");
+                    on_progress(percent);
+                }
+                None => info!("{line}"),
+            },
+            Err(_) => {
+                read_result = Err(anyhow::anyhow!("failed to read yt-dlp output"));
+                break;
+            }
+        }
+    }
+    // The watchdog has either killed yt-dlp already or stops now
+    let _ = done_tx.send(());
+    match watchdog.join() {
+        Ok(Some(Stop::TimedOut)) => {
+            error!("yt-dlp timed out after {timeout:?}");
+            return Err(TimeoutError(timeout).into());
+        }
+        Ok(Some(Stop::Cancelled)) => {
+            info!("yt-dlp was killed due to shutdown");
+            return Err(CancelledError.into());
+        }
+        _ => {}
+    }
+    if let Err(err) = read_result {
+        error!("failed to read yt-dlp output");
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Images that Telegram accepts as photos
+fn is_image_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| matches!(ext.to_lowercase().as_str(), "jpg" | "jpeg" | "png" | "webp"))
+        .unwrap_or(false)
+}
+
+/// Telegram only accepts JPEG thumbnails
+fn is_jpeg_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))
+        .unwrap_or_default()
+}
+
 fn parse_dimensions_from_path(path: &Path) -> Option<(u16, u16)> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"_(?P<width>\d+)x(?P<height>\d+)\.").unwrap();
@@ -87,8 +372,115 @@ fn parse_dimensions_from_path(path: &Path) -> Option<(u16, u16)> {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_dimensions_from_path;
-    use std::path::Path;
+    use super::{
+        is_image_path, is_jpeg_path, make_album_args, make_ytdlp_args, parse_dimensions_from_path,
+        parse_progress, redact_args, validate_extra_args, watch_download, Stop, REDUCED_FORMAT,
+    };
+    use std::{
+        ffi::OsString,
+        path::Path,
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_make_ytdlp_args_with_format() {
+        let args = make_ytdlp_args(Path::new("/tmp"), "https://v.redd.it/foo", None, None, &[]);
+        assert!(!args.contains(&OsString::from("-f")));
+
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://v.redd.it/foo",
+            Some(REDUCED_FORMAT),
+            None,
+            &[],
+        );
+        assert_eq!(args[0], "-f");
+        assert_eq!(args[1], REDUCED_FORMAT);
+        assert_eq!(args.last().unwrap(), "https://v.redd.it/foo");
+
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://v.redd.it/foo",
+            Some("bestvideo[height<=720]+bestaudio/best"),
+            None,
+            &[],
+        );
+        assert_eq!(args[0], "-f");
+        assert_eq!(args[1], "bestvideo[height<=720]+bestaudio/best");
+        // Dimensions in the filename are still needed by parse_dimensions_from_path
+        assert!(args.contains(&OsString::from("video_%(width)sx%(height)s.%(ext)s")));
+    }
+
+    #[test]
+    fn test_make_ytdlp_args_with_extra_args() {
+        let extra_args = vec![
+            "--cookies".to_string(),
+            "/data/cookies.txt".to_string(),
+            "--concurrent-fragments=4".to_string(),
+        ];
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://v.redd.it/foo",
+            Some(REDUCED_FORMAT),
+            None,
+            &extra_args,
+        );
+        assert_eq!(args[2], "--cookies");
+        assert_eq!(args[3], "/data/cookies.txt");
+        assert_eq!(args[4], "--concurrent-fragments=4");
+        assert_eq!(args.last().unwrap(), "https://v.redd.it/foo");
+    }
+
+    #[test]
+    fn test_make_ytdlp_args_with_cookies_file() {
+        let args = make_ytdlp_args(
+            Path::new("/tmp"),
+            "https://v.redd.it/foo",
+            None,
+            Some(Path::new("/data/cookies.txt")),
+            &[],
+        );
+        assert_eq!(args[0], "--cookies");
+        assert_eq!(args[1], "/data/cookies.txt");
+
+        let redacted = redact_args(&args);
+        assert_eq!(redacted[0], "--cookies");
+        assert_eq!(redacted[1], "<redacted>");
+        assert!(!redacted.contains(&OsString::from("/data/cookies.txt")));
+        assert_eq!(redacted.last().unwrap(), "https://v.redd.it/foo");
+    }
+
+    #[test]
+    fn test_make_album_args() {
+        let args = make_album_args(
+            Path::new("/tmp"),
+            "https://imgur.com/a/abc123",
+            None,
+            &["--concurrent-fragments=4".to_string()],
+        );
+        assert_eq!(args[0], "--concurrent-fragments=4");
+        assert!(args.contains(&OsString::from("%(playlist_index)03d_%(id)s.%(ext)s")));
+        assert_eq!(args.last().unwrap(), "https://imgur.com/a/abc123");
+    }
+
+    #[test]
+    fn test_is_image_path() {
+        assert!(is_image_path(Path::new("/tmp/001_abc.jpg")));
+        assert!(is_image_path(Path::new("/tmp/002_def.PNG")));
+        assert!(!is_image_path(Path::new("/tmp/003_ghi.mp4")));
+        assert!(!is_image_path(Path::new("/tmp/004_jkl")));
+    }
+
+    #[test]
+    fn test_validate_extra_args() {
+        assert!(validate_extra_args(&[]).is_ok());
+        assert!(validate_extra_args(&["--cookies".into(), "cookies.txt".into()]).is_ok());
+        assert!(validate_extra_args(&["--output-na-placeholder".into(), "x".into()]).is_ok());
+        assert!(validate_extra_args(&["-o".into(), "%(title)s.%(ext)s".into()]).is_err());
+        assert!(validate_extra_args(&["--output=%(title)s.%(ext)s".into()]).is_err());
+        assert!(validate_extra_args(&["--paths".into(), "/tmp".into()]).is_err());
+    }
 
     #[test]
     fn test_parse_dimensions_from_path() {
@@ -102,4 +494,41 @@ mod tests {
             None,
         );
     }
+
+    #[test]
+    fn test_is_jpeg_path() {
+        assert!(is_jpeg_path(Path::new("/tmp/thumbnail.jpg")));
+        assert!(is_jpeg_path(Path::new("/tmp/thumbnail.JPEG")));
+        assert!(!is_jpeg_path(Path::new("/tmp/thumbnail.webp")));
+        assert!(!is_jpeg_path(Path::new("/tmp/thumbnail")));
+    }
+
+    #[test]
+    fn test_watch_download() {
+        let (done_tx, done_rx) = mpsc::channel();
+        done_tx.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(watch_download(&done_rx, deadline), None);
+
+        let (_done_tx, done_rx) = mpsc::channel();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(watch_download(&done_rx, deadline), Some(Stop::TimedOut));
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        assert_eq!(
+            parse_progress("[download]  45.3% of 10.00MiB at  1.00MiB/s ETA 00:05"),
+            Some(45.3)
+        );
+        assert_eq!(
+            parse_progress("[download] 100% of 10.00MiB in 00:10"),
+            Some(100.0)
+        );
+        assert_eq!(
+            parse_progress("[download] Destination: /tmp/video_1280x720.mp4"),
+            None
+        );
+        assert_eq!(parse_progress("[info] Downloading 1 format(s): 0"), None);
+    }
 }